@@ -0,0 +1,112 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::prelude::SmallRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+use kvs::{BTreeEngine, KvStore, KvsEngine, LsmEngine, SledKvsEngine};
+
+/// Writes 4096 keys into a fresh store of each engine.
+fn set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_bench");
+
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| set_keys(&store),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::new(sled::open(&temp_dir).unwrap()), temp_dir)
+            },
+            |(store, _temp_dir)| set_keys(&store),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| set_keys(&store),
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("btree", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (BTreeEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| set_keys(&store),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn set_keys<E: KvsEngine>(store: &E) {
+    for i in 1..(1 << 12) {
+        store.set(format!("key{}", i), "value".to_string()).unwrap();
+    }
+}
+
+/// Reads random keys out of stores holding 2^8 to 2^20 keys.
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_bench");
+
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
+            get_keys(b, &store, *i);
+        });
+    }
+
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("sled_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = SledKvsEngine::new(sled::open(&temp_dir).unwrap());
+            get_keys(b, &store, *i);
+        });
+    }
+
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("lsm_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = LsmEngine::open(temp_dir.path()).unwrap();
+            get_keys(b, &store, *i);
+        });
+    }
+
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(format!("btree_{}", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = BTreeEngine::open(temp_dir.path()).unwrap();
+            get_keys(b, &store, *i);
+        });
+    }
+}
+
+fn get_keys<E: KvsEngine>(b: &mut criterion::Bencher, store: &E, i: u32) {
+    for key_i in 1..(1 << i) {
+        store
+            .set(format!("key{}", key_i), "value".to_string())
+            .unwrap();
+    }
+
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| store.get(format!("key{}", rng.gen_range(1, 1 << i))))
+}
+
+criterion_group!(benches, set_bench, get_bench);
+criterion_main!(benches);
@@ -0,0 +1,360 @@
+use serde::de::DeserializeOwned;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::auth::Credentials;
+use crate::codec::AsyncTransport;
+use crate::client::{get_result, remove_result, set_result};
+use crate::common::{
+    AuthResponse, ChangesResponse, GetManyResponse, GetResponse, ListNamespacesResponse,
+    NamespaceResponse, RemoveManyResponse, RemoveResponse, Request, SetManyResponse, SetResponse,
+    WatchResponse,
+};
+use crate::engines::{Change, Event, WatchTarget};
+use crate::protocol::{Features, Protocol};
+use crate::{KvsError, Result};
+
+type TcpTransport = AsyncTransport<OwnedReadHalf, OwnedWriteHalf>;
+
+/// Key value store client whose requests are futures.
+///
+/// It negotiates the protocol like `KvsClient` and works with both servers.
+/// Requests on one client are answered in order, so each method borrows the
+/// client until its response arrives.
+pub struct AsyncKvsClient {
+    transport: TcpTransport,
+}
+
+impl AsyncKvsClient {
+    /// Connect to `addr` to access a server
+    ///
+    /// See `KvsClient::connect`.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let tcp = TcpStream::connect(addr).await?;
+        let peer_addr = tcp.peer_addr()?;
+        let (reader, writer) = tcp.into_split();
+        match AsyncTransport::connect(reader, writer).await? {
+            Some(transport) => Ok(AsyncKvsClient { transport }),
+            // servers only speaking JSON close the connection on the handshake
+            None => AsyncKvsClient::connect_json(peer_addr).await,
+        }
+    }
+
+    /// Connect to `addr` with the JSON protocol, without a handshake
+    pub async fn connect_json<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        Ok(AsyncKvsClient {
+            transport: AsyncTransport::json(reader, writer),
+        })
+    }
+
+    /// Connect to `addr`, then authenticate with `credentials`
+    pub async fn connect_with_credentials<A: ToSocketAddrs>(
+        addr: A,
+        credentials: Credentials,
+    ) -> Result<Self> {
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        client.authenticate(credentials).await?;
+        Ok(client)
+    }
+
+    /// Returns the protocol spoken with the server.
+    pub fn protocol(&self) -> Protocol {
+        self.transport.protocol()
+    }
+
+    /// Returns the protocol features negotiated with the server.
+    pub fn features(&self) -> Features {
+        self.transport.features()
+    }
+
+    /// Authenticate the connection, replacing the scope granted before
+    ///
+    /// See `KvsClient::authenticate`.
+    pub async fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        match self.request(&Request::Authenticate(credentials)).await? {
+            AuthResponse::Ok(_) => Ok(()),
+            AuthResponse::Err(msg) => Err(KvsError::Unauthenticated(msg)),
+        }
+    }
+
+    /// Get the value of a given key from the server
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.send_get(Request::Get {
+            namespace: None,
+            key,
+        })
+        .await
+    }
+
+    /// Set the value of a string key in the server
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send_set(Request::Set {
+            namespace: None,
+            key,
+            value,
+        })
+        .await
+    }
+
+    /// Remove a string key in the server
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.send_remove(Request::Remove {
+            namespace: None,
+            key,
+        })
+        .await
+    }
+
+    /// Get the value of a given key in a namespace from the server
+    pub async fn get_in(&mut self, namespace: &str, key: String) -> Result<Option<String>> {
+        self.send_get(Request::Get {
+            namespace: Some(namespace.to_owned()),
+            key,
+        })
+        .await
+    }
+
+    /// Set the value of a string key in a namespace in the server
+    pub async fn set_in(&mut self, namespace: &str, key: String, value: String) -> Result<()> {
+        self.send_set(Request::Set {
+            namespace: Some(namespace.to_owned()),
+            key,
+            value,
+        })
+        .await
+    }
+
+    /// Remove a string key in a namespace in the server
+    pub async fn remove_in(&mut self, namespace: &str, key: String) -> Result<()> {
+        self.send_remove(Request::Remove {
+            namespace: Some(namespace.to_owned()),
+            key,
+        })
+        .await
+    }
+
+    /// Get the values of several keys from the server in one round trip
+    ///
+    /// See `KvsClient::get_many`.
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        self.send_get_many(Request::GetMany {
+            namespace: None,
+            keys,
+        })
+        .await
+    }
+
+    /// Set several key/value pairs in the server in one round trip
+    pub async fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        self.send_set_many(Request::SetMany {
+            namespace: None,
+            pairs,
+        })
+        .await
+    }
+
+    /// Remove several keys in the server in one round trip
+    pub async fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        self.send_remove_many(Request::RemoveMany {
+            namespace: None,
+            keys,
+        })
+        .await
+    }
+
+    /// Get the values of several keys in a namespace from the server
+    pub async fn get_many_in(
+        &mut self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        self.send_get_many(Request::GetMany {
+            namespace: Some(namespace.to_owned()),
+            keys,
+        })
+        .await
+    }
+
+    /// Set several key/value pairs in a namespace in the server
+    pub async fn set_many_in(
+        &mut self,
+        namespace: &str,
+        pairs: Vec<(String, String)>,
+    ) -> Result<Vec<Result<()>>> {
+        self.send_set_many(Request::SetMany {
+            namespace: Some(namespace.to_owned()),
+            pairs,
+        })
+        .await
+    }
+
+    /// Remove several keys in a namespace in the server
+    pub async fn remove_many_in(
+        &mut self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<()>>> {
+        self.send_remove_many(Request::RemoveMany {
+            namespace: Some(namespace.to_owned()),
+            keys,
+        })
+        .await
+    }
+
+    /// Create a namespace in the server
+    pub async fn create_namespace(&mut self, name: &str) -> Result<()> {
+        let req = Request::CreateNamespace {
+            name: name.to_owned(),
+        };
+        match self.request(&req).await? {
+            NamespaceResponse::Ok(_) => Ok(()),
+            NamespaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Drop a namespace and all of its keys in the server
+    pub async fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        let req = Request::DropNamespace {
+            name: name.to_owned(),
+        };
+        match self.request(&req).await? {
+            NamespaceResponse::Ok(_) => Ok(()),
+            NamespaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// List the namespaces in the server
+    pub async fn list_namespaces(&mut self) -> Result<Vec<String>> {
+        match self.request(&Request::ListNamespaces).await? {
+            ListNamespacesResponse::Ok(names) => Ok(names),
+            ListNamespacesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Read up to `limit` changes committed after the sequence number `seq`
+    ///
+    /// See `KvsClient::changes_since`.
+    pub async fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<Change>> {
+        match self.request(&Request::ChangesSince { seq, limit }).await? {
+            ChangesResponse::Ok(changes) => Ok(changes),
+            ChangesResponse::ResyncRequired(compacted) => Err(KvsError::ResyncRequired(compacted)),
+            ChangesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Watch the keys matching `target` in the server
+    ///
+    /// The connection is dedicated to the watch from now on, so the client is
+    /// consumed and turned into a stream of events.
+    pub async fn watch(self, target: WatchTarget) -> Result<AsyncWatchEvents> {
+        self.send_watch(Request::Watch {
+            namespace: None,
+            target,
+        })
+        .await
+    }
+
+    /// Watch the keys matching `target` in a namespace in the server
+    pub async fn watch_in(self, namespace: &str, target: WatchTarget) -> Result<AsyncWatchEvents> {
+        self.send_watch(Request::Watch {
+            namespace: Some(namespace.to_owned()),
+            target,
+        })
+        .await
+    }
+
+    async fn send_watch(mut self, req: Request) -> Result<AsyncWatchEvents> {
+        match self.request(&req).await? {
+            WatchResponse::Ok(_) => Ok(AsyncWatchEvents {
+                transport: self.transport,
+                done: false,
+            }),
+            WatchResponse::Event(_) => Err(KvsError::StringError(
+                "Unexpected event before the watch was acknowledged".to_owned(),
+            )),
+            WatchResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_get(&mut self, req: Request) -> Result<Option<String>> {
+        match self.request(&req).await? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_set(&mut self, req: Request) -> Result<()> {
+        match self.request(&req).await? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_remove(&mut self, req: Request) -> Result<()> {
+        match self.request(&req).await? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_get_many(&mut self, req: Request) -> Result<Vec<Result<Option<String>>>> {
+        match self.request(&req).await? {
+            GetManyResponse::Ok(resps) => Ok(resps.into_iter().map(get_result).collect()),
+            GetManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_set_many(&mut self, req: Request) -> Result<Vec<Result<()>>> {
+        match self.request(&req).await? {
+            SetManyResponse::Ok(resps) => Ok(resps.into_iter().map(set_result).collect()),
+            SetManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    async fn send_remove_many(&mut self, req: Request) -> Result<Vec<Result<()>>> {
+        match self.request(&req).await? {
+            RemoveManyResponse::Ok(resps) => Ok(resps.into_iter().map(remove_result).collect()),
+            RemoveManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Sends a request and reads its response.
+    async fn request<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        self.transport.send(req).await?;
+        self.transport.recv().await?.ok_or_else(connection_closed)
+    }
+}
+
+/// Stream of the events sent by a watch.
+///
+/// `next` returns `None` once the server closes the connection, and an error
+/// if the server ends the watch, for example because the watcher lagged behind.
+pub struct AsyncWatchEvents {
+    transport: TcpTransport,
+    done: bool,
+}
+
+impl AsyncWatchEvents {
+    /// Waits for the next event.
+    pub async fn next(&mut self) -> Option<Result<Event>> {
+        if self.done {
+            return None;
+        }
+        let res = match self.transport.recv().await {
+            Ok(Some(WatchResponse::Event(event))) => return Some(Ok(event)),
+            Ok(Some(WatchResponse::Ok(_))) => Some(Err(KvsError::StringError(
+                "Unexpected acknowledgement in the event stream".to_owned(),
+            ))),
+            Ok(Some(WatchResponse::Err(msg))) => Some(Err(KvsError::StringError(msg))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+        self.done = true;
+        res
+    }
+}
+
+fn connection_closed() -> KvsError {
+    KvsError::Io(std::io::ErrorKind::UnexpectedEof.into())
+}
@@ -0,0 +1,398 @@
+use std::future::Future;
+use std::net::{self, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::{runtime, time};
+
+use crate::codec::AsyncTransport;
+use crate::auth::{Authenticator, Session};
+use crate::common::{AuthResponse, Request, SetResponse, WatchResponse};
+use crate::engines::watch::WATCH_CHANNEL_CAPACITY;
+use crate::engines::{KvsEngine, WatchTarget, DEFAULT_NAMESPACE};
+use crate::metrics::Registry;
+use crate::protocol::{ErrorCode, Protocol};
+use crate::server::{
+    handle, register_engine_stats, Response, ServerMetrics, Timeouts, DEFAULT_MAX_CONNECTIONS,
+    REJECT_DRAIN_BYTES, REJECT_TIMEOUT,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+/// The server of a key value store, serving connections on an event loop.
+///
+/// Connections are tasks on a single-threaded event loop, so an idle
+/// connection holds no thread. Each request is run on the thread pool and
+/// its response is sent once the pool is done with it. It speaks the same
+/// protocol as `KvsServer`, but runs the requests of a connection one at a
+/// time, tagged or not. Its connections are limited and timed out the same
+/// way.
+pub struct AsyncKvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+    registry: Arc<Registry>,
+    metrics: Arc<ServerMetrics>,
+    max_connections: usize,
+    timeouts: Timeouts,
+    auth: Option<Arc<Authenticator>>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsServer<E, P> {
+    /// Create an `AsyncKvsServer` with a given storage engine.
+    pub fn new(engine: E, pool: P) -> Self {
+        let registry = Arc::new(Registry::new());
+        let metrics = Arc::new(ServerMetrics::new(&registry));
+        register_engine_stats(&registry, engine.clone());
+        AsyncKvsServer {
+            engine,
+            pool: Arc::new(pool),
+            registry,
+            metrics,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            timeouts: Timeouts::default(),
+            auth: None,
+        }
+    }
+
+    /// Requires the clients to authenticate, like `KvsServer::with_auth`.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Sets how long the rest of a request may take to arrive, like
+    /// `KvsServer::with_read_timeout`.
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.read = timeout;
+        self
+    }
+
+    /// Sets how long a response may take to be written, like
+    /// `KvsServer::with_write_timeout`.
+    pub fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    /// Sets how long a connection may wait for its next request, like
+    /// `KvsServer::with_idle_timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
+
+    /// Sets the number of connections served above which new ones are told
+    /// the server is busy, like `KvsServer::with_max_connections`.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Returns the registry holding the metrics of the server and its engine.
+    pub fn registry(&self) -> Arc<Registry> {
+        Arc::clone(&self.registry)
+    }
+
+    /// Listens on `addr` and runs the event loop on the current thread.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        runtime.block_on(self.accept(listener))
+    }
+
+    async fn accept(self, listener: net::TcpListener) -> Result<()> {
+        let listener = TcpListener::from_std(listener)?;
+        // each connection served holds a slot until it is closed
+        let slots = Arc::new(Semaphore::new(self.max_connections));
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    self.metrics.connection_errors.inc();
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let slot = match Arc::clone(&slots).try_acquire_owned() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    self.metrics.rejected_connections.inc();
+                    warn!(
+                        "Rejected connection from {}: too many connections",
+                        peer_addr
+                    );
+                    tokio::spawn(async move {
+                        if let Err(e) = reject(stream).await {
+                            debug!("Error on rejecting client: {}", e);
+                        }
+                    });
+                    continue;
+                }
+            };
+
+            let conn = Connection {
+                engine: self.engine.clone(),
+                pool: Arc::clone(&self.pool),
+                metrics: Arc::clone(&self.metrics),
+                timeouts: self.timeouts,
+                session: Session::new(self.auth.clone()),
+            };
+            tokio::spawn(async move {
+                let metrics = Arc::clone(&conn.metrics);
+                metrics.open_connections.inc();
+                if let Err(e) = conn.serve(stream).await {
+                    metrics.connection_errors.inc();
+                    error!("Error on serving client: {}", e);
+                }
+                metrics.open_connections.dec();
+                drop(slot);
+            });
+        }
+    }
+}
+
+/// Answers a client with a "server busy" error in the protocol it speaks,
+/// then closes the connection, like `KvsServer` does.
+async fn reject(mut stream: TcpStream) -> Result<()> {
+    within(Some(REJECT_TIMEOUT), async {
+        let (reader, writer) = stream.split();
+        let mut transport = AsyncTransport::accept(reader, writer).await?;
+        match transport.protocol() {
+            Protocol::Binary => {
+                transport
+                    .send_error_tagged(None, ErrorCode::ServerBusy, "Server busy")
+                    .await?
+            }
+            // every response has an `Err` variant, serialized the same way
+            Protocol::Json => {
+                transport
+                    .send(&SetResponse::Err("Server busy".to_owned()))
+                    .await?
+            }
+        }
+        Ok(())
+    })
+    .await?;
+
+    // closing with unread requests would reset the connection and could
+    // discard the answer, so they are read first
+    stream.shutdown().await?;
+    within(Some(REJECT_TIMEOUT), async {
+        io::copy(&mut (&mut stream).take(REJECT_DRAIN_BYTES), &mut io::sink()).await?;
+        Ok(())
+    })
+    .await
+}
+
+/// Runs `future`, failing with `KvsError::Timeout` if it takes longer than
+/// `limit`. `None` waits forever.
+async fn within<T>(limit: Option<Duration>, future: impl Future<Output = Result<T>>) -> Result<T> {
+    match limit {
+        Some(limit) => time::timeout(limit, future)
+            .await
+            .map_err(|_| KvsError::Timeout)?,
+        None => future.await,
+    }
+}
+
+// engines are not always `Sync`, so the futures of a connection own it and
+// borrow it mutably
+struct Connection<E, P> {
+    engine: E,
+    pool: Arc<P>,
+    metrics: Arc<ServerMetrics>,
+    timeouts: Timeouts,
+    session: Session,
+}
+
+impl<E: KvsEngine, P: ThreadPool> Connection<E, P> {
+    async fn serve(mut self, tcp: TcpStream) -> Result<()> {
+        let peer_addr = tcp.peer_addr()?;
+        let (reader, writer) = tcp.into_split();
+        let metrics = Arc::clone(&self.metrics);
+        let timeouts = self.timeouts;
+        // a client not even sending its handshake is idle too
+        let mut transport =
+            match within(timeouts.idle, AsyncTransport::accept(reader, writer)).await {
+                Ok(transport) => transport,
+                Err(KvsError::Timeout) => {
+                    metrics.idle_connections_closed.inc();
+                    debug!("Closing idle connection from {}", peer_addr);
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+        debug!("Speaking {:?} with {}", transport.protocol(), peer_addr);
+
+        loop {
+            match within(timeouts.idle, transport.wait_for_message()).await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(KvsError::Timeout) => {
+                    metrics.idle_connections_closed.inc();
+                    debug!("Closing idle connection from {}", peer_addr);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+
+            let (id, req) = match within(timeouts.read, transport.recv_tagged::<Request>()).await {
+                Ok(Some((id, req))) => (id, req),
+                Ok(None) => break,
+                Err(e) => (None, Err(e)),
+            };
+            let req = match req {
+                Ok(req) => req,
+                // the frames after a malformed one are still delimited
+                Err(KvsError::Protocol(ErrorCode::Malformed, msg)) => {
+                    within(
+                        timeouts.write,
+                        transport.send_error_tagged(id, ErrorCode::Malformed, &msg),
+                    )
+                    .await?;
+                    continue;
+                }
+                Err(KvsError::Protocol(code, msg)) => {
+                    within(timeouts.write, transport.send_error_tagged(id, code, &msg)).await?;
+                    return Err(KvsError::Protocol(code, msg));
+                }
+                Err(e) => return Err(e),
+            };
+            debug!("Receive request from {}: {:?}", peer_addr, req);
+            let start = Instant::now();
+            let req_metrics = metrics.request(&req);
+            req_metrics.count.inc();
+
+            if let Err((code, msg)) = self.session.check(&req) {
+                req_metrics.errors.inc();
+                debug!("Refused request from {}: {}", peer_addr, msg);
+                match transport.protocol() {
+                    Protocol::Binary => {
+                        within(timeouts.write, transport.send_error_tagged(id, code, &msg)).await?
+                    }
+                    Protocol::Json => {
+                        let resp = Response::refusal(&req, code, msg);
+                        within(timeouts.write, transport.send(&resp)).await?
+                    }
+                }
+                req_metrics.latency.observe_duration(start.elapsed());
+                continue;
+            }
+
+            if let Request::Authenticate(credentials) = &req {
+                let resp = self.session.authenticate(credentials);
+                if let AuthResponse::Err(_) = resp {
+                    req_metrics.errors.inc();
+                    warn!("Failed authentication from {}", peer_addr);
+                }
+                within(timeouts.write, transport.send_tagged(id, &resp)).await?;
+                req_metrics.latency.observe_duration(start.elapsed());
+                continue;
+            }
+
+            if let Request::Watch { namespace, target } = req {
+                let namespace = namespace.unwrap_or_else(|| DEFAULT_NAMESPACE.to_owned());
+                let mut events = match self.watch(namespace, target).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        req_metrics.errors.inc();
+                        let resp = WatchResponse::Err(format!("{}", e));
+                        within(timeouts.write, transport.send_tagged(id, &resp)).await?;
+                        req_metrics.latency.observe_duration(start.elapsed());
+                        continue;
+                    }
+                };
+                within(
+                    timeouts.write,
+                    transport.send_tagged(id, &WatchResponse::Ok(())),
+                )
+                .await?;
+                // the latency of a watch is the time taken to subscribe
+                req_metrics.latency.observe_duration(start.elapsed());
+
+                // the connection only streams events from now on
+                while let Some(resp) = events.recv().await {
+                    within(timeouts.write, transport.send_tagged(id, &resp)).await?;
+                }
+                return Ok(());
+            }
+
+            let engine = self.engine.clone();
+            let resp = self.offload(move || handle(&engine, req)).await?;
+            if resp.is_err() {
+                req_metrics.errors.inc();
+            }
+            within(timeouts.write, transport.send_tagged(id, &resp)).await?;
+            debug!("Response sent to {}: {:?}", peer_addr, resp);
+            req_metrics.latency.observe_duration(start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Runs a job on the thread pool and waits for its result.
+    async fn offload<T, F>(&mut self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let metrics = Arc::clone(&self.metrics);
+        metrics.queue_depth.inc();
+        self.pool.spawn(move || {
+            metrics.queue_depth.dec();
+            let _ = sender.send(job());
+        });
+        receiver
+            .await
+            .map_err(|_| KvsError::StringError("The request panicked".to_owned()))
+    }
+
+    /// Subscribes to the changes of `target`, returning the responses to stream.
+    ///
+    /// Watchers block, so each one is drained by a thread of its own, which
+    /// exits at the first event after the connection is closed. The responses
+    /// wait in a bounded channel, and a client falling too far behind gets
+    /// `KvsError::WatcherLagged` and its watch ends, as with the engine.
+    async fn watch(
+        &mut self,
+        namespace: String,
+        target: WatchTarget,
+    ) -> Result<mpsc::Receiver<WatchResponse>> {
+        let engine = self.engine.clone();
+        let watcher = self
+            .offload(move || engine.watch_in(&namespace, target))
+            .await??;
+
+        let (sender, receiver) = mpsc::channel(WATCH_CHANNEL_CAPACITY);
+        thread::Builder::new()
+            .name("watch".to_owned())
+            .spawn(move || {
+                for event in watcher {
+                    let resp = match event {
+                        Ok(event) => WatchResponse::Event(event),
+                        Err(e) => WatchResponse::Err(format!("{}", e)),
+                    };
+                    match sender.try_send(resp) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            let lagged = format!("{}", KvsError::WatcherLagged);
+                            let _ = sender.blocking_send(WatchResponse::Err(lagged));
+                            break;
+                        }
+                        Err(TrySendError::Closed(_)) => break,
+                    }
+                }
+            })?;
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,379 @@
+//! Authenticating the clients of a server and checking what they may access.
+//!
+//! Clients authenticate with a shared-secret token, a username and
+//! password, or over mutual TLS with the subject of their certificate. Each
+//! of them is granted a `Scope`: read-only or read-write
+//! access, optionally restricted to the keys starting with some prefixes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{AuthResponse, Request};
+use crate::engines::WatchTarget;
+use crate::protocol::ErrorCode;
+use crate::{KvsError, Result};
+
+/// What a client proves its identity with.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A shared-secret token
+    Token(String),
+    /// A username and its password
+    Password {
+        /// The name of the user
+        username: String,
+        /// The password of the user, in clear
+        password: String,
+    },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the secrets, requests are logged
+        match self {
+            Credentials::Token(_) => f.write_str("Token(..)"),
+            Credentials::Password { username, .. } => f
+                .debug_struct("Password")
+                .field("username", username)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Whether a client may change the keys it can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Gets, watches and changes only
+    ReadOnly,
+    /// Sets and removes too
+    ReadWrite,
+}
+
+/// What an authenticated client may access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    /// Whether the client may change keys
+    pub access: Access,
+    /// The prefixes of the keys the client may access, in any namespace.
+    /// Empty means all keys.
+    pub prefixes: Vec<String>,
+}
+
+impl Scope {
+    /// Returns a scope over all keys.
+    pub fn new(access: Access) -> Self {
+        Scope {
+            access,
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Restricts the scope to the keys starting with `prefix`, on top of the
+    /// prefixes added before.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Checks that the scope allows a request, returning why it does not
+    /// otherwise.
+    fn allows(&self, req: &Request) -> std::result::Result<(), String> {
+        match req {
+            Request::Get { key, .. } => self.allows_key(key),
+            Request::Set { key, .. } | Request::Remove { key, .. } => {
+                self.allows_writes()?;
+                self.allows_key(key)
+            }
+            Request::GetMany { keys, .. } => keys.iter().try_for_each(|key| self.allows_key(key)),
+            Request::SetMany { pairs, .. } => {
+                self.allows_writes()?;
+                pairs.iter().try_for_each(|(key, _)| self.allows_key(key))
+            }
+            Request::RemoveMany { keys, .. } => {
+                self.allows_writes()?;
+                keys.iter().try_for_each(|key| self.allows_key(key))
+            }
+            Request::Watch { target, .. } => match target {
+                WatchTarget::Key(key) => self.allows_key(key),
+                WatchTarget::Prefix(prefix) => self.allows_key(prefix),
+            },
+            // the changes of every key are returned
+            Request::ChangesSince { .. } => self.allows_all_keys(),
+            Request::CreateNamespace { .. } | Request::DropNamespace { .. } => {
+                self.allows_writes()?;
+                self.allows_all_keys()
+            }
+            Request::ListNamespaces | Request::Authenticate(_) => Ok(()),
+        }
+    }
+
+    fn allows_writes(&self) -> std::result::Result<(), String> {
+        match self.access {
+            Access::ReadWrite => Ok(()),
+            Access::ReadOnly => Err("read-only access".to_owned()),
+        }
+    }
+
+    /// Checks a key, or the prefix of the keys a watch covers.
+    fn allows_key(&self, key: &str) -> std::result::Result<(), String> {
+        if self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str())) {
+            Ok(())
+        } else {
+            Err(format!("'{}' is outside the allowed prefixes", key))
+        }
+    }
+
+    fn allows_all_keys(&self) -> std::result::Result<(), String> {
+        if self.prefixes.is_empty() {
+            Ok(())
+        } else {
+            Err("access to all keys is required".to_owned())
+        }
+    }
+}
+
+/// The credentials a server accepts and the scope each of them grants.
+///
+/// Passwords are kept as Argon2 hashes in the PHC string format, as returned
+/// by `hash_password`.
+#[derive(Default)]
+pub struct Authenticator {
+    tokens: Vec<(String, Scope)>,
+    users: HashMap<String, (String, Scope)>,
+    certificates: HashMap<String, Scope>,
+}
+
+/// The layout of a credentials file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+    #[serde(default)]
+    users: Vec<UserEntry>,
+    #[serde(default)]
+    certificates: Vec<CertificateEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenEntry {
+    token: String,
+    access: Access,
+    #[serde(default)]
+    prefixes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    username: String,
+    password_hash: String,
+    access: Access,
+    #[serde(default)]
+    prefixes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CertificateEntry {
+    subject: String,
+    access: Access,
+    #[serde(default)]
+    prefixes: Vec<String>,
+}
+
+impl Authenticator {
+    /// Creates an authenticator accepting no credentials.
+    pub fn new() -> Self {
+        Authenticator::default()
+    }
+
+    /// Reads the credentials from a JSON file such as:
+    ///
+    /// ```json
+    /// {
+    ///   "tokens": [{ "token": "s3cr3t", "access": "read_write" }],
+    ///   "users": [{
+    ///     "username": "reader",
+    ///     "password_hash": "$argon2id$v=19$...",
+    ///     "access": "read_only",
+    ///     "prefixes": ["public/"]
+    ///   }],
+    ///   "certificates": [{ "subject": "backup", "access": "read_only" }]
+    /// }
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file: CredentialsFile = serde_json::from_slice(&fs::read(path)?)?;
+        let mut auth = Authenticator::new();
+        for entry in file.tokens {
+            let scope = Scope {
+                access: entry.access,
+                prefixes: entry.prefixes,
+            };
+            auth.add_token(entry.token, scope);
+        }
+        for entry in file.users {
+            let scope = Scope {
+                access: entry.access,
+                prefixes: entry.prefixes,
+            };
+            auth.add_user(entry.username, entry.password_hash, scope)?;
+        }
+        for entry in file.certificates {
+            let scope = Scope {
+                access: entry.access,
+                prefixes: entry.prefixes,
+            };
+            auth.add_certificate(entry.subject, scope);
+        }
+        Ok(auth)
+    }
+
+    /// Accepts a token, granting `scope`.
+    pub fn add_token(&mut self, token: impl Into<String>, scope: Scope) {
+        self.tokens.push((token.into(), scope));
+    }
+
+    /// Accepts a user with the password hashed in `password_hash`, granting
+    /// `scope`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the hash is not a valid PHC
+    /// string.
+    pub fn add_user(
+        &mut self,
+        username: impl Into<String>,
+        password_hash: impl Into<String>,
+        scope: Scope,
+    ) -> Result<()> {
+        let username = username.into();
+        let password_hash = password_hash.into();
+        PasswordHash::new(&password_hash).map_err(|e| {
+            KvsError::StringError(format!("Invalid password hash for {}: {}", username, e))
+        })?;
+        self.users.insert(username, (password_hash, scope));
+        Ok(())
+    }
+
+    /// Accepts the clients presenting a TLS certificate whose subject common
+    /// name is `subject`, granting `scope`.
+    ///
+    /// The certificate is verified by the server against its client CA, so
+    /// only subjects that CA issues certificates to should be listed.
+    pub fn add_certificate(&mut self, subject: impl Into<String>, scope: Scope) {
+        self.certificates.insert(subject.into(), scope);
+    }
+
+    /// Returns the scope granted to the verified certificate of a client with
+    /// the subject common name `subject`, or `None` if it is not accepted.
+    pub fn authenticate_certificate(&self, subject: &str) -> Option<Scope> {
+        self.certificates.get(subject).cloned()
+    }
+
+    /// Returns the scope granted to `credentials`, or `None` if they are
+    /// not accepted.
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<Scope> {
+        match credentials {
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .find(|(known, _)| constant_time_eq(known.as_bytes(), token.as_bytes()))
+                .map(|(_, scope)| scope.clone()),
+            Credentials::Password { username, password } => {
+                let (hash, scope) = self.users.get(username)?;
+                // the hash was checked when the user was added
+                let hash = PasswordHash::new(hash).ok()?;
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .ok()
+                    .map(|_| scope.clone())
+            }
+        }
+    }
+}
+
+/// Hashes a password with Argon2 and a random salt, for a credentials file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| KvsError::StringError(format!("Cannot hash password: {}", e)))?;
+    Ok(hash.to_string())
+}
+
+/// Compares secrets in a time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// What a connection has been allowed to do so far.
+///
+/// Without an authenticator every request is allowed. Otherwise requests
+/// are refused until the client authenticates, then checked against the
+/// scope it was granted.
+pub(crate) struct Session {
+    auth: Option<Arc<Authenticator>>,
+    scope: Option<Scope>,
+}
+
+impl Session {
+    pub(crate) fn new(auth: Option<Arc<Authenticator>>) -> Self {
+        let scope = match auth {
+            Some(_) => None,
+            None => Some(Scope::new(Access::ReadWrite)),
+        };
+        Session { auth, scope }
+    }
+
+    /// Authenticates the connection, replacing the scope granted before.
+    ///
+    /// Any credentials are accepted when authentication is disabled.
+    pub(crate) fn authenticate(&mut self, credentials: &Credentials) -> AuthResponse {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return AuthResponse::Ok(()),
+        };
+        self.scope = auth.authenticate(credentials);
+        match self.scope {
+            Some(_) => AuthResponse::Ok(()),
+            None => AuthResponse::Err("invalid credentials".to_owned()),
+        }
+    }
+
+    /// Authenticates the connection by the subject of the verified certificate
+    /// of the client, returning true if it was granted a scope.
+    pub(crate) fn authenticate_certificate(&mut self, subject: &str) -> bool {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return false,
+        };
+        self.scope = auth.authenticate_certificate(subject);
+        self.scope.is_some()
+    }
+
+    /// Checks that the connection may send a request, returning the error
+    /// frame to answer with otherwise.
+    pub(crate) fn check(&self, req: &Request) -> std::result::Result<(), (ErrorCode, String)> {
+        if let Request::Authenticate(_) = req {
+            return Ok(());
+        }
+        match &self.scope {
+            Some(scope) => scope.allows(req).map_err(|msg| (ErrorCode::Forbidden, msg)),
+            None => Err((
+                ErrorCode::Unauthenticated,
+                "authentication required".to_owned(),
+            )),
+        }
+    }
+}
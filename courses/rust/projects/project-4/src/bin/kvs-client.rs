@@ -1,5 +1,340 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process::exit;
 
+use clap::AppSettings;
+use log::info;
+use structopt::StructOpt;
+
+use kvs::*;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+const ADDRESS_FORMAT: &str = "IP:PORT|unix:PATH";
+/// Number of lines read from stdin and sent in one pipeline.
+const PIPE_BATCH: usize = 1000;
+/// Holds the password of the user given with `--user`.
+const PASSWORD_VAR: &str = "KVS_PASSWORD";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-client",
+    raw(global_settings = "&[\
+    AppSettings::DisableHelpSubcommand,\
+    AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// The credentials to authenticate with.
+#[derive(StructOpt, Debug)]
+struct AuthOpt {
+    #[structopt(
+        long,
+        help = "Authenticates with a token",
+        value_name = "TOKEN",
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Authenticates as a user, with the password in KVS_PASSWORD",
+        value_name = "USER"
+    )]
+    user: Option<String>,
+}
+
+/// The TLS configuration to connect with.
+#[derive(StructOpt, Debug)]
+struct TlsOpt {
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects over TLS, trusting the PEM CA in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-cert",
+        help = "Presents the PEM client certificate chain in FILE",
+        value_name = "FILE",
+        raw(requires_all = r#"&["tls_ca", "tls_key"]"#),
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "Presents a client certificate with the PEM private key in FILE",
+        value_name = "FILE",
+        requires = "tls_cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-server-name",
+        help = "Checks the certificate of the server against NAME instead of its IP",
+        value_name = "NAME",
+        requires = "tls_ca"
+    )]
+    tls_server_name: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(
+            long,
+            help = "Sets the server address, or the path of a Unix socket",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: Address,
+
+        #[structopt(flatten)]
+        auth: AuthOpt,
+
+        #[structopt(flatten)]
+        tls: TlsOpt,
+    },
+
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(name = "VALUE", help = "The string value of the key")]
+        value: String,
+
+        #[structopt(
+            long,
+            help = "Sets the server address, or the path of a Unix socket",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: Address,
+
+        #[structopt(flatten)]
+        auth: AuthOpt,
+
+        #[structopt(flatten)]
+        tls: TlsOpt,
+    },
+
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+
+        #[structopt(
+            long,
+            help = "Sets the server address, or the path of a Unix socket",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: Address,
+
+        #[structopt(flatten)]
+        auth: AuthOpt,
+
+        #[structopt(flatten)]
+        tls: TlsOpt,
+    },
+
+    #[structopt(
+        name = "pipe",
+        about = "Run the commands read from stdin, one per line, in a pipeline",
+        after_help = "Commands are `get KEY`, `set KEY VALUE` and `rm KEY`. \
+                      The value of a set is the rest of the line."
+    )]
+    Pipe {
+        #[structopt(
+            long,
+            help = "Sets the server address, or the path of a Unix socket",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: Address,
+
+        #[structopt(flatten)]
+        auth: AuthOpt,
+
+        #[structopt(flatten)]
+        tls: TlsOpt,
+    },
+}
 
 fn main() {
+    info!("start kvs-client");
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Get { key, addr, auth, tls } => {
+            let mut client = connect(addr, auth, tls)?;
+            if let Some(value) = client.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+
+        Command::Set {
+            key,
+            value,
+            addr,
+            auth,
+            tls,
+        } => {
+            let mut client = connect(addr, auth, tls)?;
+            client.set(key, value)?;
+        }
+
+        Command::Remove { key, addr, auth, tls } => {
+            let mut client = connect(addr, auth, tls)?;
+            client.remove(key)?;
+        }
+
+        Command::Pipe { addr, auth, tls } => {
+            let mut client = connect(addr, auth, tls)?;
+            if !pipe(&mut client, io::stdin().lock())? {
+                exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the server, over TLS if a CA is given, then authenticates if
+/// credentials are given.
+fn connect(addr: Address, auth: AuthOpt, tls: TlsOpt) -> Result<KvsClient> {
+    let mut client = match client_tls(tls)? {
+        Some(tls) => KvsClient::connect_tls(addr, &tls)?,
+        None => KvsClient::connect(addr)?,
+    };
+    let credentials = match (auth.token, auth.user) {
+        (Some(token), _) => Credentials::Token(token),
+        (None, Some(username)) => {
+            let password = env::var(PASSWORD_VAR).map_err(|e| {
+                KvsError::StringError(format!(
+                    "Cannot read the password from {}: {}",
+                    PASSWORD_VAR, e
+                ))
+            })?;
+            Credentials::Password { username, password }
+        }
+        (None, None) => return Ok(client),
+    };
+    client.authenticate(credentials)?;
+    Ok(client)
+}
+
+/// Reads the TLS configuration from `--tls-ca`, `--tls-cert`, `--tls-key` and
+/// `--tls-server-name`.
+fn client_tls(opt: TlsOpt) -> Result<Option<ClientTls>> {
+    let ca = match opt.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let tls = match (opt.tls_cert, opt.tls_key) {
+        (Some(cert), Some(key)) => ClientTls::with_client_auth(ca, cert, key)?,
+        _ => ClientTls::new(ca)?,
+    };
+    match opt.tls_server_name {
+        Some(name) => Ok(Some(tls.with_server_name(&name)?)),
+        None => Ok(Some(tls)),
+    }
+}
+
+/// A command read by `pipe`.
+enum Line {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+fn parse_line(line: &str) -> std::result::Result<Line, String> {
+    let mut parts = line.splitn(2, ' ');
+    let command = parts.next().unwrap_or_default();
+    let args = parts.next().unwrap_or_default();
+    match command {
+        "get" | "rm" if args.is_empty() || args.contains(' ') => {
+            Err(format!("`{}` takes a single KEY", command))
+        }
+        "get" => Ok(Line::Get(args.to_owned())),
+        "rm" => Ok(Line::Remove(args.to_owned())),
+        "set" => match args.split_once(' ') {
+            Some((key, value)) if !key.is_empty() => {
+                Ok(Line::Set(key.to_owned(), value.to_owned()))
+            }
+            _ => Err("`set` takes a KEY and a VALUE".to_owned()),
+        },
+        _ => Err(format!("Unknown command `{}`", command)),
+    }
+}
+
+/// Runs the commands read from `input` in pipelines of `PIPE_BATCH` lines.
+///
+/// Gets print their value, and failures are reported on stderr with their line
+/// number. Returns false if any command failed.
+fn pipe(client: &mut KvsClient, input: impl BufRead) -> Result<bool> {
+    let mut lines = input.lines().enumerate().peekable();
+    let mut ok = true;
+    while lines.peek().is_some() {
+        let mut pipeline = client.pipeline();
+        let mut batch = Vec::new();
+        for (i, line) in lines.by_ref().take(PIPE_BATCH) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let command = match parse_line(&line) {
+                Ok(command) => command,
+                Err(msg) => {
+                    eprintln!("line {}: {}", i + 1, msg);
+                    ok = false;
+                    continue;
+                }
+            };
+            batch.push((i, matches!(command, Line::Get(_))));
+            match command {
+                Line::Get(key) => pipeline.get(key),
+                Line::Set(key, value) => pipeline.set(key, value),
+                Line::Remove(key) => pipeline.remove(key),
+            };
+        }
 
-}
\ No newline at end of file
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for ((i, is_get), result) in batch.into_iter().zip(pipeline.execute()?) {
+            match result {
+                Ok(Some(value)) => writeln!(stdout, "{}", value)?,
+                Ok(None) if is_get => writeln!(stdout, "Key not found")?,
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("line {}: {}", i + 1, e);
+                    ok = false;
+                }
+            }
+        }
+    }
+    Ok(ok)
+}
@@ -1,30 +1,70 @@
-use std::{env, fs};
+use std::{env, fmt, fs};
 use std::env::current_dir;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use clap::arg_enum;
 use log::{error, info, LevelFilter, warn};
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
 use kvs::*;
+use kvs::metrics;
 use kvs::server::KvsServer;
-use kvs::thread_pool::ThreadPool;
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_ACCEPT_QUEUE: usize = 128;
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+const DEFAULT_WRITE_TIMEOUT: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+const DEFAULT_SOCKET_MODE: SocketMode = SocketMode(0o660);
+const MEMORY_SNAPSHOT_FILE: &str = "memory.snapshot";
+/// Holds the hex encryption key of the kvs engine, unless a key file is given.
+const ENCRYPTION_KEY_VAR: &str = "KVS_ENCRYPTION_KEY";
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
-        value_name = "IP:PORT",
-        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+        help = "Reads the settings from a TOML file, each overridden by its flag",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Sets the listening address, or the path of a Unix socket [default: 127.0.0.1:4000]",
+        value_name = "IP:PORT|unix:PATH",
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<Address>,
+
+    #[structopt(
+        long = "socket-mode",
+        help = "Sets the permissions of the Unix socket file, in octal [default: 660]",
+        value_name = "MODE",
+        parse(try_from_str)
+    )]
+    socket_mode: Option<SocketMode>,
+
+    #[structopt(
+        long = "data-dir",
+        help = "Stores the data and the engine marker in DIR [default: .]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
 
     #[structopt(
         long,
@@ -33,34 +73,475 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+
+    #[structopt(
+        long = "snapshot-interval",
+        help = "Dumps the memory engine to a snapshot file every SECONDS",
+        value_name = "SECONDS"
+    )]
+    snapshot_interval: Option<u64>,
+
+    #[structopt(
+        long = "memtable-bytes",
+        help = "Writes the memtable of the lsm engine out at this size [default: 4194304]",
+        value_name = "BYTES"
+    )]
+    memtable_bytes: Option<u64>,
+
+    #[structopt(
+        long = "l0-compaction-trigger",
+        help = "Compacts level 0 of the lsm engine once it has N tables [default: 4]",
+        value_name = "N"
+    )]
+    l0_compaction_trigger: Option<usize>,
+
+    #[structopt(
+        long = "level-base-bytes",
+        help = "Sets the size of level 1 of the lsm engine, ten times more each level [default: 10485760]",
+        value_name = "BYTES"
+    )]
+    level_base_bytes: Option<u64>,
+
+    #[structopt(
+        long = "table-bytes",
+        help = "Sets the size of the tables written by lsm compactions [default: 2097152]",
+        value_name = "BYTES"
+    )]
+    table_bytes: Option<u64>,
+
+    #[structopt(
+        long = "encryption-key-file",
+        help = "Encrypts the kvs engine with the hex key in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "thread-pool",
+        help = "Sets the thread pool serving the connections [default: rayon]",
+        value_name = "POOL",
+        raw(possible_values = "POOL_KINDS"),
+        parse(try_from_str)
+    )]
+    thread_pool: Option<PoolKind>,
+
+    #[structopt(
+        long,
+        help = "Sets the number of threads of the pool [default: the number of CPUs]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+
+    #[structopt(
+        long = "log-level",
+        help = "Sets the log level: off, error, warn, info, debug or trace [default: info]",
+        value_name = "LEVEL",
+        parse(try_from_str)
+    )]
+    log_level: Option<LevelFilter>,
+
+    #[structopt(
+        long,
+        help = "Requires the clients to authenticate with the credentials in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    credentials: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves TLS with the PEM certificate chain in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-key",
+        help = "Serves TLS with the PEM private key in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-client-ca",
+        help = "Requires client certificates signed by the PEM CA in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(
+        long = "metrics-addr",
+        help = "Serves Prometheus metrics over HTTP on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long = "resp-addr",
+        help = "Also serves Redis clients over RESP on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long = "http-addr",
+        help = "Also serves a JSON REST API over HTTP on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+
+    #[structopt(
+        long = "max-connections",
+        help = "Rejects connections above this number as the server is busy [default: 1024]",
+        value_name = "N"
+    )]
+    max_connections: Option<usize>,
+
+    #[structopt(
+        long = "accept-queue",
+        help = "Rejects connections when this many are waiting for a thread [default: 128]",
+        value_name = "N"
+    )]
+    accept_queue: Option<usize>,
+
+    #[structopt(
+        long = "read-timeout",
+        help = "Closes connections taking longer to send a request, 0 never does [default: 30]",
+        value_name = "SECONDS"
+    )]
+    read_timeout: Option<u64>,
+
+    #[structopt(
+        long = "write-timeout",
+        help = "Closes connections taking longer to receive a response, 0 never does [default: 30]",
+        value_name = "SECONDS"
+    )]
+    write_timeout: Option<u64>,
+
+    #[structopt(
+        long = "idle-timeout",
+        help = "Closes connections idle for longer, 0 never does [default: 300]",
+        value_name = "SECONDS"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[structopt(
+        long = "async",
+        help = "Serves the connections on an event loop instead of a thread each"
+    )]
+    event_loop: bool,
 }
 
 arg_enum! {
     #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        memory,
+        lsm,
+        btree
+    }
+}
+
+/// The names of the `PoolKind`s.
+const POOL_KINDS: &[&str] = &["naive", "shared-queue", "rayon"];
+
+/// The thread pools the connections can be served on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum PoolKind {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl FromStr for PoolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(PoolKind::Naive),
+            "shared-queue" => Ok(PoolKind::SharedQueue),
+            "rayon" => Ok(PoolKind::Rayon),
+            _ => Err(format!("{} is not a thread pool", s)),
+        }
+    }
+}
+
+impl fmt::Display for PoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PoolKind::Naive => "naive",
+            PoolKind::SharedQueue => "shared-queue",
+            PoolKind::Rayon => "rayon",
+        })
+    }
+}
+
+/// The permissions of the Unix socket file, written in octal.
+#[derive(Debug, Copy, Clone)]
+struct SocketMode(u32);
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("{} is not an octal file mode", mode)),
+        }
+    }
+}
+
+impl fmt::Display for SocketMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:o}", self.0)
+    }
+}
+
+/// The settings of the server.
+///
+/// They are read from the TOML file given to `--config`, whose keys are named
+/// after the flags, and each flag given overrides its key. A relative
+/// `data_dir` is relative to the working directory.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    #[serde(with = "display")]
+    addr: Address,
+    #[serde(with = "display")]
+    socket_mode: SocketMode,
+    data_dir: PathBuf,
+    engine: Option<Engine>,
+    snapshot_interval: Option<u64>,
+    memtable_bytes: Option<u64>,
+    l0_compaction_trigger: Option<usize>,
+    level_base_bytes: Option<u64>,
+    table_bytes: Option<u64>,
+    encryption_key_file: Option<PathBuf>,
+    #[serde(with = "display")]
+    thread_pool: PoolKind,
+    threads: u32,
+    #[serde(with = "display")]
+    log_level: LevelFilter,
+    credentials: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    max_connections: usize,
+    accept_queue: usize,
+    read_timeout: u64,
+    write_timeout: u64,
+    idle_timeout: u64,
+    #[serde(rename = "async")]
+    event_loop: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: DEFAULT_LISTENING_ADDRESS.parse().unwrap(),
+            socket_mode: DEFAULT_SOCKET_MODE,
+            data_dir: PathBuf::new(),
+            engine: None,
+            snapshot_interval: None,
+            memtable_bytes: None,
+            l0_compaction_trigger: None,
+            level_base_bytes: None,
+            table_bytes: None,
+            encryption_key_file: None,
+            thread_pool: PoolKind::Rayon,
+            threads: num_cpus::get() as u32,
+            log_level: LevelFilter::Info,
+            credentials: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            metrics_addr: None,
+            resp_addr: None,
+            http_addr: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            accept_queue: DEFAULT_ACCEPT_QUEUE,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            event_loop: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file given to `--config`, if any, and applies the flags on top.
+    fn load(opt: Opt) -> Result<Config> {
+        let mut config: Config = match opt.config {
+            Some(ref path) => toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+                KvsError::StringError(format!("Invalid config file {}: {}", path.display(), e))
+            })?,
+            None => Config::default(),
+        };
+
+        if let Some(addr) = opt.addr {
+            config.addr = addr;
+        }
+        if let Some(socket_mode) = opt.socket_mode {
+            config.socket_mode = socket_mode;
+        }
+        if let Some(data_dir) = opt.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(thread_pool) = opt.thread_pool {
+            config.thread_pool = thread_pool;
+        }
+        if let Some(threads) = opt.threads {
+            config.threads = threads;
+        }
+        if let Some(log_level) = opt.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(max_connections) = opt.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(accept_queue) = opt.accept_queue {
+            config.accept_queue = accept_queue;
+        }
+        if let Some(read_timeout) = opt.read_timeout {
+            config.read_timeout = read_timeout;
+        }
+        if let Some(write_timeout) = opt.write_timeout {
+            config.write_timeout = write_timeout;
+        }
+        if let Some(idle_timeout) = opt.idle_timeout {
+            config.idle_timeout = idle_timeout;
+        }
+        config.engine = opt.engine.or(config.engine);
+        config.snapshot_interval = opt.snapshot_interval.or(config.snapshot_interval);
+        config.memtable_bytes = opt.memtable_bytes.or(config.memtable_bytes);
+        config.l0_compaction_trigger = opt.l0_compaction_trigger.or(config.l0_compaction_trigger);
+        config.level_base_bytes = opt.level_base_bytes.or(config.level_base_bytes);
+        config.table_bytes = opt.table_bytes.or(config.table_bytes);
+        config.encryption_key_file = opt.encryption_key_file.or(config.encryption_key_file);
+        config.credentials = opt.credentials.or(config.credentials);
+        config.tls_cert = opt.tls_cert.or(config.tls_cert);
+        config.tls_key = opt.tls_key.or(config.tls_key);
+        config.tls_client_ca = opt.tls_client_ca.or(config.tls_client_ca);
+        config.metrics_addr = opt.metrics_addr.or(config.metrics_addr);
+        config.resp_addr = opt.resp_addr.or(config.resp_addr);
+        config.http_addr = opt.http_addr.or(config.http_addr);
+        config.event_loop |= opt.event_loop;
+
+        if config.threads == 0 {
+            return Err(KvsError::StringError(
+                "The thread pool needs at least one thread".to_owned(),
+            ));
+        }
+        config.data_dir = current_dir()?.join(&config.data_dir);
+        Ok(config)
+    }
+
+    /// Rejects the settings of the engines other than `engine`.
+    fn check_engine_options(&self, engine: Engine) -> Result<()> {
+        let mut given = Vec::new();
+        if self.snapshot_interval.is_some() {
+            given.push(("--snapshot-interval", Engine::memory));
+        }
+        if self.encryption_key_file.is_some() {
+            given.push(("--encryption-key-file", Engine::kvs));
+        }
+        if self.memtable_bytes.is_some() {
+            given.push(("--memtable-bytes", Engine::lsm));
+        }
+        if self.l0_compaction_trigger.is_some() {
+            given.push(("--l0-compaction-trigger", Engine::lsm));
+        }
+        if self.level_base_bytes.is_some() {
+            given.push(("--level-base-bytes", Engine::lsm));
+        }
+        if self.table_bytes.is_some() {
+            given.push(("--table-bytes", Engine::lsm));
+        }
+        for (flag, applies_to) in given {
+            if applies_to != engine {
+                return Err(KvsError::StringError(format!(
+                    "{} only applies to --engine {}",
+                    flag, applies_to
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the options of the lsm engine, the ones not set being the defaults.
+    fn lsm_options(&self) -> LsmOptions {
+        let defaults = LsmOptions::default();
+        LsmOptions {
+            memtable_bytes: self.memtable_bytes.unwrap_or(defaults.memtable_bytes),
+            l0_compaction_trigger: self
+                .l0_compaction_trigger
+                .unwrap_or(defaults.l0_compaction_trigger),
+            level_base_bytes: self.level_base_bytes.unwrap_or(defaults.level_base_bytes),
+            table_bytes: self.table_bytes.unwrap_or(defaults.table_bytes),
+        }
+    }
+}
+
+/// Reads and writes the config values that have no serde representation
+/// through `FromStr` and `Display`, as their flags do.
+mod display {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Display,
+        S: Serializer,
+    {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
     }
 }
 
 fn main() {
+    // records are filtered by the max level, set from the config once read
     env_logger::builder()
-        .filter_level(LevelFilter::Info)
+        .filter_level(LevelFilter::Trace)
         .init();
+    log::set_max_level(LevelFilter::Info);
 
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
+    let res = Config::load(Opt::from_args()).and_then(|mut config| {
+        log::set_max_level(config.log_level);
+        let curr_engine = current_engine(&config.data_dir)?;
+        if config.engine.is_none() {
+            config.engine = curr_engine;
         }
 
-        if curr_engine.is_some() && opt.engine != curr_engine {
+        if curr_engine.is_some() && config.engine != curr_engine {
             error!("Wrong engine!");
             exit(1);
         }
 
-        run(opt)
+        run(config)
     });
 
     if let Err(e) = res {
@@ -70,39 +551,258 @@ fn main() {
 }
 
 
-fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+fn run(mut config: Config) -> Result<()> {
+    let engine = config.engine.unwrap_or(DEFAULT_ENGINE);
+    config.engine = Some(engine);
+    config.check_engine_options(engine)?;
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr   );
+    info!("Listening on {}", config.addr);
+    match toml::to_string(&config) {
+        Ok(effective) => info!("Effective config:\n{}", effective.trim_end()),
+        Err(e) => warn!("Unable to print the config: {}", e),
+    }
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
-    // the thread pool is used to receive the tcp connection stream
-    let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    let data_dir = config.data_dir.clone();
+    fs::create_dir_all(&data_dir)?;
+    fs::write(data_dir.join("engine"), format!("{}", engine))?;
 
     match engine {
-        Engine::kvs => run_with(
-            KvStore::open(env::current_dir()?)?,
-            pool,
-            opt.addr,
+        Engine::kvs => {
+            let store = match encryption_key(&config)? {
+                Some(key) => KvStore::open_encrypted(&data_dir, key)?,
+                None => KvStore::open(&data_dir)?,
+            };
+            run_on_pool(store, &config)
+        }
+        Engine::sled => run_on_pool(SledKvsEngine::new(sled::open(&data_dir)?), &config),
+        Engine::lsm => run_on_pool(
+            LsmEngine::open_with_options(&data_dir, config.lsm_options())?,
+            &config,
         ),
-        Engine::sled => run_with(
-            SledKvsEngine::new(sled::open(env::current_dir()?)?),
-            pool,
-            opt.addr,
-        )
+        Engine::btree => run_on_pool(BTreeEngine::open(&data_dir)?, &config),
+        // the snapshot is also written when the server shuts down and syncs
+        // the engine
+        Engine::memory => {
+            let engine = InMemoryEngine::with_snapshot(
+                data_dir.join(MEMORY_SNAPSHOT_FILE),
+                config.snapshot_interval.map(Duration::from_secs),
+            )?;
+            run_on_pool(engine, &config)
+        }
     }
 }
 
-pub fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+/// Builds the thread pool of the config, which serves the connections.
+fn run_on_pool<E: KvsEngine>(engine: E, config: &Config) -> Result<()> {
+    match config.thread_pool {
+        PoolKind::Naive => run_with(engine, NaiveThreadPool::new(config.threads)?, config),
+        PoolKind::SharedQueue => {
+            run_with(engine, SharedQueueThreadPool::new(config.threads)?, config)
+        }
+        PoolKind::Rayon => run_with(engine, RayonThreadPool::new(config.threads)?, config),
+    }
+}
+
+fn run_with<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    pool: P,
+    config: &Config,
+) -> Result<()> {
+    // the RESP and HTTP front ends neither authenticate their clients nor
+    // encrypt, so they would bypass both
+    let front_ends = config.resp_addr.is_some() || config.http_addr.is_some();
+    if front_ends && config.credentials.is_some() {
+        return Err(KvsError::StringError(
+            "--credentials is not supported with --resp-addr or --http-addr".to_owned(),
+        ));
+    }
+    if front_ends && config.tls_cert.is_some() {
+        return Err(KvsError::StringError(
+            "TLS is not supported with --resp-addr or --http-addr".to_owned(),
+        ));
+    }
+    let auth = match config.credentials {
+        Some(ref path) => Some(Authenticator::from_file(path)?),
+        None => None,
+    };
+    let tls = server_tls(config)?;
+    if tls.is_some() && config.event_loop {
+        return Err(KvsError::StringError(
+            "TLS is not supported with --async".to_owned(),
+        ));
+    }
+
+    if let Some(resp_addr) = config.resp_addr {
+        let resp_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+        let server = RespServer::new(engine.clone(), resp_pool);
+        info!("Serving RESP on {}", resp_addr);
+        run_in_background("resp", move || server.run(resp_addr))?;
+    }
+
+    // the HTTP gateway shares the pool of the TCP server
+    let pool = Arc::new(pool);
+    if let Some(http_addr) = config.http_addr {
+        let server = HttpServer::new(engine.clone(), Arc::clone(&pool));
+        info!("Serving HTTP on {}", http_addr);
+        run_in_background("http", move || server.run(http_addr))?;
+    }
+
+    if config.event_loop {
+        // the event loop cannot be stopped, so only the engine is synced
+        let synced = engine.clone();
+        on_shutdown_signal(move || {
+            if let Err(e) = synced.sync() {
+                error!("{}", e);
+                exit(1);
+            }
+            exit(0);
+        })?;
+        let addr = match config.addr {
+            Address::Tcp(addr) => addr,
+            Address::Unix(_) => {
+                return Err(KvsError::StringError(
+                    "Unix sockets are not supported with --async".to_owned(),
+                ))
+            }
+        };
+        // connections do not wait for a thread and Unix sockets are refused
+        // above, so neither the accept queue nor the socket mode applies
+        let mut server = AsyncKvsServer::new(engine, pool)
+            .with_max_connections(config.max_connections)
+            .with_read_timeout(timeout(config.read_timeout))
+            .with_write_timeout(timeout(config.write_timeout))
+            .with_idle_timeout(timeout(config.idle_timeout));
+        if let Some(auth) = auth {
+            server = server.with_auth(auth);
+        }
+        if let Some(metrics_addr) = config.metrics_addr {
+            metrics::serve(server.registry(), metrics_addr)?;
+        }
+        return server.run(addr);
+    }
+
+    let mut server = KvsServer::new(engine, pool)
+        .with_max_connections(config.max_connections)
+        .with_accept_queue(config.accept_queue)
+        .with_read_timeout(timeout(config.read_timeout))
+        .with_write_timeout(timeout(config.write_timeout))
+        .with_idle_timeout(timeout(config.idle_timeout))
+        .with_socket_mode(config.socket_mode.0);
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    if let Some(tls) = tls {
+        server = server.with_tls(tls);
+    }
+    let handle = server.shutdown_handle();
+    on_shutdown_signal(move || handle.shutdown())?;
+    if let Some(metrics_addr) = config.metrics_addr {
+        metrics::serve(server.registry(), metrics_addr)?;
+    }
+    server.run(&config.addr)?;
+    info!("Shut down");
+    Ok(())
+}
+
+/// Reads the TLS configuration from `tls_cert`, `tls_key` and `tls_client_ca`.
+fn server_tls(config: &Config) -> Result<Option<ServerTls>> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.tls_client_ca.is_none() => return Ok(None),
+        _ => {
+            return Err(KvsError::StringError(
+                "TLS requires both tls_cert and tls_key".to_owned(),
+            ))
+        }
+    };
+    let tls = match config.tls_client_ca {
+        Some(ref ca) => ServerTls::with_client_auth(cert, key, ca)?,
+        None => ServerTls::new(cert, key)?,
+    };
+    Ok(Some(tls))
+}
+
+/// Turns a timeout flag into a timeout, 0 meaning none.
+fn timeout(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// Set by the handler of SIGINT and SIGTERM.
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// Calls `shutdown` on a thread of its own once SIGINT or SIGTERM is received.
+///
+/// A second signal kills the process right away.
+#[cfg(unix)]
+fn on_shutdown_signal<F: FnOnce() + Send + 'static>(shutdown: F) -> Result<()> {
+    use std::{io, mem, ptr};
+
+    extern "C" fn handle(_: libc::c_int) {
+        SIGNALED.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND;
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+    }
+
+    thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            while !SIGNALED.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            info!("Shutdown signal received");
+            shutdown();
+        })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn on_shutdown_signal<F: FnOnce() + Send + 'static>(_shutdown: F) -> Result<()> {
+    Ok(())
+}
+
+/// Runs a server on its own thread, exiting if it fails.
+fn run_in_background<F>(name: &str, run: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    thread::Builder::new().name(name.to_owned()).spawn(move || {
+        if let Err(e) = run() {
+            error!("{}", e);
+            exit(1);
+        }
+    })?;
+    Ok(())
+}
+
+/// Reads the encryption key from `encryption_key_file` or `KVS_ENCRYPTION_KEY`.
+fn encryption_key(config: &Config) -> Result<Option<EncryptionKey>> {
+    match config.encryption_key_file {
+        Some(ref path) => Ok(Some(EncryptionKey::from_file(path)?)),
+        None if env::var_os(ENCRYPTION_KEY_VAR).is_some() => {
+            Ok(Some(EncryptionKey::from_env(ENCRYPTION_KEY_VAR)?))
+        }
+        None => Ok(None),
+    }
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+fn current_engine(data_dir: &Path) -> Result<Option<Engine>> {
+    let engine = data_dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
@@ -1,67 +1,965 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use serde::de::DeserializeOwned;
 
 use crate::{KvsError, Result};
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::codec::{decode_frame, MessageReader, MessageWriter, Transport};
+use crate::auth::Credentials;
+use crate::common::{
+    AuthResponse, ChangesResponse, GetManyResponse, GetResponse, ListNamespacesResponse,
+    NamespaceResponse, RemoveManyResponse, RemoveResponse, Request, SetManyResponse, SetResponse,
+    WatchResponse,
+};
+use crate::engines::{Change, Event, WatchTarget};
+use crate::net::{Address, Socket, ToAddrs};
+use crate::protocol::{ErrorCode, Features, Frame, Protocol};
+use crate::tls::{ClientTls, Stream};
+
+/// Number of pipelined requests sent before their responses are read.
+///
+/// The server answers while the client is still sending, so the responses
+/// have to be read before they fill the socket buffers and stall both sides.
+const PIPELINE_WINDOW: usize = 128;
+
+/// How long `KvsClient::connect` waits for the server to accept the
+/// connection and answer the handshake.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `KvsClient::connect` lets the client wait for a response.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+type TcpTransport = Transport<BufReader<Stream>, BufWriter<Stream>>;
 
 /// Key value store client
+///
+/// Clones share the connection to the server. When the server supports
+/// request IDs, the requests of the clones are sent as they come and each
+/// response is matched back to its request, so many threads can share one
+/// connection without waiting for each other. Otherwise they take turns.
+#[derive(Clone)]
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    conn: Arc<Connection>,
+}
+
+struct Connection {
+    stream: Stream,
+    protocol: Protocol,
+    features: Features,
+    mode: Mode,
+}
+
+enum Mode {
+    /// Each request holds the connection until its response is read. The
+    /// transport is taken by a watch.
+    Serial(Mutex<Option<TcpTransport>>),
+    /// Requests are tagged with IDs and a thread reads the responses.
+    Multiplexed(Multiplexer),
+}
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // wakes the thread reading the responses up
+        let _ = self.stream.socket().shutdown(Shutdown::Both);
+    }
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+    ///
+    /// `addr` is a TCP address or `unix:` followed by the path of a Unix
+    /// socket. The binary protocol is negotiated with the server, falling back to
+    /// JSON if the server does not speak it.
+    ///
+    /// It gives up after `DEFAULT_CONNECT_TIMEOUT`, and requests fail with
+    /// `KvsError::Timeout` once their response takes longer than
+    /// `DEFAULT_READ_TIMEOUT`.
+    pub fn connect<A: ToAddrs>(addr: A) -> Result<Self> {
+        KvsClient::connect_timeout(addr, DEFAULT_CONNECT_TIMEOUT, Some(DEFAULT_READ_TIMEOUT))
+    }
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+    /// Connect to `addr` like `connect`, with the given timeouts
+    ///
+    /// The connection and the handshake each take at most `connect_timeout`.
+    /// Responses are waited for at most `read_timeout`, or forever if `None`.
+    pub fn connect_timeout<A: ToAddrs>(
+        addr: A,
+        connect_timeout: Duration,
+        read_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        KvsClient::open(addr, None, connect_timeout, read_timeout)
+    }
+
+    /// Connect to `addr` like `connect`, over TLS
+    ///
+    /// The certificate of the server is checked against the CA of `tls` and,
+    /// unless `tls` has a server name, the IP address connected to.
+    pub fn connect_tls<A: ToAddrs>(addr: A, tls: &ClientTls) -> Result<Self> {
+        KvsClient::open(
+            addr,
+            Some(tls),
+            DEFAULT_CONNECT_TIMEOUT,
+            Some(DEFAULT_READ_TIMEOUT),
+        )
+    }
+
+    fn open<A: ToAddrs>(
+        addr: A,
+        tls: Option<&ClientTls>,
+        connect_timeout: Duration,
+        read_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (stream, peer_addr) = connect_any(addr, tls, connect_timeout)?;
+        stream.socket().set_read_timeout(Some(connect_timeout))?;
+        let reader = BufReader::new(stream.clone());
+        let transport = match Transport::connect(reader, BufWriter::new(stream.clone()))? {
+            Some(transport) => transport,
+            // servers only speaking JSON close the connection on the handshake
+            None => return KvsClient::json(peer_addr, tls, connect_timeout, read_timeout),
+        };
+        let features = transport.features();
+        let mode = if features.contains(Features::REQUEST_IDS) {
+            // the responses are waited for by the requests instead
+            stream.socket().set_read_timeout(None)?;
+            Mode::Multiplexed(Multiplexer::start(transport, read_timeout)?)
+        } else {
+            stream.socket().set_read_timeout(read_timeout)?;
+            Mode::Serial(Mutex::new(Some(transport)))
+        };
+        Ok(KvsClient::new(stream, Protocol::Binary, features, mode))
+    }
+
+    fn new(stream: Stream, protocol: Protocol, features: Features, mode: Mode) -> Self {
+        KvsClient {
+            conn: Arc::new(Connection {
+                stream,
+                protocol,
+                features,
+                mode,
+            }),
+        }
+    }
+
+    /// Connect to `addr` like `connect`, then authenticate with `credentials`
+    pub fn connect_with_credentials<A: ToAddrs>(
+        addr: A,
+        credentials: Credentials,
+    ) -> Result<Self> {
+        let mut client = KvsClient::connect(addr)?;
+        client.authenticate(credentials)?;
+        Ok(client)
+    }
+
+    /// Connect to `addr` with the JSON protocol, without a handshake
+    ///
+    /// The timeouts are the ones of `connect`.
+    pub fn connect_json<A: ToAddrs>(addr: A) -> Result<Self> {
+        KvsClient::json(addr, None, DEFAULT_CONNECT_TIMEOUT, Some(DEFAULT_READ_TIMEOUT))
+    }
+
+    fn json<A: ToAddrs>(
+        addr: A,
+        tls: Option<&ClientTls>,
+        connect_timeout: Duration,
+        read_timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let (stream, _) = connect_any(addr, tls, connect_timeout)?;
+        stream.socket().set_read_timeout(read_timeout)?;
+        let reader = BufReader::new(stream.clone());
+        let transport = Transport::json(reader, BufWriter::new(stream.clone()));
+        let mode = Mode::Serial(Mutex::new(Some(transport)));
+        Ok(KvsClient::new(stream, Protocol::Json, Features::default(), mode))
+    }
+
+    /// Sets how long a response is waited for, or forever if `None`, for
+    /// this client and its clones.
+    ///
+    /// A request timing out fails with `KvsError::Timeout`. Unless the
+    /// server supports request IDs, the client should not be used any more
+    /// after that.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match &self.conn.mode {
+            Mode::Serial(_) => self.conn.stream.socket().set_read_timeout(timeout)?,
+            Mode::Multiplexed(mux) => *mux.read_timeout.lock().unwrap() = timeout,
+        }
+        Ok(())
+    }
+
+    /// Returns the protocol spoken with the server.
+    pub fn protocol(&self) -> Protocol {
+        self.conn.protocol
+    }
+
+    /// Returns the protocol features negotiated with the server.
+    pub fn features(&self) -> Features {
+        self.conn.features
     }
 
 
+    /// Authenticate the connection, replacing the scope granted before
+    ///
+    /// On a server requiring authentication, the requests sent before fail
+    /// with `KvsError::Unauthenticated`, and the ones outside the scope
+    /// granted with `KvsError::PermissionDenied`. With the JSON protocol,
+    /// they fail with the message of those errors instead.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unauthenticated` if the server refuses the
+    /// credentials.
+    pub fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        match self.request(&Request::Authenticate(credentials))? {
+            AuthResponse::Ok(_) => Ok(()),
+            AuthResponse::Err(msg) => Err(KvsError::Unauthenticated(msg)),
+        }
+    }
+
     /// Get the value of a given key from the server
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
+        self.send_get(Request::Get { namespace: None, key })
+    }
+
+    /// Set the value of a string key in the server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send_set(Request::Set {
+            namespace: None,
+            key,
+            value,
+        })
+    }
+
+    /// Remove a string key in the server
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.send_remove(Request::Remove { namespace: None, key })
+    }
+
+    /// Get the value of a given key in a namespace from the server
+    pub fn get_in(&mut self, namespace: &str, key: String) -> Result<Option<String>> {
+        self.send_get(Request::Get {
+            namespace: Some(namespace.to_owned()),
+            key,
+        })
+    }
+
+    /// Set the value of a string key in a namespace in the server
+    pub fn set_in(&mut self, namespace: &str, key: String, value: String) -> Result<()> {
+        self.send_set(Request::Set {
+            namespace: Some(namespace.to_owned()),
+            key,
+            value,
+        })
+    }
+
+    /// Remove a string key in a namespace in the server
+    pub fn remove_in(&mut self, namespace: &str, key: String) -> Result<()> {
+        self.send_remove(Request::Remove {
+            namespace: Some(namespace.to_owned()),
+            key,
+        })
+    }
 
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    /// Get the values of several keys from the server in one round trip
+    ///
+    /// The result of each key is returned in the order of `keys`, so that a
+    /// missing key does not fail the others.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        self.send_get_many(Request::GetMany { namespace: None, keys })
+    }
+
+    /// Set several key/value pairs in the server in one round trip
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        self.send_set_many(Request::SetMany { namespace: None, pairs })
+    }
 
+    /// Remove several keys in the server in one round trip
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        self.send_remove_many(Request::RemoveMany { namespace: None, keys })
+    }
+
+    /// Get the values of several keys in a namespace from the server
+    pub fn get_many_in(
+        &mut self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        self.send_get_many(Request::GetMany {
+            namespace: Some(namespace.to_owned()),
+            keys,
+        })
+    }
+
+    /// Set several key/value pairs in a namespace in the server
+    pub fn set_many_in(
+        &mut self,
+        namespace: &str,
+        pairs: Vec<(String, String)>,
+    ) -> Result<Vec<Result<()>>> {
+        self.send_set_many(Request::SetMany {
+            namespace: Some(namespace.to_owned()),
+            pairs,
+        })
+    }
+
+    /// Remove several keys in a namespace in the server
+    pub fn remove_many_in(
+        &mut self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<()>>> {
+        self.send_remove_many(Request::RemoveMany {
+            namespace: Some(namespace.to_owned()),
+            keys,
+        })
+    }
+
+    /// Create a namespace in the server
+    pub fn create_namespace(&mut self, name: &str) -> Result<()> {
+        let req = Request::CreateNamespace {
+            name: name.to_owned(),
+        };
+        match self.request(&req)? {
+            NamespaceResponse::Ok(_) => Ok(()),
+            NamespaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Drop a namespace and all of its keys in the server
+    pub fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        let req = Request::DropNamespace {
+            name: name.to_owned(),
+        };
+        match self.request(&req)? {
+            NamespaceResponse::Ok(_) => Ok(()),
+            NamespaceResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// List the namespaces in the server
+    pub fn list_namespaces(&mut self) -> Result<Vec<String>> {
+        match self.request(&Request::ListNamespaces)? {
+            ListNamespacesResponse::Ok(names) => Ok(names),
+            ListNamespacesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Read up to `limit` changes committed after the sequence number `seq`
+    ///
+    /// A consumer resumes by passing the sequence number of the last change it
+    /// processed. `KvsError::ResyncRequired` means those changes have been
+    /// compacted and the consumer has to start over from `0`.
+    pub fn changes_since(&mut self, seq: u64, limit: usize) -> Result<Vec<Change>> {
+        match self.request(&Request::ChangesSince { seq, limit })? {
+            ChangesResponse::Ok(changes) => Ok(changes),
+            ChangesResponse::ResyncRequired(compacted) => Err(KvsError::ResyncRequired(compacted)),
+            ChangesResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Start a pipeline of requests, sent together once executed
+    ///
+    /// The requests of a pipeline run in order, even on a connection shared
+    /// by several threads.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let mut pipeline = client.pipeline();
+    /// pipeline.set("key".to_owned(), "value".to_owned());
+    /// pipeline.get("key".to_owned());
+    /// let results = pipeline.execute()?;
+    /// assert_eq!(results[1].as_ref().ok(), Some(&Some("value".to_owned())));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Watch the keys matching `target` in the server
+    ///
+    /// The connection is dedicated to the watch from now on, so the client is
+    /// consumed and turned into a blocking iterator of events. The requests
+    /// of its clones sent before still get their responses, the later ones
+    /// fail.
+    pub fn watch(self, target: WatchTarget) -> Result<WatchEvents> {
+        self.send_watch(Request::Watch {
+            namespace: None,
+            target,
+        })
+    }
+
+    /// Watch the keys matching `target` in a namespace in the server
+    pub fn watch_in(self, namespace: &str, target: WatchTarget) -> Result<WatchEvents> {
+        self.send_watch(Request::Watch {
+            namespace: Some(namespace.to_owned()),
+            target,
+        })
+    }
+
+    fn send_watch(self, req: Request) -> Result<WatchEvents> {
+        let (resp, events) = match &self.conn.mode {
+            Mode::Serial(transport) => {
+                let mut transport = transport.lock().unwrap().take().ok_or_else(watching)?;
+                let resp = send_serial(&mut transport, &req)?;
+                (resp, Events::Serial(transport))
+            }
+            Mode::Multiplexed(mux) => {
+                let reply = mux.send(&[req], Tagging::Watch)?.remove(0);
+                (reply.recv()?, Events::Multiplexed(reply))
+            }
+        };
+        match resp {
+            // events may be far apart, so they are waited for forever
+            WatchResponse::Ok(_) => {
+                self.conn.stream.socket().set_read_timeout(None)?;
+                Ok(WatchEvents {
+                    _conn: self.conn,
+                    events,
+                    done: false,
+                })
+            }
+            WatchResponse::Event(_) => Err(KvsError::StringError(
+                "Unexpected event before the watch was acknowledged".to_owned(),
+            )),
+            WatchResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    fn send_get(&mut self, req: Request) -> Result<Option<String>> {
+        let resp = self.request(&req)?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
             GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
 
-    /// Set the value of a string key in the server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+    fn send_set(&mut self, req: Request) -> Result<()> {
+        let resp = self.request(&req)?;
         match resp {
             SetResponse::Ok(_) => { Ok(()) }
             SetResponse::Err(msg) => { Err(KvsError::StringError(msg)) }
         }
     }
 
-    /// Remove a string key in the server
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-
+    fn send_remove(&mut self, req: Request) -> Result<()> {
+        let resp = self.request(&req)?;
         match resp {
             RemoveResponse::Ok(_) => { Ok(()) }
             RemoveResponse::Err(msg) => { Err(KvsError::StringError(msg)) }
         }
     }
-}
\ No newline at end of file
+
+    fn send_get_many(&mut self, req: Request) -> Result<Vec<Result<Option<String>>>> {
+        match self.request(&req)? {
+            GetManyResponse::Ok(resps) => Ok(resps.into_iter().map(get_result).collect()),
+            GetManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    fn send_set_many(&mut self, req: Request) -> Result<Vec<Result<()>>> {
+        match self.request(&req)? {
+            SetManyResponse::Ok(resps) => Ok(resps.into_iter().map(set_result).collect()),
+            SetManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    fn send_remove_many(&mut self, req: Request) -> Result<Vec<Result<()>>> {
+        match self.request(&req)? {
+            RemoveManyResponse::Ok(resps) => Ok(resps.into_iter().map(remove_result).collect()),
+            RemoveManyResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Send a request and read its response
+    fn request<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        match &self.conn.mode {
+            Mode::Serial(transport) => {
+                let mut transport = transport.lock().unwrap();
+                send_serial(transport.as_mut().ok_or_else(watching)?, req)
+            }
+            Mode::Multiplexed(mux) => {
+                mux.send(std::slice::from_ref(req), Tagging::Tagged)?[0].recv()
+            }
+        }
+    }
+}
+
+/// Returns the error of a request on a connection taken over by a watch.
+fn watching() -> KvsError {
+    KvsError::StringError("The connection is dedicated to a watch".to_owned())
+}
+
+fn send_serial<T: DeserializeOwned>(transport: &mut TcpTransport, req: &Request) -> Result<T> {
+    transport.send(req)?;
+    transport.flush()?;
+    recv_serial(transport)
+}
+
+fn recv_serial<T: DeserializeOwned>(transport: &mut TcpTransport) -> Result<T> {
+    transport
+        .recv()?
+        .ok_or_else(|| KvsError::Io(io::ErrorKind::UnexpectedEof.into()))
+}
+
+/// Converts the response to a get, also found in the response to a batch.
+pub(crate) fn get_result(resp: GetResponse) -> Result<Option<String>> {
+    match resp {
+        GetResponse::Ok(value) => Ok(value),
+        GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    }
+}
+
+pub(crate) fn set_result(resp: SetResponse) -> Result<()> {
+    match resp {
+        SetResponse::Ok(_) => Ok(()),
+        SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    }
+}
+
+pub(crate) fn remove_result(resp: RemoveResponse) -> Result<()> {
+    match resp {
+        RemoveResponse::Ok(_) => Ok(()),
+        RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+    }
+}
+
+/// Where the responses to requests are read from.
+trait Responses {
+    /// Reads the next response.
+    fn next<T: DeserializeOwned>(&mut self) -> Result<T>;
+}
+
+impl Responses for TcpTransport {
+    fn next<T: DeserializeOwned>(&mut self) -> Result<T> {
+        recv_serial(self)
+    }
+}
+
+/// Read the response to a pipelined request
+///
+/// The outer result fails if the connection is broken, the inner one if the
+/// server failed the request.
+fn read_result(req: &Request, responses: &mut impl Responses) -> Result<Result<Option<String>>> {
+    let result = match req {
+        Request::Get { .. } => responses.next().map(get_result),
+        Request::Set { .. } => responses.next().map(|resp| set_result(resp).map(|_| None)),
+        Request::Remove { .. } => responses.next().map(|resp| remove_result(resp).map(|_| None)),
+        _ => unreachable!("only key requests are pipelined"),
+    };
+    match result {
+        // a refused request leaves the connection usable
+        Err(e @ KvsError::Unauthenticated(_)) | Err(e @ KvsError::PermissionDenied(_)) => {
+            Ok(Err(e))
+        }
+        result => result,
+    }
+}
+
+/// Connects to the first address of `addr` accepting the connection within
+/// `timeout`, over TLS if `tls` is given, returning it with that address.
+fn connect_any<A: ToAddrs>(
+    addr: A,
+    tls: Option<&ClientTls>,
+    timeout: Duration,
+) -> Result<(Stream, Address)> {
+    let mut last_err = None;
+    for addr in addr.to_addrs()? {
+        match Socket::connect(&addr, timeout) {
+            Ok(socket) => {
+                let stream = match tls {
+                    Some(tls) => Stream::connect(socket, tls, &addr)?,
+                    None => Stream::plain(socket),
+                };
+                return Ok((stream, addr));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))
+        .into())
+}
+
+/// A batch of key requests sent without waiting for each response.
+///
+/// Requests are queued by `get`, `set` and `remove`, then `execute` writes them
+/// together and returns one result per request, in order.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queue a get of the value of a key
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { namespace: None, key });
+        self
+    }
+
+    /// Queue a set of the value of a key
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set {
+            namespace: None,
+            key,
+            value,
+        });
+        self
+    }
+
+    /// Queue a removal of a key
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { namespace: None, key });
+        self
+    }
+
+    /// Returns the number of queued requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns true if no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Send the queued requests and read their results
+    ///
+    /// Gets result in their value, sets and removes in `None`. A request
+    /// failing on the server, like the removal of a missing key, fails only
+    /// its own result.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if the connection fails, after which the client
+    /// should not be used any more.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        let mut results = Vec::with_capacity(self.requests.len());
+        match &self.client.conn.mode {
+            Mode::Serial(transport) => {
+                let mut transport = transport.lock().unwrap();
+                let transport = transport.as_mut().ok_or_else(watching)?;
+                for window in self.requests.chunks(PIPELINE_WINDOW) {
+                    for req in window {
+                        transport.send(req)?;
+                    }
+                    transport.flush()?;
+                    for req in window {
+                        results.push(read_result(req, transport)?);
+                    }
+                }
+            }
+            // untagged requests are run and answered in order
+            Mode::Multiplexed(mux) => {
+                for window in self.requests.chunks(PIPELINE_WINDOW) {
+                    let replies = mux.send(window, Tagging::InOrder)?;
+                    for (req, mut reply) in window.iter().zip(replies) {
+                        results.push(read_result(req, &mut reply)?);
+                    }
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Blocking iterator over the events streamed by a watch.
+///
+/// It ends when the server closes the connection, and yields an error if the
+/// server ends the watch, for example because the watcher lagged behind.
+pub struct WatchEvents {
+    // keeps the connection open
+    _conn: Arc<Connection>,
+    events: Events,
+    done: bool,
+}
+
+enum Events {
+    Serial(TcpTransport),
+    Multiplexed(Reply),
+}
+
+impl Iterator for WatchEvents {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let resp = match &mut self.events {
+            Events::Serial(transport) => transport.recv(),
+            Events::Multiplexed(reply) => reply.recv_event(),
+        };
+        match resp {
+            Ok(Some(WatchResponse::Event(event))) => Some(Ok(event)),
+            Ok(Some(WatchResponse::Ok(_))) => {
+                self.done = true;
+                Some(Err(KvsError::StringError(
+                    "Unexpected acknowledgement in the event stream".to_owned(),
+                )))
+            }
+            Ok(Some(WatchResponse::Err(msg))) => {
+                self.done = true;
+                Some(Err(KvsError::StringError(msg)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// How requests are sent on a multiplexed connection.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tagging {
+    /// Tagged with an ID, run concurrently with the others
+    Tagged,
+    /// Untagged, run and answered in order
+    InOrder,
+    /// Tagged, and answered by a stream of events taking the connection over
+    Watch,
+}
+
+/// The sending side of a connection whose requests are tagged with IDs.
+struct Multiplexer {
+    writer: Mutex<MessageWriter<BufWriter<Stream>>>,
+    pending: Arc<Pending>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Multiplexer {
+    /// Starts the thread reading the responses of `transport`.
+    fn start(transport: TcpTransport, read_timeout: Option<Duration>) -> Result<Self> {
+        let (reader, writer) = transport.into_split();
+        let pending = Arc::new(Pending::default());
+        let dispatcher = Arc::clone(&pending);
+        thread::Builder::new()
+            .name("kvs-client".to_owned())
+            .spawn(move || dispatch(reader, &dispatcher))?;
+        Ok(Multiplexer {
+            writer: Mutex::new(writer),
+            pending,
+            read_timeout: Mutex::new(read_timeout),
+        })
+    }
+
+    /// Sends requests, returning where each response arrives.
+    fn send(&self, reqs: &[Request], tagging: Tagging) -> Result<Vec<Reply>> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        // untagged requests are registered in the order they are written
+        let mut writer = self.writer.lock().unwrap();
+        let mut replies = Vec::with_capacity(reqs.len());
+        for req in reqs {
+            let (id, receiver) = self.pending.register(tagging)?;
+            writer.send_tagged(id, req)?;
+            replies.push(Reply {
+                pending: Arc::clone(&self.pending),
+                id,
+                receiver,
+                timeout,
+            });
+        }
+        writer.flush()?;
+        Ok(replies)
+    }
+}
+
+/// Reads the responses of a multiplexed connection and hands them to their
+/// requests, until the connection is closed.
+fn dispatch(mut reader: MessageReader<BufReader<Stream>>, pending: &Pending) {
+    let closed = loop {
+        match reader.recv_frame() {
+            Ok(Some((id, frame))) => {
+                if let Err(closed) = pending.deliver(id, frame) {
+                    break closed;
+                }
+            }
+            Ok(None) => break Closed::ByPeer,
+            Err(e) => break Closed::from_error(e),
+        }
+    };
+    pending.close(closed);
+}
+
+/// The requests of a multiplexed connection waiting for their responses,
+/// shared with the thread reading them.
+#[derive(Default)]
+struct Pending(Mutex<PendingState>);
+
+#[derive(Default)]
+struct PendingState {
+    last_id: u64,
+    tagged: HashMap<u64, Waiter>,
+    untagged: VecDeque<Sender<Frame>>,
+    // set once a watch takes the connection over
+    watching: bool,
+    closed: Option<Closed>,
+}
+
+struct Waiter {
+    sender: Sender<Frame>,
+    // a watch is answered by many frames
+    streaming: bool,
+}
+
+impl Pending {
+    /// Registers a request, returning its ID if it is tagged and where its
+    /// response arrives.
+    fn register(&self, tagging: Tagging) -> Result<(Option<u64>, Receiver<Frame>)> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(closed) = &state.closed {
+            return Err(closed.to_error());
+        }
+        if state.watching {
+            return Err(watching());
+        }
+        let (sender, receiver) = channel::unbounded();
+        if tagging == Tagging::InOrder {
+            state.untagged.push_back(sender);
+            return Ok((None, receiver));
+        }
+        state.last_id += 1;
+        let id = state.last_id;
+        let streaming = tagging == Tagging::Watch;
+        state.watching = streaming;
+        state.tagged.insert(id, Waiter { sender, streaming });
+        Ok((Some(id), receiver))
+    }
+
+    /// Hands a frame to the request it answers.
+    ///
+    /// Untagged frames answer the oldest untagged request. With none left,
+    /// an untagged error is one the server could not tie to a request, which
+    /// closes the connection.
+    fn deliver(&self, id: Option<u64>, frame: Frame) -> std::result::Result<(), Closed> {
+        let mut state = self.0.lock().unwrap();
+        let sender = match id {
+            Some(id) => match state.tagged.get(&id) {
+                Some(waiter) if waiter.streaming => waiter.sender.clone(),
+                Some(_) => state.tagged.remove(&id).unwrap().sender,
+                // the request timed out
+                None => return Ok(()),
+            },
+            None => match (state.untagged.pop_front(), frame) {
+                (Some(sender), frame) => {
+                    let _ = sender.send(frame);
+                    return Ok(());
+                }
+                (None, Frame::Error(code, msg)) => return Err(Closed::Error(code, msg)),
+                (None, Frame::Message(_)) => {
+                    return Err(Closed::Error(
+                        ErrorCode::Malformed,
+                        "Unexpected untagged response".to_owned(),
+                    ))
+                }
+            },
+        };
+        // the request may have timed out meanwhile
+        let _ = sender.send(frame);
+        Ok(())
+    }
+
+    /// Records why the connection closed, failing the requests waiting.
+    fn close(&self, closed: Closed) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = Some(closed);
+        state.tagged.clear();
+        state.untagged.clear();
+    }
+
+    fn forget(&self, id: u64) {
+        self.0.lock().unwrap().tagged.remove(&id);
+    }
+
+    /// Returns the error of a request whose response will not come.
+    fn closed_error(&self) -> KvsError {
+        match &self.0.lock().unwrap().closed {
+            Some(closed) => closed.to_error(),
+            None => Closed::ByPeer.to_error(),
+        }
+    }
+}
+
+/// Why a multiplexed connection closed.
+enum Closed {
+    ByPeer,
+    Error(ErrorCode, String),
+    Failed(io::ErrorKind, String),
+}
+
+impl Closed {
+    fn from_error(e: KvsError) -> Self {
+        match e {
+            KvsError::Protocol(code, msg) => Closed::Error(code, msg),
+            KvsError::Io(e) => Closed::Failed(e.kind(), e.to_string()),
+            e => Closed::Failed(io::ErrorKind::Other, e.to_string()),
+        }
+    }
+
+    fn to_error(&self) -> KvsError {
+        match self {
+            Closed::ByPeer => KvsError::Io(io::ErrorKind::UnexpectedEof.into()),
+            Closed::Error(code, msg) => code.into_error(msg.clone()),
+            Closed::Failed(kind, msg) => KvsError::Io(io::Error::new(*kind, msg.clone())),
+        }
+    }
+}
+
+/// Where the response to a request sent on a multiplexed connection arrives.
+struct Reply {
+    pending: Arc<Pending>,
+    id: Option<u64>,
+    receiver: Receiver<Frame>,
+    timeout: Option<Duration>,
+}
+
+impl Reply {
+    fn recv<T: DeserializeOwned>(&self) -> Result<T> {
+        let frame = match self.timeout {
+            Some(timeout) => self.receiver.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => {
+                    if let Some(id) = self.id {
+                        self.pending.forget(id);
+                    }
+                    KvsError::Timeout
+                }
+                RecvTimeoutError::Disconnected => self.pending.closed_error(),
+            })?,
+            None => self
+                .receiver
+                .recv()
+                .map_err(|_| self.pending.closed_error())?,
+        };
+        decode_frame(frame)
+    }
+
+    /// Waits for the next response of a watch, or returns `None` once the
+    /// server closed the connection.
+    fn recv_event(&self) -> Result<Option<WatchResponse>> {
+        match self.receiver.recv() {
+            Ok(frame) => decode_frame(frame).map(Some),
+            Err(_) => match self.pending.closed_error() {
+                KvsError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+impl Responses for Reply {
+    fn next<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.recv()
+    }
+}
@@ -0,0 +1,477 @@
+use std::io::{self, BufRead, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Deserializer;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+use crate::protocol::{
+    self, error_frame, message_frame, read_frame, read_frame_async, ErrorCode, Features, Frame,
+    Handshake, Protocol, HANDSHAKE_LEN, MAGIC,
+};
+use crate::{KvsError, Result};
+
+/// Reads and writes the messages of a connection in the protocol it speaks.
+pub(crate) struct Transport<R, W> {
+    reader: MessageReader<R>,
+    writer: MessageWriter<W>,
+    features: Features,
+}
+
+/// The half of a `Transport` reading messages.
+pub(crate) struct MessageReader<R> {
+    reader: R,
+    protocol: Protocol,
+}
+
+/// The half of a `Transport` writing messages.
+pub(crate) struct MessageWriter<W> {
+    writer: W,
+    protocol: Protocol,
+}
+
+impl<R: BufRead, W: Write> Transport<R, W> {
+    /// Opens the connection of a client with the handshake.
+    ///
+    /// Returns `None` if the server closed the connection instead of
+    /// answering, as servers only speaking JSON do.
+    pub(crate) fn connect(mut reader: R, mut writer: W) -> Result<Option<Self>> {
+        writer.write_all(&Handshake::ours().to_bytes())?;
+        writer.flush()?;
+
+        let mut answer = [0; HANDSHAKE_LEN];
+        match reader.read_exact(&mut answer) {
+            Ok(()) => {}
+            Err(e) if closed_by_peer(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let features = check_answer(Handshake::from_bytes(answer)?)?;
+        Ok(Some(Transport::new(reader, writer, Protocol::Binary, features)))
+    }
+
+    /// Opens the connection of a client speaking JSON.
+    pub(crate) fn json(reader: R, writer: W) -> Self {
+        Transport::new(reader, writer, Protocol::Json, Features::default())
+    }
+
+    fn new(reader: R, writer: W, protocol: Protocol, features: Features) -> Self {
+        Transport {
+            reader: MessageReader { reader, protocol },
+            writer: MessageWriter { writer, protocol },
+            features,
+        }
+    }
+
+    /// Opens a connection on the server, answering the handshake if the
+    /// client starts with one and falling back to JSON otherwise.
+    pub(crate) fn accept(mut reader: R, mut writer: W) -> Result<Self> {
+        if reader.fill_buf()?.first() != Some(&MAGIC[0]) {
+            return Ok(Transport::json(reader, writer));
+        }
+
+        let mut bytes = [0; HANDSHAKE_LEN];
+        reader.read_exact(&mut bytes)?;
+        let answer = Handshake::from_bytes(bytes)?.negotiate();
+        writer.write_all(&answer.to_bytes())?;
+        writer.flush()?;
+        check_version(answer)?;
+        Ok(Transport::new(reader, writer, Protocol::Binary, answer.features))
+    }
+
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.reader.protocol
+    }
+
+    pub(crate) fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Splits the transport, so that messages can be read and written from
+    /// different threads.
+    pub(crate) fn into_split(self) -> (MessageReader<R>, MessageWriter<W>) {
+        (self.reader, self.writer)
+    }
+
+    /// Reads the next message, like `MessageReader::recv`.
+    pub(crate) fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        self.reader.recv()
+    }
+
+    /// Writes a message, which is buffered until `flush`.
+    pub(crate) fn send<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        self.writer.send(value)
+    }
+
+    /// Reports an error to the peer, if the protocol has a way to.
+    pub(crate) fn send_error(&mut self, code: ErrorCode, msg: &str) -> Result<()> {
+        self.writer.send_error(code, msg)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R: BufRead> MessageReader<R> {
+    /// Waits for the next message to start arriving, returning false if the
+    /// stream ends first.
+    pub(crate) fn wait_for_message(&mut self) -> Result<bool> {
+        match self.protocol {
+            Protocol::Json => skip_whitespace(&mut self.reader),
+            Protocol::Binary => Ok(!self.reader.fill_buf()?.is_empty()),
+        }
+    }
+
+    /// Reads the next message, or returns `None` if the stream ends between
+    /// messages.
+    ///
+    /// A malformed binary frame is reported as `ErrorCode::Malformed` and
+    /// leaves the connection usable.
+    pub(crate) fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.recv_tagged()? {
+            Some((_, value)) => value.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the next message with the ID of its frame if it is tagged, or
+    /// returns `None` if the stream ends between messages.
+    ///
+    /// The inner result fails if the message could not be decoded, or if
+    /// the frame reports an error, which leaves the connection usable.
+    pub(crate) fn recv_tagged<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Option<u64>, Result<T>)>> {
+        match self.protocol {
+            Protocol::Json => {
+                if !skip_whitespace(&mut self.reader)? {
+                    return Ok(None);
+                }
+                let mut de = Deserializer::from_reader(&mut self.reader);
+                Ok(Some((None, Ok(T::deserialize(&mut de)?))))
+            }
+            Protocol::Binary => Ok(self
+                .recv_frame()?
+                .map(|(id, frame)| (id, decode_frame(frame)))),
+        }
+    }
+
+    /// Reads the next frame of a binary connection with its ID if it is
+    /// tagged, leaving it to be decoded by `decode_frame`.
+    pub(crate) fn recv_frame(&mut self) -> Result<Option<(Option<u64>, Frame)>> {
+        debug_assert_eq!(self.protocol, Protocol::Binary);
+        read_frame(&mut self.reader)
+    }
+}
+
+impl<W: Write> MessageWriter<W> {
+    /// Writes a message, which is buffered until `flush`.
+    pub(crate) fn send<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_tagged(None, value)
+    }
+
+    /// Writes a message in a frame tagged with `id` if there is one, which
+    /// is buffered until `flush`.
+    ///
+    /// JSON has no tags: the message is written alone.
+    pub(crate) fn send_tagged<T: Serialize>(&mut self, id: Option<u64>, value: &T) -> Result<()> {
+        match self.protocol {
+            Protocol::Json => serde_json::to_writer(&mut self.writer, value)?,
+            Protocol::Binary => self.writer.write_all(&message_frame(id, value)?)?,
+        }
+        Ok(())
+    }
+
+    /// Reports an error to the peer, if the protocol has a way to.
+    pub(crate) fn send_error(&mut self, code: ErrorCode, msg: &str) -> Result<()> {
+        self.send_error_tagged(None, code, msg)
+    }
+
+    /// Reports an error to the peer in a frame tagged with `id` if there is
+    /// one, if the protocol has a way to.
+    pub(crate) fn send_error_tagged(
+        &mut self,
+        id: Option<u64>,
+        code: ErrorCode,
+        msg: &str,
+    ) -> Result<()> {
+        if self.protocol == Protocol::Binary {
+            self.writer.write_all(&error_frame(id, code, msg))?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Decodes the message held by a frame, failing on error frames.
+pub(crate) fn decode_frame<T: DeserializeOwned>(frame: Frame) -> Result<T> {
+    protocol::decode(&frame.into_message()?)
+}
+
+/// Consumes the whitespace before the next JSON value, returning false if
+/// the stream ends first.
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        let len = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let found = len < buf.len();
+        reader.consume(len);
+        if found {
+            return Ok(true);
+        }
+    }
+}
+
+/// Returns true if the peer closed the connection.
+fn closed_by_peer(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+fn check_version(answer: Handshake) -> Result<()> {
+    if answer.version == 0 {
+        return Err(KvsError::Protocol(
+            ErrorCode::UnsupportedVersion,
+            "No protocol version in common".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the answer of a server to our handshake, returning the features
+/// to use.
+fn check_answer(answer: Handshake) -> Result<Features> {
+    check_version(answer)?;
+    if answer.version > protocol::VERSION {
+        return Err(KvsError::Protocol(
+            ErrorCode::UnsupportedVersion,
+            format!("The server answered with version {}", answer.version),
+        ));
+    }
+    Ok(answer.features)
+}
+
+/// Reads a stream of JSON values from an async reader.
+///
+/// Values are not delimited on the wire, so the bytes read so far are parsed
+/// again until they hold a complete value.
+struct JsonReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> JsonReader<R> {
+    /// Creates a reader whose stream starts with the bytes already in `buf`.
+    fn with_buffer(reader: R, buf: Vec<u8>) -> Self {
+        JsonReader { reader, buf }
+    }
+
+    /// Waits for the next value to start arriving, returning false if the
+    /// stream ends first.
+    async fn wait_for_value(&mut self) -> Result<bool> {
+        loop {
+            let len = self
+                .buf
+                .iter()
+                .take_while(|b| b.is_ascii_whitespace())
+                .count();
+            self.buf.drain(..len);
+            if !self.buf.is_empty() {
+                return Ok(true);
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Returns the next value, or `None` if the stream ends between values.
+    async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            let mut values = Deserializer::from_slice(&self.buf).into_iter::<T>();
+            match values.next() {
+                Some(Ok(value)) => {
+                    let len = values.byte_offset();
+                    self.buf.drain(..len);
+                    return Ok(Some(value));
+                }
+                Some(Err(e)) if e.is_eof() => {}
+                Some(Err(e)) => return Err(e.into()),
+                // only whitespace so far
+                None => self.buf.clear(),
+            }
+
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(serde_json::Error::io(std::io::ErrorKind::UnexpectedEof.into()).into())
+                };
+            }
+        }
+    }
+}
+
+enum AsyncReader<R> {
+    Json(JsonReader<R>),
+    Binary(BufReader<R>),
+}
+
+/// Reads and writes the messages of an async connection, like `Transport`.
+///
+/// Messages are flushed as soon as they are sent.
+pub(crate) struct AsyncTransport<R, W> {
+    reader: AsyncReader<R>,
+    writer: BufWriter<W>,
+    features: Features,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncTransport<R, W> {
+    /// Opens the connection of a client with the handshake, like
+    /// `Transport::connect`.
+    pub(crate) async fn connect(mut reader: R, writer: W) -> Result<Option<Self>> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&Handshake::ours().to_bytes()).await?;
+        writer.flush().await?;
+
+        let mut answer = [0; HANDSHAKE_LEN];
+        match reader.read_exact(&mut answer).await {
+            Ok(_) => {}
+            Err(e) if closed_by_peer(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let features = check_answer(Handshake::from_bytes(answer)?)?;
+        Ok(Some(AsyncTransport {
+            reader: AsyncReader::Binary(BufReader::new(reader)),
+            writer,
+            features,
+        }))
+    }
+
+    /// Opens the connection of a client speaking JSON.
+    pub(crate) fn json(reader: R, writer: W) -> Self {
+        AsyncTransport {
+            reader: AsyncReader::Json(JsonReader::with_buffer(reader, Vec::new())),
+            writer: BufWriter::new(writer),
+            features: Features::default(),
+        }
+    }
+
+    /// Opens a connection on the server, like `Transport::accept`.
+    pub(crate) async fn accept(mut reader: R, writer: W) -> Result<Self> {
+        let mut first = [0; 1];
+        let read = reader.read(&mut first).await?;
+        if read == 0 || first[0] != MAGIC[0] {
+            return Ok(AsyncTransport {
+                reader: AsyncReader::Json(JsonReader::with_buffer(reader, first[..read].to_vec())),
+                writer: BufWriter::new(writer),
+                features: Features::default(),
+            });
+        }
+
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = first[0];
+        reader.read_exact(&mut bytes[1..]).await?;
+        let answer = Handshake::from_bytes(bytes)?.negotiate();
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(&answer.to_bytes()).await?;
+        writer.flush().await?;
+        check_version(answer)?;
+        Ok(AsyncTransport {
+            reader: AsyncReader::Binary(BufReader::new(reader)),
+            writer,
+            features: answer.features,
+        })
+    }
+
+    pub(crate) fn protocol(&self) -> Protocol {
+        match self.reader {
+            AsyncReader::Json(_) => Protocol::Json,
+            AsyncReader::Binary(_) => Protocol::Binary,
+        }
+    }
+
+    pub(crate) fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Waits for the next message to start arriving, like
+    /// `MessageReader::wait_for_message`.
+    pub(crate) async fn wait_for_message(&mut self) -> Result<bool> {
+        match &mut self.reader {
+            AsyncReader::Json(reader) => reader.wait_for_value().await,
+            AsyncReader::Binary(reader) => Ok(!reader.fill_buf().await?.is_empty()),
+        }
+    }
+
+    /// Reads the next message, like `MessageReader::recv`.
+    pub(crate) async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        match self.recv_tagged().await? {
+            Some((_, value)) => value.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the next message with the ID of its frame, like
+    /// `MessageReader::recv_tagged`.
+    pub(crate) async fn recv_tagged<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(Option<u64>, Result<T>)>> {
+        match &mut self.reader {
+            AsyncReader::Json(reader) => Ok(reader.next().await?.map(|value| (None, Ok(value)))),
+            AsyncReader::Binary(reader) => Ok(read_frame_async(reader)
+                .await?
+                .map(|(id, frame)| (id, decode_frame(frame)))),
+        }
+    }
+
+    /// Writes a message and flushes it.
+    pub(crate) async fn send<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_tagged(None, value).await
+    }
+
+    /// Writes a message tagged with `id` if there is one and flushes it, like
+    /// `MessageWriter::send_tagged`.
+    pub(crate) async fn send_tagged<T: Serialize>(
+        &mut self,
+        id: Option<u64>,
+        value: &T,
+    ) -> Result<()> {
+        let bytes = match self.reader {
+            AsyncReader::Json(_) => serde_json::to_vec(value)?,
+            AsyncReader::Binary(_) => message_frame(id, value)?,
+        };
+        self.writer.write_all(&bytes).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Reports an error tagged with `id` if there is one, if the protocol
+    /// has a way to.
+    pub(crate) async fn send_error_tagged(
+        &mut self,
+        id: Option<u64>,
+        code: ErrorCode,
+        msg: &str,
+    ) -> Result<()> {
+        if let AsyncReader::Binary(_) = self.reader {
+            self.writer.write_all(&error_frame(id, code, msg)).await?;
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
+}
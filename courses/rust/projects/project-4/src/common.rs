@@ -1,10 +1,97 @@
 use serde::{Deserialize, Serialize};
 
+use crate::auth::Credentials;
+use crate::engines::{Change, Event, WatchTarget};
+
+/// A request sent from `KvsClient` to `KvsServer`.
+///
+/// Key requests without a namespace operate on the default namespace.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    Set {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        value: String,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    CreateNamespace { name: String },
+    DropNamespace { name: String },
+    ListNamespaces,
+    /// Turns the connection into a stream of `WatchResponse`s
+    Watch {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        target: WatchTarget,
+    },
+    /// Reads up to `limit` changes committed after the sequence number `seq`
+    ChangesSince { seq: u64, limit: usize },
+    /// Replaces the scope granted to the connection by the one of the
+    /// credentials
+    Authenticate(Credentials),
+    /// Gets several keys, answered with one result per key
+    GetMany {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        keys: Vec<String>,
+    },
+    /// Sets several key/value pairs, answered with one result per pair
+    SetMany {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        pairs: Vec<(String, String)>,
+    },
+    /// Removes several keys, answered with one result per key
+    RemoveMany {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        keys: Vec<String>,
+    },
+}
+
+impl Request {
+    /// Names of the requests, as returned by `name`.
+    pub const NAMES: [&'static str; 12] = [
+        "get",
+        "set",
+        "remove",
+        "create_namespace",
+        "drop_namespace",
+        "list_namespaces",
+        "watch",
+        "changes_since",
+        "authenticate",
+        "get_many",
+        "set_many",
+        "remove_many",
+    ];
+
+    /// Returns the name of the request, which labels its metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "remove",
+            Request::CreateNamespace { .. } => "create_namespace",
+            Request::DropNamespace { .. } => "drop_namespace",
+            Request::ListNamespaces => "list_namespaces",
+            Request::Watch { .. } => "watch",
+            Request::ChangesSince { .. } => "changes_since",
+            Request::Authenticate(_) => "authenticate",
+            Request::GetMany { .. } => "get_many",
+            Request::SetMany { .. } => "set_many",
+            Request::RemoveMany { .. } => "remove_many",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,4 +110,67 @@ pub enum SetResponse {
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NamespaceResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ListNamespacesResponse {
+    Ok(Vec<String>),
+    Err(String),
+}
+
+/// Responses streamed back for a `Request::Watch`.
+///
+/// `Ok` acknowledges the subscription and is followed by `Event`s until the
+/// connection is closed or an `Err` ends the stream.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Ok(()),
+    Event(Event),
+    Err(String),
+}
+
+/// Response to `Request::ChangesSince`.
+///
+/// `ResyncRequired` holds the sequence number up to which the log has been
+/// compacted.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChangesResponse {
+    Ok(Vec<Change>),
+    ResyncRequired(u64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// Response to `Request::GetMany`, with one `GetResponse` per key.
+///
+/// `Err` fails the whole batch, e.g. when the namespace does not exist.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetManyResponse {
+    Ok(Vec<GetResponse>),
+    Err(String),
+}
+
+/// Response to `Request::SetMany`, with one `SetResponse` per pair.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetManyResponse {
+    Ok(Vec<SetResponse>),
+    Err(String),
+}
+
+/// Response to `Request::RemoveMany`, with one `RemoveResponse` per key.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemoveManyResponse {
+    Ok(Vec<RemoveResponse>),
+    Err(String),
+}
@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::SeekFrom;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::engines::storage::{DiskStorage, Storage};
+use crate::engines::watch::Subscribers;
+use crate::engines::{scan_start, Event, KvsEngine, WatchTarget, Watcher, DEFAULT_NAMESPACE};
+use crate::{KvsError, Result};
+
+use self::page::{corrupted, Meta, Node, Page, Value};
+use self::pool::BufferPool;
+use self::txn::Txn;
+use self::wal::Wal;
+
+mod page;
+mod pool;
+mod txn;
+mod wal;
+
+const DATA_FILE: &str = "btree.db";
+const WAL_FILE: &str = "btree.wal";
+
+/// Number of pages kept in the buffer pool.
+const POOL_PAGES: usize = 1024;
+
+/// Size of the log at which the data file is synced and the log emptied.
+const CHECKPOINT_BYTES: u64 = 4 * 1024 * 1024;
+
+const DEFAULT_NAMESPACE_ID: u32 = 0;
+
+/// Keys of the namespace catalog, which maps names to ids, live under this id.
+const CATALOG_NS: u32 = u32::MAX;
+
+/// The ids of the dropped namespaces whose keys are not all reclaimed yet live
+/// under this id.
+const DROPPED_NS: u32 = CATALOG_NS - 1;
+
+/// Number of keys of a dropped namespace reclaimed by each write.
+const RECLAIM_BATCH: usize = 64;
+
+/// A B+tree engine storing all namespaces in a single file of fixed-size pages.
+///
+/// Keys are ordered by namespace id and then by key, and the leaves are linked
+/// so that range scans walk them in order. Only the pages in the buffer pool
+/// are kept in memory. Each write logs the pages it changes to a write-ahead
+/// log before updating them in place, and pages emptied by removals go back
+/// to a free list, so the file never needs to be compacted.
+///
+/// ```rust
+/// # use kvs::{BTreeEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = BTreeEngine::open(temp_dir.path())?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+///
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct BTreeEngine {
+    inner: Arc<BTreeInner>,
+}
+
+struct BTreeInner {
+    /// readers share the lock, a writer holds it until its pages are written
+    tree: RwLock<Tree>,
+    pool: BufferPool,
+    subscribers: Subscribers,
+}
+
+struct Tree {
+    meta: Meta,
+    /// the catalog, cached
+    namespaces: BTreeMap<String, u32>,
+    /// the dropped namespaces still holding keys, cached
+    dropped: BTreeSet<u32>,
+    wal: Wal,
+}
+
+impl BTreeEngine {
+    /// Opens a `BTreeEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    /// Transactions committed to the log but not yet to the data file are
+    /// written again.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the files.
+    pub fn open(path: impl Into<PathBuf>) -> Result<BTreeEngine> {
+        BTreeEngine::open_on(Arc::new(DiskStorage::new(path.into())?))
+    }
+
+    /// Opens a `BTreeEngine` on the given storage.
+    pub(crate) fn open_on(storage: Arc<dyn Storage>) -> Result<BTreeEngine> {
+        let mut file = storage.open_rw(DATA_FILE)?;
+        let is_new = file.seek(SeekFrom::End(0))? == 0;
+        let pool = BufferPool::new(file, POOL_PAGES);
+
+        let mut wal = Wal::open(storage.open_rw(WAL_FILE)?)?;
+        for (id, buf) in wal.committed_pages()? {
+            let page = Page::decode(&buf)?;
+            pool.write(id, &buf, Arc::new(page))?;
+        }
+        pool.sync()?;
+        wal.truncate()?;
+
+        let mut tree = Tree {
+            meta: Meta {
+                root: 0,
+                page_count: 1,
+                free_head: 0,
+                next_ns_id: DEFAULT_NAMESPACE_ID + 1,
+            },
+            namespaces: BTreeMap::new(),
+            dropped: BTreeSet::new(),
+            wal,
+        };
+
+        if is_new {
+            let mut txn = Txn::new(&pool, tree.meta.clone());
+            txn.init();
+            let catalog_entry = Value::Inline(DEFAULT_NAMESPACE_ID.to_string());
+            txn.insert((CATALOG_NS, DEFAULT_NAMESPACE.to_owned()), catalog_entry)?;
+            tree.commit(&pool, txn)?;
+        } else {
+            tree.meta = match &*pool.get(0)? {
+                Page::Node(Node::Meta(meta)) => meta.clone(),
+                _ => return Err(corrupted()),
+            };
+        }
+
+        let txn = Txn::new(&pool, tree.meta.clone());
+        for (name, value) in txn.range(CATALOG_NS, Bound::Unbounded, Bound::Unbounded)? {
+            let id = txn.load_value(&value)?.parse().map_err(|_| corrupted())?;
+            tree.namespaces.insert(name, id);
+        }
+        for (id, _) in txn.range(DROPPED_NS, Bound::Unbounded, Bound::Unbounded)? {
+            tree.dropped.insert(id.parse().map_err(|_| corrupted())?);
+        }
+
+        Ok(BTreeEngine {
+            inner: Arc::new(BTreeInner {
+                tree: RwLock::new(tree),
+                pool,
+                subscribers: Subscribers::default(),
+            }),
+        })
+    }
+
+    /// Returns the key/value pairs of a namespace within `range`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    pub fn range_in<R>(&self, namespace: &str, range: R) -> Result<Vec<(String, String)>>
+    where
+        R: RangeBounds<String>,
+    {
+        let tree = self.inner.tree.read().unwrap();
+        let ns = tree.namespace_id(namespace)?;
+        let txn = Txn::new(&self.inner.pool, tree.meta.clone());
+        txn.range(ns, range.start_bound(), range.end_bound())?
+            .into_iter()
+            .map(|(key, value)| Ok((key, txn.load_value(&value)?)))
+            .collect()
+    }
+}
+
+impl KvsEngine for BTreeEngine {
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        let inner = &self.inner;
+        let mut tree = inner.tree.write().unwrap();
+        let ns = tree.namespace_id(namespace)?;
+        let mut txn = Txn::new(&inner.pool, tree.meta.clone());
+        let internal_key = (ns, key.clone());
+        // free the old overflow pages first so the new value can reuse them
+        if let Some(old) = txn.get(&internal_key)? {
+            txn.free_value(&old)?;
+        }
+        let stored = txn.store_value(value.clone())?;
+        txn.insert(internal_key, stored)?;
+        tree.commit(&inner.pool, txn)?;
+        inner
+            .subscribers
+            .publish(namespace, || Event::Set { key, value });
+        Ok(())
+    }
+
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        let tree = self.inner.tree.read().unwrap();
+        let ns = tree.namespace_id(namespace)?;
+        let txn = Txn::new(&self.inner.pool, tree.meta.clone());
+        txn.get(&(ns, key))?
+            .map(|value| txn.load_value(&value))
+            .transpose()
+    }
+
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        let inner = &self.inner;
+        let mut tree = inner.tree.write().unwrap();
+        let ns = tree.namespace_id(namespace)?;
+        let mut txn = Txn::new(&inner.pool, tree.meta.clone());
+        let old = txn
+            .remove(&(ns, key.clone()))?
+            .ok_or(KvsError::KeyNotFound)?;
+        txn.free_value(&old)?;
+        tree.commit(&inner.pool, txn)?;
+        inner
+            .subscribers
+            .publish(namespace, || Event::Removed { key });
+        Ok(())
+    }
+
+    /// Follows the leaf links from the first key after `after`.
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let tree = self.inner.tree.read().unwrap();
+        let ns = tree.namespace_id(namespace)?;
+        let txn = Txn::new(&self.inner.pool, tree.meta.clone());
+        let start = scan_start(prefix, after);
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => (ns, key.to_owned()),
+            Bound::Unbounded => (ns, String::new()),
+        };
+        let mut keys = Vec::new();
+        txn.scan(&first, |(key_ns, key), _| {
+            if *key_ns != ns || !key.starts_with(prefix) || keys.len() == limit {
+                return Ok(false);
+            }
+            if !matches!(start, Bound::Excluded(after) if key == after) {
+                keys.push(key.clone());
+            }
+            Ok(true)
+        })?;
+        Ok(keys)
+    }
+
+    /// Adds the namespace to the catalog with a new id.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        let inner = &self.inner;
+        let mut tree = inner.tree.write().unwrap();
+        if tree.namespaces.contains_key(name) {
+            return Err(KvsError::NamespaceExists);
+        }
+        let mut txn = Txn::new(&inner.pool, tree.meta.clone());
+        let id = txn.meta.next_ns_id;
+        txn.meta.next_ns_id += 1;
+        txn.insert((CATALOG_NS, name.to_owned()), Value::Inline(id.to_string()))?;
+        tree.commit(&inner.pool, txn)?;
+        tree.namespaces.insert(name.to_owned(), id);
+        Ok(())
+    }
+
+    /// Unlinks the namespace from the catalog. Its keys are left in the tree,
+    /// out of reach, and the following writes reclaim them a batch at a time.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvsError::DefaultNamespace);
+        }
+        let inner = &self.inner;
+        let mut tree = inner.tree.write().unwrap();
+        let ns = tree.namespace_id(name)?;
+        let mut txn = Txn::new(&inner.pool, tree.meta.clone());
+        txn.remove(&(CATALOG_NS, name.to_owned()))?;
+        txn.insert((DROPPED_NS, ns.to_string()), Value::Inline(String::new()))?;
+        tree.commit(&inner.pool, txn)?;
+        tree.namespaces.remove(name);
+        tree.dropped.insert(ns);
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let tree = self.inner.tree.read().unwrap();
+        Ok(tree.namespaces.keys().cloned().collect())
+    }
+
+    /// Checkpoints the tree: the pages are synced and the log emptied.
+    fn sync(&self) -> Result<()> {
+        let mut tree = self.inner.tree.write().unwrap();
+        self.inner.pool.sync()?;
+        tree.wal.truncate()
+    }
+
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        // writers publish while holding the write lock, so no write is missed
+        // or delivered twice while the subscription is added
+        let tree = self.inner.tree.read().unwrap();
+        tree.namespace_id(namespace)?;
+        Ok(self.inner.subscribers.subscribe(namespace, target))
+    }
+}
+
+impl Tree {
+    fn namespace_id(&self, namespace: &str) -> Result<u32> {
+        self.namespaces
+            .get(namespace)
+            .copied()
+            .ok_or(KvsError::NamespaceNotFound)
+    }
+
+    /// Logs the pages of a transaction together with the meta page, then
+    /// writes them in place.
+    ///
+    /// The transaction also reclaims a batch of keys of a dropped namespace.
+    fn commit(&mut self, pool: &BufferPool, mut txn: Txn) -> Result<()> {
+        let reclaimed = self.reclaim(&mut txn)?;
+        txn.dirty
+            .insert(0, Arc::new(Page::Node(Node::Meta(txn.meta.clone()))));
+        let mut pages = Vec::with_capacity(txn.dirty.len());
+        for (&id, page) in &txn.dirty {
+            pages.push((id, page.encode()?));
+        }
+        pages.sort_by_key(|(id, _)| *id);
+
+        self.wal.append(&pages)?;
+        for (id, buf) in &pages {
+            pool.write(*id, buf, Arc::clone(&txn.dirty[id]))?;
+        }
+        self.meta = txn.meta;
+        if let Some(ns) = reclaimed {
+            self.dropped.remove(&ns);
+        }
+
+        if self.wal.len() >= CHECKPOINT_BYTES {
+            pool.sync()?;
+            self.wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Removes up to `RECLAIM_BATCH` keys of a dropped namespace, freeing
+    /// their pages.
+    ///
+    /// Returns the namespace once it has no key left.
+    fn reclaim(&self, txn: &mut Txn) -> Result<Option<u32>> {
+        let ns = match self.dropped.iter().next() {
+            Some(&ns) => ns,
+            None => return Ok(None),
+        };
+        let mut keys = Vec::new();
+        txn.scan(&(ns, String::new()), |(key_ns, key), _| {
+            if *key_ns != ns || keys.len() == RECLAIM_BATCH {
+                return Ok(false);
+            }
+            keys.push(key.clone());
+            Ok(true)
+        })?;
+        let done = keys.len() < RECLAIM_BATCH;
+        for key in keys {
+            if let Some(old) = txn.remove(&(ns, key))? {
+                txn.free_value(&old)?;
+            }
+        }
+        if !done {
+            return Ok(None);
+        }
+        txn.remove(&(DROPPED_NS, ns.to_string()))?;
+        Ok(Some(ns))
+    }
+}
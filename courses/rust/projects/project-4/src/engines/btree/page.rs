@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+pub(super) const PAGE_SIZE: usize = 4096;
+
+/// Room for the fields of a serialized node, leaving space for the tag, the
+/// length and the name of the `Node` variant.
+pub(super) const NODE_CAPACITY: usize = PAGE_SIZE - 64;
+
+/// Room left for data in an overflow page after the tag, the next page and the length.
+pub(super) const OVERFLOW_CAPACITY: usize = PAGE_SIZE - 13;
+
+const NODE_TAG: u8 = 1;
+const OVERFLOW_TAG: u8 = 2;
+
+/// A key qualified by the id of its namespace.
+pub(super) type InternalKey = (u32, String);
+
+/// A page of the database file.
+///
+/// Tree nodes are serialized with `serde_json` behind a tag byte and a length,
+/// overflow pages hold raw bytes so that large values are not escaped.
+#[derive(Debug, Clone)]
+pub(super) enum Page {
+    Node(Node),
+    Overflow { next: u64, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum Node {
+    /// Page 0, describing the rest of the file
+    Meta(Meta),
+    Leaf(Leaf),
+    Internal(Internal),
+    /// A page in the free list
+    Free { next: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Meta {
+    pub(super) root: u64,
+    pub(super) page_count: u64,
+    /// first page of the free list, 0 if it is empty
+    pub(super) free_head: u64,
+    pub(super) next_ns_id: u32,
+}
+
+/// Entries in key order, linked to the next leaf for in-order scans.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Leaf {
+    pub(super) entries: Vec<(InternalKey, Value)>,
+    /// the leaf with the following keys, 0 for the last one
+    pub(super) next: u64,
+}
+
+/// `keys[i]` is the smallest key under `children[i + 1]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Internal {
+    pub(super) keys: Vec<InternalKey>,
+    pub(super) children: Vec<u64>,
+}
+
+impl Internal {
+    /// Returns the position of the child whose subtree may hold the key.
+    pub(super) fn child_index(&self, key: &InternalKey) -> usize {
+        self.keys.partition_point(|k| k <= key)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum Value {
+    Inline(String),
+    /// A value too large for a leaf, stored in a chain of overflow pages
+    Overflow { page: u64, len: u64 },
+}
+
+impl Page {
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Page::Node(node) => {
+                let json = serde_json::to_vec(node)?;
+                buf.push(NODE_TAG);
+                buf.extend_from_slice(&(json.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&json);
+            }
+            Page::Overflow { next, data } => {
+                buf.push(OVERFLOW_TAG);
+                buf.extend_from_slice(&next.to_le_bytes());
+                buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(data);
+            }
+        }
+        if buf.len() > PAGE_SIZE {
+            return Err(KvsError::StringError("Page overflow".to_owned()));
+        }
+        buf.resize(PAGE_SIZE, 0);
+        Ok(buf)
+    }
+
+    pub(super) fn decode(buf: &[u8]) -> Result<Page> {
+        match buf.first() {
+            Some(&NODE_TAG) => {
+                let len = read_u32(&buf[1..]) as usize;
+                let node = serde_json::from_slice(buf.get(5..5 + len).ok_or_else(corrupted)?)?;
+                Ok(Page::Node(node))
+            }
+            Some(&OVERFLOW_TAG) => {
+                let next = read_u64(&buf[1..]);
+                let len = read_u32(&buf[9..]) as usize;
+                let data = buf.get(13..13 + len).ok_or_else(corrupted)?.to_vec();
+                Ok(Page::Overflow { next, data })
+            }
+            _ => Err(corrupted()),
+        }
+    }
+}
+
+/// Returns the size of a node once serialized.
+pub(super) fn encoded_len<T: Serialize>(value: &T) -> Result<usize> {
+    Ok(serde_json::to_vec(value)?.len())
+}
+
+pub(super) fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub(super) fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(super) fn corrupted() -> KvsError {
+    KvsError::StringError("The database file is corrupted".to_owned())
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+
+use crate::engines::storage::RandomFile;
+use crate::Result;
+
+use super::page::{Page, PAGE_SIZE};
+
+/// Caches decoded pages of the database file.
+///
+/// Pages are evicted with the clock algorithm: a page that was used since the
+/// hand last passed it gets a second chance.
+pub(super) struct BufferPool {
+    file: Mutex<Box<dyn RandomFile>>,
+    frames: Mutex<Frames>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct Frames {
+    slots: Vec<Frame>,
+    // map page id to its slot
+    index: HashMap<u64, usize>,
+    hand: usize,
+}
+
+struct Frame {
+    id: u64,
+    page: Arc<Page>,
+    referenced: bool,
+}
+
+impl BufferPool {
+    pub(super) fn new(file: Box<dyn RandomFile>, capacity: usize) -> Self {
+        BufferPool {
+            file: Mutex::new(file),
+            frames: Mutex::new(Frames::default()),
+            capacity,
+        }
+    }
+
+    /// Returns a page, reading it from the file if it is not cached.
+    pub(super) fn get(&self, id: u64) -> Result<Arc<Page>> {
+        if let Some(page) = self.frames.lock().unwrap().get(id) {
+            return Ok(page);
+        }
+        let mut buf = vec![0; PAGE_SIZE];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+            file.read_exact(&mut buf)?;
+        }
+        let page = Arc::new(Page::decode(&buf)?);
+        self.frames
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&page), self.capacity);
+        Ok(page)
+    }
+
+    /// Writes an encoded page in place and caches it.
+    pub(super) fn write(&self, id: u64, buf: &[u8], page: Arc<Page>) -> Result<()> {
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+            file.write_all(buf)?;
+        }
+        self.frames.lock().unwrap().insert(id, page, self.capacity);
+        Ok(())
+    }
+
+    pub(super) fn sync(&self) -> Result<()> {
+        self.file.lock().unwrap().sync()?;
+        Ok(())
+    }
+}
+
+impl Frames {
+    fn get(&mut self, id: u64) -> Option<Arc<Page>> {
+        let &slot = self.index.get(&id)?;
+        let frame = &mut self.slots[slot];
+        frame.referenced = true;
+        Some(Arc::clone(&frame.page))
+    }
+
+    fn insert(&mut self, id: u64, page: Arc<Page>, capacity: usize) {
+        if let Some(&slot) = self.index.get(&id) {
+            self.slots[slot].page = page;
+            self.slots[slot].referenced = true;
+            return;
+        }
+        let frame = Frame {
+            id,
+            page,
+            referenced: true,
+        };
+        if self.slots.len() < capacity {
+            self.index.insert(id, self.slots.len());
+            self.slots.push(frame);
+            return;
+        }
+        loop {
+            let victim = &mut self.slots[self.hand];
+            if victim.referenced {
+                victim.referenced = false;
+                self.hand = (self.hand + 1) % self.slots.len();
+                continue;
+            }
+            self.index.remove(&victim.id);
+            self.index.insert(id, self.hand);
+            *victim = frame;
+            self.hand = (self.hand + 1) % self.slots.len();
+            return;
+        }
+    }
+}
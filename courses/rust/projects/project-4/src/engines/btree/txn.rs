@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{KvsError, Result};
+
+use super::page::{
+    corrupted, encoded_len, InternalKey, Internal, Leaf, Meta, Node, Page, Value, NODE_CAPACITY,
+    OVERFLOW_CAPACITY,
+};
+use super::pool::BufferPool;
+
+/// Largest serialized value kept inside a leaf; larger ones go to overflow
+/// pages. Together with the key limit, every leaf holds at least four entries,
+/// so a split always produces two valid halves.
+const MAX_INLINE: usize = NODE_CAPACITY / 8;
+const MAX_KEY: usize = NODE_CAPACITY / 8;
+
+/// The first key and the page of the right half of a node that split.
+type Split = Option<(InternalKey, u64)>;
+
+/// A view of the tree that buffers its changes.
+///
+/// Readers use it without committing. Writers collect the pages they touch in
+/// `dirty`, which the engine logs and writes back on commit, so a failed
+/// operation leaves the file untouched.
+pub(super) struct Txn<'a> {
+    pool: &'a BufferPool,
+    pub(super) meta: Meta,
+    pub(super) dirty: HashMap<u64, Arc<Page>>,
+}
+
+impl<'a> Txn<'a> {
+    pub(super) fn new(pool: &'a BufferPool, meta: Meta) -> Self {
+        Txn {
+            pool,
+            meta,
+            dirty: HashMap::new(),
+        }
+    }
+
+    fn page(&self, id: u64) -> Result<Arc<Page>> {
+        match self.dirty.get(&id) {
+            Some(page) => Ok(Arc::clone(page)),
+            None => self.pool.get(id),
+        }
+    }
+
+    fn node(&self, id: u64) -> Result<Node> {
+        match &*self.page(id)? {
+            Page::Node(node) => Ok(node.clone()),
+            Page::Overflow { .. } => Err(corrupted()),
+        }
+    }
+
+    fn put(&mut self, id: u64, node: Node) {
+        self.dirty.insert(id, Arc::new(Page::Node(node)));
+    }
+
+    /// Takes a page from the free list, or grows the file.
+    fn alloc(&mut self) -> Result<u64> {
+        if self.meta.free_head == 0 {
+            let id = self.meta.page_count;
+            self.meta.page_count += 1;
+            return Ok(id);
+        }
+        let id = self.meta.free_head;
+        match self.node(id)? {
+            Node::Free { next } => self.meta.free_head = next,
+            _ => return Err(corrupted()),
+        }
+        Ok(id)
+    }
+
+    fn free(&mut self, id: u64) {
+        let next = self.meta.free_head;
+        self.put(id, Node::Free { next });
+        self.meta.free_head = id;
+    }
+
+    /// Initializes an empty tree in a new file.
+    pub(super) fn init(&mut self) {
+        self.meta.root = self.meta.page_count;
+        self.meta.page_count += 1;
+        let root = self.meta.root;
+        self.put(root, Node::Leaf(Leaf::default()));
+    }
+
+    /// Descends to the leaf whose key range covers the key.
+    fn find_leaf(&self, key: &InternalKey) -> Result<Leaf> {
+        let mut id = self.meta.root;
+        loop {
+            match self.node(id)? {
+                Node::Internal(node) => id = node.children[node.child_index(key)],
+                Node::Leaf(leaf) => return Ok(leaf),
+                _ => return Err(corrupted()),
+            }
+        }
+    }
+
+    pub(super) fn get(&self, key: &InternalKey) -> Result<Option<Value>> {
+        let leaf = self.find_leaf(key)?;
+        Ok(leaf
+            .entries
+            .binary_search_by(|(k, _)| k.cmp(key))
+            .ok()
+            .map(|i| leaf.entries[i].1.clone()))
+    }
+
+    /// Visits the entries from `start` on in key order by following the leaf
+    /// links, until `f` returns false.
+    pub(super) fn scan<F>(&self, start: &InternalKey, mut f: F) -> Result<()>
+    where
+        F: FnMut(&InternalKey, &Value) -> Result<bool>,
+    {
+        let mut leaf = self.find_leaf(start)?;
+        let mut pos = leaf.entries.partition_point(|(k, _)| k < start);
+        loop {
+            for (key, value) in &leaf.entries[pos..] {
+                if !f(key, value)? {
+                    return Ok(());
+                }
+            }
+            if leaf.next == 0 {
+                return Ok(());
+            }
+            leaf = match self.node(leaf.next)? {
+                Node::Leaf(leaf) => leaf,
+                _ => return Err(corrupted()),
+            };
+            pos = 0;
+        }
+    }
+
+    /// Collects the entries of a namespace within the given key bounds.
+    pub(super) fn range(
+        &self,
+        ns: u32,
+        start: Bound<&String>,
+        end: Bound<&String>,
+    ) -> Result<Vec<(String, Value)>> {
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => (ns, key.clone()),
+            Bound::Unbounded => (ns, String::new()),
+        };
+        let mut entries = Vec::new();
+        self.scan(&first, |(key_ns, key), value| {
+            let in_range = *key_ns == ns
+                && match end {
+                    Bound::Included(end) => key <= end,
+                    Bound::Excluded(end) => key < end,
+                    Bound::Unbounded => true,
+                };
+            if in_range && !matches!(start, Bound::Excluded(start) if key == start) {
+                entries.push((key.clone(), value.clone()));
+            }
+            Ok(in_range)
+        })?;
+        Ok(entries)
+    }
+
+    /// Inserts or replaces a value, returning the previous one.
+    pub(super) fn insert(&mut self, key: InternalKey, value: Value) -> Result<Option<Value>> {
+        if encoded_len(&key)? > MAX_KEY {
+            return Err(KvsError::StringError("Key too large".to_owned()));
+        }
+        let root = self.meta.root;
+        let (old, split) = self.insert_into(root, key, value)?;
+        if let Some((separator, right)) = split {
+            let new_root = self.alloc()?;
+            self.put(
+                new_root,
+                Node::Internal(Internal {
+                    keys: vec![separator],
+                    children: vec![root, right],
+                }),
+            );
+            self.meta.root = new_root;
+        }
+        Ok(old)
+    }
+
+    /// Inserts into the subtree at `id`, returning the previous value and the
+    /// new right sibling if the node had to split.
+    fn insert_into(
+        &mut self,
+        id: u64,
+        key: InternalKey,
+        value: Value,
+    ) -> Result<(Option<Value>, Split)> {
+        match self.node(id)? {
+            Node::Leaf(mut leaf) => {
+                let old = match leaf.entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => Some(mem::replace(&mut leaf.entries[i].1, value)),
+                    Err(i) => {
+                        leaf.entries.insert(i, (key, value));
+                        None
+                    }
+                };
+                let mut split = None;
+                if encoded_len(&leaf)? > NODE_CAPACITY {
+                    let at = split_point(&leaf.entries, 1)?;
+                    let right_id = self.alloc()?;
+                    let right = Leaf {
+                        entries: leaf.entries.split_off(at),
+                        next: leaf.next,
+                    };
+                    leaf.next = right_id;
+                    split = Some((right.entries[0].0.clone(), right_id));
+                    self.put(right_id, Node::Leaf(right));
+                }
+                self.put(id, Node::Leaf(leaf));
+                Ok((old, split))
+            }
+            Node::Internal(mut node) => {
+                let i = node.child_index(&key);
+                let (old, child_split) = self.insert_into(node.children[i], key, value)?;
+                let (separator, right) = match child_split {
+                    Some(child_split) => child_split,
+                    None => return Ok((old, None)),
+                };
+                node.keys.insert(i, separator);
+                node.children.insert(i + 1, right);
+
+                let mut split = None;
+                if encoded_len(&node)? > NODE_CAPACITY {
+                    // the key at the split point moves up to the parent
+                    let at = split_point(&node.keys, 2)?;
+                    let right_id = self.alloc()?;
+                    let right = Internal {
+                        keys: node.keys.split_off(at + 1),
+                        children: node.children.split_off(at + 1),
+                    };
+                    let separator = node.keys.pop().ok_or_else(corrupted)?;
+                    split = Some((separator, right_id));
+                    self.put(right_id, Node::Internal(right));
+                }
+                self.put(id, Node::Internal(node));
+                Ok((old, split))
+            }
+            _ => Err(corrupted()),
+        }
+    }
+
+    /// Removes a key, returning its value.
+    pub(super) fn remove(&mut self, key: &InternalKey) -> Result<Option<Value>> {
+        let root = self.meta.root;
+        let old = self.remove_from(root, key)?;
+        // a root left with a single child is replaced by it
+        if let Node::Internal(node) = self.node(root)? {
+            if node.children.len() == 1 {
+                self.meta.root = node.children[0];
+                self.free(root);
+            }
+        }
+        Ok(old)
+    }
+
+    fn remove_from(&mut self, id: u64, key: &InternalKey) -> Result<Option<Value>> {
+        match self.node(id)? {
+            Node::Leaf(mut leaf) => match leaf.entries.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(i) => {
+                    let (_, value) = leaf.entries.remove(i);
+                    self.put(id, Node::Leaf(leaf));
+                    Ok(Some(value))
+                }
+                Err(_) => Ok(None),
+            },
+            Node::Internal(mut node) => {
+                let i = node.child_index(key);
+                let old = self.remove_from(node.children[i], key)?;
+                if old.is_some() && self.rebalance(&mut node, i)? {
+                    self.put(id, Node::Internal(node));
+                }
+                Ok(old)
+            }
+            _ => Err(corrupted()),
+        }
+    }
+
+    /// Merges the child at `i` with a sibling once it is less than a quarter
+    /// full, so emptied pages return to the free list.
+    ///
+    /// Returns true if `node` was changed.
+    fn rebalance(&mut self, node: &mut Internal, i: usize) -> Result<bool> {
+        if node.children.len() < 2 || encoded_len(&self.node(node.children[i])?)? >= NODE_CAPACITY / 4 {
+            return Ok(false);
+        }
+        let left = if i + 1 < node.children.len() { i } else { i - 1 };
+        let (left_id, right_id) = (node.children[left], node.children[left + 1]);
+
+        let merged = match (self.node(left_id)?, self.node(right_id)?) {
+            (Node::Leaf(mut l), Node::Leaf(r)) => {
+                l.entries.extend(r.entries);
+                l.next = r.next;
+                Node::Leaf(l)
+            }
+            (Node::Internal(mut l), Node::Internal(r)) => {
+                l.keys.push(node.keys[left].clone());
+                l.keys.extend(r.keys);
+                l.children.extend(r.children);
+                Node::Internal(l)
+            }
+            _ => return Err(corrupted()),
+        };
+        if encoded_len(&merged)? > NODE_CAPACITY {
+            return Ok(false);
+        }
+        self.put(left_id, merged);
+        self.free(right_id);
+        node.keys.remove(left);
+        node.children.remove(left + 1);
+        Ok(true)
+    }
+
+    /// Prepares a value for a leaf, moving it to overflow pages if it is large.
+    pub(super) fn store_value(&mut self, value: String) -> Result<Value> {
+        if encoded_len(&value)? <= MAX_INLINE {
+            return Ok(Value::Inline(value));
+        }
+        let bytes = value.into_bytes();
+        let mut next = 0;
+        for chunk in bytes.chunks(OVERFLOW_CAPACITY).rev() {
+            let id = self.alloc()?;
+            self.dirty.insert(
+                id,
+                Arc::new(Page::Overflow {
+                    next,
+                    data: chunk.to_vec(),
+                }),
+            );
+            next = id;
+        }
+        Ok(Value::Overflow {
+            page: next,
+            len: bytes.len() as u64,
+        })
+    }
+
+    pub(super) fn load_value(&self, value: &Value) -> Result<String> {
+        let (mut id, len) = match value {
+            Value::Inline(value) => return Ok(value.clone()),
+            Value::Overflow { page, len } => (*page, *len),
+        };
+        let mut bytes = Vec::with_capacity(len as usize);
+        while id != 0 {
+            match &*self.page(id)? {
+                Page::Overflow { next, data } => {
+                    bytes.extend_from_slice(data);
+                    id = *next;
+                }
+                Page::Node(_) => return Err(corrupted()),
+            }
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    /// Returns the overflow pages of a replaced or removed value to the free list.
+    pub(super) fn free_value(&mut self, value: &Value) -> Result<()> {
+        let mut id = match value {
+            Value::Inline(_) => return Ok(()),
+            Value::Overflow { page, .. } => *page,
+        };
+        while id != 0 {
+            let next = match &*self.page(id)? {
+                Page::Overflow { next, .. } => *next,
+                Page::Node(_) => return Err(corrupted()),
+            };
+            self.free(id);
+            id = next;
+        }
+        Ok(())
+    }
+}
+
+/// Returns where to split items so that both halves hold about the same
+/// number of bytes, keeping at least `min` items on each side.
+fn split_point<T: Serialize>(items: &[T], min: usize) -> Result<usize> {
+    let sizes = items.iter().map(encoded_len).collect::<Result<Vec<_>>>()?;
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut total = 0;
+    let mut at = 0;
+    for size in sizes {
+        if total >= half {
+            break;
+        }
+        total += size;
+        at += 1;
+    }
+    Ok(at.clamp(min, items.len() - min))
+}
@@ -0,0 +1,85 @@
+use std::io::SeekFrom;
+
+use crate::engines::storage::RandomFile;
+use crate::Result;
+
+use super::page::{read_u64, PAGE_SIZE};
+
+/// Marks the end of a transaction, followed by its number of pages.
+const COMMIT_MARKER: u64 = u64::MAX;
+
+/// Redo log of full page images.
+///
+/// A transaction appends the new image of every page it touched followed by
+/// a commit record and syncs the log, and only then are the pages written in
+/// place. After a crash the committed transactions are written again and a
+/// torn tail is ignored, so the database file never shows half of a
+/// transaction.
+pub(super) struct Wal {
+    file: Box<dyn RandomFile>,
+    len: u64,
+}
+
+impl Wal {
+    pub(super) fn open(mut file: Box<dyn RandomFile>) -> Result<Wal> {
+        let len = file.seek(SeekFrom::End(0))?;
+        Ok(Wal { file, len })
+    }
+
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends the encoded pages of a transaction and its commit record, and
+    /// makes them durable before the pages may be written in place.
+    pub(super) fn append(&mut self, pages: &[(u64, Vec<u8>)]) -> Result<()> {
+        let mut buf = Vec::with_capacity(pages.len() * (8 + PAGE_SIZE) + 16);
+        for (id, page) in pages {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(page);
+        }
+        buf.extend_from_slice(&COMMIT_MARKER.to_le_bytes());
+        buf.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+        self.file.write_all(&buf)?;
+        self.file.sync()?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the pages of every committed transaction, oldest first.
+    pub(super) fn committed_pages(&mut self) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut buf = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut buf)?;
+
+        let mut committed = Vec::new();
+        let mut pending = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= buf.len() {
+            let id = read_u64(&buf[pos..]);
+            pos += 8;
+            if id == COMMIT_MARKER {
+                if pos + 8 > buf.len() || read_u64(&buf[pos..]) != pending.len() as u64 {
+                    break;
+                }
+                pos += 8;
+                committed.append(&mut pending);
+                continue;
+            }
+            if pos + PAGE_SIZE > buf.len() {
+                break;
+            }
+            pending.push((id, buf[pos..pos + PAGE_SIZE].to_vec()));
+            pos += PAGE_SIZE;
+        }
+        Ok(committed)
+    }
+
+    /// Empties the log once the database file has been synced.
+    pub(super) fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.len = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// A mutation read back from the change feed of an engine.
+///
+/// Sequence numbers are assigned when a mutation is committed and increase
+/// monotonically across restarts, so a consumer can resume from the last
+/// sequence number it processed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Sequence number of the mutation
+    pub seq: u64,
+    /// Namespace the mutation happened in
+    pub namespace: String,
+    /// What happened
+    pub op: ChangeOp,
+}
+
+/// The kind of a `Change`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// A key has been set to a value
+    Set { key: String, value: String },
+    /// A key has been removed
+    Remove { key: String },
+    /// The namespace has been created
+    CreateNamespace,
+    /// The namespace has been dropped together with all of its keys
+    DropNamespace,
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use crate::{KvsError, Result};
+
+/// Length of an `EncryptionKey` in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// A 256-bit key encrypting the log files of a `KvStore`.
+///
+/// Keys are read from hex strings, so that they can be kept in a file or an
+/// environment variable.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Creates a key from raw bytes.
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// Parses a key from 64 hex digits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the string is not a valid key.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let mut bytes = [0; KEY_LEN];
+        hex::decode_to_slice(hex.trim(), &mut bytes)
+            .map_err(|e| KvsError::StringError(format!("Invalid encryption key: {}", e)))?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Reads a key in hex from a file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        EncryptionKey::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// Reads a key in hex from an environment variable.
+    pub fn from_env(var: &str) -> Result<Self> {
+        let hex = std::env::var(var).map_err(|e| {
+            KvsError::StringError(format!(
+                "Cannot read the encryption key from {}: {}",
+                var, e
+            ))
+        })?;
+        EncryptionKey::from_hex(&hex)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the key material
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Seals log records with XChaCha20-Poly1305.
+///
+/// Every record gets a random nonce, stored in front of its ciphertext, as
+/// generation numbers can be handed out again after a failed compaction or a
+/// crash. The generation and the offset of the record are authenticated as
+/// associated data, so that a record cannot be moved elsewhere in the log.
+pub(crate) struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Cipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key.0)),
+        }
+    }
+
+    /// Encrypts a record written at `offset` of generation `gen`.
+    ///
+    /// Returns the nonce and the ciphertext, prefixed with their length.
+    pub(crate) fn seal(&self, gen: u64, offset: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = position(gen, offset);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KvsError::StringError("Encryption failed".to_owned()))?;
+        let len = nonce.len() + ciphertext.len();
+        let mut frame = Vec::with_capacity(4 + len);
+        frame.extend_from_slice(&(len as u32).to_le_bytes());
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
+    }
+
+    /// Decrypts and authenticates the sealed record at `offset`, made of its
+    /// nonce and its ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Decryption` if the key is wrong or the data has
+    /// been tampered with.
+    pub(crate) fn open(&self, gen: u64, offset: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        let decryption = || KvsError::Decryption(format!("{}.log", gen));
+        if sealed.len() < NONCE_LEN {
+            return Err(decryption());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = position(gen, offset);
+        self.aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| decryption())
+    }
+}
+
+/// Length of the nonce in front of every sealed record.
+const NONCE_LEN: usize = 24;
+
+fn position(gen: u64, offset: u64) -> [u8; 16] {
+    let mut position = [0; 16];
+    position[..8].copy_from_slice(&gen.to_le_bytes());
+    position[8..].copy_from_slice(&offset.to_le_bytes());
+    position
+}
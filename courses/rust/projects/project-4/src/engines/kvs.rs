@@ -1,23 +1,34 @@
-use std::{fs, io};
+use std::io;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map::Entry;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::ops::{Bound, Range};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use crossbeam::atomic::AtomicCell;
 use crossbeam_skiplist::SkipMap;
 use log::error;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
 use crate::{KvsError, Result};
-use crate::engines::KvsEngine;
+use crate::engines::encryption::{Cipher, EncryptionKey};
+use crate::engines::storage::{DiskStorage, ReadFile, Storage, WriteFile};
+use crate::engines::watch::Subscribers;
+use crate::engines::{
+    scan_start, Change, ChangeOp, EngineStats, Event, KvsEngine, WatchTarget, Watcher,
+    DEFAULT_NAMESPACE,
+};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_NAMESPACE_ID: u32 = 0;
+/// Starts every encrypted log file, followed by a sealed `FileHeader`.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"KVSENC01";
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -25,6 +36,12 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A skip list in memory stores the keys and the value locations for fast query.
 ///
+/// Keys are grouped in namespaces. Each namespace has its own skip list, and
+/// every record in the log carries the id of the namespace it belongs to.
+///
+/// A store opened with `open_encrypted` seals each record with an AEAD cipher.
+/// Its plaintext files stay readable, so that an existing store is encrypted
+/// as it is rewritten.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -41,15 +58,17 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// ```
 #[derive(Clone)]
 pub struct KvStore {
-    /// directory for the log and other data
-    path: Arc<PathBuf>,
+    /// map namespace name to its index
+    namespaces: Arc<SkipMap<String, Arc<Namespace>>>,
 
-    /// map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    /// sequence number up to which the log has been compacted
+    compacted_seq: Arc<AtomicU64>,
 
     reader: KvStoreReader,
 
     writer: Arc<Mutex<KvStoreWriter>>,
+
+    stats: Arc<StoreStats>,
 }
 
 impl KvStore {
@@ -59,90 +78,402 @@ impl KvStore {
     ///
     /// # Errors
     ///
+    /// It returns `KvsError::KeyRequired` if the store is encrypted.
+    ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        KvStore::open_on(Arc::new(DiskStorage::new(path.into())?), None)
+    }
+
+    /// Opens a `KvStore` whose new log files are encrypted with `key`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Decryption` if the store is encrypted with another key.
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_encrypted(path: impl Into<PathBuf>, key: EncryptionKey) -> Result<KvStore> {
+        let storage = Arc::new(DiskStorage::new(path.into())?);
+        KvStore::open_on(storage, Some(Arc::new(Cipher::new(&key))))
+    }
+
+    /// Opens a `KvStore` on the given storage.
+    pub(crate) fn open_on(
+        storage: Arc<dyn Storage>,
+        cipher: Option<Arc<Cipher>>,
+    ) -> Result<KvStore> {
+        remove_unfinished_compactions(&*storage)?;
 
         let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let mut replay = Replay::new();
+        let ciphers = Arc::new(GenCiphers::default());
 
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = live_gen_list(&*storage, cipher.as_deref())?;
         let mut uncompacted = 0;
-
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+        let stats = Arc::new(StoreStats::default());
+
+        for &(gen, encrypted) in &gen_list {
+            let gen_cipher = if encrypted { cipher.clone() } else { None };
+            let mut reader = BufReaderWithPos::new(storage.open(&log_name(gen))?)?;
+            uncompacted += load(gen, &mut reader, gen_cipher.as_deref(), &mut replay)?;
+            stats.add_disk_bytes(reader.seek(SeekFrom::End(0))?);
+            ciphers.insert(gen, gen_cipher);
             readers.insert(gen, reader);
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        let namespaces = Arc::new(SkipMap::new());
+        for namespace in replay.namespaces.into_values() {
+            namespaces.insert(namespace.name.clone(), namespace);
+        }
+        let compacted_seq = Arc::new(AtomicU64::new(replay.compacted_seq));
+
+        let current_gen = gen_list.last().map_or(0, |&(gen, _)| gen) + 1;
+        ciphers.insert(current_gen, cipher.clone());
+        let writer = new_log_file(&*storage, current_gen, cipher.as_deref())?;
+        stats.add_disk_bytes(writer.pos);
         let safe_point = Arc::new(AtomicU64::new(0));
 
         let reader = KvStoreReader {
-            path: Arc::clone(&path),
+            storage: Arc::clone(&storage),
             safe_point,
+            ciphers,
             readers: RefCell::new(readers),
         };
 
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
+            cipher,
             current_gen,
             uncompacted,
-            path: Arc::clone(&path),
-            index: Arc::clone(&index),
+            storage,
+            torn: false,
+            namespaces: Arc::clone(&namespaces),
+            next_ns_id: replay.next_ns_id,
+            last_seq: replay.last_seq,
+            compacted_seq: Arc::clone(&compacted_seq),
+            subscribers: Subscribers::default(),
+            stats: Arc::clone(&stats),
         };
 
         Ok(KvStore {
-            path,
             reader,
-            index,
+            namespaces,
+            compacted_seq,
             writer: Arc::new(Mutex::new(writer)),
+            stats,
         })
     }
+
+    /// Re-encrypts the store with a new key.
+    ///
+    /// A compaction rewrites the live records with `key` and deletes the older
+    /// files, after which the store has to be opened with the new key. This
+    /// also encrypts a store that was written in plaintext.
+    pub fn rotate_key(&self, key: EncryptionKey) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .compact_with(Some(Arc::new(Cipher::new(&key))))
+    }
+
+    /// Reads the changes after `since` forward through the generation files.
+    ///
+    /// A compaction may delete a file between listing and opening it, in which
+    /// case the read is retried.
+    fn read_changes(&self, since: u64, limit: usize) -> Result<Vec<Change>> {
+        let mut attempts = 0;
+        loop {
+            match self.try_read_changes(since, limit) {
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && attempts < 3 => {
+                    attempts += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_read_changes(&self, since: u64, limit: usize) -> Result<Vec<Change>> {
+        let storage = &self.reader.storage;
+        // open every file before checking the compaction point: open handles keep
+        // the data readable even if a compaction deletes the files meanwhile
+        let files = sorted_gen_list(&**storage)?
+            .into_iter()
+            .map(|gen| Ok((gen, storage.open(&log_name(gen))?)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let compacted_seq = self.compacted_seq.load(Ordering::SeqCst);
+        if since > 0 && since < compacted_seq {
+            return Err(KvsError::ResyncRequired(compacted_seq));
+        }
+
+        let mut names = HashMap::new();
+        names.insert(DEFAULT_NAMESPACE_ID, DEFAULT_NAMESPACE.to_owned());
+        // a compaction file repeats records of the generations before it, so
+        // everything not newer than the last change is skipped
+        let mut last_seq = since;
+        let mut changes = Vec::new();
+
+        for (gen, file) in files {
+            let cipher = self.reader.ciphers.get(gen);
+            let res = for_each_record(gen, BufReader::new(file), cipher.as_deref(), |cmd, _| {
+                let (seq, namespace, op) = match cmd {
+                    Command::Set { seq, ns, key, value } => {
+                        (seq, names.get(&ns).cloned(), ChangeOp::Set { key, value })
+                    }
+                    Command::Remove { seq, ns, key } => {
+                        (seq, names.get(&ns).cloned(), ChangeOp::Remove { key })
+                    }
+                    Command::CreateNamespace { seq, id, name } => {
+                        names.insert(id, name.clone());
+                        (seq, Some(name), ChangeOp::CreateNamespace)
+                    }
+                    Command::DropNamespace { seq, id } => {
+                        (seq, names.remove(&id), ChangeOp::DropNamespace)
+                    }
+                    Command::Checkpoint { .. } => return Ok(true),
+                };
+                if seq > last_seq {
+                    last_seq = seq;
+                    if let Some(namespace) = namespace {
+                        changes.push(Change { seq, namespace, op });
+                    }
+                }
+                Ok(changes.len() < limit)
+            });
+            // the writer may be in the middle of appending the last record
+            allow_torn_tail(res)?;
+            if changes.len() >= limit {
+                break;
+            }
+        }
+        Ok(changes)
+    }
 }
 
 impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a string key to a string in the given namespace.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        self.writer.lock().unwrap().set(namespace, key, value)
     }
 
-    /// Gets the string value of a given string key.
+    /// Gets the string value of a given string key in the given namespace.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        let cmd_pos = self
+            .namespaces
+            .get(namespace)
+            .ok_or(KvsError::NamespaceNotFound)?
+            .value()
+            .index
+            .get(&key)
+            .map(|entry| entry.value().load());
+        cmd_pos.map(|cmd_pos| self.reader.read_value(cmd_pos)).transpose()
     }
 
-    /// Remove a given key.
+    /// Remove a given key from the given namespace.
     ///
     /// # Error
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(namespace, key)
+    }
+
+    /// Looks all the keys up in the index first, then reads the values sorted
+    /// by generation and offset, so that the log is read forward.
+    fn get_many_in(
+        &self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        let namespace = self
+            .namespaces
+            .get(namespace)
+            .ok_or(KvsError::NamespaceNotFound)?;
+        let index = &namespace.value().index;
+        let mut lookups: Vec<(usize, CommandPos)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| index.get(key).map(|entry| (i, entry.value().load())))
+            .collect();
+        lookups.sort_by_key(|&(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+
+        let mut results: Vec<Result<Option<String>>> = keys.iter().map(|_| Ok(None)).collect();
+        for (i, cmd_pos) in lookups {
+            results[i] = self.reader.read_value(cmd_pos).map(Some);
+        }
+        Ok(results)
+    }
+
+    /// Appends the records while holding the writer once.
+    fn set_many_in(
+        &self,
+        namespace: &str,
+        pairs: Vec<(String, String)>,
+    ) -> Result<Vec<Result<()>>> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.namespace(namespace)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| writer.set(namespace, key, value))
+            .collect())
+    }
+
+    /// Appends the records while holding the writer once.
+    fn remove_many_in(&self, namespace: &str, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.namespace(namespace)?;
+        Ok(keys
+            .into_iter()
+            .map(|key| writer.remove(namespace, key))
+            .collect())
+    }
+
+    /// Creates a namespace by appending a record that assigns it a new id.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.writer.lock().unwrap().create_namespace(name)
+    }
+
+    /// Drops a namespace by appending a single record and discarding its index.
+    ///
+    /// Its entries stay on disk until the next compaction.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.writer.lock().unwrap().drop_namespace(name)
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(self
+            .namespaces
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    /// Walks the in-memory index, without reading the log.
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let namespace = self
+            .namespaces
+            .get(namespace)
+            .ok_or(KvsError::NamespaceNotFound)?;
+        Ok(namespace
+            .value()
+            .index
+            .range::<str, _>((scan_start(prefix, after), Bound::Unbounded))
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .collect())
+    }
+
+    /// Flushes the current log file and syncs it.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.writer.writer.get_mut().sync()?;
+        Ok(())
+    }
+
+    /// Subscribes to the changes committed by the writer after this call.
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        self.writer.lock().unwrap().watch(namespace, target)
+    }
+
+    /// Reads the changes forward through the generation files.
+    ///
+    /// Records written before sequence numbers existed are not part of the feed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ResyncRequired` if a compaction has discarded
+    /// changes after `seq`. Reading again from `0` replays the compacted state.
+    fn changes_since(&self, seq: u64, limit: usize) -> Result<Vec<Change>> {
+        self.read_changes(seq, limit)
+    }
+
+    fn stats(&self) -> EngineStats {
+        let live_keys = self
+            .namespaces
+            .iter()
+            .map(|namespace| namespace.value().index.len() as u64)
+            .sum();
+        EngineStats {
+            live_keys: Some(live_keys),
+            disk_bytes: Some(self.stats.disk_bytes.load(Ordering::Relaxed)),
+            compactions: Some(self.stats.compactions.load(Ordering::Relaxed)),
+        }
     }
 }
 
+/// Statistics kept up to date by the writer.
+#[derive(Default)]
+struct StoreStats {
+    // size of the log files not yet deleted by a compaction
+    disk_bytes: AtomicU64,
+    compactions: AtomicU64,
+}
+
+impl StoreStats {
+    fn add_disk_bytes(&self, n: u64) {
+        self.disk_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// A named keyspace with its own index.
+///
+/// Log records refer to a namespace by its `id`, which is never reused while
+/// records of a dropped namespace may still be on disk.
+struct Namespace {
+    id: u32,
+    name: String,
+    index: SkipMap<String, AtomicCell<CommandPos>>,
+}
+
+impl Namespace {
+    fn new(id: u32, name: String) -> Self {
+        Namespace {
+            id,
+            name,
+            index: SkipMap::new(),
+        }
+    }
+
+    /// Returns the number of bytes the live entries take in the log.
+    fn live_bytes(&self) -> u64 {
+        self.index.iter().map(|entry| entry.value().load().len).sum()
+    }
+
+    /// Points a key at a new record, returning the previous one.
+    ///
+    /// Existing entries are updated in place: replacing them in the skip list
+    /// would briefly hide the key from concurrent readers.
+    fn update(&self, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+        match self.index.get(&key) {
+            Some(entry) => Some(entry.value().swap(cmd_pos)),
+            None => {
+                self.index.insert(key, AtomicCell::new(cmd_pos));
+                None
+            }
+        }
+    }
+}
 
 /// A single thread reader.
 ///
@@ -157,10 +488,11 @@ impl KvsEngine for KvStore {
 /// 所以，实现内部可变性的 Cell RefCell 正是为了解决这类问题存在的，通过它们可以实现 struct 部分字段
 /// 可变，而不用将整个 struct 设置为 mutable
 struct KvStoreReader {
-    path: Arc<PathBuf>,
+    storage: Arc<dyn Storage>,
     // generation of the latest compaction file
     safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    ciphers: Arc<GenCiphers>,
+    readers: RefCell<BTreeMap<u64, LogReader>>,
 }
 
 impl KvStoreReader {
@@ -183,16 +515,15 @@ impl KvStoreReader {
     /// Read the log file at the given `CommandPos`.
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(io::Take<&mut LogReader>) -> Result<R>,
     {
         self.close_stale_handles();
 
         let mut readers = self.readers.borrow_mut();
         // Open the file if we haven't opened it in this `KvStoreReader`
-        // We don't use entry API here because we want the errors to be propagated
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+        if let Entry::Vacant(entry) = readers.entry(cmd_pos.gen) {
+            let reader = BufReaderWithPos::new(self.storage.open(&log_name(cmd_pos.gen))?)?;
+            entry.insert(reader);
         }
 
         let reader = readers.get_mut(&cmd_pos.gen).unwrap();
@@ -203,17 +534,33 @@ impl KvStoreReader {
 
     /// Read the log file at the given `CommandPos` and deserialize it to `Command`
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        let cipher = self.ciphers.get(cmd_pos.gen);
+        self.read_and(cmd_pos, |mut cmd_reader| match cipher {
+            None => Ok(serde_json::from_reader(cmd_reader)?),
+            Some(cipher) => {
+                let frame = read_frame(&mut cmd_reader)?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                open_record(cmd_pos.gen, cmd_pos.pos, &frame, &cipher)
+            }
         })
     }
+
+    /// Read the `Set` command at the given `CommandPos` and return its value
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<String> {
+        if let Command::Set { value, .. } = self.read_command(cmd_pos)? {
+            Ok(value)
+        } else {
+            Err(KvsError::UnexpectedCommandType)
+        }
+    }
 }
 
 impl Clone for KvStoreReader {
     fn clone(&self) -> Self {
         KvStoreReader {
-            path: Arc::clone(&self.path),
+            storage: Arc::clone(&self.storage),
             safe_point: Arc::clone(&self.safe_point),
+            ciphers: Arc::clone(&self.ciphers),
             // don't use other KvStoreReader's readers
             readers: RefCell::new(BTreeMap::new()),
         }
@@ -222,30 +569,40 @@ impl Clone for KvStoreReader {
 
 struct KvStoreWriter {
     reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
+    writer: LogWriter,
+    // the cipher of the files written from now on
+    cipher: Option<Arc<Cipher>>,
     current_gen: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during compaction
     uncompacted: u64,
-    path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    storage: Arc<dyn Storage>,
+    // a write failed and may have left a partial record at the end of the log
+    torn: bool,
+    namespaces: Arc<SkipMap<String, Arc<Namespace>>>,
+    next_ns_id: u32,
+    // sequence number of the last record written
+    last_seq: u64,
+    compacted_seq: Arc<AtomicU64>,
+    subscribers: Subscribers,
+    stats: Arc<StoreStats>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+    fn set(&mut self, namespace: &str, key: String, value: String) -> Result<()> {
+        let namespace = self.namespace(namespace)?;
+        let cmd = Command::set(self.next_seq(), namespace.id, key, value);
+        let range = self.append(&cmd)?;
+
+        if let Command::Set { seq, key, value, .. } = cmd {
+            self.subscribers.publish(&namespace.name, || Event::Set {
+                key: key.clone(),
+                value,
+            });
+            let cmd_pos = CommandPos::new(self.current_gen, range, seq);
+            if let Some(old_cmd) = namespace.update(key, cmd_pos) {
+                self.uncompacted += old_cmd.len;
             }
-
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
         }
 
         if self.uncompacted > COMPACTION_THRESHOLD {
@@ -255,20 +612,22 @@ impl KvStoreWriter {
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+    fn remove(&mut self, namespace: &str, key: String) -> Result<()> {
+        let namespace = self.namespace(namespace)?;
+        if namespace.index.contains_key(&key) {
+            let cmd = Command::remove(self.next_seq(), namespace.id, key);
+            let range = self.append(&cmd)?;
 
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
+            if let Command::Remove { key, .. } = cmd {
+                let old_cmd = namespace.index.remove(&key).expect("key not found");
+                self.uncompacted += old_cmd.value().load().len;
 
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
+                self.uncompacted += range.end - range.start;
+
+                self.subscribers
+                    .publish(&namespace.name, || Event::Removed { key });
             }
             if self.uncompacted > COMPACTION_THRESHOLD {
                 self.compact()?;
@@ -280,32 +639,186 @@ impl KvStoreWriter {
         }
     }
 
+    fn create_namespace(&mut self, name: &str) -> Result<()> {
+        if self.namespaces.contains_key(name) {
+            return Err(KvsError::NamespaceExists);
+        }
+
+        let id = self.next_ns_id;
+        let seq = self.next_seq();
+        self.append(&Command::CreateNamespace {
+            seq,
+            id,
+            name: name.to_owned(),
+        })?;
+        self.next_ns_id += 1;
+        self.namespaces
+            .insert(name.to_owned(), Arc::new(Namespace::new(id, name.to_owned())));
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvsError::DefaultNamespace);
+        }
+
+        let namespace = self.namespace(name)?;
+        let seq = self.next_seq();
+        let range = self.append(&Command::DropNamespace {
+            seq,
+            id: namespace.id,
+        })?;
+        self.namespaces.remove(name);
+
+        // both the entries of the namespace and the "drop" command itself
+        // can be deleted in the next compaction
+        self.uncompacted += namespace.live_bytes() + range.end - range.start;
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    fn watch(&mut self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        let namespace = self.namespace(namespace)?;
+        Ok(self.subscribers.subscribe(&namespace.name, target))
+    }
+
+    fn namespace(&self, name: &str) -> Result<Arc<Namespace>> {
+        self.namespaces
+            .get(name)
+            .map(|entry| Arc::clone(entry.value()))
+            .ok_or(KvsError::NamespaceNotFound)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    /// Appends a command to the current log file.
+    ///
+    /// Returns the range of the log the command occupies.
+    fn append(&mut self, cmd: &Command) -> Result<Range<u64>> {
+        if self.torn {
+            // a partial record must stay the last one of its file, where the
+            // replay stops, so the log goes on in a new file
+            self.current_gen += 1;
+            self.reader.ciphers.insert(self.current_gen, self.cipher.clone());
+            let writer = new_log_file(&*self.storage, self.current_gen, self.cipher.as_deref())?;
+            mem::replace(&mut self.writer, writer).discard();
+            self.torn = false;
+            self.stats.add_disk_bytes(self.writer.pos);
+        }
+
+        let pos = self.writer.pos;
+        let res = write_record(&mut self.writer, self.current_gen, self.cipher.as_deref(), cmd)
+            .and_then(|()| Ok(self.writer.flush()?));
+        self.torn = res.is_err();
+        self.stats.add_disk_bytes(self.writer.pos - pos);
+        res?;
+        Ok(pos..self.writer.pos)
+    }
+
     /// Clears stale entries in the log.
     fn compact(&mut self) -> Result<()> {
-        // increase current gen by 2. current_gen + 1 is for the compaction file
+        self.compact_with(self.cipher.clone())
+    }
+
+    /// Clears stale entries in the log, writing the new files with `cipher`.
+    fn compact_with(&mut self, cipher: Option<Arc<Cipher>>) -> Result<()> {
+        // current_gen + 1 is for the compaction file, current_gen + 2 for the new writer
         let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+        // the compaction file is renamed into place once complete, so that a
+        // crash never leaves a partial one behind
+        let tmp_name = compaction_tmp_name(compaction_gen);
+        let mut compaction_writer =
+            create_log_file(&*self.storage, &tmp_name, compaction_gen, cipher.as_deref(), true)?;
+
+        // the change feed cannot go back past this point any more
+        let compacted_seq = self.last_seq;
+        write_record(
+            &mut compaction_writer,
+            compaction_gen,
+            cipher.as_deref(),
+            &Command::Checkpoint { seq: compacted_seq },
+        )?;
+
+        // namespaces must be declared before any of their entries
+        let mut live = Vec::new();
+        for namespace in self.namespaces.iter() {
+            let namespace = namespace.value();
+            if namespace.id != DEFAULT_NAMESPACE_ID {
+                write_record(
+                    &mut compaction_writer,
+                    compaction_gen,
+                    cipher.as_deref(),
+                    &Command::CreateNamespace {
+                        seq: 0,
+                        id: namespace.id,
+                        name: namespace.name.clone(),
+                    },
+                )?;
+            }
+            for entry in namespace.index.iter() {
+                live.push((entry.value().load(), Arc::clone(namespace), entry.key().clone()));
+            }
+        }
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+        // keep the records in sequence order so the change feed can resume
+        // anywhere in the compaction file
+        live.sort_unstable_by_key(|(cmd_pos, ..)| cmd_pos.seq);
+        let mut moved = Vec::with_capacity(live.len());
+        for (cmd_pos, namespace, key) in live {
+            let pos = compaction_writer.pos;
+            if cipher.is_none() && self.reader.ciphers.get(cmd_pos.gen).is_none() {
+                self.reader.read_and(cmd_pos, |mut entry_reader| {
+                    Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+                })?;
+            } else {
+                // sealed records are bound to their position, so they are
+                // re-encrypted rather than copied
+                let cmd = self.reader.read_command(cmd_pos)?;
+                write_record(&mut compaction_writer, compaction_gen, cipher.as_deref(), &cmd)?;
+            }
+            let cmd_pos = CommandPos::new(compaction_gen, pos..compaction_writer.pos, cmd_pos.seq);
+            moved.push((namespace, key, cmd_pos));
         }
         compaction_writer.flush()?;
+        compaction_writer.writer.get_mut().sync()?;
+        let compaction_size = compaction_writer.pos;
+        self.reader.ciphers.insert(compaction_gen, cipher.clone());
+        self.storage.rename(&tmp_name, &log_name(compaction_gen))?;
+
+        // readers keep using the old files until the compaction file is in place
+        for (namespace, key, cmd_pos) in moved {
+            namespace.update(key, cmd_pos);
+        }
 
-        self.reader
+        self.current_gen += 2;
+        self.reader.ciphers.insert(self.current_gen, cipher.clone());
+        let writer = new_log_file(&*self.storage, self.current_gen, cipher.as_deref())?;
+        let old_writer = mem::replace(&mut self.writer, writer);
+        if self.torn {
+            old_writer.discard();
+        }
+        self.torn = false;
+        self.cipher = cipher;
+        self.compacted_seq.store(compacted_seq, Ordering::SeqCst);
+        self.stats
+            .disk_bytes
+            .store(compaction_size + self.writer.pos, Ordering::Relaxed);
+        self.stats.compactions.fetch_add(1, Ordering::Relaxed);
+
+        let previous_safe_point = self
+            .reader
             .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
+            .swap(compaction_gen, Ordering::SeqCst);
         self.reader.close_stale_handles();
+        // a reader may still hold positions in the files just replaced
+        self.reader.ciphers.forget_before(previous_safe_point);
 
         // remove stale log files
         // Note that actually these files are not deleted immediately because `KvStoreReader`s
@@ -314,13 +827,13 @@ impl KvStoreWriter {
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
 
-        let stale_gens = sorted_gen_list(&self.path)?
+        let stale_gens = sorted_gen_list(&*self.storage)?
             .into_iter()
             .filter(|&gen| gen < compaction_gen);
         for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+            let name = log_name(stale_gen);
+            if let Err(e) = self.storage.remove(&name) {
+                error!("{} cannot be deleted: {}", name, e);
             }
         }
         self.uncompacted = 0;
@@ -329,116 +842,408 @@ impl KvStoreWriter {
     }
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Create a new log file with given generation number, encrypted if a cipher is given.
 ///
 /// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
+fn new_log_file(storage: &dyn Storage, gen: u64, cipher: Option<&Cipher>) -> Result<LogWriter> {
+    create_log_file(storage, &log_name(gen), gen, cipher, false)
+}
+
+fn create_log_file(
+    storage: &dyn Storage,
+    name: &str,
+    gen: u64,
+    cipher: Option<&Cipher>,
+    compaction: bool,
+) -> Result<LogWriter> {
+    let mut writer = BufWriterWithPos::new(storage.create(name)?)?;
+    if let Some(cipher) = cipher {
+        writer.write_all(ENCRYPTED_MAGIC)?;
+        write_record(&mut writer, gen, Some(cipher), &FileHeader { compaction })?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
 
-/// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
+/// Writes a record at the end of a log file, sealed if the file is encrypted.
+fn write_record<W: Write + Seek, T: Serialize>(
+    writer: &mut BufWriterWithPos<W>,
+    gen: u64,
+    cipher: Option<&Cipher>,
+    record: &T,
+) -> Result<()> {
+    match cipher {
+        None => serde_json::to_writer(writer, record)?,
+        Some(cipher) => {
+            let frame = cipher.seal(gen, writer.pos, &serde_json::to_vec(record)?)?;
+            writer.write_all(&frame)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the ciphertext of a sealed record, or `None` at the end of the file.
+fn read_frame<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    let mut frame = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Decrypts a record read at `pos` of generation `gen`.
+fn open_record<T: DeserializeOwned>(
+    gen: u64,
+    pos: u64,
+    frame: &[u8],
+    cipher: &Cipher,
+) -> Result<T> {
+    Ok(serde_json::from_slice(&cipher.open(gen, pos, frame)?)?)
+}
+
+/// Reads the header of a log file.
+///
+/// Returns `None` if the file is not encrypted.
+fn read_header(
+    gen: u64,
+    file: &mut dyn ReadFile,
+    cipher: Option<&Cipher>,
+) -> Result<Option<FileHeader>> {
+    let mut magic = Vec::new();
+    file.take(ENCRYPTED_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    if magic.len() < ENCRYPTED_MAGIC.len() && !magic.is_empty() && ENCRYPTED_MAGIC.starts_with(&magic) {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if magic != ENCRYPTED_MAGIC {
+        return Ok(None);
+    }
+    let cipher = cipher.ok_or(KvsError::KeyRequired)?;
+    let frame = read_frame(file)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    Ok(Some(open_record(gen, ENCRYPTED_MAGIC.len() as u64, &frame, cipher)?))
+}
+
+/// Returns the generations to load in order, along with whether they are encrypted.
+///
+/// Encrypted compaction files are marked in their header. The generations
+/// before the latest one are stale files a compaction did not get to delete,
+/// so they are deleted now. So are files whose header was never completely
+/// written, as they hold no record.
+fn live_gen_list(storage: &dyn Storage, cipher: Option<&Cipher>) -> Result<Vec<(u64, bool)>> {
+    let gen_list = sorted_gen_list(storage)?;
+    let mut live = Vec::new();
+    for (i, &gen) in gen_list.iter().enumerate().rev() {
+        let header = match read_header(gen, &mut *storage.open(&log_name(gen))?, cipher) {
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                storage.remove(&log_name(gen))?;
+                continue;
+            }
+            res => res?,
+        };
+        live.push((gen, header.is_some()));
+        if header.is_some_and(|header| header.compaction) {
+            for &stale_gen in &gen_list[..i] {
+                let name = log_name(stale_gen);
+                if let Err(e) = storage.remove(&name) {
+                    error!("{} cannot be deleted: {}", name, e);
+                }
+            }
+            break;
+        }
+    }
+    live.reverse();
+    Ok(live)
+}
+
+/// Removes the compaction files a crash left unfinished.
+fn remove_unfinished_compactions(storage: &dyn Storage) -> Result<()> {
+    for name in storage.list()? {
+        if name.ends_with(".log.tmp") {
+            storage.remove(&name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Ends the replay of a file at a record torn by a crash or a failed write,
+/// which was never acknowledged.
+fn allow_torn_tail(res: Result<()>) -> Result<()> {
+    match res {
+        Err(KvsError::Serde(ref e)) if e.is_eof() => Ok(()),
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+        res => res,
+    }
+}
+
+/// The first record of an encrypted log file.
+///
+/// Decrypting it checks the key before anything is replayed.
+#[derive(Serialize, Deserialize, Debug)]
+struct FileHeader {
+    /// whether the file was written by a compaction
+    compaction: bool,
+}
+
+/// The ciphers of the encrypted generation files, shared by the readers and the writer.
+#[derive(Default)]
+struct GenCiphers(RwLock<HashMap<u64, Arc<Cipher>>>);
+
+impl GenCiphers {
+    fn get(&self, gen: u64) -> Option<Arc<Cipher>> {
+        self.0.read().unwrap().get(&gen).cloned()
+    }
+
+    fn insert(&self, gen: u64, cipher: Option<Arc<Cipher>>) {
+        if let Some(cipher) = cipher {
+            self.0.write().unwrap().insert(gen, cipher);
+        }
+    }
+
+    fn forget_before(&self, gen: u64) {
+        self.0.write().unwrap().retain(|&g, _| g >= gen);
+    }
+}
+
+/// Returns sorted generation numbers in the given storage
+fn sorted_gen_list(storage: &dyn Storage) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = storage
+        .list()?
+        .iter()
+        .filter_map(|name| name.strip_suffix(".log"))
+        .flat_map(str::parse::<u64>)
         .collect();
     gen_list.sort_unstable();
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// State rebuilt while replaying the log files in generation order.
+struct Replay {
+    // map namespace id to the namespace
+    namespaces: HashMap<u32, Arc<Namespace>>,
+    next_ns_id: u32,
+    last_seq: u64,
+    compacted_seq: u64,
+}
+
+impl Replay {
+    fn new() -> Self {
+        let mut namespaces = HashMap::new();
+        namespaces.insert(
+            DEFAULT_NAMESPACE_ID,
+            Arc::new(Namespace::new(DEFAULT_NAMESPACE_ID, DEFAULT_NAMESPACE.to_owned())),
+        );
+        Replay {
+            namespaces,
+            next_ns_id: DEFAULT_NAMESPACE_ID + 1,
+            last_seq: 0,
+            compacted_seq: 0,
+        }
+    }
+}
+
+/// Load the whole log file and store value locations in the index maps of
+/// the namespaces.
 ///
 /// Returns how many bytes can be saved after a compaction.
 fn load(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
+    reader: &mut LogReader,
+    cipher: Option<&Cipher>,
+    replay: &mut Replay,
 ) -> Result<u64> {
     // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream =
-        Deserializer::from_reader(reader).into_iter::<Command>();
+    reader.seek(SeekFrom::Start(0))?;
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+    let res = for_each_record(gen, reader, cipher, |cmd, range| {
+        let (pos, new_pos) = (range.start, range.end);
+        replay.last_seq = replay.last_seq.max(cmd.seq());
+        match cmd {
+            Command::Set { seq, ns, key, .. } => match replay.namespaces.get(&ns) {
+                Some(namespace) => {
+                    let cmd_pos = CommandPos::new(gen, pos..new_pos, seq);
+                    if let Some(old_cmd) = namespace.update(key, cmd_pos) {
+                        uncompacted += old_cmd.len;
+                    }
                 }
-                index.insert(key, (gen, pos..new_pos).into());
-            }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+                // the namespace has been dropped
+                None => uncompacted += new_pos - pos,
+            },
+            Command::Remove { ns, key, .. } => {
+                if let Some(namespace) = replay.namespaces.get(&ns) {
+                    if let Some(old_cmd) = namespace.index.remove(&key) {
+                        uncompacted += old_cmd.value().load().len;
+                    }
                 }
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 uncompacted += new_pos - pos;
             }
+            Command::CreateNamespace { id, name, .. } => {
+                replay.next_ns_id = replay.next_ns_id.max(id + 1);
+                replay
+                    .namespaces
+                    .insert(id, Arc::new(Namespace::new(id, name)));
+            }
+            Command::DropNamespace { id, .. } => {
+                if let Some(namespace) = replay.namespaces.remove(&id) {
+                    uncompacted += namespace.live_bytes();
+                }
+                uncompacted += new_pos - pos;
+            }
+            Command::Checkpoint { seq } => {
+                replay.compacted_seq = replay.compacted_seq.max(seq);
+            }
         }
-        pos = new_pos;
-    }
+        Ok(true)
+    });
+    allow_torn_tail(res)?;
     Ok(uncompacted)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+/// Calls `f` with every record of a log file and the range it occupies,
+/// until `f` returns `false`.
+///
+/// `cipher` is the key of an encrypted file, `None` for a plaintext one.
+fn for_each_record<R, F>(gen: u64, mut reader: R, cipher: Option<&Cipher>, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(Command, Range<u64>) -> Result<bool>,
+{
+    match cipher {
+        None => {
+            let mut pos = 0;
+            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let new_pos = stream.byte_offset() as u64;
+                if !f(cmd?, pos..new_pos)? {
+                    break;
+                }
+                pos = new_pos;
+            }
+        }
+        Some(cipher) => {
+            // the header has been checked when the store was opened
+            let mut magic = [0; ENCRYPTED_MAGIC.len()];
+            reader.read_exact(&mut magic)?;
+            let header = read_frame(&mut reader)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            let mut pos = (magic.len() + 4 + header.len()) as u64;
+            while let Some(frame) = read_frame(&mut reader)? {
+                let new_pos = pos + 4 + frame.len() as u64;
+                if !f(open_record(gen, pos, &frame, cipher)?, pos..new_pos)? {
+                    break;
+                }
+                pos = new_pos;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn log_name(gen: u64) -> String {
+    format!("{}.log", gen)
+}
+
+fn compaction_tmp_name(gen: u64) -> String {
+    format!("{}.log.tmp", gen)
 }
 
 /// Struct representing a command
+///
+/// Every record carries the sequence number it was committed with. Records
+/// of the default namespace omit `ns`, and records written before namespaces
+/// or sequence numbers existed read them as zero.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(default, skip_serializing_if = "is_zero")]
+        seq: u64,
+        #[serde(default, skip_serializing_if = "is_default_namespace")]
+        ns: u32,
+        key: String,
+        value: String,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "is_zero")]
+        seq: u64,
+        #[serde(default, skip_serializing_if = "is_default_namespace")]
+        ns: u32,
+        key: String,
+    },
+    CreateNamespace {
+        #[serde(default, skip_serializing_if = "is_zero")]
+        seq: u64,
+        id: u32,
+        name: String,
+    },
+    DropNamespace {
+        #[serde(default, skip_serializing_if = "is_zero")]
+        seq: u64,
+        id: u32,
+    },
+    /// Starts a compaction file: changes up to `seq` have been compacted
+    Checkpoint { seq: u64 },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(seq: u64, ns: u32, key: String, value: String) -> Command {
+        Command::Set { seq, ns, key, value }
     }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    fn remove(seq: u64, ns: u32, key: String) -> Command {
+        Command::Remove { seq, ns, key }
     }
+
+    fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. }
+            | Command::Remove { seq, .. }
+            | Command::CreateNamespace { seq, .. }
+            | Command::DropNamespace { seq, .. }
+            | Command::Checkpoint { seq } => *seq,
+        }
+    }
+}
+
+fn is_default_namespace(ns: &u32) -> bool {
+    *ns == DEFAULT_NAMESPACE_ID
 }
 
+fn is_zero(seq: &u64) -> bool {
+    *seq == 0
+}
 
-/// Represents the position and length of a json-serialized in the log
+/// Represents the position and length of a json-serialized in the log,
+/// along with the sequence number of the command
 #[derive(Debug, Clone, Copy)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    seq: u64,
 }
 
-
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
+impl CommandPos {
+    fn new(gen: u64, range: Range<u64>, seq: u64) -> Self {
         CommandPos {
             gen,
             pos: range.start,
-            len: range.start - range.start,
+            len: range.end - range.start,
+            seq,
         }
     }
 }
 
 
+type LogReader = BufReaderWithPos<Box<dyn ReadFile>>;
+type LogWriter = BufWriterWithPos<Box<dyn WriteFile>>;
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -447,7 +1252,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -455,7 +1260,6 @@ impl<R: Read + Seek> BufReaderWithPos<R> {
     }
 }
 
-
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = self.reader.read(buf)?;
@@ -479,12 +1283,18 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     pub fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
         })
     }
+
+    /// Drops the writer without writing out what is still buffered, which
+    /// is what a failed write leaves behind.
+    fn discard(self) {
+        let _ = self.writer.into_parts();
+    }
 }
 
 
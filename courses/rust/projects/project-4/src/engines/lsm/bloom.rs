@@ -0,0 +1,84 @@
+/// Number of filter bits spent per key, which gives roughly a 1% false
+/// positive rate with `NUM_HASHES` probes.
+const BITS_PER_KEY: usize = 10;
+const NUM_HASHES: u32 = 7;
+
+/// A bloom filter over the keys of an SSTable.
+///
+/// Hashing uses FNV-1a rather than the std hasher, whose algorithm is not
+/// guaranteed to stay the same across Rust releases, because the filter is
+/// persisted in the table file.
+pub(super) struct Bloom {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl Bloom {
+    /// Creates an empty filter sized for `num_keys` keys.
+    pub(super) fn with_capacity(num_keys: usize) -> Self {
+        let num_bits = (num_keys * BITS_PER_KEY).max(64);
+        Bloom {
+            bits: vec![0; num_bits.div_ceil(8)],
+            num_hashes: NUM_HASHES,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &[u8]) {
+        let num_bits = self.bits.len() as u64 * 8;
+        for bit in probes(key, self.num_hashes, num_bits) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false if the key is definitely not in the set.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.bits.len() as u64 * 8;
+        if num_bits == 0 {
+            return true;
+        }
+        probes(key, self.num_hashes, num_bits)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Encodes the filter as the number of hashes followed by the bit array.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        buf.extend_from_slice(&self.num_hashes.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    /// Decodes a filter produced by `encode`.
+    ///
+    /// A truncated filter matches every key rather than hiding data.
+    pub(super) fn decode(buf: &[u8]) -> Self {
+        if buf.len() < 4 {
+            return Bloom {
+                bits: Vec::new(),
+                num_hashes: 0,
+            };
+        }
+        let mut num_hashes = [0; 4];
+        num_hashes.copy_from_slice(&buf[..4]);
+        Bloom {
+            bits: buf[4..].to_vec(),
+            num_hashes: u32::from_le_bytes(num_hashes),
+        }
+    }
+}
+
+/// Yields the bit positions of a key using double hashing.
+fn probes(key: &[u8], num_hashes: u32, num_bits: u64) -> impl Iterator<Item = u64> {
+    let h1 = fnv1a(key);
+    let h2 = h1.rotate_right(17) | 1;
+    (0..num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
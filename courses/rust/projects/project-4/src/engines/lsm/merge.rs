@@ -0,0 +1,92 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::Result;
+
+use super::table::{Entry, InternalKey};
+
+/// A source of entries sorted by key.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merges sorted sources into a single run of entries in key order.
+///
+/// Sources are given newest first. When several hold the same key only the
+/// entry of the newest one is yielded, so a tombstone hides the older values.
+/// Each source is only read as far as the merge gets.
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    heads: BinaryHeap<Head>,
+}
+
+/// The next entry of a source.
+struct Head {
+    key: InternalKey,
+    source: usize,
+    value: Option<String>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub(super) fn new(sources: Vec<Source<'a>>) -> Result<Self> {
+        let mut merge = MergeIter {
+            sources,
+            heads: BinaryHeap::new(),
+        };
+        for source in 0..merge.sources.len() {
+            merge.advance(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Reads the next entry of `source` into the heads.
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(entry) = self.sources[source].next() {
+            let (key, value) = entry?;
+            self.heads.push(Head { key, source, value });
+        }
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let head = match self.heads.pop() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        self.advance(head.source)?;
+        // the older entries of the same key are shadowed
+        while self.heads.peek().is_some_and(|next| next.key == head.key) {
+            let shadowed = self.heads.pop().unwrap();
+            self.advance(shadowed.source)?;
+        }
+        Ok(Some((head.key, head.value)))
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+impl Ord for Head {
+    /// The heap pops the greatest head, which is the smallest key, found in
+    /// the newest source.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&other.key, other.source).cmp(&(&self.key, self.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
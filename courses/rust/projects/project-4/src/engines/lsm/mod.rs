@@ -0,0 +1,881 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::iter;
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam_skiplist::SkipMap;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::engines::watch::Subscribers;
+use crate::engines::{scan_start, Event, KvsEngine, WatchTarget, Watcher, DEFAULT_NAMESPACE};
+use crate::{KvsError, Result};
+
+use self::merge::{MergeIter, Source};
+use self::table::{table_path, InternalKey, Table, TableBuilder, TableMeta};
+
+mod bloom;
+mod merge;
+mod table;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const NUM_LEVELS: usize = 7;
+const DEFAULT_NAMESPACE_ID: u32 = 0;
+
+/// Tuning parameters of an `LsmEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Size in bytes at which the memtable is written out as an SSTable
+    pub memtable_bytes: u64,
+    /// Number of level 0 tables that triggers a compaction into level 1
+    pub l0_compaction_trigger: usize,
+    /// Maximum size in bytes of level 1, each further level may hold ten times more
+    pub level_base_bytes: u64,
+    /// Size in bytes at which a compaction starts a new output table
+    pub table_bytes: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 * 1024 * 1024,
+            l0_compaction_trigger: 4,
+            level_base_bytes: 10 * 1024 * 1024,
+            table_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+/// A log-structured merge-tree engine.
+///
+/// Writes go to a write-ahead log and an in-memory skip list, the memtable.
+/// A full memtable is written out as a sorted, immutable SSTable in level 0,
+/// and a background thread merges the tables down into larger levels whose
+/// tables do not overlap. Unlike `KvStore`, the keys don't need to fit in
+/// memory: only the block index and the bloom filter of each table are kept.
+///
+/// The `MANIFEST` file lists the tables of every level and the namespaces.
+///
+/// ```rust
+/// # use kvs::{LsmEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = LsmEngine::open(temp_dir.path())?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+///
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmEngine {
+    inner: Arc<LsmInner>,
+    // only held for its `Drop`
+    _compactor: Arc<Compactor>,
+}
+
+struct LsmInner {
+    path: PathBuf,
+    options: LsmOptions,
+    state: RwLock<State>,
+    /// the log of the active memtable, the lock serializes the writers
+    wal: Mutex<Wal>,
+    /// wakes the compaction thread up
+    compactions: Sender<()>,
+    subscribers: Subscribers,
+}
+
+struct State {
+    memtable: Arc<MemTable>,
+    /// the memtable being written out, still visible to readers
+    immutable: Option<Arc<MemTable>>,
+    /// the log started when `immutable` was frozen, the first one without its
+    /// entries
+    immutable_log: u64,
+    /// level 0 is ordered newest first, the other levels by key range
+    levels: Arc<Vec<Vec<Arc<Table>>>>,
+    namespaces: BTreeMap<String, u32>,
+    next_ns_id: u32,
+    next_file_id: u64,
+    log_number: u64,
+    /// largest key of the table each level was last compacted from
+    compact_pointers: Vec<Option<InternalKey>>,
+}
+
+impl LsmEngine {
+    /// Opens an `LsmEngine` with the given path and default options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while reading the manifest,
+    /// the tables or the write-ahead logs.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmEngine> {
+        LsmEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Opens an `LsmEngine` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// Same as `LsmEngine::open`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest = Manifest::load(&path)?;
+        let mut next_file_id = manifest.next_file_id;
+
+        let mut live_tables = HashSet::new();
+        let mut levels = vec![Vec::new(); NUM_LEVELS];
+        for (level, metas) in manifest.levels.into_iter().enumerate() {
+            for meta in metas {
+                live_tables.insert(meta.id);
+                let table = Table::open(&table_path(&path, meta.id), meta)?;
+                levels[level].push(Arc::new(table));
+            }
+        }
+
+        // tables left over by an interrupted flush or compaction
+        for id in sorted_file_ids(&path, "sst")? {
+            next_file_id = next_file_id.max(id + 1);
+            if !live_tables.contains(&id) {
+                fs::remove_file(table_path(&path, id))?;
+            }
+        }
+
+        let memtable = MemTable::default();
+        for id in sorted_file_ids(&path, "wal")? {
+            next_file_id = next_file_id.max(id + 1);
+            if id < manifest.log_number {
+                fs::remove_file(wal_path(&path, id))?;
+            } else {
+                replay_wal(&wal_path(&path, id), &memtable)?;
+            }
+        }
+
+        let wal = Wal::create(&path, next_file_id)?;
+        let state = State {
+            memtable: Arc::new(memtable),
+            immutable: None,
+            immutable_log: 0,
+            levels: Arc::new(levels),
+            namespaces: manifest.namespaces,
+            next_ns_id: manifest.next_ns_id,
+            next_file_id: next_file_id + 1,
+            log_number: manifest.log_number,
+            compact_pointers: vec![None; NUM_LEVELS],
+        };
+
+        let (sender, receiver) = channel::unbounded();
+        let inner = Arc::new(LsmInner {
+            path,
+            options,
+            state: RwLock::new(state),
+            wal: Mutex::new(wal),
+            compactions: sender,
+            subscribers: Subscribers::default(),
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let inner = Arc::downgrade(&inner);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("kvs-lsm-compaction".to_owned())
+                .spawn(move || run_compactions(inner, receiver, stop))?
+        };
+        let compactor = Arc::new(Compactor {
+            stop,
+            wakeups: inner.compactions.clone(),
+            handle: Some(handle),
+        });
+        // level 0 may have filled up before the last shutdown
+        let _ = inner.compactions.send(());
+
+        Ok(LsmEngine {
+            inner,
+            _compactor: compactor,
+        })
+    }
+}
+
+impl KvsEngine for LsmEngine {
+    /// Appends the value to the write-ahead log and inserts it in the memtable,
+    /// which is written out as a table once it is full.
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        let mut wal = self.inner.wal.lock().unwrap();
+        let ns = self.inner.namespace_id(namespace)?;
+        wal.append(&WalRecord::Set {
+            ns,
+            key: key.clone(),
+            value: value.clone(),
+        })?;
+        self.inner.memtable().insert((ns, key.clone()), Some(value.clone()));
+        self.inner
+            .subscribers
+            .publish(namespace, || Event::Set { key, value });
+        self.inner.maybe_flush(&mut wal)
+    }
+
+    /// Looks the key up in the memtables, then in level 0 from the newest table
+    /// on, then in the single table of each deeper level covering the key.
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        let ns = self.inner.namespace_id(namespace)?;
+        self.inner.lookup(&(ns, key))
+    }
+
+    /// Writes a tombstone for the key, which shadows the older values until a
+    /// compaction into the last non-empty level drops them all.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        let mut wal = self.inner.wal.lock().unwrap();
+        let ns = self.inner.namespace_id(namespace)?;
+        let internal_key = (ns, key);
+        if self.inner.lookup(&internal_key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        wal.append(&WalRecord::Remove {
+            ns,
+            key: internal_key.1.clone(),
+        })?;
+        let key = internal_key.1.clone();
+        self.inner.memtable().insert(internal_key, None);
+        self.inner
+            .subscribers
+            .publish(namespace, || Event::Removed { key });
+        self.inner.maybe_flush(&mut wal)
+    }
+
+    /// Merges the matching entries of the memtables and of every table whose
+    /// key range overlaps the prefix, the newest entry of a key winning.
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let ns = self.inner.namespace_id(namespace)?;
+        self.inner.scan(ns, prefix, after, limit)
+    }
+
+    /// Assigns the namespace a new id in the manifest.
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        let _wal = self.inner.wal.lock().unwrap();
+        let mut state = self.inner.state.write().unwrap();
+        if state.namespaces.contains_key(name) {
+            return Err(KvsError::NamespaceExists);
+        }
+        let id = state.next_ns_id;
+        state.next_ns_id += 1;
+        state.namespaces.insert(name.to_owned(), id);
+        state.manifest().store(&self.inner.path)
+    }
+
+    /// Removes the namespace from the manifest.
+    ///
+    /// Ids are never reused, so its entries are unreachable right away. They
+    /// are discarded by the next flush or compaction that reads them.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvsError::DefaultNamespace);
+        }
+        let _wal = self.inner.wal.lock().unwrap();
+        let mut state = self.inner.state.write().unwrap();
+        if state.namespaces.remove(name).is_none() {
+            return Err(KvsError::NamespaceNotFound);
+        }
+        state.manifest().store(&self.inner.path)
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let state = self.inner.state.read().unwrap();
+        Ok(state.namespaces.keys().cloned().collect())
+    }
+
+    /// Syncs the write-ahead log, which holds what the memtables hold.
+    fn sync(&self) -> Result<()> {
+        self.inner.wal.lock().unwrap().sync()
+    }
+
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        // hold the writer lock so no write is missed or delivered twice
+        let _wal = self.inner.wal.lock().unwrap();
+        self.inner.namespace_id(namespace)?;
+        Ok(self.inner.subscribers.subscribe(namespace, target))
+    }
+}
+
+impl LsmInner {
+    fn namespace_id(&self, namespace: &str) -> Result<u32> {
+        let state = self.state.read().unwrap();
+        state
+            .namespaces
+            .get(namespace)
+            .copied()
+            .ok_or(KvsError::NamespaceNotFound)
+    }
+
+    fn memtable(&self) -> Arc<MemTable> {
+        Arc::clone(&self.state.read().unwrap().memtable)
+    }
+
+    fn lookup(&self, key: &InternalKey) -> Result<Option<String>> {
+        let (memtable, immutable, levels) = {
+            let state = self.state.read().unwrap();
+            (
+                Arc::clone(&state.memtable),
+                state.immutable.clone(),
+                Arc::clone(&state.levels),
+            )
+        };
+
+        for memtable in iter::once(&memtable).chain(immutable.as_ref()) {
+            if let Some(value) = memtable.get(key) {
+                return Ok(value);
+            }
+        }
+        for table in &levels[0] {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for tables in &levels[1..] {
+            let i = tables.partition_point(|table| table.meta.largest < *key);
+            match tables.get(i) {
+                Some(table) if table.meta.smallest <= *key => {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan(
+        &self,
+        ns: u32,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let (memtable, immutable, levels) = {
+            let state = self.state.read().unwrap();
+            (
+                Arc::clone(&state.memtable),
+                state.immutable.clone(),
+                Arc::clone(&state.levels),
+            )
+        };
+
+        let start = scan_start(prefix, after);
+        let matches = |key: &InternalKey| {
+            key.0 == ns
+                && key.1.starts_with(prefix)
+                && match start {
+                    Bound::Excluded(after) => key.1.as_str() > after,
+                    _ => true,
+                }
+        };
+        let first = (ns, prefix.to_owned());
+        // true for the keys sorting after every key with the prefix
+        let past = |key: &InternalKey| key > &first && !(key.0 == ns && key.1.starts_with(prefix));
+        let seek = match start {
+            Bound::Excluded(after) if after > prefix => (ns, after.to_owned()),
+            _ => first.clone(),
+        };
+        let overlaps = |table: &Table| !(table.meta.largest < seek || past(&table.meta.smallest));
+
+        // sources are given from the newest on, so the merge yields the
+        // current value or tombstone of each key
+        let mut sources: Vec<Source> = Vec::new();
+        for memtable in iter::once(&memtable).chain(immutable.as_ref()) {
+            sources.push(memtable.iter_from(&seek));
+        }
+        for table in levels[0].iter().filter(|table| overlaps(table)) {
+            sources.push(Box::new(table.iter_from(&seek)));
+        }
+        for tables in &levels[1..] {
+            // the tables of a level do not overlap, so they are read in turn
+            let tables: Vec<Arc<Table>> = tables
+                .iter()
+                .filter(|table| overlaps(table))
+                .cloned()
+                .collect();
+            let seek = seek.clone();
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| table.iter_from(&seek)),
+            ));
+        }
+
+        let mut keys = Vec::new();
+        for entry in MergeIter::new(sources)? {
+            let (key, value) = entry?;
+            if keys.len() >= limit || past(&key) {
+                break;
+            }
+            if matches(&key) && value.is_some() {
+                keys.push(key.1);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn maybe_flush(&self, wal: &mut Wal) -> Result<()> {
+        if self.memtable().bytes() < self.options.memtable_bytes {
+            return Ok(());
+        }
+        self.flush(wal)
+    }
+
+    /// Writes the memtable out as a level 0 table.
+    ///
+    /// A new log is started and the memtable frozen first, so readers keep
+    /// seeing the frozen entries until the table is installed. If writing the
+    /// table fails, the frozen memtable stays readable and the next flush
+    /// writes it out before freezing another one.
+    fn flush(&self, wal: &mut Wal) -> Result<()> {
+        // freezing another memtable would drop the one a failed flush left
+        let pending = {
+            let state = self.state.read().unwrap();
+            state
+                .immutable
+                .clone()
+                .map(|memtable| (memtable, state.immutable_log))
+        };
+        if let Some((memtable, wal_id)) = pending {
+            self.write_out(&memtable, wal_id)?;
+        }
+
+        let wal_id = self.state.write().unwrap().alloc_file_id();
+        *wal = Wal::create(&self.path, wal_id)?;
+        let memtable = {
+            let mut state = self.state.write().unwrap();
+            let memtable = mem::take(&mut state.memtable);
+            state.immutable = Some(Arc::clone(&memtable));
+            state.immutable_log = wal_id;
+            memtable
+        };
+        self.write_out(&memtable, wal_id)
+    }
+
+    /// Writes the frozen memtable out as a table and installs it in level 0.
+    ///
+    /// The logs before `wal_id` are deleted once the manifest no longer needs
+    /// them.
+    fn write_out(&self, memtable: &MemTable, wal_id: u64) -> Result<()> {
+        let (table_id, namespaces) = {
+            let mut state = self.state.write().unwrap();
+            let namespaces: HashSet<u32> = state.namespaces.values().copied().collect();
+            (state.alloc_file_id(), namespaces)
+        };
+        let table = match self.write_table(memtable, table_id, &namespaces) {
+            Ok(table) => table,
+            Err(e) => {
+                // a partial table left behind is removed on the next open
+                let _ = fs::remove_file(table_path(&self.path, table_id));
+                return Err(e);
+            }
+        };
+
+        {
+            let mut state = self.state.write().unwrap();
+            if let Some(table) = table {
+                let mut levels = (*state.levels).clone();
+                levels[0].insert(0, table);
+                state.levels = Arc::new(levels);
+            }
+            state.immutable = None;
+            state.log_number = wal_id;
+            state.manifest().store(&self.path)?;
+        }
+
+        for id in sorted_file_ids(&self.path, "wal")? {
+            if id < wal_id {
+                fs::remove_file(wal_path(&self.path, id))?;
+            }
+        }
+        let _ = self.compactions.send(());
+        Ok(())
+    }
+
+    /// Writes the entries of the live namespaces to a new table, returning
+    /// `None` if there are none.
+    fn write_table(
+        &self,
+        memtable: &MemTable,
+        table_id: u64,
+        namespaces: &HashSet<u32>,
+    ) -> Result<Option<Arc<Table>>> {
+        let mut builder = TableBuilder::create(&self.path, table_id)?;
+        for entry in memtable.map.iter() {
+            if namespaces.contains(&entry.key().0) {
+                builder.add(entry.key().clone(), entry.value().read().unwrap().clone())?;
+            }
+        }
+        if builder.is_empty() {
+            drop(builder);
+            fs::remove_file(table_path(&self.path, table_id))?;
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(builder.finish()?)))
+    }
+
+    fn max_level_bytes(&self, level: usize) -> u64 {
+        self.options.level_base_bytes * 10u64.pow(level as u32 - 1)
+    }
+
+    /// Runs one compaction if any level is over its limit.
+    ///
+    /// Returns false if there was nothing to do.
+    fn compact(&self) -> Result<bool> {
+        let task = match self.pick_compaction() {
+            Some(task) => task,
+            None => return Ok(false),
+        };
+
+        // inputs are ordered newest first, so the merge keeps the newest value of a key
+        let sources = task
+            .inputs
+            .iter()
+            .map(|table| Box::new(table.iter_from(&InternalKey::default())) as Source)
+            .collect();
+
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in MergeIter::new(sources)? {
+            let (key, value) = entry?;
+            if !task.namespaces.contains(&key.0) || (task.bottom && value.is_none()) {
+                continue;
+            }
+            let b = match builder.as_mut() {
+                Some(b) => b,
+                None => {
+                    let id = self.state.write().unwrap().alloc_file_id();
+                    builder.insert(TableBuilder::create(&self.path, id)?)
+                }
+            };
+            b.add(key, value)?;
+            if b.estimated_size() >= self.options.table_bytes {
+                outputs.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+        if let Some(b) = builder {
+            outputs.push(Arc::new(b.finish()?));
+        }
+
+        let inputs: HashSet<u64> = task.inputs.iter().map(|table| table.meta.id).collect();
+        {
+            let mut state = self.state.write().unwrap();
+            let mut levels = (*state.levels).clone();
+            levels[task.level].retain(|table| !inputs.contains(&table.meta.id));
+            levels[task.level + 1].retain(|table| !inputs.contains(&table.meta.id));
+            levels[task.level + 1].extend(outputs);
+            levels[task.level + 1].sort_by(|a, b| a.meta.smallest.cmp(&b.meta.smallest));
+            state.levels = Arc::new(levels);
+            state.manifest().store(&self.path)?;
+        }
+        // the files go away once the last reader drops them
+        for table in &task.inputs {
+            table.mark_obsolete();
+        }
+        Ok(true)
+    }
+
+    /// Picks all of level 0 once it has enough tables, or else one table of the
+    /// first level over its size limit, together with the overlapping tables of
+    /// the next level. Tables of a level are picked in turn across its key range.
+    fn pick_compaction(&self) -> Option<Compaction> {
+        let mut state = self.state.write().unwrap();
+        let levels = Arc::clone(&state.levels);
+
+        let level = if levels[0].len() >= self.options.l0_compaction_trigger {
+            0
+        } else {
+            (1..NUM_LEVELS - 1).find(|&level| {
+                let bytes: u64 = levels[level].iter().map(|table| table.meta.size).sum();
+                bytes > self.max_level_bytes(level)
+            })?
+        };
+
+        let upper = if level == 0 {
+            levels[0].clone()
+        } else {
+            let tables = &levels[level];
+            let next = match &state.compact_pointers[level] {
+                Some(pointer) => tables.partition_point(|table| table.meta.largest <= *pointer),
+                None => 0,
+            };
+            let table = tables.get(next).unwrap_or(&tables[0]);
+            state.compact_pointers[level] = Some(table.meta.largest.clone());
+            vec![Arc::clone(table)]
+        };
+
+        let smallest = upper.iter().map(|table| &table.meta.smallest).min()?;
+        let largest = upper.iter().map(|table| &table.meta.largest).max()?;
+        let lower = levels[level + 1]
+            .iter()
+            .filter(|table| table.meta.largest >= *smallest && table.meta.smallest <= *largest)
+            .cloned();
+
+        Some(Compaction {
+            level,
+            inputs: upper.iter().cloned().chain(lower).collect(),
+            // tombstones can only go when no older value may be left below
+            bottom: levels[level + 2..].iter().all(Vec::is_empty),
+            namespaces: state.namespaces.values().copied().collect(),
+        })
+    }
+}
+
+/// A merge of tables from `level` into `level + 1`.
+struct Compaction {
+    level: usize,
+    /// newest first
+    inputs: Vec<Arc<Table>>,
+    bottom: bool,
+    /// ids of the namespaces whose entries are kept
+    namespaces: HashSet<u32>,
+}
+
+/// Stops the compaction thread once the last handle of the engine is dropped,
+/// waiting for a running compaction so the directory can be reopened safely.
+struct Compactor {
+    stop: Arc<AtomicBool>,
+    wakeups: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.wakeups.send(());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+fn run_compactions(inner: Weak<LsmInner>, wakeups: Receiver<()>, stop: Arc<AtomicBool>) {
+    while wakeups.recv().is_ok() {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        while !stop.load(Ordering::SeqCst) {
+            match inner.compact() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!("Compaction failed: {}", e);
+                    break;
+                }
+            }
+        }
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+    }
+}
+
+impl State {
+    fn alloc_file_id(&mut self) -> u64 {
+        let id = self.next_file_id;
+        self.next_file_id += 1;
+        id
+    }
+
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            next_file_id: self.next_file_id,
+            log_number: self.log_number,
+            next_ns_id: self.next_ns_id,
+            namespaces: self.namespaces.clone(),
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.meta.clone()).collect())
+                .collect(),
+        }
+    }
+}
+
+/// The persistent layout of the engine.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    next_file_id: u64,
+    /// logs with a smaller id have been written out to tables
+    log_number: u64,
+    next_ns_id: u32,
+    namespaces: BTreeMap<String, u32>,
+    levels: Vec<Vec<TableMeta>>,
+}
+
+impl Manifest {
+    fn load(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            let mut namespaces = BTreeMap::new();
+            namespaces.insert(DEFAULT_NAMESPACE.to_owned(), DEFAULT_NAMESPACE_ID);
+            return Ok(Manifest {
+                next_file_id: 1,
+                log_number: 0,
+                next_ns_id: DEFAULT_NAMESPACE_ID + 1,
+                namespaces,
+                levels: vec![Vec::new(); NUM_LEVELS],
+            });
+        }
+        let mut manifest: Manifest = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        manifest.levels.resize(NUM_LEVELS, Vec::new());
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a temporary file, syncs it and renames it over
+    /// the current one.
+    fn store(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer
+            .into_inner()
+            .map_err(|e| KvsError::Io(e.into_error()))?
+            .sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// The in-memory table receiving the writes. `None` marks a removed key.
+///
+/// Values are overwritten in place: replacing an entry of the skip list would
+/// briefly hide the key, letting readers fall through to older tables.
+#[derive(Default)]
+struct MemTable {
+    map: SkipMap<InternalKey, RwLock<Option<String>>>,
+    bytes: AtomicU64,
+}
+
+impl MemTable {
+    /// Inserts or overwrites a value, the writers being serialized by the caller.
+    fn insert(&self, key: InternalKey, value: Option<String>) {
+        let len = key.1.len() + value.as_ref().map_or(0, String::len);
+        self.bytes.fetch_add(len as u64, Ordering::SeqCst);
+        match self.map.get(&key) {
+            Some(entry) => *entry.value().write().unwrap() = value,
+            None => {
+                self.map.insert(key, RwLock::new(value));
+            }
+        }
+    }
+
+    fn get(&self, key: &InternalKey) -> Option<Option<String>> {
+        self.map
+            .get(key)
+            .map(|entry| entry.value().read().unwrap().clone())
+    }
+
+    /// Iterates over the entries from `key` on, in key order.
+    fn iter_from(&self, key: &InternalKey) -> Source<'_> {
+        Box::new(self.map.range(key.clone()..).map(|entry| {
+            let value = entry.value().read().unwrap().clone();
+            Ok((entry.key().clone(), value))
+        }))
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+}
+
+/// Record of the write-ahead log.
+#[derive(Serialize, Deserialize)]
+enum WalRecord {
+    Set { ns: u32, key: String, value: String },
+    Remove { ns: u32, key: String },
+}
+
+struct Wal {
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    fn create(dir: &Path, id: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(Wal {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn append(&mut self, record: &WalRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+fn replay_wal(path: &Path, memtable: &MemTable) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    for record in Deserializer::from_reader(reader).into_iter::<WalRecord>() {
+        match record {
+            Ok(WalRecord::Set { ns, key, value }) => memtable.insert((ns, key), Some(value)),
+            Ok(WalRecord::Remove { ns, key }) => memtable.insert((ns, key), None),
+            // a record torn by a crash was never acknowledged
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// Returns the sorted ids of the files with the given extension.
+fn sorted_file_ids(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
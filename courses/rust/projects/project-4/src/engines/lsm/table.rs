@@ -0,0 +1,321 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+use super::bloom::Bloom;
+
+/// Target size of a data block before it is cut.
+const BLOCK_BYTES: usize = 4 * 1024;
+
+/// The footer holds the offsets and lengths of the index and the filter.
+const FOOTER_LEN: u64 = 32;
+
+/// A key qualified by the id of its namespace.
+pub(super) type InternalKey = (u32, String);
+
+/// A key with its value, or `None` for a tombstone.
+pub(super) type Entry = (InternalKey, Option<String>);
+
+/// Describes a table in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct TableMeta {
+    pub(super) id: u64,
+    pub(super) smallest: InternalKey,
+    pub(super) largest: InternalKey,
+    pub(super) size: u64,
+}
+
+/// Location of a data block, found by the last key it contains.
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: InternalKey,
+    offset: u64,
+    len: u64,
+}
+
+/// Writes sorted entries into an immutable SSTable file.
+///
+/// The file is a run of data blocks followed by the block index, the bloom
+/// filter and a fixed-size footer:
+///
+/// ```text
+/// [block 0] ... [block n] [index] [filter] [index offset, index len, filter offset, filter len]
+/// ```
+///
+/// Blocks and the index are serialized with `serde_json` like the rest of
+/// the crate's on-disk data.
+pub(super) struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<Entry>,
+    block_bytes: usize,
+    index: Vec<BlockHandle>,
+    filter_keys: Vec<Vec<u8>>,
+    smallest: Option<InternalKey>,
+    largest: Option<InternalKey>,
+}
+
+impl TableBuilder {
+    pub(super) fn create(dir: &Path, id: u64) -> Result<Self> {
+        let path = table_path(dir, id);
+        Ok(TableBuilder {
+            id,
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            offset: 0,
+            block: Vec::new(),
+            block_bytes: 0,
+            index: Vec::new(),
+            filter_keys: Vec::new(),
+            smallest: None,
+            largest: None,
+        })
+    }
+
+    /// Appends an entry. Keys must be added in increasing order.
+    pub(super) fn add(&mut self, key: InternalKey, value: Option<String>) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.clone());
+        }
+        self.largest = Some(key.clone());
+        self.filter_keys.push(filter_key(&key));
+        self.block_bytes += key.1.len() + value.as_ref().map_or(0, String::len) + 16;
+        self.block.push((key, value));
+        if self.block_bytes >= BLOCK_BYTES {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes written so far, including the open block.
+    pub(super) fn estimated_size(&self) -> u64 {
+        self.offset + self.block_bytes as u64
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.smallest.is_none()
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let last_key = match self.block.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let buf = serde_json::to_vec(&self.block)?;
+        self.writer.write_all(&buf)?;
+        self.index.push(BlockHandle {
+            last_key,
+            offset: self.offset,
+            len: buf.len() as u64,
+        });
+        self.offset += buf.len() as u64;
+        self.block.clear();
+        self.block_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes the index, the filter and the footer, syncs the file and opens it.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+
+        let index = serde_json::to_vec(&self.index)?;
+        let mut bloom = Bloom::with_capacity(self.filter_keys.len());
+        for key in &self.filter_keys {
+            bloom.insert(key);
+        }
+        let filter = bloom.encode();
+
+        let index_offset = self.offset;
+        let filter_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&filter)?;
+        for n in &[
+            index_offset,
+            index.len() as u64,
+            filter_offset,
+            filter.len() as u64,
+        ] {
+            self.writer.write_all(&n.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        let size = filter_offset + filter.len() as u64 + FOOTER_LEN;
+        let meta = TableMeta {
+            id: self.id,
+            smallest: self.smallest.take().unwrap_or_default(),
+            largest: self.largest.take().unwrap_or_default(),
+            size,
+        };
+        Table::open(&self.path, meta)
+    }
+}
+
+/// An open SSTable. Its block index and bloom filter are kept in memory.
+///
+/// Once a compaction has replaced the table it is marked obsolete, and the
+/// file is deleted when the last reader lets go of it.
+pub(super) struct Table {
+    pub(super) meta: TableMeta,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    pub(super) fn open(path: &Path, meta: TableMeta) -> Result<Table> {
+        let mut file = File::open(path)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < FOOTER_LEN {
+            return Err(corrupted(path));
+        }
+
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let mut numbers = [0u64; 4];
+        for (i, n) in numbers.iter_mut().enumerate() {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&footer[i * 8..i * 8 + 8]);
+            *n = u64::from_le_bytes(bytes);
+        }
+        let [index_offset, index_len, filter_offset, filter_len] = numbers;
+        if filter_offset + filter_len + FOOTER_LEN != len {
+            return Err(corrupted(path));
+        }
+
+        let index: Vec<BlockHandle> = serde_json::from_slice(&read_at(&mut file, index_offset, index_len)?)?;
+        let bloom = Bloom::decode(&read_at(&mut file, filter_offset, filter_len)?);
+
+        Ok(Table {
+            meta,
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            index,
+            bloom,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Looks a key up.
+    ///
+    /// Returns `None` if the table has no entry for the key, and `Some(None)`
+    /// if it holds a tombstone.
+    pub(super) fn get(&self, key: &InternalKey) -> Result<Option<Option<String>>> {
+        if !self.bloom.may_contain(&filter_key(key)) {
+            return Ok(None);
+        }
+        // the first block whose last key is not smaller than the key
+        let block = self.index.partition_point(|handle| handle.last_key < *key);
+        if block == self.index.len() {
+            return Ok(None);
+        }
+        let entries = self.read_block(&self.index[block])?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.cmp(key))
+            .ok()
+            .map(|i| entries[i].1.clone()))
+    }
+
+    /// Iterates over the entries from `key` on, in key order.
+    ///
+    /// The index finds the first block to read, and the following blocks are
+    /// only read as the iteration gets to them.
+    pub(super) fn iter_from(self: &Arc<Self>, key: &InternalKey) -> TableIter {
+        TableIter {
+            table: Arc::clone(self),
+            block: self.index.partition_point(|handle| handle.last_key < *key),
+            seek: Some(key.clone()),
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Vec<Entry>> {
+        let buf = read_at(&mut self.file.lock().unwrap(), handle.offset, handle.len)?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
+
+/// An iterator over the entries of a table, reading a block at a time.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    /// the next block to read
+    block: usize,
+    /// entries before this key are skipped in the first block read
+    seek: Option<InternalKey>,
+    entries: vec::IntoIter<Entry>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            let handle = self.table.index.get(self.block)?;
+            self.block += 1;
+            let mut entries = match self.table.read_block(handle) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            };
+            if let Some(seek) = self.seek.take() {
+                entries.retain(|(key, _)| *key >= seek);
+            }
+            self.entries = entries.into_iter();
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("{:?} cannot be deleted: {}", self.path, e);
+            }
+        }
+    }
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// The bytes hashed into the bloom filter for a key.
+fn filter_key((ns, key): &InternalKey) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + key.len());
+    buf.extend_from_slice(&ns.to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf
+}
+
+fn corrupted(path: &Path) -> KvsError {
+    KvsError::StringError(format!("{:?} is not a valid SSTable", path))
+}
@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_skiplist::SkipMap;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+use super::watch::Subscribers;
+use super::{scan_start, Event, KvsEngine, WatchTarget, Watcher, DEFAULT_NAMESPACE};
+
+/// An in-memory `KvsEngine` backed by a concurrent skip list per namespace.
+///
+/// Without a snapshot path nothing touches the disk, which makes it handy in
+/// tests. With one, the whole map is dumped atomically into a single file
+/// periodically and when the last clone is dropped, and reloaded on open,
+/// much like a Redis RDB file.
+///
+/// ```rust
+/// # use kvs::{InMemoryEngine, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let store = InMemoryEngine::new();
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct InMemoryEngine(Arc<MemoryInner>);
+
+struct MemoryInner {
+    // map namespace name to its keyspace
+    namespaces: SkipMap<String, Arc<Keyspace>>,
+    snapshot: Option<Snapshot>,
+    subscribers: Subscribers,
+    // serializes writes with their watch events so watchers see them in the
+    // order they were applied; reads don't take it
+    write_lock: Mutex<()>,
+}
+
+type Keyspace = SkipMap<String, String>;
+
+/// Namespace name to its key/value pairs.
+type SnapshotData = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Version of the snapshot layout written by this engine.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Contents of the snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    version: u32,
+    namespaces: SnapshotData,
+}
+
+/// Location and state of the snapshot file.
+struct Snapshot {
+    path: PathBuf,
+    // set on every mutation, cleared by a successful dump
+    dirty: AtomicBool,
+    // serializes concurrent dumps so they don't race on the temporary file
+    lock: Mutex<()>,
+}
+
+impl InMemoryEngine {
+    /// Creates an empty engine that is never persisted.
+    pub fn new() -> Self {
+        InMemoryEngine(Arc::new(MemoryInner {
+            namespaces: default_namespaces(),
+            snapshot: None,
+            subscribers: Subscribers::default(),
+            write_lock: Mutex::new(()),
+        }))
+    }
+
+    /// Opens an engine persisted to the snapshot file at `path`.
+    ///
+    /// The snapshot is loaded if it exists. If `interval` is given, a background
+    /// thread dumps the map at that period while any clone of the engine is alive.
+    /// A final dump is always made when the last clone is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors while loading the snapshot.
+    pub fn with_snapshot(path: impl Into<PathBuf>, interval: Option<Duration>) -> Result<Self> {
+        let path = path.into();
+        let namespaces = default_namespaces();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            let file: SnapshotFile = serde_json::from_reader(reader).map_err(|e| {
+                KvsError::StringError(format!("{:?} is not a valid snapshot: {}", path, e))
+            })?;
+            if file.version != SNAPSHOT_VERSION {
+                return Err(KvsError::StringError(format!(
+                    "Unsupported snapshot version {}",
+                    file.version
+                )));
+            }
+            for (name, entries) in file.namespaces {
+                let keyspace = Keyspace::new();
+                for (key, value) in entries {
+                    keyspace.insert(key, value);
+                }
+                namespaces.insert(name, Arc::new(keyspace));
+            }
+        }
+
+        let engine = InMemoryEngine(Arc::new(MemoryInner {
+            namespaces,
+            snapshot: Some(Snapshot {
+                path,
+                dirty: AtomicBool::new(false),
+                lock: Mutex::new(()),
+            }),
+            subscribers: Subscribers::default(),
+            write_lock: Mutex::new(()),
+        }));
+
+        if let Some(interval) = interval {
+            let weak = Arc::downgrade(&engine.0);
+            thread::Builder::new()
+                .name("kvs-snapshot".to_owned())
+                .spawn(move || run_snapshots(weak, interval))?;
+        }
+
+        Ok(engine)
+    }
+
+    /// Dumps the map to the snapshot file if it changed since the last dump.
+    ///
+    /// It does nothing if the engine has no snapshot path.
+    pub fn snapshot(&self) -> Result<()> {
+        self.0.dump()
+    }
+}
+
+impl Default for InMemoryEngine {
+    fn default() -> Self {
+        InMemoryEngine::new()
+    }
+}
+
+impl MemoryInner {
+    fn keyspace(&self, namespace: &str) -> Result<Arc<Keyspace>> {
+        self.namespaces
+            .get(namespace)
+            .map(|entry| Arc::clone(entry.value()))
+            .ok_or(KvsError::NamespaceNotFound)
+    }
+
+    fn mark_dirty(&self) {
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Writes the map to a temporary file, syncs it and renames it over the
+    /// snapshot, so a crash never leaves a half-written snapshot behind.
+    fn dump(&self) -> Result<()> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        let _guard = snapshot.lock.lock().unwrap();
+        if !snapshot.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let data: SnapshotData = self
+            .namespaces
+            .iter()
+            .map(|namespace| {
+                let entries = namespace
+                    .value()
+                    .iter()
+                    .map(|entry| (entry.key().clone(), entry.value().clone()))
+                    .collect();
+                (namespace.key().clone(), entries)
+            })
+            .collect();
+
+        let tmp_path = snapshot.path.with_extension("tmp");
+        let result = (|| -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            let file = SnapshotFile {
+                version: SNAPSHOT_VERSION,
+                namespaces: data,
+            };
+            serde_json::to_writer(&mut writer, &file)?;
+            writer.flush()?;
+            writer
+                .into_inner()
+                .map_err(|e| KvsError::Io(e.into_error()))?
+                .sync_all()?;
+            fs::rename(&tmp_path, &snapshot.path)?;
+            Ok(())
+        })();
+
+        match result {
+            Ok(()) => {
+                debug!("Snapshot written to {:?}", snapshot.path);
+                Ok(())
+            }
+            Err(e) => {
+                // keep the data marked as unsaved so the next dump retries
+                snapshot.dirty.store(true, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for MemoryInner {
+    fn drop(&mut self) {
+        if let Err(e) = self.dump() {
+            error!("Failed to write the final snapshot: {}", e);
+        }
+    }
+}
+
+fn run_snapshots(inner: Weak<MemoryInner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match inner.upgrade() {
+            Some(inner) => {
+                if let Err(e) = inner.dump() {
+                    error!("Failed to write snapshot: {}", e);
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+fn default_namespaces() -> SkipMap<String, Arc<Keyspace>> {
+    let namespaces = SkipMap::new();
+    namespaces.insert(DEFAULT_NAMESPACE.to_owned(), Arc::new(Keyspace::new()));
+    namespaces
+}
+
+impl KvsEngine for InMemoryEngine {
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        let keyspace = self.0.keyspace(namespace)?;
+        let _guard = self.0.write_lock.lock().unwrap();
+        let entry = keyspace.insert(key, value);
+        self.0.mark_dirty();
+        self.0.subscribers.publish(namespace, || Event::Set {
+            key: entry.key().clone(),
+            value: entry.value().clone(),
+        });
+        Ok(())
+    }
+
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        Ok(self
+            .0
+            .keyspace(namespace)?
+            .get(&key)
+            .map(|entry| entry.value().clone()))
+    }
+
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        let keyspace = self.0.keyspace(namespace)?;
+        let _guard = self.0.write_lock.lock().unwrap();
+        keyspace.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        self.0.mark_dirty();
+        self.0
+            .subscribers
+            .publish(namespace, || Event::Removed { key });
+        Ok(())
+    }
+
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        // `get_or_insert` keeps the existing keyspace if another thread won the race
+        let keyspace = Arc::new(Keyspace::new());
+        let entry = self.0.namespaces.get_or_insert(name.to_owned(), Arc::clone(&keyspace));
+        if !Arc::ptr_eq(entry.value(), &keyspace) {
+            return Err(KvsError::NamespaceExists);
+        }
+        self.0.mark_dirty();
+        Ok(())
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvsError::DefaultNamespace);
+        }
+        self.0
+            .namespaces
+            .remove(name)
+            .ok_or(KvsError::NamespaceNotFound)?;
+        self.0.mark_dirty();
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .namespaces
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .keyspace(namespace)?
+            .range::<str, _>((scan_start(prefix, after), Bound::Unbounded))
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .collect())
+    }
+
+    /// Dumps the snapshot, if the engine has one.
+    fn sync(&self) -> Result<()> {
+        self.snapshot()
+    }
+
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        self.0.keyspace(namespace)?;
+        Ok(self.0.subscribers.subscribe(namespace, target))
+    }
+}
@@ -1,29 +1,249 @@
-use std::convert::TryInto;
-use crate::{Result};
+use std::ops::Bound;
 
+use crate::{KvsError, Result};
 
+pub mod btree;
+pub mod change;
+pub mod encryption;
 pub mod kvs;
+pub mod lsm;
+pub mod memory;
 pub mod sled;
+pub(crate) mod storage;
+pub mod watch;
 
+pub use self::btree::BTreeEngine;
+pub use self::change::{Change, ChangeOp};
+pub use self::encryption::EncryptionKey;
 pub use self::kvs::KvStore;
+pub use self::lsm::{LsmEngine, LsmOptions};
+pub use self::memory::InMemoryEngine;
 pub use self::sled::SledKvsEngine;
+pub use self::watch::{Event, WatchTarget, Watcher};
+
+/// Name of the namespace that always exists and is used when none is given.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Statistics of an engine, exported as server metrics.
+///
+/// Engines leave out the statistics they do not keep.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Number of keys in all namespaces
+    pub live_keys: Option<u64>,
+    /// Size of the files of the engine
+    pub disk_bytes: Option<u64>,
+    /// Number of compactions since the engine was opened
+    pub compactions: Option<u64>,
+}
 
 /// Trait for a key value store engines.
+///
+/// Keys live in named namespaces, each with its own keyspace. The methods
+/// without a namespace argument operate on `DEFAULT_NAMESPACE`.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Sets the value of a string key to a string
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_in(DEFAULT_NAMESPACE, key, value)
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_in(DEFAULT_NAMESPACE, key)
+    }
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Gets the values of several keys, see `get_many_in`.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        self.get_many_in(DEFAULT_NAMESPACE, keys)
+    }
+
+    /// Sets several key/value pairs, see `set_many_in`.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        self.set_many_in(DEFAULT_NAMESPACE, pairs)
+    }
+
+    /// Removes several keys, see `remove_many_in`.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        self.remove_many_in(DEFAULT_NAMESPACE, keys)
+    }
+
+    /// Sets the value of a string key in the given namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given key in the given namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>>;
+
+    /// Removes a given key from the given namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist
+    /// and `KvsError::KeyNotFound` if the key is not found.
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()>;
+
+    /// Gets the values of several keys of the given namespace, returning one
+    /// result per key in the order of `keys`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    /// Other errors are reported for the key they occurred on.
+    fn get_many_in(
+        &self,
+        namespace: &str,
+        keys: Vec<String>,
+    ) -> Result<Vec<Result<Option<String>>>> {
+        for_each_key(keys, |key| self.get_in(namespace, key))
+    }
+
+    /// Sets several key/value pairs in the given namespace, returning one result
+    /// per pair. The pairs are not written atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    fn set_many_in(
+        &self,
+        namespace: &str,
+        pairs: Vec<(String, String)>,
+    ) -> Result<Vec<Result<()>>> {
+        for_each_key(pairs, |(key, value)| self.set_in(namespace, key, value))
+    }
+
+    /// Removes several keys from the given namespace, returning one result per
+    /// key. A missing key yields `KvsError::KeyNotFound` without failing the
+    /// others.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    fn remove_many_in(&self, namespace: &str, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        for_each_key(keys, |key| self.remove_in(namespace, key))
+    }
+
+    /// Creates an empty namespace.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceExists` if the namespace already exists.
+    fn create_namespace(&self, name: &str) -> Result<()>;
+
+    /// Drops a namespace together with all of its keys.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist,
+    /// and `KvsError::DefaultNamespace` for the default namespace, which cannot
+    /// be dropped.
+    fn drop_namespace(&self, name: &str) -> Result<()>;
+
+    /// Lists the names of all namespaces, including the default one, in order.
+    fn list_namespaces(&self) -> Result<Vec<String>>;
+
+    /// Lists keys in order, see `scan_in`.
+    fn scan(&self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        self.scan_in(DEFAULT_NAMESPACE, prefix, after, limit)
+    }
+
+    /// Lists up to `limit` keys of the given namespace starting with `prefix`,
+    /// in order. Passing the last key returned as `after` reads the next page.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist,
+    /// and `KvsError::Unsupported` if the engine cannot list its keys.
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let _ = (namespace, prefix, after, limit);
+        Err(KvsError::Unsupported("key scans".to_owned()))
+    }
+
+    /// Subscribes to changes of the keys matching `target`.
+    fn watch(&self, target: WatchTarget) -> Result<Watcher> {
+        self.watch_in(DEFAULT_NAMESPACE, target)
+    }
+
+    /// Subscribes to changes of the keys matching `target` in the given namespace.
+    ///
+    /// Only changes committed after this call are delivered.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace does not exist.
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher>;
+
+    /// Returns up to `limit` changes committed after the sequence number `seq`,
+    /// oldest first. Pass `0` to read everything that is still retained.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ResyncRequired` if changes after `seq` are no longer
+    /// retained, and `KvsError::Unsupported` if the engine has no change feed.
+    fn changes_since(&self, seq: u64, limit: usize) -> Result<Vec<Change>> {
+        let _ = (seq, limit);
+        Err(KvsError::Unsupported("change feed".to_owned()))
+    }
+
+    /// Returns the statistics the engine keeps.
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+
+    /// Writes out everything buffered and makes it durable, as done before
+    /// shutting down.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the bound a scan of the keys starting with `prefix` after `after`
+/// starts from.
+pub(crate) fn scan_start<'a>(prefix: &'a str, after: Option<&'a str>) -> Bound<&'a str> {
+    match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    }
+}
+
+/// Runs `f` on each item of a batch, collecting the results per item.
+///
+/// A missing namespace fails the whole batch rather than every item.
+pub(crate) fn for_each_key<T, R, F>(items: Vec<T>, mut f: F) -> Result<Vec<Result<R>>>
+where
+    F: FnMut(T) -> Result<R>,
+{
+    let mut results = Vec::with_capacity(items.len());
+    for item in items {
+        match f(item) {
+            Err(KvsError::NamespaceNotFound) => return Err(KvsError::NamespaceNotFound),
+            res => results.push(res),
+        }
+    }
+    Ok(results)
+}
@@ -1,31 +1,75 @@
+use std::ops::Bound;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_skiplist::SkipMap;
+use log::warn;
 use sled::{Db, Tree};
 
 use crate::{KvsError, Result};
 
-use super::KvsEngine;
+use super::watch::WatchSender;
+use super::{scan_start, Event, KvsEngine, WatchTarget, Watcher, DEFAULT_NAMESPACE};
 
 /// Wrapper of `sled::Db`
+///
+/// The default namespace is the default tree of the database, and every other
+/// namespace is a `sled::Tree` of the same name.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    // trees opened so far, so that lookups don't have to list all tree names
+    trees: Arc<SkipMap<String, Tree>>,
+}
 
 impl SledKvsEngine {
     /// Creates a `sledKvsEngine` from `sled::Db`.
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        SledKvsEngine {
+            db,
+            trees: Arc::new(SkipMap::new()),
+        }
+    }
+
+    /// Returns the tree backing the given namespace.
+    fn tree(&self, namespace: &str) -> Result<Tree> {
+        if namespace == DEFAULT_NAMESPACE {
+            let tree: &Tree = &self.db;
+            return Ok(tree.clone());
+        }
+        if let Some(entry) = self.trees.get(namespace) {
+            return Ok(entry.value().clone());
+        }
+        // `open_tree` creates missing trees, so check that it exists first
+        if !self.tree_exists(namespace) {
+            return Err(KvsError::NamespaceNotFound);
+        }
+        let tree = self.db.open_tree(namespace)?;
+        self.trees.insert(namespace.to_owned(), tree.clone());
+        Ok(tree)
+    }
+
+    fn tree_exists(&self, namespace: &str) -> bool {
+        self.db
+            .tree_names()
+            .iter()
+            .any(|name| name.as_ref() == namespace.as_bytes())
     }
 }
 
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        let tree = self.tree(namespace)?;
         tree.insert(key, value.into_bytes()).map(|_| ())?;
         tree.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        let tree = self.tree(namespace)?;
 
         Ok(tree
             .get(key)?
@@ -34,10 +78,126 @@ impl KvsEngine for SledKvsEngine {
             .transpose()?)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        let tree = self.tree(namespace)?;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
         tree.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE || self.tree_exists(name) {
+            return Err(KvsError::NamespaceExists);
+        }
+        let tree = self.db.open_tree(name)?;
+        self.trees.insert(name.to_owned(), tree);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvsError::DefaultNamespace);
+        }
+        self.trees.remove(name);
+        if !self.db.drop_tree(name)? {
+            return Err(KvsError::NamespaceNotFound);
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        let default_tree: &Tree = &self.db;
+        let default_name = default_tree.name();
+        let mut names = vec![DEFAULT_NAMESPACE.to_owned()];
+        for name in self.db.tree_names() {
+            if name != default_name {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn scan_in(
+        &self,
+        namespace: &str,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let start = match scan_start(prefix, after) {
+            Bound::Excluded(key) => Bound::Excluded(key.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut keys = Vec::new();
+        for entry in self.tree(namespace)?.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let key = String::from_utf8(entry?.0.to_vec())?;
+            if !key.starts_with(prefix) || keys.len() == limit {
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Subscribes through `Tree::watch_prefix`.
+    ///
+    /// A thread forwards sled's events into the watcher's bounded buffer, so
+    /// the writers are never held up by a slow watcher. It ends shortly after
+    /// the watcher is dropped.
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        let tree = self.tree(namespace)?;
+        let prefix = match &target {
+            WatchTarget::Key(key) | WatchTarget::Prefix(key) => key.clone(),
+        };
+        let subscriber = tree.watch_prefix(prefix);
+        let (sender, watcher) = Watcher::channel();
+        thread::Builder::new()
+            .name("kvs-sled-watch".to_owned())
+            .spawn(move || forward_events(subscriber, target, sender))?;
+        Ok(watcher)
+    }
+}
+
+/// How often a forwarding thread without events checks if its watcher is gone.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn forward_events(mut subscriber: sled::Subscriber, target: WatchTarget, sender: WatchSender) {
+    loop {
+        let event = match subscriber.next_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if sender.is_closed() => break,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // the database was closed
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let event = match event {
+            sled::Event::Insert { key, value } => {
+                match (String::from_utf8(key.to_vec()), String::from_utf8(value.to_vec())) {
+                    (Ok(key), Ok(value)) => Event::Set { key, value },
+                    _ => {
+                        warn!("Skipping a change with a non UTF-8 key or value");
+                        continue;
+                    }
+                }
+            }
+            sled::Event::Remove { key } => match String::from_utf8(key.to_vec()) {
+                Ok(key) => Event::Removed { key },
+                Err(_) => {
+                    warn!("Skipping a change with a non UTF-8 key");
+                    continue;
+                }
+            },
+        };
+        if target.matches(event.key()) && !sender.send(event) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+
+/// The file operations of `KvStore` and `BTreeEngine`.
+///
+/// Files are named relative to the store, so that the store can run on a
+/// simulated disk as well as in a directory.
+pub(crate) trait Storage: Send + Sync {
+    /// Creates an empty file for writing, replacing any file of that name.
+    fn create(&self, name: &str) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Opens a file for reading.
+    fn open(&self, name: &str) -> io::Result<Box<dyn ReadFile>>;
+
+    /// Opens a file for reading and writing anywhere, creating it if it does
+    /// not exist.
+    fn open_rw(&self, name: &str) -> io::Result<Box<dyn RandomFile>>;
+
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    /// Renames a file, replacing `to` if it exists.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Returns the names of all the files.
+    fn list(&self) -> io::Result<Vec<String>>;
+}
+
+pub(crate) trait ReadFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadFile for T {}
+
+pub(crate) trait WriteFile: Write + Seek + Send {
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl WriteFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// A file written in place, such as the pages of `BTreeEngine`.
+pub(crate) trait RandomFile: Read + WriteFile + Sync {
+    /// Truncates or extends the file.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl RandomFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// Files in a directory of the local file system.
+pub(crate) struct DiskStorage {
+    dir: PathBuf,
+}
+
+impl DiskStorage {
+    /// Creates the directory if it does not exist.
+    pub(crate) fn new(dir: PathBuf) -> io::Result<DiskStorage> {
+        fs::create_dir_all(&dir)?;
+        Ok(DiskStorage { dir })
+    }
+}
+
+impl Storage for DiskStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(File::create(self.dir.join(name))?))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(self.dir.join(name))?))
+    }
+
+    fn open_rw(&self, name: &str) -> io::Result<Box<dyn RandomFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join(name))?;
+        Ok(Box::new(file))
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.dir.join(from), self.dir.join(to))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                if let Some(name) = path.file_name().and_then(OsStr::to_str) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+}
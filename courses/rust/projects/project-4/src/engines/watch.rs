@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// Number of events a watcher may fall behind before it is disconnected.
+pub(crate) const WATCH_CHANNEL_CAPACITY: usize = 1024;
+
+/// A change committed to the store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// The key has been set to a new value
+    Set { key: String, value: String },
+    /// The key has been removed
+    Removed { key: String },
+}
+
+impl Event {
+    /// Returns the key the event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Removed { key } => key,
+        }
+    }
+}
+
+/// The keys a watcher is interested in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchTarget {
+    /// A single key
+    Key(String),
+    /// Every key starting with the prefix
+    Prefix(String),
+}
+
+impl WatchTarget {
+    /// Returns true if changes to `key` should be delivered.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            WatchTarget::Key(target) => key == target,
+            WatchTarget::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A blocking iterator over the changes matching a `WatchTarget`.
+///
+/// Events are buffered in a bounded channel so writers never wait for a slow
+/// watcher. A watcher that falls too far behind is disconnected and yields
+/// `KvsError::WatcherLagged` once before ending.
+pub struct Watcher {
+    receiver: Receiver<Event>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    /// Creates a watcher together with the sender feeding it.
+    pub(crate) fn channel() -> (WatchSender, Watcher) {
+        let (sender, receiver) = channel::bounded(WATCH_CHANNEL_CAPACITY);
+        let lagged = Arc::new(AtomicBool::new(false));
+        (
+            WatchSender {
+                sender,
+                lagged: Arc::clone(&lagged),
+            },
+            Watcher { receiver, lagged },
+        )
+    }
+
+    /// Waits for the next item like `next`, for at most `timeout`.
+    ///
+    /// Returns `Err(RecvTimeoutError::Timeout)` if nothing came in time.
+    pub(crate) fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<Option<Result<Event>>, RecvTimeoutError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(Ok(event))),
+            Err(RecvTimeoutError::Timeout) => Err(RecvTimeoutError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Ok(self.end()),
+        }
+    }
+
+    /// Returns the last item once the sender is gone.
+    fn end(&mut self) -> Option<Result<Event>> {
+        if self.lagged.swap(false, Ordering::SeqCst) {
+            Some(Err(KvsError::WatcherLagged))
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.recv() {
+            Ok(event) => Some(Ok(event)),
+            Err(_) => self.end(),
+        }
+    }
+}
+
+/// The sending half of a `Watcher`.
+pub(crate) struct WatchSender {
+    sender: Sender<Event>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl WatchSender {
+    /// Delivers an event without blocking.
+    ///
+    /// Returns false if the watcher is gone, either because it was dropped or
+    /// because its buffer is full, in which case it is marked as lagged.
+    pub(crate) fn send(&self, event: Event) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.lagged.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Returns true once the watcher has been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        // the watcher holds the only other reference to the flag
+        Arc::strong_count(&self.lagged) == 1
+    }
+}
+
+struct Subscription {
+    namespace: String,
+    target: WatchTarget,
+    sender: WatchSender,
+}
+
+/// Fans out committed changes to the matching watchers.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Subscribers {
+    /// Registers a watcher for changes to `target` in `namespace`.
+    pub(crate) fn subscribe(&self, namespace: &str, target: WatchTarget) -> Watcher {
+        let (sender, watcher) = Watcher::channel();
+        self.subscriptions.lock().unwrap().push(Subscription {
+            namespace: namespace.to_owned(),
+            target,
+            sender,
+        });
+        watcher
+    }
+
+    /// Delivers an event to every matching watcher, dropping the ones that are
+    /// gone or lagging behind.
+    ///
+    /// The event is only built if anyone is watching.
+    pub(crate) fn publish<F>(&self, namespace: &str, event: F)
+    where
+        F: FnOnce() -> Event,
+    {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.is_empty() {
+            return;
+        }
+        let event = event();
+        subscriptions.retain(|sub| {
+            if sub.namespace != namespace || !sub.target.matches(event.key()) {
+                return true;
+            }
+            sub.sender.send(event.clone())
+        });
+    }
+}
@@ -1,8 +1,13 @@
+// `failure_derive` expands to impls nested inside an anonymous const
+#![allow(non_local_definitions)]
+
 use std::io;
 use std::io::Error;
 use std::string::FromUtf8Error;
 
-use failure::{Fail, Fallible};
+use failure::Fail;
+
+use crate::protocol::ErrorCode;
 
 /// Error type for kvs
 #[derive(Fail, Debug)]
@@ -16,9 +21,34 @@ pub enum KvsError {
     Serde(#[cause] serde_json::Error),
 
     /// Removing non-existent key error
-    #[fail(display = "key not found")]
+    #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// Accessing or dropping a non-existent namespace error
+    #[fail(display = "Namespace not found")]
+    NamespaceNotFound,
+
+    /// Creating a namespace that already exists error
+    #[fail(display = "Namespace already exists")]
+    NamespaceExists,
+
+    /// Dropping the default namespace error
+    #[fail(display = "The default namespace cannot be dropped")]
+    DefaultNamespace,
+
+    /// A watcher fell too far behind the writers and was disconnected
+    #[fail(display = "Watcher lagged behind and was disconnected")]
+    WatcherLagged,
+
+    /// The requested changes have been compacted away. It holds the sequence
+    /// number up to which the log was compacted.
+    #[fail(display = "Resync required: changes up to {} have been compacted", _0)]
+    ResyncRequired(u64),
+
+    /// The engine does not support the operation
+    #[fail(display = "Unsupported operation: {}", _0)]
+    Unsupported(String),
+
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
@@ -28,10 +58,41 @@ pub enum KvsError {
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
 
+    /// A log file could not be decrypted, because the key is wrong or the
+    /// file has been tampered with
+    #[fail(display = "Cannot decrypt {}: wrong encryption key or corrupted data", _0)]
+    Decryption(String),
+
+    /// The store is encrypted but was opened without a key
+    #[fail(display = "The store is encrypted, an encryption key is required")]
+    KeyRequired,
+
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
 
+    /// The peer broke the wire protocol, or reported that we did
+    #[fail(display = "Protocol error ({}): {}", _0, _1)]
+    Protocol(ErrorCode, String),
+
+    /// A read or write on a connection did not complete in time. The
+    /// connection may be left in the middle of a message and should be closed
+    #[fail(display = "Timed out")]
+    Timeout,
+
+    /// The server requires the client to authenticate, or refused its
+    /// credentials
+    #[fail(display = "Unauthenticated: {}", _0)]
+    Unauthenticated(String),
+
+    /// The client is not allowed to send the request
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+
+    /// A TLS configuration could not be loaded
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -41,16 +102,28 @@ pub enum KvsError {
 
 impl From<io::Error> for KvsError {
     fn from(value: Error) -> Self {
+        if timed_out(value.kind()) {
+            return KvsError::Timeout;
+        }
         KvsError::Io(value)
     }
 }
 
 impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> KvsError {
+        if err.io_error_kind().is_some_and(timed_out) {
+            return KvsError::Timeout;
+        }
         KvsError::Serde(err)
     }
 }
 
+/// Returns true if an IO error is a socket timeout, which is reported as
+/// `WouldBlock` on Unix and `TimedOut` on Windows.
+fn timed_out(kind: io::ErrorKind) -> bool {
+    matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -0,0 +1,409 @@
+//! An HTTP gateway exposing any `KvsEngine` as a JSON REST API.
+//!
+//! | Route                      | Answer                                                   |
+//! |----------------------------|----------------------------------------------------------|
+//! | `GET /keys/{key}`          | `200` with `{"key": ..., "value": ...}`, or `404`        |
+//! | `PUT /keys/{key}`          | `204`, the body being `{"value": ...}`                   |
+//! | `DELETE /keys/{key}`       | `204`, or `404`                                          |
+//! | `GET /keys?prefix=&after=&limit=` | `200` with `{"keys": [...], "next": ...}`         |
+//! | `GET /health`              | `200` with `{"status": "ok"}`                            |
+//!
+//! Keys are percent-decoded from the path, and the key routes take an optional
+//! `namespace` query parameter. A listing returns at most `limit` keys in
+//! order, and `next` is the `after` to pass for the next page, or `null` after
+//! the last one. Errors are answered with `{"error": ...}`.
+//!
+//! Connections are kept alive between requests unless the client asks to
+//! close them, and closed once idle for `IDLE_TIMEOUT`.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use log::{debug, error};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::engines::{KvsEngine, DEFAULT_NAMESPACE};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+/// How long a connection may stay idle between requests.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest request line or header accepted.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+/// The largest number of headers accepted.
+const MAX_HEADERS: usize = 100;
+/// The largest body accepted.
+const MAX_BODY_LEN: usize = 64 << 20;
+/// Keys listed when the request sets no `limit`.
+const DEFAULT_LIMIT: usize = 100;
+/// The largest `limit` accepted.
+const MAX_LIMIT: usize = 1000;
+
+/// A server exposing an engine over HTTP.
+pub struct HttpServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool> HttpServer<E, P> {
+    /// Creates an `HttpServer` serving the given engine.
+    pub fn new(engine: E, pool: P) -> Self {
+        HttpServer { engine, pool }
+    }
+
+    /// Serves the connections to `addr`, each on a thread of the pool.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let engine = self.engine.clone();
+            self.pool.spawn(move || match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve(engine, stream) {
+                        error!("Error on serving HTTP client: {}", e);
+                    }
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            })
+        }
+        Ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    close: bool,
+}
+
+impl Request {
+    /// Returns the decoded value of a query parameter.
+    fn param(&self, name: &str) -> std::result::Result<Option<String>, Response> {
+        let query = match self.query {
+            Some(ref query) => query,
+            None => return Ok(None),
+        };
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if decode(key, true)? == name {
+                return decode(value, true).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Option<Value>,
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            body: Some(body),
+            allow: None,
+        }
+    }
+
+    fn no_content() -> Self {
+        Response {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, msg: impl Into<String>) -> Self {
+        Response::json(status, json!({ "error": msg.into() }))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "Method not allowed")
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> io::Result<()> {
+        let body = match self.body {
+            Some(ref body) => body.to_string(),
+            None => String::new(),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.body.is_some() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n{}", body)
+    }
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Self {
+        match e {
+            KvsError::KeyNotFound | KvsError::NamespaceNotFound => {
+                Response::error(404, e.to_string())
+            }
+            KvsError::Unsupported(_) => Response::error(501, e.to_string()),
+            e => {
+                error!("Error on serving HTTP request: {}", e);
+                Response::error(500, e.to_string())
+            }
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "Unknown",
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    tcp.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    loop {
+        let req = match read_request(&mut reader) {
+            Ok(Some(Ok(req))) => req,
+            Ok(None) => break,
+            // the rest of the stream cannot be parsed, so it is closed
+            Ok(Some(Err(resp))) => {
+                resp.write_to(&mut writer, true)?;
+                writer.flush()?;
+                break;
+            }
+            Err(KvsError::Timeout) => {
+                debug!("Closing idle HTTP connection from {}", peer_addr);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(
+            "Receive HTTP request from {}: {} {}",
+            peer_addr, req.method, req.path
+        );
+
+        let resp = route(&engine, &req).unwrap_or_else(|resp| resp);
+        resp.write_to(&mut writer, req.close)?;
+        writer.flush()?;
+        if req.close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the next request, or returns `None` if the stream ends between
+/// requests.
+///
+/// A request that cannot be parsed is returned as the response to send before
+/// closing the connection.
+fn read_request<R: BufRead>(
+    reader: &mut R,
+) -> Result<Option<std::result::Result<Request, Response>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (method, target, version) = {
+        let mut parts = line.split(' ');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+                (method.to_owned(), target.to_owned(), version.to_owned())
+            }
+            _ => return Ok(Some(Err(Response::error(400, "Invalid request line")))),
+        }
+    };
+
+    let mut close = version == "HTTP/1.0";
+    let mut content_len = None;
+    let mut headers = 0;
+    loop {
+        let header = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Ok(Some(Err(Response::error(431, "Too many headers"))));
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => return Ok(Some(Err(Response::error(400, "Invalid header")))),
+        };
+        match name.as_str() {
+            "content-length" => match value.parse::<usize>() {
+                Ok(len) if len <= MAX_BODY_LEN => content_len = Some(len),
+                Ok(_) => return Ok(Some(Err(Response::error(413, "Body too large")))),
+                Err(_) => return Ok(Some(Err(Response::error(400, "Invalid Content-Length")))),
+            },
+            "transfer-encoding" => {
+                return Ok(Some(Err(Response::error(
+                    411,
+                    "Content-Length is required",
+                ))))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0; content_len.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target, None),
+    };
+    Ok(Some(Ok(Request {
+        method,
+        path,
+        query,
+        body,
+        close,
+    })))
+}
+
+/// Reads a line without its line break, or returns `None` at the end of the
+/// stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *reader).take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(unexpected_eof());
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+fn route<E: KvsEngine>(engine: &E, req: &Request) -> std::result::Result<Response, Response> {
+    if req.path == "/health" {
+        return match req.method.as_str() {
+            "GET" => Ok(Response::json(200, json!({ "status": "ok" }))),
+            _ => Err(Response::method_not_allowed("GET")),
+        };
+    }
+    if req.path == "/keys" {
+        return match req.method.as_str() {
+            "GET" => list(engine, req),
+            _ => Err(Response::method_not_allowed("GET")),
+        };
+    }
+    let key = match req.path.strip_prefix("/keys/") {
+        Some(key) if !key.is_empty() => decode(key, false)?,
+        _ => return Err(Response::error(404, "Not found")),
+    };
+    let namespace = req
+        .param("namespace")?
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_owned());
+
+    match req.method.as_str() {
+        "GET" => match engine.get_in(&namespace, key.clone())? {
+            Some(value) => Ok(Response::json(200, json!({ "key": key, "value": value }))),
+            None => Err(KvsError::KeyNotFound.into()),
+        },
+        "PUT" => {
+            let body: PutBody = serde_json::from_slice(&req.body)
+                .map_err(|e| Response::error(400, format!("Invalid body: {}", e)))?;
+            engine.set_in(&namespace, key, body.value)?;
+            Ok(Response::no_content())
+        }
+        "DELETE" => {
+            engine.remove_in(&namespace, key)?;
+            Ok(Response::no_content())
+        }
+        _ => Err(Response::method_not_allowed("GET, PUT, DELETE")),
+    }
+}
+
+fn list<E: KvsEngine>(engine: &E, req: &Request) -> std::result::Result<Response, Response> {
+    let namespace = req
+        .param("namespace")?
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_owned());
+    let prefix = req.param("prefix")?.unwrap_or_default();
+    let after = req.param("after")?;
+    let limit = match req.param("limit")? {
+        None => DEFAULT_LIMIT,
+        Some(limit) => match limit.parse() {
+            Ok(limit) if limit > 0 && limit <= MAX_LIMIT => limit,
+            _ => {
+                return Err(Response::error(
+                    400,
+                    format!("limit must be between 1 and {}", MAX_LIMIT),
+                ))
+            }
+        },
+    };
+
+    let keys = engine.scan_in(&namespace, &prefix, after.as_deref(), limit)?;
+    let next = match keys.last() {
+        Some(last) if keys.len() == limit => Some(last.clone()),
+        _ => None,
+    };
+    Ok(Response::json(200, json!({ "keys": keys, "next": next })))
+}
+
+/// Percent-decodes a path segment, or a query component if `query` is set, in
+/// which `+` stands for a space.
+fn decode(s: &str, query: bool) -> std::result::Result<String, Response> {
+    let invalid = || Response::error(400, format!("Invalid percent-encoding in '{}'", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    iter.next().ok_or_else(invalid)?,
+                    iter.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
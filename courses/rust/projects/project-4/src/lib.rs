@@ -2,15 +2,51 @@
 
 
 pub mod error;
+pub mod auth;
 pub mod common;
 pub mod thread_pool;
 pub mod engines;
 
 pub mod server;
 
+pub mod async_server;
+
 pub mod client;
 
+pub mod async_client;
+
+mod codec;
+
+pub mod protocol;
+
+pub mod resp;
+
+pub mod http;
+
+pub mod metrics;
+
+pub mod shutdown;
+
+pub mod net;
+
+pub mod tls;
+
+pub mod testkit;
+
+pub use async_client::{AsyncKvsClient, AsyncWatchEvents};
+pub use async_server::AsyncKvsServer;
+pub use auth::{Access, Authenticator, Credentials, Scope};
+pub use client::{KvsClient, Pipeline, WatchEvents};
 pub use error::{Result, KvsError};
+pub use http::HttpServer;
 
-pub use engines::{KvsEngine, KvStore, SledKvsEngine};
-pub use thread_pool::RayonThreadPool;
\ No newline at end of file
+pub use engines::{
+    BTreeEngine, Change, ChangeOp, EncryptionKey, EngineStats, Event, InMemoryEngine, KvsEngine,
+    KvStore, LsmEngine, LsmOptions, SledKvsEngine, WatchTarget, Watcher,
+};
+pub use net::{Address, ToAddrs};
+pub use resp::RespServer;
+pub use server::KvsServer;
+pub use shutdown::ShutdownHandle;
+pub use thread_pool::RayonThreadPool;
+pub use tls::{ClientTls, ServerTls};
\ No newline at end of file
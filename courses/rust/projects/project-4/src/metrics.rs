@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::Result;
+
+/// Upper bounds in seconds of the buckets of latency histograms.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+    1.0, 2.5,
+];
+
+type Labels = Vec<(&'static str, String)>;
+type Collector = Box<dyn Fn(&Registry) + Send + Sync>;
+
+/// A set of metrics rendered in the Prometheus text exposition format.
+///
+/// Metrics are registered by name and labels, and registering the same
+/// series again returns the existing one. Collectors registered with
+/// `add_collector` refresh metrics kept elsewhere, such as engine
+/// statistics, right before each rendering.
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+struct Family {
+    help: &'static str,
+    series: BTreeMap<Labels, Metric>,
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Returns the counter of the given name and labels, registering it if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is registered as another type of metric.
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Counter> {
+        match self.register(name, help, labels, || Metric::Counter(Arc::default())) {
+            Metric::Counter(counter) => counter,
+            metric => panic!("{} is registered as a {}", name, metric.type_name()),
+        }
+    }
+
+    /// Returns the gauge of the given name and labels, registering it if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is registered as another type of metric.
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Gauge> {
+        match self.register(name, help, labels, || Metric::Gauge(Arc::default())) {
+            Metric::Gauge(gauge) => gauge,
+            metric => panic!("{} is registered as a {}", name, metric.type_name()),
+        }
+    }
+
+    /// Returns the histogram of the given name and labels, registering it
+    /// with `LATENCY_BUCKETS` if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is registered as another type of metric.
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+    ) -> Arc<Histogram> {
+        let new = || Metric::Histogram(Arc::new(Histogram::new(LATENCY_BUCKETS)));
+        match self.register(name, help, labels, new) {
+            Metric::Histogram(histogram) => histogram,
+            metric => panic!("{} is registered as a {}", name, metric.type_name()),
+        }
+    }
+
+    /// Adds a function run before each rendering to update metrics.
+    pub fn add_collector<F>(&self, collector: F)
+    where
+        F: Fn(&Registry) + Send + Sync + 'static,
+    {
+        self.collectors.lock().unwrap().push(Box::new(collector));
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        for collector in self.collectors.lock().unwrap().iter() {
+            collector(self);
+        }
+
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let type_name = match family.series.values().next() {
+                Some(metric) => metric.type_name(),
+                None => continue,
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+            for (labels, metric) in &family.series {
+                match metric {
+                    Metric::Counter(counter) => {
+                        write_sample(&mut out, name, labels, None, counter.get())
+                    }
+                    Metric::Gauge(gauge) => write_sample(&mut out, name, labels, None, gauge.get()),
+                    Metric::Histogram(histogram) => histogram.render(&mut out, name, labels),
+                }
+            }
+        }
+        out
+    }
+
+    fn register<F>(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+        new: F,
+    ) -> Metric
+    where
+        F: FnOnce() -> Metric,
+    {
+        let labels = labels
+            .iter()
+            .map(|&(label, value)| (label, value.to_owned()))
+            .collect();
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            series: BTreeMap::new(),
+        });
+        family.series.entry(labels).or_insert_with(new).clone()
+    }
+}
+
+/// A value that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Sets the counter to a total kept elsewhere, which must never decrease.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts observations in buckets of increasing upper bounds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Debug)]
+struct HistogramData {
+    // observations per bucket, the last one being unbounded
+    buckets: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            data: Mutex::new(HistogramData {
+                buckets: vec![0; bounds.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        let mut data = self.data.lock().unwrap();
+        data.buckets[bucket] += 1;
+        data.sum += value;
+    }
+
+    /// Observes a duration in seconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// Returns the number of observations.
+    pub fn count(&self) -> u64 {
+        self.data.lock().unwrap().buckets.iter().sum()
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &Labels) {
+        let data = self.data.lock().unwrap();
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (i, count) in data.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            write_sample(out, &bucket_name, labels, Some(&le), cumulative);
+        }
+        write_sample(out, &format!("{}_sum", name), labels, None, data.sum);
+        write_sample(out, &format!("{}_count", name), labels, None, cumulative);
+    }
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &Labels,
+    le: Option<&str>,
+    value: impl std::fmt::Display,
+) {
+    out.push_str(name);
+    let le = le.map(|le| ("le", le));
+    let mut labels = labels
+        .iter()
+        .map(|(label, value)| (*label, value.as_str()))
+        .chain(le)
+        .peekable();
+    if labels.peek().is_some() {
+        out.push('{');
+        for (i, (label, value)) in labels.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", label, escape(value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics of `registry` over HTTP at `/metrics`.
+///
+/// The listener is bound before returning, and connections are answered one
+/// at a time on a background thread.
+pub fn serve<A: ToSocketAddrs>(registry: Arc<Registry>, addr: A) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on {}", listener.local_addr()?);
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream
+                    .map_err(Into::into)
+                    .and_then(|stream| respond(&registry, stream));
+                if let Err(e) = res {
+                    error!("Error on serving metrics: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(registry: &Registry, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed, but are read so the client sees a clean close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", registry.render())
+        }
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_owned()),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
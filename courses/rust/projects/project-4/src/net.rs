@@ -0,0 +1,318 @@
+//! The addresses servers listen on and clients connect to: TCP, or Unix
+//! domain sockets for clients on the same host.
+//!
+//! Unix socket addresses are written `unix:` followed by the path of the
+//! socket file, as in `unix:/run/kvs/kvs.sock`.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// The prefix of Unix socket addresses.
+const UNIX_PREFIX: &str = "unix:";
+
+/// The address of a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// A TCP address
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    /// Parses `IP:PORT` or `unix:PATH`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err("the path of the Unix socket is missing".to_owned()),
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Address::Tcp)
+                .map_err(|e| format!("{}: expected IP:PORT or unix:PATH", e)),
+        }
+    }
+}
+
+/// Values that resolve to the addresses of a server, like `ToSocketAddrs`
+/// with Unix sockets on top.
+///
+/// Strings of the form `unix:PATH` are Unix sockets, the others are resolved
+/// by `ToSocketAddrs`.
+pub trait ToAddrs {
+    /// Returns the addresses, to be tried in order.
+    fn to_addrs(&self) -> io::Result<Vec<Address>>;
+}
+
+impl ToAddrs for Address {
+    fn to_addrs(&self) -> io::Result<Vec<Address>> {
+        Ok(vec![self.clone()])
+    }
+}
+
+impl ToAddrs for SocketAddr {
+    fn to_addrs(&self) -> io::Result<Vec<Address>> {
+        Ok(vec![Address::Tcp(*self)])
+    }
+}
+
+impl ToAddrs for str {
+    fn to_addrs(&self) -> io::Result<Vec<Address>> {
+        if self.starts_with(UNIX_PREFIX) {
+            let addr = self
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            return Ok(vec![addr]);
+        }
+        Ok(self.to_socket_addrs()?.map(Address::Tcp).collect())
+    }
+}
+
+impl ToAddrs for String {
+    fn to_addrs(&self) -> io::Result<Vec<Address>> {
+        self.as_str().to_addrs()
+    }
+}
+
+impl<T: ToAddrs + ?Sized> ToAddrs for &T {
+    fn to_addrs(&self) -> io::Result<Vec<Address>> {
+        (**self).to_addrs()
+    }
+}
+
+/// Returns the error of an address that cannot be used.
+fn no_address() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no address to use")
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    )
+}
+
+/// A listening socket.
+///
+/// The file of a Unix socket is removed once the listener is dropped.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Listens on the first address of `addr` that can be bound.
+    ///
+    /// The file of a Unix socket is given the permissions `socket_mode`. A
+    /// stale file left by a server that did not shut down is replaced, but a
+    /// socket another server still accepts connections on is not.
+    pub(crate) fn bind<A: ToAddrs>(addr: A, socket_mode: u32) -> io::Result<Listener> {
+        let mut last_err = None;
+        for addr in addr.to_addrs()? {
+            let res = match addr {
+                Address::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+                Address::Unix(path) => Listener::bind_unix(path, socket_mode),
+            };
+            match res {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(no_address))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: PathBuf, socket_mode: u32) -> io::Result<Listener> {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another server", path.display()),
+                    ));
+                }
+                fs::remove_file(&path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = Listener::Unix(UnixListener::bind(&path)?, path.clone());
+        // dropping the listener removes the file if this fails
+        fs::set_permissions(&path, fs::Permissions::from_mode(socket_mode))?;
+        Ok(listener)
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_path: PathBuf, _socket_mode: u32) -> io::Result<Listener> {
+        Err(unix_unsupported())
+    }
+
+    /// Returns the address the listener is bound to.
+    pub(crate) fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+
+    /// Accepts a connection, returning it with the address of the peer.
+    ///
+    /// Unix socket peers are anonymous, so they are given the address of the
+    /// listener.
+    pub(crate) fn accept(&self) -> io::Result<(Socket, Address)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (tcp, addr) = listener.accept()?;
+                Ok((Socket::Tcp(tcp), Address::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                let (unix, _) = listener.accept()?;
+                Ok((Socket::Unix(unix), Address::Unix(path.clone())))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A connected socket.
+#[derive(Debug)]
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Connects to `addr`, waiting at most `timeout` for a TCP server to
+    /// accept the connection.
+    pub(crate) fn connect(addr: &Address, timeout: Duration) -> io::Result<Socket> {
+        match addr {
+            Address::Tcp(addr) => TcpStream::connect_timeout(addr, timeout).map(Socket::Tcp),
+            Address::Unix(path) => connect_unix(path),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(tcp) => tcp.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.try_clone().map(Socket::Unix),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.set_write_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(tcp) => tcp.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.shutdown(how),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> io::Result<Socket> {
+    UnixStream::connect(path).map(Socket::Unix)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &Path) -> io::Result<Socket> {
+    Err(unix_unsupported())
+}
+
+impl Read for &Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(tcp) => (&*tcp).read(buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => (&*unix).read(buf),
+        }
+    }
+}
+
+impl Write for &Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(tcp) => (&*tcp).write(buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => (&*unix).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(tcp) => (&*tcp).flush(),
+            #[cfg(unix)]
+            Socket::Unix(unix) => (&*unix).flush(),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -0,0 +1,394 @@
+//! The framed binary protocol spoken between clients and servers.
+//!
+//! A connection opens with a handshake: the client sends `MAGIC`, the highest
+//! protocol version it speaks as a big-endian `u16` and the features it
+//! wants as a big-endian `u32`. The server answers with `MAGIC`, the version
+//! both sides speak and the features they share, or with version `0` before
+//! closing the connection if it speaks none of the client's versions.
+//!
+//! Messages are then exchanged in frames: a big-endian `u32` length, a kind
+//! byte and a payload of `length - 1` bytes. A `MESSAGE` frame holds a request
+//! or a response in the compact encoding of `encode`. An `ERROR` frame holds
+//! a big-endian `u16` `ErrorCode` and a UTF-8 message, and reports a frame the
+//! peer could not process. As frames are delimited, a malformed one does not
+//! break the frames after it.
+//!
+//! Once the `REQUEST_IDS` feature is negotiated, a client may also send its
+//! requests in `TAGGED_MESSAGE` frames, whose payload starts with a
+//! big-endian `u64` ID of its choosing. The server runs tagged requests
+//! concurrently and answers each in a `TAGGED_MESSAGE` or `TAGGED_ERROR`
+//! frame carrying its ID, in the order they complete; the events of a tagged
+//! watch carry its ID too. Errors about frames whose ID could not be read are
+//! sent untagged.
+//!
+//! Servers still accept the JSON protocol, a bare stream of JSON values, from
+//! clients that do not start with the handshake.
+
+mod encoding;
+
+use std::fmt;
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{KvsError, Result};
+
+/// Bytes opening a handshake.
+///
+/// A JSON value never starts with them, which lets servers tell the two
+/// protocols apart.
+pub const MAGIC: [u8; 4] = *b"KVSB";
+
+/// The highest protocol version this crate speaks.
+pub const VERSION: u16 = 1;
+
+/// The largest frame accepted, kind byte included.
+pub const MAX_FRAME_LEN: u32 = 64 << 20;
+
+/// Kind of the frames holding a message.
+pub const MESSAGE: u8 = 0;
+
+/// Kind of the frames reporting an error.
+pub const ERROR: u8 = 1;
+
+/// Kind of the frames holding a message tagged with a request ID.
+pub const TAGGED_MESSAGE: u8 = 2;
+
+/// Kind of the frames reporting an error tagged with a request ID.
+pub const TAGGED_ERROR: u8 = 3;
+
+/// Bytes of the request ID of tagged frames.
+const ID_LEN: usize = 8;
+
+pub(crate) const HANDSHAKE_LEN: usize = 10;
+
+/// The protocol spoken on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// A bare stream of JSON values, spoken by clients predating the handshake
+    Json,
+    /// Length-prefixed frames of binary messages
+    Binary,
+}
+
+/// Optional protocol features, negotiated by the handshake.
+///
+/// Features only known to one side are dropped from the negotiated set, so
+/// they can be added without a new protocol version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Requests may be tagged with IDs and answered out of order.
+    pub const REQUEST_IDS: Features = Features(1);
+
+    /// The features this crate supports.
+    pub const SUPPORTED: Features = Features::REQUEST_IDS;
+
+    /// Returns the set of features encoded by `bits`.
+    pub const fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    /// Returns the bits encoding the set on the wire.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every feature of `other` is in the set.
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the features in both sets.
+    pub const fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+/// The reason sent in an `ERROR` frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame could not be decoded; the frames after it are still read
+    Malformed,
+    /// The frame is larger than `MAX_FRAME_LEN`; the connection is closed
+    FrameTooLarge,
+    /// The peer speaks none of our protocol versions
+    UnsupportedVersion,
+    /// The server has too many connections; the connection is closed
+    ServerBusy,
+    /// The request requires the client to authenticate first
+    Unauthenticated,
+    /// The client is not allowed to send the request
+    Forbidden,
+    /// A code unknown to this version
+    Unknown(u16),
+}
+
+impl ErrorCode {
+    /// Returns the code sent on the wire.
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::Malformed => 1,
+            ErrorCode::FrameTooLarge => 2,
+            ErrorCode::UnsupportedVersion => 3,
+            ErrorCode::ServerBusy => 4,
+            ErrorCode::Unauthenticated => 5,
+            ErrorCode::Forbidden => 6,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+
+    /// Returns the error reported by an `ERROR` frame with this code.
+    ///
+    /// Refused requests have errors of their own, the other codes are
+    /// protocol errors.
+    pub(crate) fn into_error(self, msg: String) -> KvsError {
+        match self {
+            ErrorCode::Unauthenticated => KvsError::Unauthenticated(msg),
+            ErrorCode::Forbidden => KvsError::PermissionDenied(msg),
+            code => KvsError::Protocol(code, msg),
+        }
+    }
+
+    /// Returns the error with the code `code`.
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::Malformed,
+            2 => ErrorCode::FrameTooLarge,
+            3 => ErrorCode::UnsupportedVersion,
+            4 => ErrorCode::ServerBusy,
+            5 => ErrorCode::Unauthenticated,
+            6 => ErrorCode::Forbidden,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::Malformed => write!(f, "malformed frame"),
+            ErrorCode::FrameTooLarge => write!(f, "frame too large"),
+            ErrorCode::UnsupportedVersion => write!(f, "unsupported version"),
+            ErrorCode::ServerBusy => write!(f, "server busy"),
+            ErrorCode::Unauthenticated => write!(f, "unauthenticated"),
+            ErrorCode::Forbidden => write!(f, "forbidden"),
+            ErrorCode::Unknown(code) => write!(f, "error {}", code),
+        }
+    }
+}
+
+/// Encodes a message in the compact binary encoding of frames.
+pub fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    encoding::to_vec(value).map_err(|e| KvsError::Protocol(ErrorCode::Malformed, e.to_string()))
+}
+
+/// Decodes a message from the payload of a frame.
+pub fn decode<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T> {
+    encoding::from_slice(payload)
+        .map_err(|e| KvsError::Protocol(ErrorCode::Malformed, e.to_string()))
+}
+
+/// The versions and features a side of a connection speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Handshake {
+    pub(crate) version: u16,
+    pub(crate) features: Features,
+}
+
+impl Handshake {
+    /// The handshake sent by clients of this crate.
+    pub(crate) fn ours() -> Self {
+        Handshake {
+            version: VERSION,
+            features: Features::SUPPORTED,
+        }
+    }
+
+    /// Returns the answer of a server to the handshake of a client.
+    pub(crate) fn negotiate(self) -> Self {
+        Handshake {
+            version: self.version.min(VERSION),
+            features: self.features.intersection(Features::SUPPORTED),
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_be_bytes());
+        bytes[6..].copy_from_slice(&self.features.bits().to_be_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; HANDSHAKE_LEN]) -> Result<Self> {
+        if bytes[..4] != MAGIC {
+            return Err(KvsError::Protocol(
+                ErrorCode::Malformed,
+                "Invalid handshake".to_owned(),
+            ));
+        }
+        Ok(Handshake {
+            version: u16::from_be_bytes([bytes[4], bytes[5]]),
+            features: Features::from_bits(u32::from_be_bytes([
+                bytes[6], bytes[7], bytes[8], bytes[9],
+            ])),
+        })
+    }
+}
+
+/// A frame read from a connection.
+pub(crate) enum Frame {
+    Message(Vec<u8>),
+    Error(ErrorCode, String),
+}
+
+impl Frame {
+    /// Returns the message held by the frame, failing on error frames.
+    pub(crate) fn into_message(self) -> Result<Vec<u8>> {
+        match self {
+            Frame::Message(payload) => Ok(payload),
+            Frame::Error(code, msg) => Err(code.into_error(msg)),
+        }
+    }
+}
+
+/// Encodes a message frame, length prefix included, tagged with `id` if
+/// there is one.
+pub(crate) fn message_frame<T: Serialize + ?Sized>(id: Option<u64>, value: &T) -> Result<Vec<u8>> {
+    let payload = encode(value)?;
+    let mut frame = frame_header(MESSAGE, id, payload.len())?;
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Encodes an error frame, length prefix included, tagged with `id` if there
+/// is one.
+pub(crate) fn error_frame(id: Option<u64>, code: ErrorCode, msg: &str) -> Vec<u8> {
+    let mut frame =
+        frame_header(ERROR, id, 2 + msg.len()).expect("error messages are shorter than a frame");
+    frame.extend_from_slice(&code.code().to_be_bytes());
+    frame.extend_from_slice(msg.as_bytes());
+    frame
+}
+
+/// Encodes the length prefix, kind byte and ID of a frame of kind `MESSAGE`
+/// or `ERROR`, or their tagged kinds if there is an ID.
+fn frame_header(kind: u8, id: Option<u64>, payload_len: usize) -> Result<Vec<u8>> {
+    let payload_len = payload_len + id.map_or(0, |_| ID_LEN);
+    let len = u32::try_from(payload_len + 1)
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            KvsError::Protocol(
+                ErrorCode::FrameTooLarge,
+                format!("A message of {} bytes does not fit in a frame", payload_len),
+            )
+        })?;
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend_from_slice(&len.to_be_bytes());
+    match id {
+        Some(id) => {
+            frame.push(kind + TAGGED_MESSAGE);
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        None => frame.push(kind),
+    }
+    Ok(frame)
+}
+
+/// Checks the length prefix of a frame.
+fn frame_len(prefix: [u8; 4]) -> Result<usize> {
+    match u32::from_be_bytes(prefix) {
+        0 => Err(KvsError::Protocol(
+            ErrorCode::Malformed,
+            "Empty frame".to_owned(),
+        )),
+        len if len > MAX_FRAME_LEN => Err(KvsError::Protocol(
+            ErrorCode::FrameTooLarge,
+            format!("Frame of {} bytes exceeds {} bytes", len, MAX_FRAME_LEN),
+        )),
+        len => Ok(len as usize),
+    }
+}
+
+/// Checks the whole body of a frame was read before the stream ended.
+///
+/// Bodies are read as they arrive rather than into a buffer of the length
+/// announced, so a peer announcing large frames without sending them holds
+/// no memory.
+fn check_body(body: &[u8], len: usize) -> Result<()> {
+    if body.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Parses the kind byte, ID and payload of a frame, returning the frame with
+/// its ID if it is tagged.
+///
+/// Frames of an unknown kind are reported as malformed, after they have been
+/// read entirely.
+fn parse_frame(mut body: Vec<u8>) -> Result<(Option<u64>, Frame)> {
+    let (id, kind, start) = match body[0] {
+        kind @ (TAGGED_MESSAGE | TAGGED_ERROR) if body.len() > ID_LEN => {
+            let mut id = [0; ID_LEN];
+            id.copy_from_slice(&body[1..=ID_LEN]);
+            (Some(u64::from_be_bytes(id)), kind - TAGGED_MESSAGE, 1 + ID_LEN)
+        }
+        TAGGED_MESSAGE | TAGGED_ERROR => {
+            return Err(KvsError::Protocol(
+                ErrorCode::Malformed,
+                "Tagged frame without an ID".to_owned(),
+            ))
+        }
+        kind => (None, kind, 1),
+    };
+    match kind {
+        MESSAGE => Ok((id, Frame::Message(body.split_off(start)))),
+        ERROR if body.len() >= start + 2 => {
+            let code = ErrorCode::from_code(u16::from_be_bytes([body[start], body[start + 1]]));
+            let msg = String::from_utf8_lossy(&body[start + 2..]).into_owned();
+            Ok((id, Frame::Error(code, msg)))
+        }
+        _ => Err(KvsError::Protocol(
+            ErrorCode::Malformed,
+            format!("Invalid frame of kind {}", body[0]),
+        )),
+    }
+}
+
+/// Reads a frame with its ID if it is tagged, or returns `None` if the stream
+/// ends between frames.
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(Option<u64>, Frame)>> {
+    let mut prefix = [0; 4];
+    match reader.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = frame_len(prefix)?;
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body)?;
+    check_body(&body, len)?;
+    parse_frame(body).map(Some)
+}
+
+/// Reads a frame from an async reader, like `read_frame`.
+pub(crate) async fn read_frame_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<(Option<u64>, Frame)>> {
+    let mut prefix = [0; 4];
+    match reader.read_exact(&mut prefix).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = frame_len(prefix)?;
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body).await?;
+    check_body(&body, len)?;
+    parse_frame(body).map(Some)
+}
@@ -0,0 +1,413 @@
+//! A RESP2 front end, letting Redis clients talk to any `KvsEngine`.
+//!
+//! Commands are read as arrays of bulk strings, or as inline commands split on
+//! whitespace as typed in a terminal. The commands below are served on the
+//! default namespace; any other is answered with an error.
+//!
+//! | Command                                  | Reply                           |
+//! |------------------------------------------|---------------------------------|
+//! | `PING [message]`                         | `PONG`, or the message          |
+//! | `GET key`                                | the value, or a null bulk string |
+//! | `SET key value`                          | `OK`                            |
+//! | `MGET key [key ...]`                     | the values, or null bulk strings |
+//! | `MSET key value [key value ...]`         | `OK`                            |
+//! | `DEL key [key ...]`                      | the number of keys removed      |
+//! | `EXISTS key [key ...]`                   | the number of keys found        |
+//! | `SCAN cursor [MATCH pattern] [COUNT n]`  | the next cursor and a page of keys |
+//! | `DBSIZE`                                 | the number of keys              |
+//! | `INFO [section]`                         | the version and key count       |
+//! | `QUIT`                                   | `OK`, then closes the connection |
+//!
+//! A `SCAN` cursor is the number of keys visited so far, so it stays valid
+//! across connections but may skip or repeat keys written during the scan.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use log::{debug, error};
+
+use crate::engines::KvsEngine;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+/// The largest bulk string accepted in a command.
+const MAX_BULK_LEN: usize = 64 << 20;
+/// The largest number of arguments accepted in a command.
+const MAX_ARGS: usize = 1 << 20;
+/// Keys visited by a `SCAN` without `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Keys listed at once while counting them.
+const COUNT_PAGE: usize = 1024;
+
+/// A server speaking the Redis protocol.
+pub struct RespServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool> RespServer<E, P> {
+    /// Creates a `RespServer` serving the given engine.
+    pub fn new(engine: E, pool: P) -> Self {
+        RespServer { engine, pool }
+    }
+
+    /// Serves the connections to `addr`, each on a thread of the pool.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let engine = self.engine.clone();
+            self.pool.spawn(move || match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve(engine, stream) {
+                        error!("Error on serving RESP client: {}", e);
+                    }
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            })
+        }
+        Ok(())
+    }
+}
+
+/// A reply to a command.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            // a line break would end the error early
+            Reply::Error(msg) => write!(writer, "-{}\r\n", msg.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write_to(writer))
+            }
+        }
+    }
+}
+
+impl From<KvsError> for Reply {
+    fn from(e: KvsError) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            // the rest of the stream cannot be parsed, so it is closed
+            Err(KvsError::StringError(msg)) => {
+                Reply::Error(format!("ERR Protocol error: {}", msg)).write_to(&mut writer)?;
+                writer.flush()?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let args: std::result::Result<Vec<String>, _> =
+            args.into_iter().map(String::from_utf8).collect();
+        debug!("Receive RESP command from {}: {:?}", peer_addr, args);
+
+        let quit = matches!(&args, Ok(args) if args[0].eq_ignore_ascii_case("quit"));
+        let reply = match args {
+            Err(_) => Reply::Error("ERR keys and values must be valid UTF-8".to_owned()),
+            Ok(_) if quit => Reply::Simple("OK"),
+            Ok(args) => execute(&engine, &args).unwrap_or_else(Reply::from),
+        };
+        reply.write_to(&mut writer)?;
+        // replies to pipelined commands are sent together
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the arguments of the next command, or returns `None` if the stream
+/// ends between commands.
+///
+/// Malformed commands are reported as `KvsError::StringError`.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        let args = line.split_whitespace().map(|arg| arg.as_bytes().to_vec());
+        return Ok(Some(args.collect()));
+    }
+    // a null array holds no command
+    if line == "*-1" {
+        return Ok(Some(Vec::new()));
+    }
+
+    let len = parse_len(&line[1..], MAX_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(len.min(64));
+    for _ in 0..len {
+        let header = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if !header.starts_with('$') {
+            return Err(KvsError::StringError(format!(
+                "expected '$', got '{}'",
+                header.chars().next().unwrap_or(' ')
+            )));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN, "bulk length")?;
+        let mut bulk = vec![0; len + 2];
+        reader.read_exact(&mut bulk)?;
+        if !bulk.ends_with(b"\r\n") {
+            return Err(KvsError::StringError(
+                "bulk string not terminated by CRLF".to_owned(),
+            ));
+        }
+        bulk.truncate(len);
+        args.push(bulk);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line break, or returns `None` at the end of the
+/// stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(unexpected_eof());
+    }
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(Some(line))
+}
+
+fn parse_len(s: &str, max: usize, what: &str) -> Result<usize> {
+    match s.parse::<usize>() {
+        Ok(len) if len <= max => Ok(len),
+        _ => Err(KvsError::StringError(format!("invalid {}", what))),
+    }
+}
+
+fn unexpected_eof() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+fn execute<E: KvsEngine>(engine: &E, args: &[String]) -> Result<Reply> {
+    let name = args[0].to_ascii_lowercase();
+    let args = &args[1..];
+    let wrong_arity = || {
+        Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )))
+    };
+
+    match name.as_str() {
+        "ping" => match args {
+            [] => Ok(Reply::Simple("PONG")),
+            [msg] => Ok(Reply::Bulk(Some(msg.clone()))),
+            _ => wrong_arity(),
+        },
+        "get" => match args {
+            [key] => Ok(Reply::Bulk(engine.get(key.clone())?)),
+            _ => wrong_arity(),
+        },
+        "set" => match args {
+            [key, value] => {
+                engine.set(key.clone(), value.clone())?;
+                Ok(Reply::Simple("OK"))
+            }
+            [_, _, ..] => Ok(Reply::Error("ERR SET options are not supported".to_owned())),
+            _ => wrong_arity(),
+        },
+        "mget" if !args.is_empty() => Ok(Reply::Array(
+            engine
+                .get_many(args.to_vec())?
+                .into_iter()
+                .map(|res| res.map(Reply::Bulk))
+                .collect::<Result<_>>()?,
+        )),
+        "mset" if !args.is_empty() && args.len().is_multiple_of(2) => {
+            let pairs = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            for res in engine.set_many(pairs)? {
+                res?;
+            }
+            Ok(Reply::Simple("OK"))
+        }
+        "del" if !args.is_empty() => {
+            let mut removed = 0;
+            for res in engine.remove_many(args.to_vec())? {
+                match res {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        "exists" if !args.is_empty() => {
+            let mut found = 0;
+            for res in engine.get_many(args.to_vec())? {
+                if res?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        "scan" => match args {
+            [cursor, options @ ..] => scan(engine, cursor, options),
+            _ => wrong_arity(),
+        },
+        "dbsize" => match args {
+            [] => Ok(Reply::Integer(count_keys(engine)? as i64)),
+            _ => wrong_arity(),
+        },
+        "info" => match args {
+            [] | [_] => Ok(Reply::Bulk(Some(format!(
+                "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\ndb0:keys={}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                count_keys(engine)?
+            )))),
+            _ => wrong_arity(),
+        },
+        "mget" | "mset" | "del" | "exists" => wrong_arity(),
+        _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+    }
+}
+
+fn scan<E: KvsEngine>(engine: &E, cursor: &str, options: &[String]) -> Result<Reply> {
+    let offset: usize = match cursor.parse() {
+        Ok(offset) => offset,
+        Err(_) => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_ascii_lowercase().as_str(), options.next()) {
+            ("match", Some(value)) => pattern = Some(value.as_str()),
+            ("count", Some(value)) => match value.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::Error("ERR value is out of range".to_owned())),
+            },
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    // only the keys sharing the literal start of the pattern are visited
+    let prefix = pattern.map_or("", literal_prefix);
+    let keys = engine.scan(prefix, None, offset.saturating_add(count))?;
+    let visited = keys.len().saturating_sub(offset);
+    let next = if visited < count { 0 } else { offset + count };
+    let page = keys
+        .into_iter()
+        .skip(offset)
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string())),
+        Reply::Array(page),
+    ]))
+}
+
+fn count_keys<E: KvsEngine>(engine: &E) -> Result<usize> {
+    let mut count = 0;
+    let mut after = None;
+    loop {
+        let keys = engine.scan("", after.as_deref(), COUNT_PAGE)?;
+        count += keys.len();
+        if keys.len() < COUNT_PAGE {
+            return Ok(count);
+        }
+        after = keys.into_iter().last();
+    }
+}
+
+/// Returns the start of a glob pattern before its first special character.
+fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches a key against a Redis glob pattern, supporting `*`, `?`, `[...]`
+/// classes with ranges and `^` negation, and `\` escapes.
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    match_from(&pattern, &key)
+}
+
+fn match_from(pattern: &[char], key: &[char]) -> bool {
+    let (p, k) = match (pattern.first(), key.first()) {
+        (None, _) => return key.is_empty(),
+        (Some('*'), _) => {
+            let rest = &pattern[1..];
+            return (0..=key.len()).any(|skip| match_from(rest, &key[skip..]));
+        }
+        (Some(_), None) => return false,
+        (Some(&p), Some(&k)) => (p, k),
+    };
+
+    match p {
+        '?' => match_from(&pattern[1..], &key[1..]),
+        '[' => match match_class(&pattern[1..], k) {
+            Some((true, len)) => match_from(&pattern[1 + len..], &key[1..]),
+            Some((false, _)) => false,
+            // an unclosed class is taken literally
+            None => k == '[' && match_from(&pattern[1..], &key[1..]),
+        },
+        '\\' if pattern.len() > 1 => pattern[1] == k && match_from(&pattern[2..], &key[1..]),
+        p => p == k && match_from(&pattern[1..], &key[1..]),
+    }
+}
+
+/// Matches a character against the class after a `[`, returning whether it
+/// matched and the length of the class including its `]`.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = class.first() == Some(&'^');
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    while i < class.len() {
+        match class[i] {
+            ']' => return Some((matched != negated, i + 1)),
+            '\\' if i + 1 < class.len() => {
+                matched |= class[i + 1] == c;
+                i += 2;
+            }
+            start if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' => {
+                let end = class[i + 2];
+                matched |= (start.min(end)..=start.max(end)).contains(&c);
+                i += 3;
+            }
+            other => {
+                matched |= other == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
@@ -1,89 +1,856 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 
-use std::io::{BufReader, BufWriter, Write};// when use flush and write, you must import this Write
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use crossbeam::channel::{self, Sender};
+use log::{debug, error, info, warn};
+use serde::Serialize;
 
-
-use log::{debug, error};
-use serde_json::Deserializer;
-
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::engines::KvsEngine;
-use crate::Result;
+use crate::codec::{MessageReader, MessageWriter, Transport};
+use crate::auth::{Authenticator, Session};
+use crate::common::{
+    AuthResponse, ChangesResponse, GetManyResponse, GetResponse, ListNamespacesResponse,
+    NamespaceResponse, RemoveManyResponse, RemoveResponse, Request, SetManyResponse, SetResponse,
+    WatchResponse,
+};
+use crate::engines::{KvsEngine, Watcher, DEFAULT_NAMESPACE};
+use crate::metrics::{Counter, Gauge, Histogram, Registry};
+use crate::net::{Address, Listener, ToAddrs};
+use crate::protocol::{ErrorCode, Protocol};
+use crate::shutdown::{Connections, ShutdownHandle, DEFAULT_SHUTDOWN_TIMEOUT};
+use crate::{KvsError, Result};
 use crate::thread_pool::ThreadPool;
+use crate::tls::{ServerTls, Stream};
+
+/// Connections served or waiting for a thread above which new ones are
+/// rejected by default.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Connections waiting for a thread above which new ones are rejected by
+/// default.
+pub const DEFAULT_ACCEPT_QUEUE: usize = 128;
+/// Rejected connections waiting to be told the server is busy.
+const REJECT_QUEUE: usize = 64;
+/// How long a rejected client may take to send its first bytes.
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bytes read from a rejected client before closing its connection.
+pub(crate) const REJECT_DRAIN_BYTES: u64 = 64 * 1024;
+/// The permissions of the file of a Unix socket by default: read and write
+/// for the owner and group.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+/// How long the rest of a request may take to arrive by default.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a response may take to be written by default.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a connection may wait for its next request by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long a watch waits for an event before checking its connection.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long checking the connection of a watch waits for its end.
+const WATCH_EOF_CHECK: Duration = Duration::from_millis(1);
 
 /// The server of a key value store.
+///
+/// Each connection is served on a thread of the pool. Requests tagged with
+/// IDs are run concurrently, each on its own thread, and answered as they
+/// complete, so a slow request does not hold up the others of its
+/// connection.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    pool: P,
+    pool: Arc<P>,
+    registry: Arc<Registry>,
+    metrics: Arc<ServerMetrics>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    max_connections: usize,
+    accept_queue: usize,
+    timeouts: Timeouts,
+    auth: Option<Arc<Authenticator>>,
+    tls: Option<ServerTls>,
+    socket_mode: u32,
+}
+
+/// The timeouts of the connections of a server, `None` waiting forever.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    pub(crate) read: Option<Duration>,
+    pub(crate) write: Option<Duration>,
+    pub(crate) idle: Option<Duration>,
 }
 
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            read: Some(DEFAULT_READ_TIMEOUT),
+            write: Some(DEFAULT_WRITE_TIMEOUT),
+            idle: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
     /// Create a `KvsServer` with a given storage engines.
     pub fn new(engine: E, pool: P) -> Self {
+        let registry = Arc::new(Registry::new());
+        let metrics = Arc::new(ServerMetrics::new(&registry));
+        register_engine_stats(&registry, engine.clone());
         KvsServer {
             engine,
-            pool,
+            pool: Arc::new(pool),
+            registry,
+            metrics,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            accept_queue: DEFAULT_ACCEPT_QUEUE,
+            timeouts: Timeouts::default(),
+            auth: None,
+            tls: None,
+            socket_mode: DEFAULT_SOCKET_MODE,
         }
     }
 
-    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    /// Requires the clients to authenticate with the credentials `auth`
+    /// accepts, and limits their requests to the scope granted.
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Serves the connections over TLS.
+    ///
+    /// With mutual TLS, a client presenting a certificate whose subject is
+    /// known to the authenticator is granted its scope without sending
+    /// credentials.
+    pub fn with_tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
 
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
+    /// Sets the permissions of the socket file when listening on a Unix
+    /// socket, which decide the local users allowed to connect.
+    pub fn with_socket_mode(mut self, mode: u32) -> Self {
+        self.socket_mode = mode;
+        self
+    }
+
+    /// Sets how long the rest of a request may take to arrive once it
+    /// started, after which the connection is closed. `None` waits forever.
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.read = timeout;
+        self
+    }
+
+    /// Sets how long a response may take to be written, after which the
+    /// connection is closed. `None` waits forever.
+    pub fn with_write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    /// Sets how long a connection may wait for its next request, after which
+    /// it is closed to free its thread. `None` waits forever.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.idle = timeout;
+        self
+    }
 
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
+    /// Sets the number of connections served or waiting for a thread above
+    /// which new ones are rejected.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets the number of connections waiting for a thread of the pool above
+    /// which new ones are rejected.
+    pub fn with_accept_queue(mut self, accept_queue: usize) -> Self {
+        self.accept_queue = accept_queue;
+        self
+    }
+
+    /// Sets how long a shutdown waits for the requests in flight, after which
+    /// the connections left are closed.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle stopping the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns the registry holding the metrics of the server and its engine.
+    pub fn registry(&self) -> Arc<Registry> {
+        Arc::clone(&self.registry)
+    }
+
+    /// Serves the connections to `addr`, each on a thread of the pool, until
+    /// the server is shut down through its `ShutdownHandle`.
+    ///
+    /// `addr` is a TCP address or `unix:` followed by the path of a Unix
+    /// socket. A socket file left by a server that did not shut down is
+    /// replaced, and the socket file is removed on shutdown.
+    ///
+    /// Once shut down, it waits for the connections to finish the requests in
+    /// flight and syncs the engine before returning.
+    pub fn run<A: ToAddrs>(self, addr: A) -> Result<()> {
+        let listener = Listener::bind(addr, self.socket_mode)?;
+        self.shutdown.listening(listener.local_addr()?);
+        let connections = Arc::new(Connections::default());
+        let rejections = spawn_rejecter()?;
+
+        while !self.shutdown.is_shutdown() {
+            let accepted = listener.accept();
+            // the connection waking the loop up on shutdown is dropped
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let (socket, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    self.metrics.connection_errors.inc();
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let stream = match &self.tls {
+                Some(tls) => match Stream::accept(socket, tls) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        self.metrics.connection_errors.inc();
+                        error!("Connection failed: {}", e);
+                        continue;
                     }
+                },
+                None => Stream::plain(socket),
+            };
+            if let Err(reason) = self.check_limits(&connections) {
+                self.metrics.rejected_connections.inc();
+                warn!("Rejected connection from {}: {}", peer_addr, reason);
+                // when even the rejecter is behind, the connection is just closed
+                let _ = rejections.try_send(stream);
+                continue;
+            }
+            let id = match connections.add(stream.socket()) {
+                Ok(id) => id,
+                Err(e) => {
+                    self.metrics.connection_errors.inc();
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            let pool = Arc::clone(&self.pool);
+            let metrics = Arc::clone(&self.metrics);
+            let connections = Arc::clone(&connections);
+            let timeouts = self.timeouts;
+            let session = Session::new(self.auth.clone());
+
+            metrics.queue_depth.inc();
+            self.pool.spawn(move || {
+                metrics.queue_depth.dec();
+                metrics.open_connections.inc();
+                let registration = Registration {
+                    metrics: Arc::clone(&metrics),
+                    connections,
+                    id,
+                };
+                if let Err(e) = serve(engine, pool, timeouts, session, stream, peer_addr, registration) {
+                    metrics.connection_errors.inc();
+                    error!("Error on serving client: {}", e);
                 }
-                Err(e) => error!("Connection failed: {}", e),
             })
         }
 
+        drop(listener);
+        drop(rejections);
+        info!("Shutting down, waiting for the requests in flight");
+        let left = connections.drain(self.shutdown_timeout);
+        if left > 0 {
+            warn!("Closed {} connections still busy after {:?}", left, self.shutdown_timeout);
+        }
+        self.engine.sync()
+    }
+
+    /// Checks that a new connection can be queued for the pool.
+    fn check_limits(&self, connections: &Connections) -> std::result::Result<(), &'static str> {
+        if connections.len() >= self.max_connections {
+            return Err("too many connections");
+        }
+        if self.metrics.queue_depth.get() >= self.accept_queue as i64 {
+            return Err("accept queue full");
+        }
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+/// Starts the thread telling rejected clients that the server is busy,
+/// returning the queue of connections to reject.
+fn spawn_rejecter() -> Result<Sender<Stream>> {
+    let (sender, receiver) = channel::bounded::<Stream>(REJECT_QUEUE);
+    thread::Builder::new()
+        .name("kvs-rejecter".to_owned())
+        .spawn(move || {
+            for stream in receiver {
+                if let Err(e) = reject(stream) {
+                    debug!("Error on rejecting client: {}", e);
+                }
+            }
+        })?;
+    Ok(sender)
+}
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        };};
+/// Answers a client with a "server busy" error in the protocol it speaks,
+/// then closes the connection.
+fn reject(stream: Stream) -> Result<()> {
+    let socket = stream.socket();
+    socket.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    socket.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    let mut transport =
+        Transport::accept(BufReader::new(stream.clone()), BufWriter::new(stream.clone()))?;
+    match transport.protocol() {
+        Protocol::Binary => transport.send_error(ErrorCode::ServerBusy, "Server busy")?,
+        // every response has an `Err` variant, serialized the same way
+        Protocol::Json => {
+            transport.send(&SetResponse::Err("Server busy".to_owned()))?;
+            transport.flush()?;
+        }
     }
+    drop(transport);
 
-    for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
+    // closing with unread requests would reset the connection and could
+    // discard the answer, so they are read first
+    socket.shutdown(Shutdown::Write)?;
+    io::copy(&mut stream.take(REJECT_DRAIN_BYTES), &mut io::sink())?;
+    Ok(())
+}
 
-        match req {
-            Request::Get { key } => {
-                send_resp!(match engine.get(key) {
-                Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e))
+/// The metrics the server updates while serving requests.
+pub(crate) struct ServerMetrics {
+    requests: HashMap<&'static str, RequestMetrics>,
+    pub(crate) connection_errors: Arc<Counter>,
+    pub(crate) open_connections: Arc<Gauge>,
+    // jobs spawned but not yet picked up by the thread pool
+    pub(crate) queue_depth: Arc<Gauge>,
+    pub(crate) rejected_connections: Arc<Counter>,
+    pub(crate) idle_connections_closed: Arc<Counter>,
+}
+
+pub(crate) struct RequestMetrics {
+    pub(crate) count: Arc<Counter>,
+    pub(crate) errors: Arc<Counter>,
+    pub(crate) latency: Arc<Histogram>,
+}
+
+impl ServerMetrics {
+    pub(crate) fn new(registry: &Registry) -> Self {
+        let requests = Request::NAMES
+            .iter()
+            .map(|&name| {
+                let labels = [("request", name)];
+                let metrics = RequestMetrics {
+                    count: registry.counter(
+                        "kvs_requests_total",
+                        "Number of requests received",
+                        &labels,
+                    ),
+                    errors: registry.counter(
+                        "kvs_request_errors_total",
+                        "Number of requests answered with an error",
+                        &labels,
+                    ),
+                    latency: registry.histogram(
+                        "kvs_request_duration_seconds",
+                        "Time taken to answer requests",
+                        &labels,
+                    ),
+                };
+                (name, metrics)
             })
+            .collect();
+
+        ServerMetrics {
+            requests,
+            connection_errors: registry.counter(
+                "kvs_connection_errors_total",
+                "Number of connections closed on an error",
+                &[],
+            ),
+            open_connections: registry.gauge(
+                "kvs_open_connections",
+                "Number of connections being served",
+                &[],
+            ),
+            queue_depth: registry.gauge(
+                "kvs_thread_pool_queue_depth",
+                "Number of jobs waiting for a thread",
+                &[],
+            ),
+            rejected_connections: registry.counter(
+                "kvs_rejected_connections_total",
+                "Number of connections rejected as the server was busy",
+                &[],
+            ),
+            idle_connections_closed: registry.counter(
+                "kvs_idle_connections_closed_total",
+                "Number of connections closed after waiting too long for a request",
+                &[],
+            ),
+        }
+    }
+
+    pub(crate) fn request(&self, req: &Request) -> &RequestMetrics {
+        &self.requests[req.name()]
+    }
+}
+
+/// Exports the statistics of the engine, which are read at each scrape.
+pub(crate) fn register_engine_stats<E: KvsEngine>(registry: &Registry, engine: E) {
+    // engines are `Send` but not always `Sync`
+    let engine = Mutex::new(engine);
+    registry.add_collector(move |registry| {
+        let stats = engine.lock().unwrap().stats();
+        if let Some(live_keys) = stats.live_keys {
+            registry
+                .gauge("kvs_engine_live_keys", "Number of keys in the engine", &[])
+                .set(live_keys as i64);
+        }
+        if let Some(disk_bytes) = stats.disk_bytes {
+            registry
+                .gauge("kvs_engine_disk_bytes", "Size of the engine files", &[])
+                .set(disk_bytes as i64);
+        }
+        if let Some(compactions) = stats.compactions {
+            registry
+                .counter("kvs_engine_compactions_total", "Number of compactions run", &[])
+                .set(compactions);
+        }
+    });
+}
+
+/// The response to any request but `Watch`, serialized as the inner response.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Response {
+    Get(GetResponse),
+    Set(SetResponse),
+    Remove(RemoveResponse),
+    Namespace(NamespaceResponse),
+    ListNamespaces(ListNamespacesResponse),
+    Changes(ChangesResponse),
+    Watch(WatchResponse),
+    Auth(AuthResponse),
+    GetMany(GetManyResponse),
+    SetMany(SetManyResponse),
+    RemoveMany(RemoveManyResponse),
+}
+
+impl Response {
+    /// Returns true if the response reports a failed request.
+    pub(crate) fn is_err(&self) -> bool {
+        matches!(
+            self,
+            Response::Get(GetResponse::Err(_))
+                | Response::Set(SetResponse::Err(_))
+                | Response::Remove(RemoveResponse::Err(_))
+                | Response::Namespace(NamespaceResponse::Err(_))
+                | Response::ListNamespaces(ListNamespacesResponse::Err(_))
+                | Response::Changes(ChangesResponse::Err(_))
+                | Response::Watch(WatchResponse::Err(_))
+                | Response::Auth(AuthResponse::Err(_))
+                | Response::GetMany(GetManyResponse::Err(_))
+                | Response::SetMany(SetManyResponse::Err(_))
+                | Response::RemoveMany(RemoveManyResponse::Err(_))
+        )
+    }
+
+    /// Returns the failed response to a request refused by the session of a
+    /// connection speaking JSON, which has no error frames.
+    pub(crate) fn refusal(req: &Request, code: ErrorCode, msg: String) -> Self {
+        let msg = format!("{}", code.into_error(msg));
+        match req {
+            Request::Get { .. } => Response::Get(GetResponse::Err(msg)),
+            Request::Set { .. } => Response::Set(SetResponse::Err(msg)),
+            Request::Remove { .. } => Response::Remove(RemoveResponse::Err(msg)),
+            Request::CreateNamespace { .. } | Request::DropNamespace { .. } => {
+                Response::Namespace(NamespaceResponse::Err(msg))
             }
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
-            }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
+            Request::ListNamespaces => Response::ListNamespaces(ListNamespacesResponse::Err(msg)),
+            Request::ChangesSince { .. } => Response::Changes(ChangesResponse::Err(msg)),
+            Request::Watch { .. } => Response::Watch(WatchResponse::Err(msg)),
+            Request::Authenticate(_) => Response::Auth(AuthResponse::Err(msg)),
+            Request::GetMany { .. } => Response::GetMany(GetManyResponse::Err(msg)),
+            Request::SetMany { .. } => Response::SetMany(SetManyResponse::Err(msg)),
+            Request::RemoveMany { .. } => Response::RemoveMany(RemoveManyResponse::Err(msg)),
+        }
+    }
+}
+
+/// Runs a request on the engine.
+///
+/// # Panics
+///
+/// Panics on `Request::Watch`, which takes over the connection, and on
+/// `Request::Authenticate`, which changes its session. Both are served by the
+/// caller.
+pub(crate) fn handle<E: KvsEngine>(engine: &E, req: Request) -> Response {
+    match req {
+        Request::Get { namespace, key } => {
+            Response::Get(get_response(engine.get_in(namespace_or_default(&namespace), key)))
+        }
+        Request::Set { namespace, key, value } => {
+            let res = engine.set_in(namespace_or_default(&namespace), key, value);
+            Response::Set(set_response(res))
+        }
+        Request::Remove { namespace, key } => {
+            let res = engine.remove_in(namespace_or_default(&namespace), key);
+            Response::Remove(remove_response(res))
+        }
+        Request::GetMany { namespace, keys } => {
+            Response::GetMany(match engine.get_many_in(namespace_or_default(&namespace), keys) {
+                Ok(results) => GetManyResponse::Ok(results.into_iter().map(get_response).collect()),
+                Err(e) => GetManyResponse::Err(format!("{}", e)),
             })
         }
+        Request::SetMany { namespace, pairs } => {
+            Response::SetMany(match engine.set_many_in(namespace_or_default(&namespace), pairs) {
+                Ok(results) => SetManyResponse::Ok(results.into_iter().map(set_response).collect()),
+                Err(e) => SetManyResponse::Err(format!("{}", e)),
+            })
+        }
+        Request::RemoveMany { namespace, keys } => {
+            let res = engine.remove_many_in(namespace_or_default(&namespace), keys);
+            Response::RemoveMany(match res {
+                Ok(results) => {
+                    RemoveManyResponse::Ok(results.into_iter().map(remove_response).collect())
+                }
+                Err(e) => RemoveManyResponse::Err(format!("{}", e)),
+            })
+        }
+        Request::CreateNamespace { name } => Response::Namespace(match engine.create_namespace(&name) {
+            Ok(_) => NamespaceResponse::Ok(()),
+            Err(e) => NamespaceResponse::Err(format!("{}", e)),
+        }),
+        Request::DropNamespace { name } => Response::Namespace(match engine.drop_namespace(&name) {
+            Ok(_) => NamespaceResponse::Ok(()),
+            Err(e) => NamespaceResponse::Err(format!("{}", e)),
+        }),
+        Request::ListNamespaces => Response::ListNamespaces(match engine.list_namespaces() {
+            Ok(names) => ListNamespacesResponse::Ok(names),
+            Err(e) => ListNamespacesResponse::Err(format!("{}", e)),
+        }),
+        Request::ChangesSince { seq, limit } => Response::Changes(match engine.changes_since(seq, limit) {
+            Ok(changes) => ChangesResponse::Ok(changes),
+            Err(KvsError::ResyncRequired(compacted)) => ChangesResponse::ResyncRequired(compacted),
+            Err(e) => ChangesResponse::Err(format!("{}", e)),
+        }),
+        Request::Watch { .. } => panic!("Watch requests are served by the connection"),
+        Request::Authenticate(_) => panic!("Authenticate requests are served by the connection"),
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+fn get_response(res: Result<Option<String>>) -> GetResponse {
+    match res {
+        Ok(value) => GetResponse::Ok(value),
+        Err(e) => GetResponse::Err(format!("{}", e)),
+    }
+}
+
+fn set_response(res: Result<()>) -> SetResponse {
+    match res {
+        Ok(_) => SetResponse::Ok(()),
+        Err(e) => SetResponse::Err(format!("{}", e)),
+    }
+}
+
+fn remove_response(res: Result<()>) -> RemoveResponse {
+    match res {
+        Ok(_) => RemoveResponse::Ok(()),
+        Err(e) => RemoveResponse::Err(format!("{}", e)),
+    }
+}
+
+/// Keeps a connection counted as open until the last job serving it is done.
+struct Registration {
+    metrics: Arc<ServerMetrics>,
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.metrics.open_connections.dec();
+        self.connections.remove(self.id);
+    }
+}
+
+/// A connection, shared by the jobs serving its requests.
+struct Connection {
+    stream: Stream,
+    writer: Mutex<MessageWriter<BufWriter<Stream>>>,
+    protocol: Protocol,
+    peer_addr: Address,
+    timeouts: Timeouts,
+    metrics: Arc<ServerMetrics>,
+    // tagged requests being served
+    in_flight: AtomicUsize,
+    _registration: Registration,
+}
+
+impl Connection {
+    /// Sends a response, tagged with `id` if the request was.
+    fn send<T: Serialize + std::fmt::Debug>(&self, id: Option<u64>, resp: &T) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.send_tagged(id, resp)?;
+        writer.flush()?;
+        debug!("Response sent to {}: {:?}", self.peer_addr, resp);
+        Ok(())
+    }
+
+    fn send_error(&self, id: Option<u64>, code: ErrorCode, msg: &str) -> Result<()> {
+        self.writer.lock().unwrap().send_error_tagged(id, code, msg)
+    }
+}
+
+fn serve<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    pool: Arc<P>,
+    timeouts: Timeouts,
+    mut session: Session,
+    stream: Stream,
+    peer_addr: Address,
+    registration: Registration,
+) -> Result<()> {
+    let metrics = Arc::clone(&registration.metrics);
+    let socket = stream.socket();
+    socket.set_write_timeout(timeouts.write)?;
+    // a client not even sending its handshake is idle too
+    socket.set_read_timeout(timeouts.idle)?;
+    let reader = BufReader::new(stream.clone());
+    let transport = match Transport::accept(reader, BufWriter::new(stream.clone())) {
+        Ok(transport) => transport,
+        Err(KvsError::Timeout) => {
+            metrics.idle_connections_closed.inc();
+            debug!("Closing idle connection from {}", peer_addr);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    debug!("Speaking {:?} with {}", transport.protocol(), peer_addr);
+    // the TLS handshake is done once the protocol is known
+    if let Some(identity) = stream.peer_identity() {
+        if session.authenticate_certificate(&identity) {
+            debug!("Authenticated {} by its certificate for {}", peer_addr, identity);
+        }
+    }
+
+    let protocol = transport.protocol();
+    let (reader, writer) = transport.into_split();
+    let conn = Arc::new(Connection {
+        stream,
+        writer: Mutex::new(writer),
+        protocol,
+        peer_addr,
+        timeouts,
+        metrics,
+        in_flight: AtomicUsize::new(0),
+        _registration: registration,
+    });
+    Reader {
+        engine,
+        pool,
+        session,
+        transport: reader,
+        conn,
+    }
+    .run()
+}
+
+/// Reads the requests of a connection, handed from job to job as tagged
+/// requests are served.
+struct Reader<E, P> {
+    engine: E,
+    pool: Arc<P>,
+    session: Session,
+    transport: MessageReader<BufReader<Stream>>,
+    conn: Arc<Connection>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Reader<E, P> {
+    /// Serves requests until the connection is closed or taken over by a
+    /// watch, or until a tagged request is read. The reading then goes on in
+    /// another job while this one serves the tagged request.
+    fn run(mut self) -> Result<()> {
+        let conn = Arc::clone(&self.conn);
+        let socket = conn.stream.socket();
+        let peer_addr = &conn.peer_addr;
+        let metrics = &conn.metrics;
+
+        loop {
+            socket.set_read_timeout(conn.timeouts.idle)?;
+            match self.transport.wait_for_message() {
+                Ok(true) => {}
+                Ok(false) => break,
+                // a connection waiting for its responses is not idle
+                Err(KvsError::Timeout) if conn.in_flight.load(Ordering::SeqCst) > 0 => continue,
+                Err(KvsError::Timeout) => {
+                    metrics.idle_connections_closed.inc();
+                    debug!("Closing idle connection from {}", peer_addr);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            socket.set_read_timeout(conn.timeouts.read)?;
+
+            let (id, req) = match self.transport.recv_tagged::<Request>() {
+                Ok(Some((id, req))) => (id, req),
+                Ok(None) => break,
+                Err(e) => (None, Err(e)),
+            };
+            let req = match req {
+                Ok(req) => req,
+                // the frames after a malformed one are still delimited
+                Err(KvsError::Protocol(ErrorCode::Malformed, msg)) => {
+                    conn.send_error(id, ErrorCode::Malformed, &msg)?;
+                    continue;
+                }
+                Err(KvsError::Protocol(code, msg)) => {
+                    conn.send_error(id, code, &msg)?;
+                    return Err(KvsError::Protocol(code, msg));
+                }
+                Err(e) => return Err(e),
+            };
+            debug!("Receive request from {}: {:?}", peer_addr, req);
+            let start = Instant::now();
+            let req_metrics = metrics.request(&req);
+            req_metrics.count.inc();
+
+            if let Err((code, msg)) = self.session.check(&req) {
+                req_metrics.errors.inc();
+                debug!("Refused request from {}: {}", peer_addr, msg);
+                match conn.protocol {
+                    Protocol::Binary => conn.send_error(id, code, &msg)?,
+                    Protocol::Json => conn.send(id, &Response::refusal(&req, code, msg))?,
+                }
+                req_metrics.latency.observe_duration(start.elapsed());
+                continue;
+            }
+
+            if let Request::Authenticate(credentials) = &req {
+                let resp = self.session.authenticate(credentials);
+                if let AuthResponse::Err(_) = resp {
+                    req_metrics.errors.inc();
+                    warn!("Failed authentication from {}", peer_addr);
+                }
+                conn.send(id, &resp)?;
+                req_metrics.latency.observe_duration(start.elapsed());
+                continue;
+            }
+
+            if let Request::Watch { namespace, target } = req {
+                let watcher = match self.engine.watch_in(namespace_or_default(&namespace), target) {
+                    Ok(watcher) => watcher,
+                    Err(e) => {
+                        req_metrics.errors.inc();
+                        conn.send(id, &WatchResponse::Err(format!("{}", e)))?;
+                        req_metrics.latency.observe_duration(start.elapsed());
+                        continue;
+                    }
+                };
+                conn.send(id, &WatchResponse::Ok(()))?;
+                // the latency of a watch is the time taken to subscribe
+                req_metrics.latency.observe_duration(start.elapsed());
+
+                // the connection only streams events from now on. That can
+                // last as long as the connection, so it gets its own thread
+                // instead of holding one of the pool; the connection limit
+                // bounds their number.
+                thread::Builder::new()
+                    .name("kvs-watch".to_owned())
+                    .spawn(move || {
+                        let metrics = Arc::clone(&self.conn.metrics);
+                        if let Err(e) = self.stream_events(id, watcher) {
+                            metrics.connection_errors.inc();
+                            error!("Error on serving client: {}", e);
+                        }
+                    })?;
+                return Ok(());
+            }
+
+            if id.is_some() {
+                // another job reads the next requests while this one runs
+                conn.in_flight.fetch_add(1, Ordering::SeqCst);
+                let engine = self.engine.clone();
+                self.spawn();
+                let resp = handle(&engine, req);
+                if resp.is_err() {
+                    req_metrics.errors.inc();
+                }
+                let res = conn.send(id, &resp);
+                req_metrics.latency.observe_duration(start.elapsed());
+                conn.in_flight.fetch_sub(1, Ordering::SeqCst);
+                return res;
+            }
+
+            let resp = handle(&self.engine, req);
+            if resp.is_err() {
+                req_metrics.errors.inc();
+            }
+            conn.send(id, &resp)?;
+            req_metrics.latency.observe_duration(start.elapsed());
+        }
+
+        Ok(())
+    }
+
+    /// Sends the events of a watcher until the client goes away, the server
+    /// shuts down or the watcher is disconnected.
+    ///
+    /// The client sends nothing once watching, so between events the socket
+    /// is only checked for its end, which a shutdown also brings about.
+    fn stream_events(mut self, id: Option<u64>, mut watcher: Watcher) -> Result<()> {
+        let conn = Arc::clone(&self.conn);
+        conn.stream.socket().set_read_timeout(Some(WATCH_EOF_CHECK))?;
+        loop {
+            match watcher.next_timeout(WATCH_POLL_INTERVAL) {
+                Ok(Some(Ok(event))) => conn.send(id, &WatchResponse::Event(event))?,
+                Ok(Some(Err(e))) => {
+                    return conn.send(id, &WatchResponse::Err(format!("{}", e)));
+                }
+                Ok(None) => return Ok(()),
+                Err(_) => match self.transport.wait_for_message() {
+                    Err(KvsError::Timeout) => {}
+                    Err(e) => return Err(e),
+                    // the client is gone, or sent a request it cannot get an
+                    // answer to
+                    Ok(_) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Goes on reading the requests in a new job of the pool.
+    fn spawn(self) {
+        let pool = Arc::clone(&self.pool);
+        let metrics = Arc::clone(&self.conn.metrics);
+        metrics.queue_depth.inc();
+        pool.spawn(move || {
+            metrics.queue_depth.dec();
+            if let Err(e) = self.run() {
+                metrics.connection_errors.inc();
+                error!("Error on serving client: {}", e);
+            }
+        });
+    }
+}
+
+pub(crate) fn namespace_or_default(namespace: &Option<String>) -> &str {
+    namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+}
@@ -0,0 +1,118 @@
+//! Stopping a server gracefully.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::net::{Address, Socket};
+use crate::Result;
+
+/// How long a shutdown waits for the requests in flight by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stops the server it was taken from, from any thread.
+///
+/// Once shut down, the server stops accepting connections, lets the requests
+/// in flight finish, closes the connections and syncs the engine before `run`
+/// returns.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<State>);
+
+#[derive(Default)]
+struct State {
+    requested: AtomicBool,
+    // the address the server listens on, once bound
+    addr: Mutex<Option<Address>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        ShutdownHandle(Arc::default())
+    }
+
+    /// Asks the server to shut down, without waiting for it.
+    pub fn shutdown(&self) {
+        if self.0.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // wake the accept loop up with a connection of our own
+        if let Some(mut addr) = self.0.addr.lock().unwrap().clone() {
+            if let Address::Tcp(addr) = &mut addr {
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+            }
+            let _ = Socket::connect(&addr, Duration::from_secs(1));
+        }
+    }
+
+    /// Returns true once `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records the address the server accepts connections on.
+    ///
+    /// The server checks `is_shutdown` afterwards, so a shutdown requested
+    /// before is not missed.
+    pub(crate) fn listening(&self, addr: Address) {
+        *self.0.addr.lock().unwrap() = Some(addr);
+    }
+}
+
+/// The connections accepted by a server and not closed yet, including those
+/// waiting for a thread of the pool.
+#[derive(Default)]
+pub(crate) struct Connections {
+    streams: Mutex<HashMap<u64, Socket>>,
+    closed: Condvar,
+    next_id: AtomicU64,
+}
+
+impl Connections {
+    /// Tracks a connection until `remove` is called with the returned id.
+    pub(crate) fn add(&self, stream: &Socket) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.streams.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(id)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.streams.lock().unwrap().remove(&id);
+        self.closed.notify_all();
+    }
+
+    /// Lets the connections finish the request they are serving and waits
+    /// for them to close, for at most `timeout`.
+    ///
+    /// The connections are closed for reading, so they see the end of the
+    /// stream once done with the requests already read. The ones still open
+    /// at the deadline are closed entirely. Returns the number of them.
+    pub(crate) fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut streams = self.streams.lock().unwrap();
+        for stream in streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            streams = self.closed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        for stream in streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        streams.len()
+    }
+}
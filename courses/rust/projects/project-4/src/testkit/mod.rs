@@ -0,0 +1,350 @@
+//! A conformance suite for `KvsEngine` implementations.
+//!
+//! Every check takes a factory opening the engine in a directory, so that it
+//! can drop the engine and open it again to check persistence. The checks
+//! panic when the engine misbehaves and propagate the errors it returns.
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use kvs::{KvStore, Result};
+//! # fn try_main() -> Result<()> {
+//! let dir = std::env::temp_dir().join("kvs-conformance");
+//! kvs::testkit::run_all(|path: &Path| KvStore::open(path), &dir)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `SimDisk` runs a `KvStore` or a `BTreeEngine` on a simulated disk, to test
+//! how they cope with failing writes and crashes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use crate::{KvsEngine, Result};
+
+mod sim;
+
+pub use self::sim::{Fault, SimDisk};
+
+type Check<F> = fn(&F, &Path) -> Result<()>;
+
+/// Runs every check, each in its own subdirectory of `dir`.
+pub fn run_all<E, F>(open: F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let checks: [(&str, Check<F>); 10] = [
+        ("get_stored_value", get_stored_value),
+        ("overwrite_value", overwrite_value),
+        ("get_non_existent_value", get_non_existent_value),
+        ("remove_non_existent_key", remove_non_existent_key),
+        ("remove_key", remove_key),
+        ("reopen_persistence", reopen_persistence),
+        ("scan_keys", scan_keys),
+        ("concurrent_set", concurrent_set),
+        ("concurrent_read_write", concurrent_read_write),
+        ("random_operations", random_operations),
+    ];
+    for (name, check) in checks.iter() {
+        let check_dir = dir.join(name);
+        fs::create_dir_all(&check_dir)?;
+        check(&open, &check_dir)?;
+    }
+    Ok(())
+}
+
+/// Should get previously stored values, also after reopening.
+pub fn get_stored_value<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Should overwrite existing values, also after reopening.
+pub fn overwrite_value<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let store = open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+/// Should get `None` for a key that was never set.
+pub fn get_non_existent_value<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = open(dir)?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// Should fail to remove a key that was never set.
+pub fn remove_non_existent_key<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+/// Should not get a removed key, also after reopening.
+pub fn remove_key<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    drop(store);
+    let store = open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+/// Should keep many overwritten and removed keys across several reopens.
+pub fn reopen_persistence<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    for round in 0..3 {
+        let store = open(dir)?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}-{}", round, key_id))?;
+        }
+        for key_id in (0..1000).step_by(3) {
+            store.remove(format!("key{}", key_id))?;
+        }
+        drop(store);
+
+        let store = open(dir)?;
+        for key_id in 0..1000 {
+            let expected = match key_id % 3 {
+                0 => None,
+                _ => Some(format!("{}-{}", round, key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+/// Should list the keys with a prefix in order and in pages, also after
+/// reopening.
+pub fn scan_keys<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    for key in &["a", "b1", "b3", "b2", "b20", "c"] {
+        store.set((*key).to_owned(), "value".to_owned())?;
+    }
+    store.remove("b3".to_owned())?;
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.scan("b", None, usize::MAX)?, ["b1", "b2", "b20"]);
+        assert_eq!(store.scan("b", None, 2)?, ["b1", "b2"]);
+        assert_eq!(store.scan("b", Some("b2"), 2)?, ["b20"]);
+        assert_eq!(store.scan("b", Some("a"), 1)?, ["b1"]);
+        assert!(store.scan("b", Some("b20"), 2)?.is_empty());
+        assert!(store.scan("d", None, 2)?.is_empty());
+        assert_eq!(store.scan("", None, usize::MAX)?.len(), 5);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&open(dir)?)
+}
+
+/// Should keep the writes of many threads.
+pub fn concurrent_set<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let store = open(dir)?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}-{}", thread_id, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("a writer thread panicked")?;
+    }
+
+    drop(store);
+    let store = open(dir)?;
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Readers running alongside a writer should never see a value go back in time.
+pub fn concurrent_read_write<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const KEYS: u64 = 20;
+    const VERSIONS: u64 = 50;
+
+    let store = open(dir)?;
+    for key_id in 0..KEYS {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let barrier = Arc::new(Barrier::new(5));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                let mut seen = vec![0; KEYS as usize];
+                barrier.wait();
+                while !done.load(Ordering::SeqCst) {
+                    for key_id in 0..KEYS {
+                        let value = store
+                            .get(format!("key{}", key_id))?
+                            .expect("a key disappeared while being overwritten");
+                        let version: u64 = value.parse().expect("a value was torn");
+                        assert!(
+                            version >= seen[key_id as usize],
+                            "a value went back in time"
+                        );
+                        seen[key_id as usize] = version;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    for version in 1..=VERSIONS {
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), version.to_string())?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().expect("a reader thread panicked")?;
+    }
+
+    for key_id in 0..KEYS {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(VERSIONS.to_string())
+        );
+    }
+    Ok(())
+}
+
+/// Random operations should agree with a `BTreeMap`, also across reopens.
+pub fn random_operations<E, F>(open: &F, dir: &Path) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let mut model = BTreeMap::new();
+    let mut store = open(dir)?;
+
+    for round in 0..4 {
+        for _ in 0..2000 {
+            let key = format!("key{}", rng.below(300));
+            match rng.below(10) {
+                0..=5 => {
+                    let value = "v".repeat(rng.below(200) as usize + 1);
+                    store.set(key.clone(), value.clone())?;
+                    model.insert(key, value);
+                }
+                6..=8 => {
+                    let expected = model.remove(&key);
+                    assert_eq!(
+                        store.remove(key.clone()).is_ok(),
+                        expected.is_some(),
+                        "removing {} disagrees with the model",
+                        key
+                    );
+                }
+                _ => assert_eq!(store.get(key.clone())?, model.get(&key).cloned()),
+            }
+        }
+        if round % 2 == 1 {
+            drop(store);
+            store = open(dir)?;
+        }
+        for key_id in 0..300 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key.clone())?, model.get(&key).cloned());
+        }
+    }
+    Ok(())
+}
+
+/// A small deterministic generator, so that failures can be reproduced.
+struct XorShift(u64);
+
+impl XorShift {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
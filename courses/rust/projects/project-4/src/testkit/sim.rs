@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::engines::encryption::Cipher;
+use crate::engines::storage::{RandomFile, ReadFile, Storage, WriteFile};
+use crate::{BTreeEngine, EncryptionKey, KvStore, Result};
+
+/// A fault injected into a write of a `SimDisk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The write fails without writing anything
+    Error,
+    /// Only half of the buffer is written, and the caller has to write the rest
+    ShortWrite,
+    /// Half of the buffer is written before the write fails
+    TornWrite,
+}
+
+/// An in-memory disk on which a `KvStore` or a `BTreeEngine` can fail and
+/// crash.
+///
+/// Written data only becomes durable once synced, or written back with
+/// `write_back`: `crash` reverts every file to its last durable contents and
+/// breaks the handles opened before. Creating, renaming and removing files
+/// are durable right away.
+#[derive(Clone, Default)]
+pub struct SimDisk {
+    state: Arc<Mutex<DiskState>>,
+}
+
+#[derive(Default)]
+struct DiskState {
+    files: HashMap<String, Arc<Mutex<SimFile>>>,
+    // handles opened before the last crash carry an older epoch
+    epoch: u64,
+    // number of writes and syncs so far
+    writes: u64,
+    fault: Option<(u64, Fault)>,
+}
+
+/// The durable part of a file only appended to is a prefix of its data, so
+/// a copy is only kept once the file is written in place or truncated.
+#[derive(Default)]
+struct SimFile {
+    data: Vec<u8>,
+    synced: usize,
+    // the durable contents, when they are not a prefix of `data`
+    durable: Option<Vec<u8>>,
+}
+
+impl SimFile {
+    /// Keeps a copy of the durable contents before they are changed.
+    fn preserve(&mut self, from: usize) {
+        if from < self.synced && self.durable.is_none() {
+            self.durable = Some(self.data[..self.synced].to_vec());
+        }
+    }
+
+    fn write_at(&mut self, pos: usize, buf: &[u8]) {
+        self.preserve(pos);
+        if self.data.len() < pos + buf.len() {
+            self.data.resize(pos + buf.len(), 0);
+        }
+        self.data[pos..pos + buf.len()].copy_from_slice(buf);
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.preserve(len);
+        self.data.resize(len, 0);
+    }
+
+    fn make_durable(&mut self) {
+        self.synced = self.data.len();
+        self.durable = None;
+    }
+
+    fn revert(&mut self) {
+        match self.durable.take() {
+            Some(durable) => self.data = durable,
+            None => self.data.truncate(self.synced),
+        }
+        self.synced = self.data.len();
+    }
+}
+
+impl SimDisk {
+    /// Creates an empty disk.
+    pub fn new() -> SimDisk {
+        SimDisk::default()
+    }
+
+    /// Injects a fault into the write numbered `n`, counted since the disk
+    /// was created.
+    ///
+    /// Syncs are counted as writes, and a fault makes a sync fail whatever
+    /// its kind. Only one fault is pending at a time.
+    pub fn inject(&self, n: u64, fault: Fault) {
+        self.state.lock().unwrap().fault = Some((n, fault));
+    }
+
+    /// Returns the number of writes and syncs so far.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().unwrap().writes
+    }
+
+    /// Loses everything that was not made durable.
+    ///
+    /// The handles opened so far fail from now on, so the store running on
+    /// the disk has to be dropped and opened again.
+    pub fn crash(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        for file in state.files.values() {
+            file.lock().unwrap().revert();
+        }
+    }
+
+    /// Makes everything written to the file `name` durable without syncing
+    /// it, as the OS may write dirty pages back at any time.
+    pub fn write_back(&self, name: &str) {
+        if let Some(file) = self.state.lock().unwrap().files.get(name) {
+            file.lock().unwrap().make_durable();
+        }
+    }
+
+    /// Returns the name and the current contents of every file.
+    pub fn files(&self) -> Vec<(String, Vec<u8>)> {
+        let state = self.state.lock().unwrap();
+        state
+            .files
+            .iter()
+            .map(|(name, file)| (name.clone(), file.lock().unwrap().data.clone()))
+            .collect()
+    }
+
+    /// Opens a `KvStore` on the disk.
+    pub fn open_kv_store(&self) -> Result<KvStore> {
+        KvStore::open_on(Arc::new(self.clone()), None)
+    }
+
+    /// Opens a `KvStore` on the disk, encrypting it with `key`.
+    pub fn open_kv_store_encrypted(&self, key: EncryptionKey) -> Result<KvStore> {
+        KvStore::open_on(Arc::new(self.clone()), Some(Arc::new(Cipher::new(&key))))
+    }
+
+    /// Opens a `BTreeEngine` on the disk.
+    pub fn open_btree_engine(&self) -> Result<BTreeEngine> {
+        BTreeEngine::open_on(Arc::new(self.clone()))
+    }
+
+    fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    fn check_epoch(&self, epoch: u64) -> io::Result<()> {
+        if self.state.lock().unwrap().epoch == epoch {
+            Ok(())
+        } else {
+            Err(io::Error::other("The disk crashed"))
+        }
+    }
+
+    /// Counts a write, returning the fault to inject into it.
+    fn begin_write(&self, epoch: u64) -> io::Result<Option<Fault>> {
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return Err(io::Error::other("The disk crashed"));
+        }
+        let n = state.writes;
+        state.writes += 1;
+        match state.fault {
+            Some((at, fault)) if at == n => {
+                state.fault = None;
+                Ok(Some(fault))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Storage for SimDisk {
+    fn create(&self, name: &str) -> io::Result<Box<dyn WriteFile>> {
+        let file = Arc::new(Mutex::new(SimFile::default()));
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(name.to_owned(), Arc::clone(&file));
+        Ok(Box::new(SimWriter {
+            disk: self.clone(),
+            file,
+            epoch: state.epoch,
+        }))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn ReadFile>> {
+        let file = self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(Box::new(SimReader {
+            disk: self.clone(),
+            file,
+            epoch: self.epoch(),
+            pos: 0,
+        }))
+    }
+
+    fn open_rw(&self, name: &str) -> io::Result<Box<dyn RandomFile>> {
+        let mut state = self.state.lock().unwrap();
+        let file = Arc::clone(state.files.entry(name.to_owned()).or_default());
+        Ok(Box::new(SimRandomFile {
+            disk: self.clone(),
+            file,
+            epoch: state.epoch,
+            pos: 0,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        match self.state.lock().unwrap().files.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.state.lock().unwrap().files.keys().cloned().collect())
+    }
+}
+
+struct SimWriter {
+    disk: SimDisk,
+    file: Arc<Mutex<SimFile>>,
+    epoch: u64,
+}
+
+impl Write for SimWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fault = self.disk.begin_write(self.epoch)?;
+        let mut file = self.file.lock().unwrap();
+        let end = file.data.len();
+        write_with_fault(&mut file, end, buf, fault)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.check_epoch(self.epoch)
+    }
+}
+
+impl Seek for SimWriter {
+    /// Writers only append, so only the end of the file can be sought.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.disk.check_epoch(self.epoch)?;
+        match pos {
+            SeekFrom::Current(0) | SeekFrom::End(0) => {
+                Ok(self.file.lock().unwrap().data.len() as u64)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Simulated files are append-only",
+            )),
+        }
+    }
+}
+
+impl WriteFile for SimWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        sync(&self.disk, self.epoch, &self.file)
+    }
+}
+
+struct SimReader {
+    disk: SimDisk,
+    file: Arc<Mutex<SimFile>>,
+    epoch: u64,
+    pos: u64,
+}
+
+impl Read for SimReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.disk.check_epoch(self.epoch)?;
+        Ok(read_at(&self.file, &mut self.pos, buf))
+    }
+}
+
+impl Seek for SimReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.disk.check_epoch(self.epoch)?;
+        seek(&self.file, &mut self.pos, pos)
+    }
+}
+
+/// A handle reading and writing anywhere in a file.
+struct SimRandomFile {
+    disk: SimDisk,
+    file: Arc<Mutex<SimFile>>,
+    epoch: u64,
+    pos: u64,
+}
+
+impl Read for SimRandomFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.disk.check_epoch(self.epoch)?;
+        Ok(read_at(&self.file, &mut self.pos, buf))
+    }
+}
+
+impl Write for SimRandomFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fault = self.disk.begin_write(self.epoch)?;
+        let mut file = self.file.lock().unwrap();
+        let written = write_with_fault(&mut file, self.pos as usize, buf, fault)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.check_epoch(self.epoch)
+    }
+}
+
+impl Seek for SimRandomFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.disk.check_epoch(self.epoch)?;
+        seek(&self.file, &mut self.pos, pos)
+    }
+}
+
+impl WriteFile for SimRandomFile {
+    fn sync(&mut self) -> io::Result<()> {
+        sync(&self.disk, self.epoch, &self.file)
+    }
+}
+
+impl RandomFile for SimRandomFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.disk.check_epoch(self.epoch)?;
+        self.file.lock().unwrap().set_len(len as usize);
+        Ok(())
+    }
+}
+
+/// Writes `buf` at `pos` as the fault dictates, returning the length written.
+fn write_with_fault(
+    file: &mut SimFile,
+    pos: usize,
+    buf: &[u8],
+    fault: Option<Fault>,
+) -> io::Result<usize> {
+    let half = buf.len().div_ceil(2);
+    match fault {
+        None => {
+            file.write_at(pos, buf);
+            Ok(buf.len())
+        }
+        Some(Fault::Error) => Err(injected()),
+        Some(Fault::ShortWrite) => {
+            file.write_at(pos, &buf[..half]);
+            Ok(half)
+        }
+        Some(Fault::TornWrite) => {
+            file.write_at(pos, &buf[..half]);
+            Err(injected())
+        }
+    }
+}
+
+fn sync(disk: &SimDisk, epoch: u64, file: &Mutex<SimFile>) -> io::Result<()> {
+    if disk.begin_write(epoch)?.is_some() {
+        return Err(injected());
+    }
+    file.lock().unwrap().make_durable();
+    Ok(())
+}
+
+fn read_at(file: &Mutex<SimFile>, pos: &mut u64, buf: &mut [u8]) -> usize {
+    let file = file.lock().unwrap();
+    let start = (*pos as usize).min(file.data.len());
+    let len = buf.len().min(file.data.len() - start);
+    buf[..len].copy_from_slice(&file.data[start..start + len]);
+    *pos += len as u64;
+    len
+}
+
+fn seek(file: &Mutex<SimFile>, cur: &mut u64, pos: SeekFrom) -> io::Result<u64> {
+    let len = file.lock().unwrap().data.len() as i64;
+    let pos = match pos {
+        SeekFrom::Start(pos) => pos as i64,
+        SeekFrom::Current(offset) => *cur as i64 + offset,
+        SeekFrom::End(offset) => len + offset,
+    };
+    if pos < 0 {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    *cur = pos as u64;
+    Ok(*cur)
+}
+
+fn injected() -> io::Error {
+    io::Error::other("Injected fault")
+}
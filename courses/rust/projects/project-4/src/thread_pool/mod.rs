@@ -1,10 +1,14 @@
+use std::sync::Arc;
+
 use crate::Result;
 
 mod naive;
 mod rayon;
 mod shared_queue;
 
+pub use naive::NaiveThreadPool;
 pub use rayon::RayonThreadPool;
+pub use shared_queue::SharedQueueThreadPool;
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool {
@@ -23,4 +27,16 @@ pub trait ThreadPool {
     /// reduce nor is the thread pool destroyed, corrupted or invalidated.
     fn spawn<F>(&self, job: F)
         where F: FnOnce() + Send + 'static;
-}
\ No newline at end of file
+}
+
+/// Lets several servers share one pool.
+impl<P: ThreadPool> ThreadPool for Arc<P> {
+    fn new(threads: u32) -> Result<Self> {
+        Ok(Arc::new(P::new(threads)?))
+    }
+
+    fn spawn<F>(&self, job: F)
+        where F: FnOnce() + Send + 'static {
+        (**self).spawn(job)
+    }
+}
@@ -6,7 +6,7 @@ use crate::thread_pool::ThreadPool;
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> crate::Result<Self> where Self: Sized {
+    fn new(_threads: u32) -> crate::Result<Self> where Self: Sized {
         Ok(NaiveThreadPool)
     }
 
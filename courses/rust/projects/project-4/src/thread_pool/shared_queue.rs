@@ -1,6 +1,6 @@
 use std::thread;
 
-use crossbeam::{channel, Receiver, RecvError, Sender};
+use crossbeam::{channel, Receiver, Sender};
 use log::{debug, error};
 
 use crate::thread_pool::ThreadPool;
@@ -63,7 +63,6 @@ impl Drop for TaskReceiver {
                 error!("Failed to spawn a thread: {}", e);
             }
         }
-        todo!()
     }
 }
 
@@ -75,6 +74,7 @@ fn run_tasks(task_receiver: TaskReceiver) {
             }
             Err(_) => {
                 debug!("Thread exists because the thread pool is destroyed.");
+                break;
             }
         }
     }
@@ -0,0 +1,348 @@
+//! Encrypting the connections between `KvsClient` and `KvsServer` with TLS.
+//!
+//! Certificates and keys are read from PEM files. With mutual TLS, the
+//! server also checks the certificate of the client, whose subject common
+//! name identifies it to the `Authenticator`.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, TryLockError};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+use crate::net::{Address, Socket};
+use crate::{KvsError, Result};
+
+/// The TLS configuration of a server: its certificate and, for mutual TLS,
+/// the CA the certificates of the clients are checked against.
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Serves the certificate chain in `cert_file` with the private key in
+    /// `key_file`.
+    pub fn new(cert_file: impl AsRef<Path>, key_file: impl AsRef<Path>) -> Result<Self> {
+        let config = server_builder()?
+            .with_no_client_auth()
+            .with_single_cert(read_certs(cert_file)?, read_key(key_file)?)
+            .map_err(|e| KvsError::Tls(format!("Invalid server certificate: {}", e)))?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Serves a certificate like `new`, and requires the clients to present
+    /// a certificate signed by a CA in `client_ca_file`.
+    pub fn with_client_auth(
+        cert_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>,
+        client_ca_file: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(read_roots(client_ca_file)?, provider())
+                .build()
+                .map_err(|e| KvsError::Tls(format!("Invalid client CA: {}", e)))?;
+        let config = server_builder()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(read_certs(cert_file)?, read_key(key_file)?)
+            .map_err(|e| KvsError::Tls(format!("Invalid server certificate: {}", e)))?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// The TLS configuration of a client: the CA the certificate of the server
+/// is checked against and, for mutual TLS, its own certificate.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    /// Trusts the servers with a certificate signed by a CA in `ca_file`.
+    ///
+    /// A self-signed certificate is its own CA.
+    pub fn new(ca_file: impl AsRef<Path>) -> Result<Self> {
+        let config = client_builder(ca_file)?.with_no_client_auth();
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Trusts the servers like `new`, and presents the certificate chain in
+    /// `cert_file` with the private key in `key_file` to them.
+    pub fn with_client_auth(
+        ca_file: impl AsRef<Path>,
+        cert_file: impl AsRef<Path>,
+        key_file: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let config = client_builder(ca_file)?
+            .with_client_auth_cert(read_certs(cert_file)?, read_key(key_file)?)
+            .map_err(|e| KvsError::Tls(format!("Invalid client certificate: {}", e)))?;
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Checks the certificate of the server against `name` instead of the IP
+    /// address connected to.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_owned())
+            .map_err(|e| KvsError::Tls(format!("Invalid server name {}: {}", name, e)))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn server_builder() -> Result<rustls::ConfigBuilder<ServerConfig, rustls::WantsVerifier>> {
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| KvsError::Tls(format!("{}", e)))
+}
+
+fn client_builder(
+    ca_file: impl AsRef<Path>,
+) -> Result<rustls::ConfigBuilder<ClientConfig, rustls::client::WantsClientCert>> {
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| KvsError::Tls(format!("{}", e)))?
+        .with_root_certificates(read_roots(ca_file)?))
+}
+
+fn read_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| KvsError::Tls(format!("Cannot read {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| KvsError::Tls(format!("Cannot read {}: {}", path.display(), e)))
+}
+
+fn read_roots(path: impl AsRef<Path>) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| KvsError::Tls(format!("Invalid CA certificate: {}", e)))?;
+    }
+    Ok(Arc::new(roots))
+}
+
+/// A connection in clear or over TLS.
+///
+/// Clones share the connection, so that it can be read and written through
+/// different buffers. One thread may read while others write: a read waiting
+/// for data from the peer does not block the writes.
+#[derive(Clone)]
+pub(crate) struct Stream {
+    socket: Arc<Socket>,
+    tls: Option<Arc<Tls>>,
+}
+
+/// The TLS state of a connection, driven by hand over its socket.
+struct Tls {
+    conn: Mutex<Connection>,
+    /// Held while sending records, which keeps them in order when they are
+    /// written out of the lock of `conn`
+    sending: Mutex<()>,
+}
+
+/// The most bytes of records read from the socket at a time.
+const READ_CHUNK: usize = 8 * 1024;
+
+impl Tls {
+    fn new(conn: Connection) -> Arc<Self> {
+        Arc::new(Tls {
+            conn: Mutex::new(conn),
+            sending: Mutex::new(()),
+        })
+    }
+
+    fn read(&self, socket: &Socket, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            let mut raw = [0; READ_CHUNK];
+            let n = (&*socket).read(&mut raw)?;
+            let res = self.receive(&raw[..n]);
+            // the handshake and alerts call for answers
+            self.send(socket, false)?;
+            res?;
+        }
+    }
+
+    /// Processes the records in `raw`, an empty slice telling that the peer
+    /// closed the connection.
+    fn receive(&self, mut raw: &[u8]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        loop {
+            conn.read_tls(&mut raw)?;
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if raw.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn write(&self, socket: &Socket, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.lock().unwrap().writer().write(buf)?;
+        self.send(socket, true)?;
+        Ok(n)
+    }
+
+    /// Sends the pending records.
+    ///
+    /// Unless `wait`, the records are left to the thread already sending,
+    /// which checks for more once it is done.
+    fn send(&self, socket: &Socket, wait: bool) -> io::Result<()> {
+        loop {
+            let sending = if wait {
+                self.sending.lock().unwrap()
+            } else {
+                match self.sending.try_lock() {
+                    Ok(sending) => sending,
+                    Err(TryLockError::WouldBlock) => return Ok(()),
+                    Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+                }
+            };
+            loop {
+                let mut records = Vec::new();
+                {
+                    let mut conn = self.conn.lock().unwrap();
+                    while conn.wants_write() {
+                        conn.write_tls(&mut records)?;
+                    }
+                }
+                if records.is_empty() {
+                    break;
+                }
+                (&*socket).write_all(&records)?;
+            }
+            drop(sending);
+            // records queued while the lock was released are ours to send
+            if !self.conn.lock().unwrap().wants_write() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Stream {
+    /// Returns a connection in clear.
+    pub(crate) fn plain(socket: Socket) -> Self {
+        Stream {
+            socket: Arc::new(socket),
+            tls: None,
+        }
+    }
+
+    /// Returns the server side of a TLS connection, whose handshake happens
+    /// on the first read.
+    pub(crate) fn accept(socket: Socket, tls: &ServerTls) -> Result<Self> {
+        let conn = ServerConnection::new(Arc::clone(&tls.config))
+            .map_err(|e| KvsError::Tls(format!("{}", e)))?;
+        Ok(Stream {
+            socket: Arc::new(socket),
+            tls: Some(Tls::new(Connection::Server(conn))),
+        })
+    }
+
+    /// Returns the client side of a TLS connection to `peer`, whose
+    /// handshake happens on the first write and the reads that follow.
+    ///
+    /// Unless `tls` has a server name, the certificate of the server is
+    /// checked against the IP address of `peer`, or `localhost` for a Unix
+    /// socket.
+    pub(crate) fn connect(socket: Socket, tls: &ClientTls, peer: &Address) -> Result<Self> {
+        let name = match (&tls.server_name, peer) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Tcp(addr)) => ServerName::IpAddress(addr.ip().into()),
+            (None, Address::Unix(_)) => ServerName::try_from("localhost").unwrap(),
+        };
+        let conn = ClientConnection::new(Arc::clone(&tls.config), name)
+            .map_err(|e| KvsError::Tls(format!("{}", e)))?;
+        Ok(Stream {
+            socket: Arc::new(socket),
+            tls: Some(Tls::new(Connection::Client(conn))),
+        })
+    }
+
+    /// Returns the underlying socket, to set its timeouts or shut it down.
+    pub(crate) fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    /// Returns the subject common name of the certificate the client
+    /// presented, once the handshake is done.
+    pub(crate) fn peer_identity(&self) -> Option<String> {
+        let conn = self.tls.as_ref()?.conn.lock().unwrap();
+        let cert = match &*conn {
+            Connection::Server(conn) => conn.peer_certificates()?.first()?.clone(),
+            Connection::Client(_) => return None,
+        };
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+        let name = cert.subject().iter_common_name().next()?;
+        name.as_str().ok().map(str::to_owned)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = match &self.tls {
+            Some(tls) => tls.read(&self.socket, buf),
+            None => return (&*self.socket).read(buf),
+        };
+        match res {
+            // messages are delimited, so a peer closing without a
+            // close_notify cannot truncate one unnoticed
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            res => res,
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.tls {
+            Some(tls) => tls.write(&self.socket, buf),
+            None => (&*self.socket).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.tls {
+            Some(tls) => tls.send(&self.socket, true),
+            None => (&*self.socket).flush(),
+        }
+    }
+}
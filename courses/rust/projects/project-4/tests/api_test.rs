@@ -1,3 +1,6 @@
+// Scratch tests exploring std APIs; some bindings exist only to be moved or dropped.
+#![allow(dead_code, unused_variables, clippy::empty_line_after_doc_comments, clippy::useless_vec)]
+
 use std::sync::{Arc, Barrier};
 use std::thread;
 
@@ -0,0 +1,235 @@
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::protocol::ErrorCode;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, Event, InMemoryEngine, KvStore, KvsClient, KvsError, KvsServer,
+    Result, WatchTarget,
+};
+use tempfile::TempDir;
+use tokio::runtime;
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .expect("unable to create a runtime")
+        .block_on(future)
+}
+
+fn start_async_server(addr: &'static str, threads: u32) -> Result<()> {
+    let server = AsyncKvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(threads)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// The async client should read and write through the async server
+#[test]
+fn async_client_and_server() -> Result<()> {
+    let addr = "127.0.0.1:4301";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = AsyncKvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    block_on(async {
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.get("key2".to_owned()).await?, None);
+        client.remove("key1".to_owned()).await?;
+        assert_eq!(client.get("key1".to_owned()).await?, None);
+        assert!(client.remove("key1".to_owned()).await.is_err());
+
+        client.create_namespace("users").await?;
+        client
+            .set_in("users", "key1".to_owned(), "user".to_owned())
+            .await?;
+        assert_eq!(
+            client.get_in("users", "key1".to_owned()).await?,
+            Some("user".to_owned())
+        );
+        assert_eq!(client.list_namespaces().await?, vec!["default", "users"]);
+        assert_eq!(client.changes_since(0, 100).await?.len(), 4);
+        Ok(())
+    })
+}
+
+// Both clients should work with both servers, as they share the protocol
+#[test]
+fn clients_and_servers_interoperate() -> Result<()> {
+    let (async_addr, sync_addr) = ("127.0.0.1:4302", "127.0.0.1:4303");
+    start_async_server(async_addr, 2)?;
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(sync_addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(async_addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    block_on(async {
+        let mut client = AsyncKvsClient::connect(sync_addr).await?;
+        client.set("key".to_owned(), "value".to_owned()).await?;
+        assert_eq!(
+            client.get("key".to_owned()).await?,
+            Some("value".to_owned())
+        );
+        Ok(())
+    })
+}
+
+// Idle connections should not hold the threads of the pool
+#[test]
+fn idle_connections_do_not_block_requests() -> Result<()> {
+    let addr = "127.0.0.1:4304";
+    start_async_server(addr, 2)?;
+
+    let idle = (0..400)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    block_on(async {
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            clients.push(AsyncKvsClient::connect(addr).await?);
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            assert_eq!(
+                client.get(format!("key{}", i)).await?,
+                Some(format!("value{}", i))
+            );
+        }
+        Ok::<_, kvs::KvsError>(())
+    })?;
+
+    drop(idle);
+    Ok(())
+}
+
+// Watches should stream events over the event loop
+#[test]
+fn async_watch() -> Result<()> {
+    let addr = "127.0.0.1:4305";
+    start_async_server(addr, 2)?;
+
+    block_on(async {
+        let watcher = AsyncKvsClient::connect(addr).await?;
+        let mut events = watcher.watch(WatchTarget::Prefix("key".to_owned())).await?;
+
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        client.set("other".to_owned(), "value".to_owned()).await?;
+        client.remove("key1".to_owned()).await?;
+
+        assert_eq!(
+            events.next().await.unwrap()?,
+            Event::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned()
+            }
+        );
+        assert_eq!(
+            events.next().await.unwrap()?,
+            Event::Removed {
+                key: "key1".to_owned()
+            }
+        );
+        Ok(())
+    })
+}
+
+// A client reading its watch slower than the events come should get an error
+// and have its watch ended, instead of the server buffering without bound
+#[test]
+fn async_watch_lagged() -> Result<()> {
+    let addr = "127.0.0.1:5414";
+    start_async_server(addr, 2)?;
+
+    block_on(async {
+        let watcher = AsyncKvsClient::connect(addr).await?;
+        let mut events = watcher.watch(WatchTarget::Prefix("key".to_owned())).await?;
+
+        // more than the socket buffers hold, so the responses back up
+        let mut client = AsyncKvsClient::connect(addr).await?;
+        for i in 0..5000 {
+            client.set(format!("key{}", i), "x".repeat(4096)).await?;
+        }
+
+        let mut received = 0;
+        loop {
+            match events.next().await {
+                Some(Ok(_)) => received += 1,
+                Some(Err(_)) => break,
+                None => panic!("the watch ended without an error"),
+            }
+        }
+        assert!(received < 5000);
+        assert!(events.next().await.is_none());
+        Ok(())
+    })
+}
+
+// Connections over the limit should be told the server is busy, and the idle
+// or slow ones closed, as with the threaded server
+#[test]
+fn async_limits_and_timeouts() -> Result<()> {
+    let addr = "127.0.0.1:5417";
+    let server = AsyncKvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?)
+        .with_max_connections(1)
+        .with_idle_timeout(Some(Duration::from_millis(300)))
+        .with_read_timeout(Some(Duration::from_millis(300)));
+    let registry = server.registry();
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.get("key".to_owned()) {
+        Err(KvsError::Protocol(ErrorCode::ServerBusy, _)) => {}
+        res => panic!("Expected a server busy error, got {:?}", res),
+    }
+    assert!(registry
+        .render()
+        .contains("kvs_rejected_connections_total 1\n"));
+
+    // the idle client is closed, freeing its slot
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key".to_owned()).is_err());
+    assert!(registry
+        .render()
+        .contains("kvs_idle_connections_closed_total 1\n"));
+
+    // a request started but not finished in time closes the connection
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"#)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let start = Instant::now();
+    while let Ok(len) = stream.read(&mut [0; 64]) {
+        if len == 0 {
+            break;
+        }
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use kvs::auth::hash_password;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Access, AsyncKvsServer, Authenticator, Credentials, InMemoryEngine, KvsClient, KvsError,
+    KvsServer, Result, Scope, WatchTarget,
+};
+use tempfile::TempDir;
+
+fn authenticator() -> Result<Authenticator> {
+    let mut auth = Authenticator::new();
+    auth.add_token("writer-token", Scope::new(Access::ReadWrite));
+    auth.add_token(
+        "reader-token",
+        Scope::new(Access::ReadOnly).with_prefix("public/"),
+    );
+    auth.add_user(
+        "alice",
+        hash_password("alice's password")?,
+        Scope::new(Access::ReadWrite).with_prefix("alice/"),
+    )?;
+    Ok(auth)
+}
+
+fn token(token: &str) -> Credentials {
+    Credentials::Token(token.to_owned())
+}
+
+fn assert_unauthenticated<T>(res: Result<T>) {
+    match res.map(|_| ()) {
+        Err(KvsError::Unauthenticated(_)) => {}
+        res => panic!("Expected an unauthenticated error, got {:?}", res),
+    }
+}
+
+fn assert_denied<T>(res: Result<T>) {
+    match res.map(|_| ()) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("Expected a permission denied error, got {:?}", res),
+    }
+}
+
+// Requests should be refused until the client authenticates, then limited to
+// the scope of its credentials
+#[test]
+fn token_scopes() -> Result<()> {
+    let addr = "127.0.0.1:5101";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?)
+        .with_auth(authenticator()?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // refused requests leave the connection usable
+    let mut client = KvsClient::connect(addr)?;
+    assert_unauthenticated(client.get("public/key".to_owned()));
+    assert_unauthenticated(client.authenticate(token("wrong-token")));
+    assert_unauthenticated(client.list_namespaces());
+    client.authenticate(token("writer-token"))?;
+    client.set("public/key".to_owned(), "value".to_owned())?;
+    client.set("private/key".to_owned(), "value".to_owned())?;
+    client.create_namespace("other")?;
+
+    let mut reader = KvsClient::connect_with_credentials(addr, token("reader-token"))?;
+    assert_eq!(
+        reader.get("public/key".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(reader.get("public/none".to_owned())?, None);
+    assert_denied(reader.get("private/key".to_owned()));
+    assert_denied(reader.set("public/key".to_owned(), "other".to_owned()));
+    assert_denied(reader.remove("public/key".to_owned()));
+    assert_denied(reader.drop_namespace("other"));
+    assert_denied(reader.changes_since(0, 10));
+    assert_eq!(reader.list_namespaces()?.len(), 2);
+
+    // each pipelined request is checked on its own
+    let mut pipeline = reader.pipeline();
+    pipeline
+        .get("public/key".to_owned())
+        .set("public/key".to_owned(), "other".to_owned())
+        .get("private/key".to_owned());
+    let results = pipeline.execute()?;
+    assert_eq!(results[0].as_ref().ok(), Some(&Some("value".to_owned())));
+    assert!(matches!(results[1], Err(KvsError::PermissionDenied(_))));
+    assert!(matches!(results[2], Err(KvsError::PermissionDenied(_))));
+
+    assert!(reader
+        .watch(WatchTarget::Prefix("public/".to_owned()))
+        .is_ok());
+    let reader = KvsClient::connect_with_credentials(addr, token("reader-token"))?;
+    assert_denied(reader.watch(WatchTarget::Prefix(String::new())));
+    Ok(())
+}
+
+// Users should authenticate with their password, and JSON clients should be
+// refused with an error message
+#[test]
+fn passwords() -> Result<()> {
+    let addr = "127.0.0.1:5102";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?)
+        .with_auth(authenticator()?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let password = |password: &str| Credentials::Password {
+        username: "alice".to_owned(),
+        password: password.to_owned(),
+    };
+    assert_unauthenticated(KvsClient::connect_with_credentials(
+        addr,
+        password("wrong password"),
+    ));
+    let mut client = KvsClient::connect_with_credentials(addr, password("alice's password"))?;
+    client.set("alice/key".to_owned(), "value".to_owned())?;
+    assert_denied(client.set("bob/key".to_owned(), "value".to_owned()));
+
+    let mut client = KvsClient::connect_json(addr)?;
+    match client.get("alice/key".to_owned()) {
+        Err(KvsError::StringError(msg)) => {
+            assert_eq!(msg, "Unauthenticated: authentication required")
+        }
+        res => panic!("Expected an error message, got {:?}", res),
+    }
+    client.authenticate(password("alice's password"))?;
+    assert_eq!(
+        client.get("alice/key".to_owned())?,
+        Some("value".to_owned())
+    );
+    match client.get("bob/key".to_owned()) {
+        Err(KvsError::StringError(msg)) => assert!(msg.starts_with("Permission denied")),
+        res => panic!("Expected an error message, got {:?}", res),
+    }
+    Ok(())
+}
+
+// The event loop server should check requests the same way
+#[test]
+fn async_server() -> Result<()> {
+    let addr = "127.0.0.1:5103";
+    let server = AsyncKvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?)
+        .with_auth(authenticator()?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_unauthenticated(client.set("public/key".to_owned(), "value".to_owned()));
+    client.authenticate(token("reader-token"))?;
+    assert_denied(client.set("public/key".to_owned(), "value".to_owned()));
+    assert_eq!(client.get("public/key".to_owned())?, None);
+    Ok(())
+}
+
+// Servers without authentication should accept any credentials
+#[test]
+fn no_authentication() -> Result<()> {
+    let addr = "127.0.0.1:5104";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect_with_credentials(addr, token("any"))?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    Ok(())
+}
+
+// Credentials files should grant the scopes they list
+#[test]
+fn credentials_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("credentials.json");
+    let hash = hash_password("password")?;
+    fs::write(
+        &path,
+        format!(
+            r#"{{
+                "tokens": [{{ "token": "token", "access": "read_write" }}],
+                "users": [{{
+                    "username": "user",
+                    "password_hash": "{}",
+                    "access": "read_only",
+                    "prefixes": ["a/", "b/"]
+                }}],
+                "certificates": [{{ "subject": "backup", "access": "read_only" }}]
+            }}"#,
+            hash
+        ),
+    )?;
+    let auth = Authenticator::from_file(&path)?;
+    assert_eq!(
+        auth.authenticate(&token("token")),
+        Some(Scope::new(Access::ReadWrite))
+    );
+    assert_eq!(auth.authenticate(&token("other")), None);
+    assert_eq!(
+        auth.authenticate(&Credentials::Password {
+            username: "user".to_owned(),
+            password: "password".to_owned(),
+        }),
+        Some(
+            Scope::new(Access::ReadOnly)
+                .with_prefix("a/")
+                .with_prefix("b/")
+        )
+    );
+    assert_eq!(
+        auth.authenticate_certificate("backup"),
+        Some(Scope::new(Access::ReadOnly))
+    );
+    assert_eq!(auth.authenticate_certificate("other"), None);
+
+    fs::write(
+        &path,
+        r#"{ "users": [{ "username": "user", "password_hash": "clear", "access": "read_only" }] }"#,
+    )?;
+    assert!(Authenticator::from_file(&path).is_err());
+    fs::write(
+        &path,
+        r#"{ "tokens": [{ "token": "token", "access": "all" }] }"#,
+    )?;
+    assert!(Authenticator::from_file(&path).is_err());
+    Ok(())
+}
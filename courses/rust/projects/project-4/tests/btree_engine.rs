@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use kvs::{BTreeEngine, KvsEngine, KvsError, Result};
+use rand::prelude::SmallRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+fn data_file_len(temp_dir: &TempDir) -> u64 {
+    temp_dir
+        .path()
+        .join("btree.db")
+        .metadata()
+        .expect("unable to read the data file")
+        .len()
+}
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = BTreeEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Values larger than a page should round-trip through overflow pages
+#[test]
+fn large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+
+    let large = "x".repeat(20_000);
+    store.set("key1".to_owned(), large.clone())?;
+    store.set("key2".to_owned(), "small".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large.clone()));
+
+    drop(store);
+    let store = BTreeEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(large));
+
+    // the overflow pages of a replaced value are reused
+    let len = data_file_len(&temp_dir);
+    for i in 0..10 {
+        store.set("key1".to_owned(), i.to_string().repeat(20_000))?;
+    }
+    assert_eq!(data_file_len(&temp_dir), len);
+    Ok(())
+}
+
+// Removed keys give their pages back, so the file stops growing without compaction
+#[test]
+fn space_is_reused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let len = data_file_len(&temp_dir);
+
+    for _ in 0..3 {
+        for key_id in 0..2000 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        for key_id in 0..2000 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+    }
+    assert!(data_file_len(&temp_dir) <= len * 2);
+
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Dropping a namespace should only unlink it, and later writes should give
+// the pages of its keys back
+#[test]
+fn dropped_namespace_is_reclaimed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    for key_id in 0..2000 {
+        store.set_in("users", format!("key{}", key_id), "x".repeat(100))?;
+    }
+    let len = data_file_len(&temp_dir);
+
+    // the drop logs a handful of pages rather than every leaf of the namespace
+    store.sync()?;
+    store.drop_namespace("users")?;
+    let wal = temp_dir.path().join("btree.wal");
+    assert!(wal.metadata()?.len() < 10 * 4096);
+
+    drop(store);
+    let store = BTreeEngine::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    assert!(store.scan_in("users", "", None, usize::MAX)?.is_empty());
+
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    assert!(data_file_len(&temp_dir) <= len + len / 4);
+    Ok(())
+}
+
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), key_id.to_string())?;
+        store.set_in("users", format!("key{:04}", key_id), "user".to_owned())?;
+    }
+
+    let entries = store.range_in("default", "key0100".to_owned().."key0200".to_owned())?;
+    assert_eq!(entries.len(), 100);
+    assert_eq!(entries[0], ("key0100".to_owned(), "100".to_owned()));
+    assert_eq!(entries[99], ("key0199".to_owned(), "199".to_owned()));
+
+    // a scan does not run into the next namespace
+    let entries = store.range_in("default", "key0990".to_owned()..)?;
+    assert_eq!(entries.len(), 10);
+    assert_eq!(store.range_in("users", ..)?.len(), 1000);
+    Ok(())
+}
+
+// Random operations should agree with a `BTreeMap`, also after reopening
+#[test]
+fn random_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = BTreeEngine::open(temp_dir.path())?;
+    let mut model = BTreeMap::new();
+    let mut rng = SmallRng::from_seed([7; 16]);
+
+    for round in 0..5 {
+        for _ in 0..2000 {
+            let key = format!("key{}", rng.gen_range(0, 500));
+            if rng.gen_bool(0.6) {
+                let value = "v".repeat(rng.gen_range(1, 300));
+                store.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            } else {
+                let expected = model.remove(&key);
+                assert_eq!(store.remove(key).is_ok(), expected.is_some());
+            }
+        }
+        if round % 2 == 1 {
+            drop(store);
+            store = BTreeEngine::open(temp_dir.path())?;
+        }
+        for key_id in 0..500 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key.clone())?, model.get(&key).cloned());
+        }
+        let scanned = store.range_in("default", ..)?;
+        let expected: Vec<_> = model.clone().into_iter().collect();
+        assert_eq!(scanned, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = BTreeEngine::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
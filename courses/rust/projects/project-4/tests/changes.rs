@@ -0,0 +1,161 @@
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Change, ChangeOp, InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result,
+};
+use tempfile::TempDir;
+
+fn ops(changes: &[Change]) -> Vec<(u64, &str, &ChangeOp)> {
+    changes
+        .iter()
+        .map(|change| (change.seq, change.namespace.as_str(), &change.op))
+        .collect()
+}
+
+// Every mutation should be numbered in commit order
+#[test]
+fn changes_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.create_namespace("users")?;
+    store.set_in("users", "key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.drop_namespace("users")?;
+
+    let changes = store.changes_since(0, 100)?;
+    assert_eq!(
+        ops(&changes),
+        vec![
+            (
+                1,
+                "default",
+                &ChangeOp::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned()
+                }
+            ),
+            (2, "users", &ChangeOp::CreateNamespace),
+            (
+                3,
+                "users",
+                &ChangeOp::Set {
+                    key: "key2".to_owned(),
+                    value: "value2".to_owned()
+                }
+            ),
+            (
+                4,
+                "default",
+                &ChangeOp::Remove {
+                    key: "key1".to_owned()
+                }
+            ),
+            (5, "users", &ChangeOp::DropNamespace),
+        ]
+    );
+
+    // resume in batches
+    let changes = store.changes_since(2, 2)?;
+    assert_eq!(changes.iter().map(|c| c.seq).collect::<Vec<_>>(), vec![3, 4]);
+    assert!(store.changes_since(5, 100)?.is_empty());
+
+    Ok(())
+}
+
+// Sequence numbers should keep increasing across restarts
+#[test]
+fn changes_resume_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let changes = store.changes_since(3, 100)?;
+    assert_eq!(
+        ops(&changes),
+        vec![(
+            4,
+            "default",
+            &ChangeOp::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned()
+            }
+        )]
+    );
+    Ok(())
+}
+
+// Consumers behind the compaction point must resync, and a resync from 0
+// should replay the compacted state in sequence order
+#[test]
+fn resync_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut last_seq = 0;
+    for iter in 0..300 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            last_seq += 1;
+        }
+    }
+
+    let compacted = match store.changes_since(1, 10) {
+        Err(KvsError::ResyncRequired(compacted)) => compacted,
+        other => panic!("expected a resync, got {:?}", other.map(|c| c.len())),
+    };
+    assert!(compacted > 1 && compacted <= last_seq);
+
+    let changes = store.changes_since(0, usize::MAX)?;
+    assert!(changes.windows(2).all(|w| w[0].seq < w[1].seq));
+    assert_eq!(changes.last().unwrap().seq, last_seq);
+
+    // the compaction point survives reopening
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.changes_since(1, 10),
+        Err(KvsError::ResyncRequired(c)) if c == compacted
+    ));
+    assert!(store.changes_since(compacted, 10).is_ok());
+    Ok(())
+}
+
+#[test]
+fn changes_over_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4102";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    let changes = client.changes_since(1, 10)?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].seq, 2);
+    Ok(())
+}
+
+#[test]
+fn change_feed_unsupported() {
+    let store = InMemoryEngine::new();
+    assert!(matches!(
+        store.changes_since(0, 10),
+        Err(KvsError::Unsupported(_))
+    ));
+}
@@ -0,0 +1,45 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{testkit, BTreeEngine, KvStore, LsmEngine, Result, SledKvsEngine};
+use tempfile::TempDir;
+
+#[test]
+fn kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    testkit::run_all(|path: &Path| KvStore::open(path), temp_dir.path())
+}
+
+#[test]
+fn sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    testkit::run_all(open_sled, temp_dir.path())
+}
+
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    testkit::run_all(|path: &Path| LsmEngine::open(path), temp_dir.path())
+}
+
+#[test]
+fn btree_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    testkit::run_all(|path: &Path| BTreeEngine::open(path), temp_dir.path())
+}
+
+/// Opens sled, waiting for the background threads of a dropped instance to
+/// release the lock on the database.
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(_)) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            res => return Ok(SledKvsEngine::new(res?)),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+
+use kvs::{EncryptionKey, KvStore, KvsEngine, KvsError, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+fn key(byte: u8) -> EncryptionKey {
+    EncryptionKey::new([byte; 32])
+}
+
+/// Returns the concatenated contents of the log files.
+fn log_contents(temp_dir: &TempDir) -> Vec<u8> {
+    WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .flat_map(|entry| fs::read(entry.path()).expect("unable to read the log"))
+        .collect()
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+// Values should round-trip and never reach the disk in plaintext
+#[test]
+fn encrypted_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+
+    store.set("key1".to_owned(), "secret-value".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert!(!contains(&log_contents(&temp_dir), "secret-value"));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Opening with the wrong key or without a key should fail before any replay
+#[test]
+fn wrong_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open_encrypted(temp_dir.path(), key(2)),
+        Err(KvsError::Decryption(_))
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::KeyRequired)
+    ));
+    Ok(())
+}
+
+// Encrypted records should survive compaction
+#[test]
+fn encrypted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+
+    for iter in 0..300 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("299".to_owned()));
+    }
+    Ok(())
+}
+
+// Rotating the key should re-encrypt the store, also a plaintext one
+#[test]
+fn rotate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "plain-value".to_owned())?;
+    drop(store);
+
+    // a plaintext store stays readable, new files are encrypted
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("plain-value".to_owned())
+    );
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rotate_key(key(2))?;
+    assert!(!contains(&log_contents(&temp_dir), "plain-value"));
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("plain-value".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open_encrypted(temp_dir.path(), key(1)),
+        Err(KvsError::Decryption(_))
+    ));
+    let store = KvStore::open_encrypted(temp_dir.path(), key(2))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("plain-value".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// The change feed should read encrypted files
+#[test]
+fn encrypted_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_encrypted(temp_dir.path(), key(1))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let changes = store.changes_since(0, 10)?;
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[1].seq, 2);
+    Ok(())
+}
+
+#[test]
+fn key_sources() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hex = "11".repeat(32);
+
+    let key_file = temp_dir.path().join("key");
+    fs::write(&key_file, format!("{}\n", hex))?;
+    let store = KvStore::open_encrypted(
+        temp_dir.path().join("db"),
+        EncryptionKey::from_file(&key_file)?,
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    std::env::set_var("KVS_TEST_ENCRYPTION_KEY", &hex);
+    let store = KvStore::open_encrypted(
+        temp_dir.path().join("db"),
+        EncryptionKey::from_env("KVS_TEST_ENCRYPTION_KEY")?,
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(EncryptionKey::from_hex("not a key").is_err());
+    assert!(EncryptionKey::from_hex(&"11".repeat(16)).is_err());
+    Ok(())
+}
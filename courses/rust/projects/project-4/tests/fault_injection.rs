@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+
+use kvs::testkit::{Fault, SimDisk};
+use kvs::{EncryptionKey, KvStore, KvsEngine, KvsError, Result};
+use rand::prelude::SmallRng;
+use rand::{Rng, SeedableRng};
+
+const KEYS: u32 = 20;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+}
+
+type Model = BTreeMap<String, String>;
+
+/// Generates operations overwriting a few keys with values large enough to
+/// trigger a compaction.
+fn workload(len: usize) -> Vec<Op> {
+    let mut rng = SmallRng::from_seed([3; 16]);
+    let mut model = Model::new();
+    (0..len)
+        .map(|i| {
+            let key = format!("key{}", rng.gen_range(0, KEYS));
+            let op = if model.contains_key(&key) && rng.gen_bool(0.2) {
+                Op::Remove(key)
+            } else {
+                Op::Set(key, format!("{:04}", i).repeat(250))
+            };
+            apply(&mut model, &op);
+            op
+        })
+        .collect()
+}
+
+fn apply(model: &mut Model, op: &Op) {
+    match op {
+        Op::Set(key, value) => {
+            model.insert(key.clone(), value.clone());
+        }
+        Op::Remove(key) => {
+            model.remove(key);
+        }
+    }
+}
+
+fn execute<E: KvsEngine>(store: &E, op: &Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => store.set(key.clone(), value.clone()),
+        Op::Remove(key) => store.remove(key.clone()),
+    }
+}
+
+fn contents<E: KvsEngine>(store: &E) -> Result<Model> {
+    let mut contents = Model::new();
+    for key_id in 0..KEYS {
+        let key = format!("key{}", key_id);
+        if let Some(value) = store.get(key.clone())? {
+            contents.insert(key, value);
+        }
+    }
+    Ok(contents)
+}
+
+/// Returns the number of writes each operation starts at, and the total.
+fn write_counts(ops: &[Op], open: impl Fn(&SimDisk) -> Result<KvStore>) -> Result<(Vec<u64>, u64)> {
+    let disk = SimDisk::new();
+    let store = open(&disk)?;
+    let mut starts = Vec::new();
+    for op in ops {
+        starts.push(disk.writes());
+        execute(&store, op)?;
+    }
+    Ok((starts, disk.writes()))
+}
+
+/// Picks the writes to inject faults into: some spread over the whole run,
+/// and more in the operations issuing many writes, which are compactions.
+fn fault_points(ops: &[Op], open: impl Fn(&SimDisk) -> Result<KvStore>) -> Result<Vec<u64>> {
+    let (starts, total) = write_counts(ops, open)?;
+    let mut points: Vec<u64> = (0..total).step_by(total as usize / 30).collect();
+    let mut compactions = 0;
+    for window in starts.windows(2) {
+        if window[1] - window[0] > 2 {
+            compactions += 1;
+            points.extend(window[0]..window[1]);
+        }
+    }
+    assert!(compactions > 1, "the workload does not compact");
+    points.sort_unstable();
+    points.dedup();
+    Ok(points)
+}
+
+/// Runs the operations, retrying those failing on the injected fault as a
+/// client would.
+fn run_with_retries(store: &KvStore, ops: &[Op]) -> Result<()> {
+    for op in ops {
+        let mut attempts = 0;
+        loop {
+            match execute(store, op) {
+                Ok(()) => break,
+                // the failure came after the record was written
+                Err(KvsError::KeyNotFound) if attempts > 0 => break,
+                Err(_) if attempts == 0 => attempts += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+// A store that survives failed writes should keep every acknowledged write,
+// before and after reopening
+#[test]
+fn failed_writes_keep_acknowledged_data() -> Result<()> {
+    let ops = workload(3000);
+    let mut expected = Model::new();
+    for op in &ops {
+        apply(&mut expected, op);
+    }
+
+    let points = fault_points(&ops, SimDisk::open_kv_store)?;
+    for fault in [Fault::Error, Fault::ShortWrite, Fault::TornWrite] {
+        for &point in &points {
+            let disk = SimDisk::new();
+            disk.inject(point, fault);
+            let store = disk.open_kv_store()?;
+            run_with_retries(&store, &ops)?;
+            assert_eq!(
+                contents(&store)?,
+                expected,
+                "{:?} at write {}",
+                fault,
+                point
+            );
+
+            drop(store);
+            let store = disk.open_kv_store()?;
+            assert_eq!(
+                contents(&store)?,
+                expected,
+                "{:?} at write {}",
+                fault,
+                point
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs the operations until one fails, then crashes the disk.
+///
+/// Returns the acknowledged operations and the one that failed, whose
+/// outcome is unknown.
+fn run_until_crash<'a, E: KvsEngine>(
+    disk: &SimDisk,
+    store: &E,
+    ops: &'a [Op],
+) -> (&'a [Op], Option<&'a Op>) {
+    for (i, op) in ops.iter().enumerate() {
+        if execute(store, op).is_err() {
+            disk.crash();
+            return (&ops[..i], Some(op));
+        }
+    }
+    disk.crash();
+    (ops, None)
+}
+
+/// Checks the recovered contents are the state after some of the
+/// acknowledged operations, in order, maybe followed by the failed one.
+fn assert_prefix_state(recovered: &Model, acked: &[Op], failed: Option<&Op>, context: &str) {
+    let mut model = Model::new();
+    if *recovered == model {
+        return;
+    }
+    for op in acked.iter().chain(failed) {
+        apply(&mut model, op);
+        if *recovered == model {
+            return;
+        }
+    }
+    panic!(
+        "{}: the recovered state is not a prefix of the history",
+        context
+    );
+}
+
+// After a crash, the store should open and hold the state of a prefix of
+// the acknowledged writes
+#[test]
+fn crash_recovers_a_prefix() -> Result<()> {
+    let ops = workload(3000);
+    let points = fault_points(&ops, SimDisk::open_kv_store)?;
+    for fault in [Fault::Error, Fault::TornWrite] {
+        for &point in &points {
+            let disk = SimDisk::new();
+            disk.inject(point, fault);
+            let store = disk.open_kv_store()?;
+            let (acked, failed) = run_until_crash(&disk, &store, &ops);
+            drop(store);
+
+            let store = disk.open_kv_store()?;
+            let context = format!("{:?} at write {}", fault, point);
+            assert_prefix_state(&contents(&store)?, acked, failed, &context);
+
+            // the recovered store keeps working
+            store.set("key0".to_owned(), "after".to_owned())?;
+            drop(store);
+            let store = disk.open_kv_store()?;
+            assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// A compaction synced before the crash should survive it
+#[test]
+fn compacted_data_survives_crash() -> Result<()> {
+    let ops = workload(3000);
+    let disk = SimDisk::new();
+    let store = disk.open_kv_store()?;
+    let (acked, _) = run_until_crash(&disk, &store, &ops);
+    assert_eq!(acked.len(), ops.len());
+    drop(store);
+
+    let store = disk.open_kv_store()?;
+    assert!(!contents(&store)?.is_empty());
+    assert_prefix_state(&contents(&store)?, acked, None, "crash after the workload");
+    Ok(())
+}
+
+#[test]
+fn encrypted_crash_recovers_a_prefix() -> Result<()> {
+    let key = EncryptionKey::new([5; 32]);
+    let ops = workload(3000);
+    for &point in fault_points(&ops, SimDisk::open_kv_store)?
+        .iter()
+        .step_by(5)
+    {
+        let disk = SimDisk::new();
+        disk.inject(point, Fault::TornWrite);
+        // opening writes the header of the first file, which may fail too
+        let (acked, failed) = match disk.open_kv_store_encrypted(key.clone()) {
+            Ok(store) => run_until_crash(&disk, &store, &ops),
+            Err(_) => {
+                disk.crash();
+                (&ops[..0], None)
+            }
+        };
+
+        let store = disk.open_kv_store_encrypted(key.clone())?;
+        let context = format!("torn write at {}", point);
+        assert_prefix_state(&contents(&store)?, acked, failed, &context);
+    }
+    Ok(())
+}
+
+/// Records the nonce of every sealed record in the files of the disk,
+/// failing if two records were sealed with the same one.
+fn record_nonces(disk: &SimDisk, nonces: &mut HashMap<Vec<u8>, Vec<u8>>) {
+    for (name, data) in disk.files() {
+        let mut frames = match data.strip_prefix(b"KVSENC01".as_ref()) {
+            Some(frames) => frames,
+            None => continue,
+        };
+        while frames.len() >= 4 {
+            let len = u32::from_le_bytes(frames[..4].try_into().unwrap()) as usize;
+            let sealed = match frames.get(4..4 + len) {
+                Some(sealed) => sealed,
+                // a record torn by the fault
+                None => break,
+            };
+            let (nonce, ciphertext) = sealed.split_at(24);
+            let seen = nonces
+                .entry(nonce.to_vec())
+                .or_insert_with(|| ciphertext.to_vec());
+            assert_eq!(seen, ciphertext, "a nonce is reused in {}", name);
+            frames = &frames[4 + len..];
+        }
+    }
+}
+
+// Retrying a failed compaction, or reopening after a crash, may hand out a
+// generation again, and every record should still get a nonce of its own
+#[test]
+fn failed_compactions_do_not_reuse_nonces() -> Result<()> {
+    let key = EncryptionKey::new([5; 32]);
+    let open = |disk: &SimDisk| disk.open_kv_store_encrypted(key.clone());
+    let ops = workload(3000);
+    let (starts, _) = write_counts(&ops, open)?;
+    // the first writes of the compaction create its file and write its header
+    let first = starts
+        .windows(2)
+        .position(|window| window[1] - window[0] > 2)
+        .expect("the workload does not compact");
+    let points = starts[first]..starts[first] + 5;
+    let ops = &ops[..first + 100];
+    for point in points {
+        for fault in [Fault::Error, Fault::TornWrite] {
+            let disk = SimDisk::new();
+            let mut nonces = HashMap::new();
+            disk.inject(point, fault);
+            let mut store = match open(&disk) {
+                Ok(store) => store,
+                // the fault hit the header of the first file
+                Err(_) => continue,
+            };
+            for op in ops {
+                if execute(&store, op).is_err() {
+                    record_nonces(&disk, &mut nonces);
+                    if fault == Fault::TornWrite {
+                        disk.crash();
+                        drop(store);
+                        store = open(&disk)?;
+                    }
+                    // the record may have been written before the failure
+                    let _ = execute(&store, op);
+                }
+            }
+            record_nonces(&disk, &mut nonces);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of writes opening a `BTreeEngine` takes, and the total
+/// after running the operations.
+fn btree_write_counts(ops: &[Op]) -> Result<(u64, u64)> {
+    let disk = SimDisk::new();
+    let store = disk.open_btree_engine()?;
+    let opened = disk.writes();
+    for op in ops {
+        execute(&store, op)?;
+    }
+    Ok((opened, disk.writes()))
+}
+
+// A B-tree commit failing between its log record and its page writes, or
+// part way through the page writes, should be redone after a crash even when
+// the OS wrote some of the pages back
+#[test]
+fn btree_crash_recovers_a_prefix() -> Result<()> {
+    let ops = workload(100);
+    let (opened, total) = btree_write_counts(&ops)?;
+    for point in opened..total {
+        let disk = SimDisk::new();
+        let store = disk.open_btree_engine()?;
+        disk.inject(point, Fault::Error);
+        let acked = ops
+            .iter()
+            .position(|op| execute(&store, op).is_err())
+            .unwrap_or(ops.len());
+        let (acked, failed) = (&ops[..acked], ops.get(acked));
+        disk.write_back("btree.db");
+        disk.crash();
+        drop(store);
+
+        let store = disk.open_btree_engine()?;
+        let context = format!("error at write {}", point);
+        assert_prefix_state(&contents(&store)?, acked, failed, &context);
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use kvs::protocol::{MAGIC, MAX_FRAME_LEN, MESSAGE, VERSION};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{AsyncKvsServer, InMemoryEngine, KvsServer, Result};
+
+/// Counts the bytes allocated and not yet freed by the whole test binary.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::SeqCst);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Opens a binary connection announcing the largest frame allowed, of which
+/// only the kind byte is sent.
+fn announce_large_frame(addr: &str) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&VERSION.to_be_bytes())?;
+    stream.write_all(&0u32.to_be_bytes())?;
+    stream.read_exact(&mut [0; 10])?;
+    stream.write_all(&MAX_FRAME_LEN.to_be_bytes())?;
+    stream.write_all(&[MESSAGE])?;
+    Ok(stream)
+}
+
+// Clients announcing large frames without sending them should not make the
+// servers allocate the frames up front
+#[test]
+fn announced_frames_are_not_allocated() -> Result<()> {
+    let addr = "127.0.0.1:5418";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(8)?);
+    thread::spawn(move || server.run(addr));
+    let async_addr = "127.0.0.1:5419";
+    let server = AsyncKvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(async_addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let mut streams = Vec::new();
+    for _ in 0..4 {
+        streams.push(announce_large_frame(addr)?);
+        streams.push(announce_large_frame(async_addr)?);
+    }
+    thread::sleep(Duration::from_millis(500));
+    let allocated = ALLOCATED.load(Ordering::SeqCst).saturating_sub(before);
+    assert!(
+        allocated < MAX_FRAME_LEN as usize,
+        "{} bytes allocated for 8 frames announced",
+        allocated
+    );
+    Ok(())
+}
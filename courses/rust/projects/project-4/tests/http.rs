@@ -0,0 +1,210 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{HttpServer, InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+fn start_server<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+    let server = HttpServer::new(engine, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+/// Reads a response, returning its status, headers and body.
+fn read_response<R: BufRead>(reader: &mut R) -> Result<(u16, Vec<String>, Option<Value>)> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+
+    let mut headers = Vec::new();
+    let mut content_len = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end().to_owned();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length: ") {
+            content_len = len.parse().unwrap();
+        }
+        headers.push(header);
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+    let body = if body.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_slice(&body)?)
+    };
+    Ok((status, headers, body))
+}
+
+/// Sends a request on a new connection.
+fn request(addr: &str, method: &str, target: &str, body: &str) -> Result<(u16, Option<Value>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        addr,
+        body.len(),
+        body
+    )?;
+    let (status, _, body) = read_response(&mut BufReader::new(stream))?;
+    Ok((status, body))
+}
+
+// The key routes should read, write and remove keys with the right statuses
+#[test]
+fn key_routes() -> Result<()> {
+    let addr = "127.0.0.1:4701";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server(KvStore::open(temp_dir.path())?, addr)?;
+
+    assert_eq!(
+        request(addr, "GET", "/health", "")?,
+        (200, Some(json!({ "status": "ok" })))
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys/key1", "")?,
+        (404, Some(json!({ "error": "Key not found" })))
+    );
+    assert_eq!(
+        request(addr, "PUT", "/keys/key1", r#"{"value": "value1"}"#)?,
+        (204, None)
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys/key1", "")?,
+        (200, Some(json!({ "key": "key1", "value": "value1" })))
+    );
+
+    // keys are percent-decoded
+    assert_eq!(
+        request(addr, "PUT", "/keys/a%20b%2Fc", r#"{"value": "välue"}"#)?,
+        (204, None)
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys/a%20b%2Fc", "")?,
+        (200, Some(json!({ "key": "a b/c", "value": "välue" })))
+    );
+
+    assert_eq!(request(addr, "DELETE", "/keys/key1", "")?, (204, None));
+    assert_eq!(request(addr, "DELETE", "/keys/key1", "")?.0, 404);
+    assert_eq!(request(addr, "GET", "/keys/key1", "")?.0, 404);
+
+    assert_eq!(
+        request(addr, "GET", "/keys/key1?namespace=missing", "")?,
+        (404, Some(json!({ "error": "Namespace not found" })))
+    );
+    assert_eq!(request(addr, "PUT", "/keys/key1", "value")?.0, 400);
+    assert_eq!(request(addr, "GET", "/keys/%zz", "")?.0, 400);
+    assert_eq!(request(addr, "POST", "/keys/key1", "")?.0, 405);
+    assert_eq!(request(addr, "GET", "/other", "")?.0, 404);
+    Ok(())
+}
+
+// Listing should page through the keys with a prefix
+#[test]
+fn list_keys() -> Result<()> {
+    let addr = "127.0.0.1:4702";
+    let engine = InMemoryEngine::new();
+    for i in 0..25 {
+        engine.set(format!("user:{:02}", i), "value".to_owned())?;
+        engine.set(format!("item:{:02}", i), "value".to_owned())?;
+    }
+    start_server(engine, addr)?;
+
+    let mut keys = Vec::new();
+    let mut target = "/keys?prefix=user%3A&limit=10".to_owned();
+    loop {
+        let (status, body) = request(addr, "GET", &target, "")?;
+        assert_eq!(status, 200);
+        let body = body.unwrap();
+        for key in body["keys"].as_array().unwrap() {
+            keys.push(key.as_str().unwrap().to_owned());
+        }
+        match body["next"].as_str() {
+            Some(next) => target = format!("/keys?prefix=user:&limit=10&after={}", next),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = (0..25).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(
+        request(addr, "GET", "/keys?limit=2", "")?,
+        (
+            200,
+            Some(json!({ "keys": ["item:00", "item:01"], "next": "item:01" }))
+        )
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys?prefix=none", "")?,
+        (200, Some(json!({ "keys": [], "next": null })))
+    );
+    assert_eq!(request(addr, "GET", "/keys?limit=0", "")?.0, 400);
+    assert_eq!(request(addr, "DELETE", "/keys", "")?.0, 405);
+    Ok(())
+}
+
+// Connections should be kept alive between requests
+#[test]
+fn keep_alive() -> Result<()> {
+    let addr = "127.0.0.1:4703";
+    start_server(InMemoryEngine::new(), addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let body = r#"{"value": "value"}"#;
+    write!(
+        stream,
+        "PUT /keys/key HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /keys/key HTTP/1.1\r\n\r\n",
+        body.len(),
+        body
+    )?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (status, headers, _) = read_response(&mut reader)?;
+    assert_eq!(status, 204);
+    assert!(!headers.contains(&"Connection: close".to_owned()));
+    let (status, _, body) = read_response(&mut reader)?;
+    assert_eq!(status, 200);
+    assert_eq!(body.unwrap()["value"], "value");
+
+    stream.write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let (status, headers, _) = read_response(&mut reader)?;
+    assert_eq!(status, 200);
+    assert!(headers.contains(&"Connection: close".to_owned()));
+    assert_eq!(reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// The gateway should serve the same engine on the same pool as the TCP server
+#[test]
+fn shared_with_tcp_server() -> Result<()> {
+    let (tcp_addr, http_addr) = ("127.0.0.1:4704", "127.0.0.1:4705");
+    let engine = InMemoryEngine::new();
+    let pool = Arc::new(SharedQueueThreadPool::new(2)?);
+    let http = HttpServer::new(engine.clone(), Arc::clone(&pool));
+    let tcp = KvsServer::new(engine, pool);
+    thread::spawn(move || http.run(http_addr));
+    thread::spawn(move || tcp.run(tcp_addr));
+    thread::sleep(Duration::from_millis(500));
+
+    request(http_addr, "PUT", "/keys/key", r#"{"value": "value"}"#)?;
+    let mut client = KvsClient::connect(tcp_addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.set("key".to_owned(), "other".to_owned())?;
+    drop(client);
+    assert_eq!(
+        request(http_addr, "GET", "/keys/key", "")?.1.unwrap()["value"],
+        "other"
+    );
+    Ok(())
+}
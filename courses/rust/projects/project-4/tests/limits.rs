@@ -0,0 +1,77 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use kvs::protocol::ErrorCode;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{InMemoryEngine, KvsClient, KvsError, KvsServer, Result};
+
+// Connections over the limit should be told the server is busy, in the
+// protocol they speak, and be counted
+#[test]
+fn max_connections() -> Result<()> {
+    let addr = "127.0.0.1:4901";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?)
+        .with_max_connections(1);
+    let registry = server.registry();
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.get("key".to_owned()) {
+        Err(KvsError::Protocol(ErrorCode::ServerBusy, _)) => {}
+        res => panic!("Expected a server busy error, got {:?}", res),
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"key":"key"}}"#)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    assert!(response.starts_with(r#"{"Err":"Server busy"}"#));
+
+    assert!(registry
+        .render()
+        .contains("kvs_rejected_connections_total 2\n"));
+
+    // the slot is free again once the client leaves
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Connections waiting for a thread should be served in turn, and those over
+// the queue limit rejected
+#[test]
+fn accept_queue() -> Result<()> {
+    let addr = "127.0.0.1:4902";
+    let server =
+        KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?).with_accept_queue(1);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // the only thread serves this client until it leaves
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let queued = thread::spawn(move || -> Result<Option<String>> {
+        let mut client = KvsClient::connect(addr)?;
+        client.get("key".to_owned())
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.get("key".to_owned()) {
+        Err(KvsError::Protocol(ErrorCode::ServerBusy, _)) => {}
+        res => panic!("Expected a server busy error, got {:?}", res),
+    }
+
+    drop(client);
+    assert_eq!(queued.join().unwrap()?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use std::fs;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use kvs::{KvsEngine, KvsError, LsmEngine, LsmOptions, Result};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Small limits so that a few thousand writes go through flushes and compactions
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_bytes: 16 * 1024,
+        l0_compaction_trigger: 2,
+        level_base_bytes: 32 * 1024,
+        table_bytes: 8 * 1024,
+    }
+}
+
+fn count_files(temp_dir: &TempDir, extension: &str) -> usize {
+    WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+        .count()
+}
+
+// Should get previously stored value
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Open from disk again and check the data replayed from the log
+    drop(store);
+    let store = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn overwrite_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    drop(store);
+    let store = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Values and tombstones should stay correct while the memtable is flushed and
+// the tables are merged down the levels
+#[test]
+fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+
+    for iter in 0..20 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in (0..500).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let check = |store: &LsmEngine| -> Result<()> {
+        for key_id in 0..500 {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some("value19".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    assert!(count_files(&temp_dir, "sst") > 0);
+
+    // the logs of flushed memtables are deleted
+    assert!(count_files(&temp_dir, "wal") <= 2);
+
+    drop(store);
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+    check(&store)?;
+
+    // overwritten values were merged away: 20 versions of 500 keys are far
+    // larger than the live data
+    let sst_bytes: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("sst".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(sst_bytes < 20 * 500 * 20);
+    Ok(())
+}
+
+// Scans should merge the memtable with the tables, skipping removed keys
+#[test]
+fn scan_across_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            store.set(format!("key{:04}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{:04}", key_id))?;
+    }
+    assert!(count_files(&temp_dir, "sst") > 0);
+
+    let keys = store.scan("key0", None, usize::MAX)?;
+    let expected: Vec<String> = (1..1000).step_by(2).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let mut after = None;
+    let mut pages = Vec::new();
+    loop {
+        let page = store.scan("key", after.as_deref(), 100)?;
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().cloned();
+        pages.extend(page);
+    }
+    assert_eq!(pages, expected);
+    Ok(())
+}
+
+// A dropped namespace should not come back after flushes and reopening
+#[test]
+fn dropped_namespace_stays_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+
+    store.create_namespace("users")?;
+    for key_id in 0..1000 {
+        store.set_in("users", format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.drop_namespace("users")?;
+    store.create_namespace("users")?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    drop(store);
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+    assert_eq!(
+        store.list_namespaces()?,
+        vec!["default".to_owned(), "users".to_owned()]
+    );
+    assert_eq!(store.get_in("users", "key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Entries of a memtable whose table could not be written should stay readable
+// until a later flush writes them out
+#[test]
+fn failed_flush_keeps_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+
+    // directories in the way of the next tables make their creation fail
+    let blockers: Vec<_> = (0..200)
+        .map(|id| temp_dir.path().join(format!("{}.sst", id)))
+        .collect();
+    for blocker in &blockers {
+        fs::create_dir(blocker)?;
+    }
+    // fill two memtables so the failed one is retried
+    let mut failures = 0;
+    for key_id in 0..5000 {
+        // the write itself is logged even when the flush after it fails
+        if store.set(format!("key{}", key_id), "value".to_owned()).is_err() {
+            failures += 1;
+        }
+    }
+    assert!(failures >= 2);
+    for key_id in 0..5000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+
+    for blocker in &blockers {
+        fs::remove_dir(blocker)?;
+    }
+    for key_id in 5000..6000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert!(count_files(&temp_dir, "sst") > 0);
+
+    drop(store);
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+    for key_id in 0..6000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmEngine::open_with_options(temp_dir.path(), small_options())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
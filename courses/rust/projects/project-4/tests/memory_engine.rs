@@ -0,0 +1,119 @@
+use kvs::{InMemoryEngine, KvsEngine, Result};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn set_get_remove() -> Result<()> {
+    let store = InMemoryEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Dropping the last clone should dump a snapshot that is loaded on the next open
+#[test]
+fn reload_snapshot_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("memory.snapshot");
+
+    let store = InMemoryEngine::with_snapshot(&snapshot, None)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let clone = store.clone();
+    drop(store);
+    assert!(!snapshot.exists());
+    drop(clone);
+    assert!(snapshot.exists());
+
+    let store = InMemoryEngine::with_snapshot(&snapshot, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn periodic_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("memory.snapshot");
+
+    let store = InMemoryEngine::with_snapshot(&snapshot, Some(Duration::from_millis(50)))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert!(snapshot.exists());
+
+    // The snapshot written in the background is complete on its own
+    std::fs::copy(&snapshot, temp_dir.path().join("copy"))?;
+    let reopened = InMemoryEngine::with_snapshot(temp_dir.path().join("copy"), None)?;
+    assert_eq!(reopened.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Only snapshots of the current layout should load
+#[test]
+fn reject_other_snapshot_layouts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let current = temp_dir.path().join("current.snapshot");
+    fs::write(
+        &current,
+        r#"{"version":1,"namespaces":{"default":{"key1":"value1"},"users":{"key2":"value2"}}}"#,
+    )?;
+    let store = InMemoryEngine::with_snapshot(&current, None)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_in("users", "key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    let flat = temp_dir.path().join("flat.snapshot");
+    fs::write(&flat, r#"{"key1":"value1"}"#)?;
+    assert!(InMemoryEngine::with_snapshot(&flat, None).is_err());
+
+    let namespaced = temp_dir.path().join("namespaced.snapshot");
+    fs::write(&namespaced, r#"{"default":{"key1":"value1"}}"#)?;
+    assert!(InMemoryEngine::with_snapshot(&namespaced, None).is_err());
+
+    let newer = temp_dir.path().join("newer.snapshot");
+    fs::write(&newer, r#"{"version":2,"namespaces":{}}"#)?;
+    assert!(InMemoryEngine::with_snapshot(&newer, None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let store = InMemoryEngine::new();
+
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
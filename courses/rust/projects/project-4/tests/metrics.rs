@@ -0,0 +1,120 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use kvs::metrics::{self, Registry};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, Result};
+use tempfile::TempDir;
+
+// Metrics should be rendered in the Prometheus text format
+#[test]
+fn render_text_format() {
+    let registry = Registry::new();
+    registry
+        .counter("requests_total", "Requests", &[("request", "get")])
+        .inc_by(3);
+    registry
+        .counter("requests_total", "Requests", &[("request", "get")])
+        .inc();
+    registry
+        .gauge("connections", "Open connections", &[("peer", "a\"b")])
+        .set(-2);
+    let latency = registry.histogram("latency_seconds", "Latency", &[]);
+    latency.observe(0.002);
+    latency.observe(0.3);
+    latency.observe(100.0);
+
+    let text = registry.render();
+    assert!(text.contains("# HELP requests_total Requests\n# TYPE requests_total counter\n"));
+    assert!(text.contains("requests_total{request=\"get\"} 4\n"));
+    assert!(text.contains("# TYPE connections gauge\n"));
+    assert!(text.contains("connections{peer=\"a\\\"b\"} -2\n"));
+    assert!(text.contains("# TYPE latency_seconds histogram\n"));
+    assert!(text.contains("latency_seconds_bucket{le=\"0.001\"} 0\n"));
+    assert!(text.contains("latency_seconds_bucket{le=\"0.0025\"} 1\n"));
+    assert!(text.contains("latency_seconds_bucket{le=\"0.5\"} 2\n"));
+    assert!(text.contains("latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(text.contains("latency_seconds_count 3\n"));
+    assert!(text.contains("latency_seconds_sum 100.302\n"));
+}
+
+// Collectors should refresh metrics before each rendering
+#[test]
+fn collectors_run_on_render() {
+    let registry = Registry::new();
+    registry.add_collector(|registry| registry.gauge("scrapes", "Scrapes", &[]).inc());
+    assert!(registry.render().contains("scrapes 1\n"));
+    assert!(registry.render().contains("scrapes 2\n"));
+}
+
+// KvStore should count its keys, bytes on disk and compactions
+#[test]
+fn kv_store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1000);
+    for iter in 0..1500 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+    }
+    store.create_namespace("other")?;
+    store.set_in("other", "key".to_owned(), value.clone())?;
+
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, Some(11));
+    assert_eq!(stats.compactions, Some(1));
+    let disk_bytes = stats.disk_bytes.unwrap();
+    assert!(disk_bytes > 11 * 1000 && disk_bytes < 1024 * 1024);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, Some(11));
+    assert_eq!(stats.disk_bytes, Some(disk_bytes));
+    Ok(())
+}
+
+fn scrape(addr: &str, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+// The server should export request, connection and engine metrics over HTTP
+#[test]
+fn server_exports_metrics() -> Result<()> {
+    let (addr, metrics_addr) = ("127.0.0.1:4201", "127.0.0.1:4202");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    metrics::serve(server.registry(), metrics_addr)?;
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.remove("missing".to_owned()).is_err());
+
+    let response = scrape(metrics_addr, "/metrics")?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_requests_total{request=\"set\"} 2\n"));
+    assert!(response.contains("kvs_requests_total{request=\"get\"} 1\n"));
+    assert!(response.contains("kvs_requests_total{request=\"watch\"} 0\n"));
+    assert!(response.contains("kvs_request_errors_total{request=\"remove\"} 1\n"));
+    assert!(response.contains("kvs_request_errors_total{request=\"set\"} 0\n"));
+    assert!(response.contains("kvs_request_duration_seconds_count{request=\"set\"} 2\n"));
+    assert!(response.contains("kvs_open_connections 1\n"));
+    assert!(response.contains("kvs_thread_pool_queue_depth 0\n"));
+    assert!(response.contains("kvs_engine_live_keys 2\n"));
+    assert!(response.contains("kvs_engine_compactions_total 0\n"));
+
+    assert!(scrape(metrics_addr, "/other")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Access, Authenticator, Credentials, InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, Result, Scope,
+};
+use tempfile::TempDir;
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
+fn keys(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|&key| key.to_owned()).collect()
+}
+
+// A batch should report a result per key, in the order of the request
+fn per_key_results<E: KvsEngine>(store: &E) -> Result<()> {
+    let results = store.set_many(pairs(&[("key1", "value1"), ("key2", "value2")]))?;
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|res| res.is_ok()));
+
+    let values: Vec<_> = store
+        .get_many(keys(&["key2", "missing", "key1"]))?
+        .into_iter()
+        .map(|res| res.ok())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some(Some("value2".to_owned())),
+            Some(None),
+            Some(Some("value1".to_owned()))
+        ]
+    );
+
+    let results = store.remove_many(keys(&["key1", "missing"]))?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.get_many(Vec::new())?.is_empty());
+    Ok(())
+}
+
+// A missing namespace should fail the whole batch
+fn missing_namespace<E: KvsEngine>(store: &E) -> Result<()> {
+    assert!(matches!(
+        store.get_many_in("missing", keys(&["key1"])),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    assert!(matches!(
+        store.set_many_in("missing", pairs(&[("key1", "value1")])),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    assert!(matches!(
+        store.remove_many_in("missing", keys(&["key1"])),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn kvs_per_key_results() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    per_key_results(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn memory_per_key_results() -> Result<()> {
+    per_key_results(&InMemoryEngine::new())
+}
+
+#[test]
+fn kvs_missing_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    missing_namespace(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn memory_missing_namespace() -> Result<()> {
+    missing_namespace(&InMemoryEngine::new())
+}
+
+// Values spread over several log files should come back in the order of the
+// keys, not of the files
+#[test]
+fn kvs_get_many_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let names: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+    for (i, key) in names.iter().enumerate().rev() {
+        // reopening starts a new generation
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(key.clone(), format!("value{}", i))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key10".to_owned(), "updated".to_owned())?;
+    let values = store.get_many(names)?;
+    for (i, value) in values.into_iter().enumerate() {
+        let expected = if i == 10 {
+            "updated".to_owned()
+        } else {
+            format!("value{}", i)
+        };
+        assert_eq!(value?, Some(expected));
+    }
+    Ok(())
+}
+
+fn start_server(addr: &'static str, auth: Option<Authenticator>) -> Result<()> {
+    let mut server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// The clients should send a batch in one request and get a result per key
+#[test]
+fn client_batches() -> Result<()> {
+    let addr = "127.0.0.1:5401";
+    start_server(addr, None)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set_many(pairs(&[("key1", "value1"), ("key2", "value2")]))?;
+    let values = client.get_many(keys(&["key1", "missing", "key2"]))?;
+    assert_eq!(values[0].as_ref().ok(), Some(&Some("value1".to_owned())));
+    assert_eq!(values[1].as_ref().ok(), Some(&None));
+    assert_eq!(values[2].as_ref().ok(), Some(&Some("value2".to_owned())));
+
+    let results = client.remove_many(keys(&["key1", "missing"]))?;
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+
+    client.create_namespace("users")?;
+    client.set_many_in("users", pairs(&[("key1", "users")]))?;
+    let values = client.get_many_in("users", keys(&["key1"]))?;
+    assert_eq!(values[0].as_ref().ok(), Some(&Some("users".to_owned())));
+    assert!(client.get_many_in("missing", keys(&["key1"])).is_err());
+
+    let mut json = KvsClient::connect_json(addr)?;
+    let values = json.get_many(keys(&["key2"]))?;
+    assert_eq!(values[0].as_ref().ok(), Some(&Some("value2".to_owned())));
+    Ok(())
+}
+
+// A scope should allow a batch only if it allows every key in it
+#[test]
+fn scoped_batches() -> Result<()> {
+    let addr = "127.0.0.1:5402";
+    let mut auth = Authenticator::new();
+    auth.add_token("reader", Scope::new(Access::ReadOnly).with_prefix("public/"));
+    start_server(addr, Some(auth))?;
+    let credentials = Credentials::Token("reader".to_owned());
+    let mut client = KvsClient::connect_with_credentials(addr, credentials)?;
+
+    assert!(client.get_many(keys(&["public/a", "public/b"])).is_ok());
+    assert!(client.get_many(keys(&["public/a", "private/b"])).is_err());
+    assert!(client.set_many(pairs(&[("public/a", "value")])).is_err());
+    assert!(client.remove_many(keys(&["public/a"])).is_err());
+    Ok(())
+}
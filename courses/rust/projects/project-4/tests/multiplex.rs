@@ -0,0 +1,217 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::common::{GetResponse, Request, SetResponse};
+use kvs::protocol::{
+    self, ErrorCode, Features, ERROR, MAGIC, TAGGED_ERROR, TAGGED_MESSAGE, VERSION,
+};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Event, InMemoryEngine, KvsClient, KvsEngine, KvsServer, Result, WatchTarget, Watcher};
+
+/// An engine taking a second to get the keys starting with `slow`.
+#[derive(Clone)]
+struct SlowEngine(InMemoryEngine);
+
+impl KvsEngine for SlowEngine {
+    fn set_in(&self, namespace: &str, key: String, value: String) -> Result<()> {
+        self.0.set_in(namespace, key, value)
+    }
+
+    fn get_in(&self, namespace: &str, key: String) -> Result<Option<String>> {
+        if key.starts_with("slow") {
+            thread::sleep(Duration::from_secs(1));
+        }
+        self.0.get_in(namespace, key)
+    }
+
+    fn remove_in(&self, namespace: &str, key: String) -> Result<()> {
+        self.0.remove_in(namespace, key)
+    }
+
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        self.0.create_namespace(name)
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.0.drop_namespace(name)
+    }
+
+    fn list_namespaces(&self) -> Result<Vec<String>> {
+        self.0.list_namespaces()
+    }
+
+    fn watch_in(&self, namespace: &str, target: WatchTarget) -> Result<Watcher> {
+        self.0.watch_in(namespace, target)
+    }
+}
+
+fn start_server(addr: &'static str) -> Result<()> {
+    let engine = SlowEngine(InMemoryEngine::new());
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, kind: u8, id: u64, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32 + 9).to_be_bytes())?;
+    stream.write_all(&[kind])?;
+    stream.write_all(&id.to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body)?;
+    let payload = body.split_off(1);
+    Ok((body[0], payload))
+}
+
+/// Splits the ID off the payload of a tagged frame.
+fn split_id(mut payload: Vec<u8>) -> (u64, Vec<u8>) {
+    let rest = payload.split_off(8);
+    (u64::from_be_bytes(payload.try_into().unwrap()), rest)
+}
+
+// Threads sharing a client should each get the responses to their own
+// requests
+#[test]
+fn shared_connection() -> Result<()> {
+    let addr = "127.0.0.1:5301";
+    start_server(addr)?;
+    let client = KvsClient::connect(addr)?;
+    assert!(client.features().contains(Features::REQUEST_IDS));
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let mut client = client.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    // pipelined requests still run in order
+    let mut client = client;
+    let mut pipeline = client.pipeline();
+    pipeline.set("key".to_owned(), "value".to_owned());
+    pipeline.get("key".to_owned());
+    pipeline.remove("key".to_owned());
+    pipeline.get("key".to_owned());
+    let results = pipeline.execute()?;
+    assert_eq!(results[1].as_ref().ok(), Some(&Some("value".to_owned())));
+    assert_eq!(results[3].as_ref().ok(), Some(&None));
+    Ok(())
+}
+
+// A slow request should not hold up the requests sent after it on the same
+// connection
+#[test]
+fn slow_request() -> Result<()> {
+    let addr = "127.0.0.1:5302";
+    start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("slow".to_owned(), "value".to_owned())?;
+    client.set("fast".to_owned(), "value".to_owned())?;
+
+    let mut slow_client = client.clone();
+    let slow = thread::spawn(move || slow_client.get("slow".to_owned()));
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    assert_eq!(client.get("fast".to_owned())?, Some("value".to_owned()));
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(slow.join().unwrap()?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Tagged requests should be answered in the order they complete, each with
+// its ID
+#[test]
+fn tagged_frames() -> Result<()> {
+    let addr = "127.0.0.1:5303";
+    start_server(addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut handshake = MAGIC.to_vec();
+    handshake.extend_from_slice(&VERSION.to_be_bytes());
+    handshake.extend_from_slice(&Features::REQUEST_IDS.bits().to_be_bytes());
+    stream.write_all(&handshake)?;
+    let mut answer = [0; 10];
+    stream.read_exact(&mut answer)?;
+    assert_eq!(answer.to_vec(), handshake);
+
+    let get = protocol::encode(&Request::Get {
+        namespace: None,
+        key: "slow".to_owned(),
+    })?;
+    let set = protocol::encode(&Request::Set {
+        namespace: None,
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    })?;
+    write_frame(&mut stream, TAGGED_MESSAGE, 7, &get)?;
+    write_frame(&mut stream, TAGGED_MESSAGE, 3, &set)?;
+
+    let (kind, payload) = read_frame(&mut stream)?;
+    assert_eq!(kind, TAGGED_MESSAGE);
+    let (id, payload) = split_id(payload);
+    assert_eq!(id, 3);
+    assert!(matches!(protocol::decode(&payload)?, SetResponse::Ok(())));
+
+    let (kind, payload) = read_frame(&mut stream)?;
+    assert_eq!(kind, TAGGED_MESSAGE);
+    let (id, payload) = split_id(payload);
+    assert_eq!(id, 7);
+    assert!(matches!(protocol::decode(&payload)?, GetResponse::Ok(None)));
+
+    // a malformed request is reported under its ID, unless the ID is missing
+    write_frame(&mut stream, TAGGED_MESSAGE, 9, &[0xff, 0xff])?;
+    let (kind, payload) = read_frame(&mut stream)?;
+    assert_eq!(kind, TAGGED_ERROR);
+    let (id, payload) = split_id(payload);
+    assert_eq!(id, 9);
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    assert_eq!(ErrorCode::from_code(code), ErrorCode::Malformed);
+
+    stream.write_all(&3u32.to_be_bytes())?;
+    stream.write_all(&[TAGGED_MESSAGE, 0, 0])?;
+    let (kind, payload) = read_frame(&mut stream)?;
+    assert_eq!(kind, ERROR);
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    assert_eq!(ErrorCode::from_code(code), ErrorCode::Malformed);
+    Ok(())
+}
+
+// Once a clone watches, the connection streams events and the other clones'
+// later requests fail
+#[test]
+fn watch_takes_connection_over() -> Result<()> {
+    let addr = "127.0.0.1:5304";
+    start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    let mut events = client.clone().watch(WatchTarget::Key("key".to_owned()))?;
+    assert!(client.get("key".to_owned()).is_err());
+
+    KvsClient::connect(addr)?.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        events.next().unwrap()?,
+        Event::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        }
+    );
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use kvs::{
+    BTreeEngine, InMemoryEngine, KvStore, KvsEngine, KvsError, LsmEngine, Result, SledKvsEngine,
+};
+use tempfile::TempDir;
+
+// Keys in different namespaces should not see each other
+fn isolated_keyspaces<E: KvsEngine>(store: &E) -> Result<()> {
+    store.create_namespace("users")?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    store.set_in("users", "key1".to_owned(), "users".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(
+        store.get_in("users", "key1".to_owned())?,
+        Some("users".to_owned())
+    );
+
+    store.remove_in("users", "key1".to_owned())?;
+    assert_eq!(store.get_in("users", "key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+
+    assert_eq!(
+        store.list_namespaces()?,
+        vec!["default".to_owned(), "users".to_owned()]
+    );
+    Ok(())
+}
+
+fn create_and_drop<E: KvsEngine>(store: &E) -> Result<()> {
+    store.create_namespace("orders")?;
+    assert!(matches!(
+        store.create_namespace("orders"),
+        Err(KvsError::NamespaceExists)
+    ));
+    store.set_in("orders", "key1".to_owned(), "value1".to_owned())?;
+
+    store.drop_namespace("orders")?;
+    assert!(matches!(
+        store.get_in("orders", "key1".to_owned()),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    assert!(matches!(
+        store.set_in("orders", "key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    assert!(matches!(
+        store.drop_namespace("orders"),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    assert!(matches!(
+        store.drop_namespace("default"),
+        Err(KvsError::DefaultNamespace)
+    ));
+
+    // A recreated namespace starts empty
+    store.create_namespace("orders")?;
+    assert_eq!(store.get_in("orders", "key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_isolated_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    isolated_keyspaces(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_isolated_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    isolated_keyspaces(&SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn memory_isolated_keyspaces() -> Result<()> {
+    isolated_keyspaces(&InMemoryEngine::new())
+}
+
+#[test]
+fn lsm_isolated_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    isolated_keyspaces(&LsmEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn btree_isolated_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    isolated_keyspaces(&BTreeEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn kvs_create_and_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    create_and_drop(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_create_and_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    create_and_drop(&SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn memory_create_and_drop() -> Result<()> {
+    create_and_drop(&InMemoryEngine::new())
+}
+
+#[test]
+fn lsm_create_and_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    create_and_drop(&LsmEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn btree_create_and_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    create_and_drop(&BTreeEngine::open(temp_dir.path())?)
+}
+
+// Namespaces, their keys and drops should survive reopening the log
+#[test]
+fn kvs_namespaces_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    store.create_namespace("orders")?;
+    store.set_in("users", "key1".to_owned(), "value1".to_owned())?;
+    store.set_in("orders", "key1".to_owned(), "value2".to_owned())?;
+    store.drop_namespace("orders")?;
+    store.create_namespace("orders")?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.list_namespaces()?,
+        vec!["default".to_owned(), "orders".to_owned(), "users".to_owned()]
+    );
+    assert_eq!(
+        store.get_in("users", "key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_in("orders", "key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction should keep live namespaces and discard dropped ones
+#[test]
+fn kvs_compaction_with_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("users")?;
+    store.create_namespace("scratch")?;
+    store.set_in("scratch", "key".to_owned(), "value".to_owned())?;
+    store.drop_namespace("scratch")?;
+
+    // overwrite enough data to trigger at least one compaction
+    for iter in 0..300 {
+        for key_id in 0..100 {
+            store.set_in("users", format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.list_namespaces()?,
+        vec!["default".to_owned(), "users".to_owned()]
+    );
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get_in("users", format!("key{}", key_id))?,
+            Some("299".to_owned())
+        );
+    }
+
+    Ok(())
+}
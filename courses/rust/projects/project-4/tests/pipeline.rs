@@ -0,0 +1,74 @@
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result};
+use tempfile::TempDir;
+
+// Pipelined requests should be answered in order, each with its own result
+#[test]
+fn pipeline_results_in_order() -> Result<()> {
+    let addr = "127.0.0.1:4401";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    pipeline
+        .remove("key0".to_owned())
+        .remove("missing".to_owned());
+    for i in 0..1000 {
+        pipeline.get(format!("key{}", i));
+    }
+    assert_eq!(pipeline.len(), 2002);
+
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 2002);
+    for result in &results[..1001] {
+        assert_eq!(result.as_ref().ok(), Some(&None));
+    }
+    assert!(results[1001].is_err());
+    assert_eq!(results[1002].as_ref().ok(), Some(&None));
+    for (i, result) in results[1003..].iter().enumerate() {
+        assert_eq!(result.as_ref().ok(), Some(&Some(format!("value{}", i + 1))));
+    }
+
+    // the client is still usable after a pipeline
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.pipeline().execute()?.is_empty());
+    Ok(())
+}
+
+// Large responses should not stall a pipeline while its requests are sent
+#[test]
+fn pipeline_large_values() -> Result<()> {
+    let addr = "127.0.0.1:4402";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let value = "v".repeat(10_000);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), value.clone())?;
+
+    let mut pipeline = client.pipeline();
+    for _ in 0..300 {
+        pipeline.get("key".to_owned());
+    }
+    for result in pipeline.execute()? {
+        assert_eq!(result?, Some(value.clone()));
+    }
+    Ok(())
+}
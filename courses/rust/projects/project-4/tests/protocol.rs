@@ -0,0 +1,227 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use kvs::common::{GetResponse, Request, SetResponse};
+use kvs::protocol::{self, ErrorCode, Features, Protocol, ERROR, MAGIC, MESSAGE, VERSION};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsServer, Change, ChangeOp, InMemoryEngine, KvsClient, KvsError, KvsServer, Result,
+};
+use serde_json::Deserializer;
+
+fn start_server(addr: &'static str) -> Result<()> {
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+fn start_async_server(addr: &'static str) -> Result<()> {
+    let server = AsyncKvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+fn handshake(version: u16, features: u32) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.extend_from_slice(&features.to_be_bytes());
+    bytes
+}
+
+fn write_frame(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32 + 1).to_be_bytes())?;
+    stream.write_all(&[kind])?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body)?;
+    let payload = body.split_off(1);
+    Ok((body[0], payload))
+}
+
+// Messages should survive the binary encoding and be smaller than in JSON
+#[test]
+fn encoding_round_trip() -> Result<()> {
+    let changes = vec![
+        Change {
+            seq: 1,
+            namespace: "default".to_owned(),
+            op: ChangeOp::Set {
+                key: "key".to_owned(),
+                value: "välue".to_owned(),
+            },
+        },
+        Change {
+            seq: u64::MAX,
+            namespace: "users".to_owned(),
+            op: ChangeOp::DropNamespace,
+        },
+    ];
+    let bytes = protocol::encode(&changes)?;
+    assert_eq!(protocol::decode::<Vec<Change>>(&bytes)?, changes);
+    assert!(bytes.len() < serde_json::to_vec(&changes)?.len() / 2);
+
+    // fields skipped when serializing are decoded to their default
+    let bytes = protocol::encode(&Request::Get {
+        namespace: None,
+        key: "key".to_owned(),
+    })?;
+    match protocol::decode(&bytes)? {
+        Request::Get { namespace, key } => {
+            assert_eq!(namespace, None);
+            assert_eq!(key, "key");
+        }
+        req => panic!("Unexpected request {:?}", req),
+    }
+
+    assert!(protocol::decode::<Vec<Change>>(&bytes[..bytes.len() - 1]).is_err());
+    assert!(protocol::decode::<Request>(&[bytes.as_slice(), &[0]].concat()).is_err());
+    Ok(())
+}
+
+// Clients should negotiate the binary protocol, and JSON should still be served
+#[test]
+fn binary_and_json_clients() -> Result<()> {
+    for (addr, start) in [
+        (
+            "127.0.0.1:4501",
+            start_server as fn(&'static str) -> Result<()>,
+        ),
+        ("127.0.0.1:4502", start_async_server),
+    ] {
+        start(addr)?;
+
+        let mut client = KvsClient::connect(addr)?;
+        assert_eq!(client.protocol(), Protocol::Binary);
+        assert_eq!(client.features(), Features::SUPPORTED);
+        client.set("key".to_owned(), "value".to_owned())?;
+        assert!(client.remove("missing".to_owned()).is_err());
+
+        let mut json = KvsClient::connect_json(addr)?;
+        assert_eq!(json.protocol(), Protocol::Json);
+        assert_eq!(json.get("key".to_owned())?, Some("value".to_owned()));
+        json.set("key".to_owned(), "other".to_owned())?;
+        assert_eq!(client.get("key".to_owned())?, Some("other".to_owned()));
+
+        // a client written before the handshake existed
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(br#" {"Get":{"key":"key"}} "ListNamespaces""#)?;
+        let mut responses = Deserializer::from_reader(&stream).into_iter::<serde_json::Value>();
+        assert_eq!(
+            responses.next().unwrap()?,
+            serde_json::json!({"Ok": "other"})
+        );
+        assert_eq!(
+            responses.next().unwrap()?,
+            serde_json::json!({"Ok": ["default"]})
+        );
+    }
+    Ok(())
+}
+
+// A malformed frame should be answered with an error frame and not break
+// the frames after it
+#[test]
+fn malformed_frames() -> Result<()> {
+    for (addr, start) in [
+        (
+            "127.0.0.1:4503",
+            start_server as fn(&'static str) -> Result<()>,
+        ),
+        ("127.0.0.1:4504", start_async_server),
+    ] {
+        start(addr)?;
+
+        let mut stream = TcpStream::connect(addr)?;
+        // unknown features are dropped from the answer
+        stream.write_all(&handshake(VERSION + 1, u32::MAX))?;
+        let mut answer = [0; 10];
+        stream.read_exact(&mut answer)?;
+        assert_eq!(
+            answer.to_vec(),
+            handshake(VERSION, Features::SUPPORTED.bits())
+        );
+
+        write_frame(&mut stream, MESSAGE, &[0xff, 0xff])?;
+        write_frame(&mut stream, 42, b"unknown kind")?;
+        let set = protocol::encode(&Request::Set {
+            namespace: None,
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+        })?;
+        write_frame(&mut stream, MESSAGE, &set)?;
+
+        for _ in 0..2 {
+            let (kind, payload) = read_frame(&mut stream)?;
+            assert_eq!(kind, ERROR);
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            assert_eq!(ErrorCode::from_code(code), ErrorCode::Malformed);
+        }
+        let (kind, payload) = read_frame(&mut stream)?;
+        assert_eq!(kind, MESSAGE);
+        assert!(matches!(protocol::decode(&payload)?, SetResponse::Ok(())));
+
+        // an oversized frame cannot be skipped, so the connection is closed
+        stream.write_all(&u32::MAX.to_be_bytes())?;
+        let (kind, payload) = read_frame(&mut stream)?;
+        assert_eq!(kind, ERROR);
+        let code = u16::from_be_bytes([payload[0], payload[1]]);
+        assert_eq!(ErrorCode::from_code(code), ErrorCode::FrameTooLarge);
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+    }
+    Ok(())
+}
+
+// Servers should refuse a handshake without a version in common
+#[test]
+fn unsupported_version() -> Result<()> {
+    let addr = "127.0.0.1:4505";
+    start_server(addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&handshake(0, 0))?;
+    let mut answer = [0; 10];
+    stream.read_exact(&mut answer)?;
+    assert_eq!(answer.to_vec(), handshake(0, 0));
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// Clients should fall back to JSON when the server does not speak the
+// binary protocol
+#[test]
+fn fall_back_to_json() -> Result<()> {
+    let addr = "127.0.0.1:4506";
+    let listener = TcpListener::bind(addr)?;
+    // a server predating the handshake, closing connections on invalid JSON
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            for req in Deserializer::from_reader(&stream).into_iter::<Request>() {
+                let resp = match req {
+                    Ok(Request::Get { key, .. }) => GetResponse::Ok(Some(key)),
+                    _ => break,
+                };
+                serde_json::to_writer(&stream, &resp).unwrap();
+            }
+        }
+    });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol(), Protocol::Json);
+    assert_eq!(client.get("key".to_owned())?, Some("key".to_owned()));
+    match client.set("key".to_owned(), "value".to_owned()) {
+        Err(KvsError::Io(_)) => {}
+        res => panic!("Unexpected result {:?}", res.map_err(|e| e.to_string())),
+    }
+    Ok(())
+}
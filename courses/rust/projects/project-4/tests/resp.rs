@@ -0,0 +1,223 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{InMemoryEngine, KvStore, KvsEngine, RespServer, Result};
+use tempfile::TempDir;
+
+fn start_server<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+    let server = RespServer::new(engine, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+/// Encodes a command as an array of bulk strings.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        bytes.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    bytes
+}
+
+/// Reads a reply, rendering it in the notation of redis-cli.
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let line = line.trim_end();
+    let (kind, rest) = line.split_at(1);
+    Ok(match kind {
+        "+" => rest.to_owned(),
+        "-" => format!("(error) {}", rest),
+        ":" => format!("(integer) {}", rest),
+        "$" if rest == "-1" => "(nil)".to_owned(),
+        "$" => {
+            let mut bulk = vec![0; rest.parse::<usize>().unwrap() + 2];
+            reader.read_exact(&mut bulk)?;
+            format!("{:?}", String::from_utf8_lossy(&bulk[..bulk.len() - 2]))
+        }
+        "*" => {
+            let items = (0..rest.parse().unwrap())
+                .map(|_| read_reply(reader))
+                .collect::<Result<Vec<_>>>()?;
+            format!("[{}]", items.join(", "))
+        }
+        _ => panic!("Unexpected reply {:?}", line),
+    })
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(addr: &str) -> Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(Connection {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<String> {
+        self.writer.write_all(&command(args))?;
+        read_reply(&mut self.reader)
+    }
+}
+
+// The supported commands should behave as in Redis
+#[test]
+fn commands() -> Result<()> {
+    let addr = "127.0.0.1:4601";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server(KvStore::open(temp_dir.path())?, addr)?;
+    let mut conn = Connection::open(addr)?;
+
+    assert_eq!(conn.call(&["PING"])?, "PONG");
+    assert_eq!(conn.call(&["ping", "hello"])?, "\"hello\"");
+    assert_eq!(conn.call(&["GET", "key1"])?, "(nil)");
+    assert_eq!(conn.call(&["SET", "key1", "value 1"])?, "OK");
+    assert_eq!(conn.call(&["SET", "key2", ""])?, "OK");
+    assert_eq!(conn.call(&["Get", "key1"])?, "\"value 1\"");
+    assert_eq!(conn.call(&["GET", "key2"])?, "\"\"");
+    assert_eq!(
+        conn.call(&["EXISTS", "key1", "key2", "key3", "key1"])?,
+        "(integer) 3"
+    );
+    assert_eq!(conn.call(&["DBSIZE"])?, "(integer) 2");
+    assert_eq!(conn.call(&["MSET", "key3", "value 3", "key4", "value 4"])?, "OK");
+    assert_eq!(
+        conn.call(&["MGET", "key1", "key5", "key4"])?,
+        "[\"value 1\", (nil), \"value 4\"]"
+    );
+    assert_eq!(conn.call(&["DEL", "key3", "key4"])?, "(integer) 2");
+    assert_eq!(conn.call(&["DEL", "key1", "key3"])?, "(integer) 1");
+    assert_eq!(conn.call(&["GET", "key1"])?, "(nil)");
+    assert_eq!(conn.call(&["DBSIZE"])?, "(integer) 1");
+
+    let info = conn.call(&["INFO"])?;
+    assert!(info.contains(&format!("kvs_version:{}", env!("CARGO_PKG_VERSION"))));
+    assert!(info.contains("db0:keys=1"));
+
+    assert_eq!(
+        conn.call(&["HGET", "hash", "field"])?,
+        "(error) ERR unknown command 'hget'"
+    );
+    assert_eq!(
+        conn.call(&["GET"])?,
+        "(error) ERR wrong number of arguments for 'get' command"
+    );
+    assert_eq!(
+        conn.call(&["DEL"])?,
+        "(error) ERR wrong number of arguments for 'del' command"
+    );
+    assert_eq!(
+        conn.call(&["MSET", "key", "value", "key2"])?,
+        "(error) ERR wrong number of arguments for 'mset' command"
+    );
+    assert_eq!(
+        conn.call(&["SET", "key", "value", "EX", "10"])?,
+        "(error) ERR SET options are not supported"
+    );
+    assert_eq!(conn.call(&["GET", "key"])?, "(nil)");
+
+    assert_eq!(conn.call(&["QUIT"])?, "OK");
+    assert_eq!(conn.reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// SCAN should page through the keys, filtering them with MATCH
+#[test]
+fn scan() -> Result<()> {
+    let addr = "127.0.0.1:4602";
+    let engine = InMemoryEngine::new();
+    for i in 0..25 {
+        engine.set(format!("user:{:02}", i), "value".to_owned())?;
+        engine.set(format!("item:{:02}", i), "value".to_owned())?;
+    }
+    start_server(engine, addr)?;
+    let mut conn = Connection::open(addr)?;
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        conn.writer.write_all(&command(&[
+            "SCAN", &cursor, "MATCH", "user:*", "COUNT", "10",
+        ]))?;
+        let reply = read_reply(&mut conn.reader)?;
+        let (next, page) = reply
+            .trim_start_matches("[\"")
+            .trim_end_matches(']')
+            .split_once("\", [")
+            .unwrap();
+        keys.extend(
+            page.split(", ")
+                .filter(|key| !key.is_empty())
+                .map(str::to_owned),
+        );
+        cursor = next.to_owned();
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<String> = (0..25).map(|i| format!("\"user:{:02}\"", i)).collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(
+        conn.call(&["SCAN", "0", "MATCH", "*:1[0-2]", "COUNT", "100"])?,
+        "[\"0\", [\"item:10\", \"item:11\", \"item:12\", \"user:10\", \"user:11\", \"user:12\"]]"
+    );
+    assert_eq!(
+        conn.call(&["SCAN", "0", "MATCH", "user:?[^0-8]", "COUNT", "30"])?,
+        "[\"0\", [\"user:09\", \"user:19\"]]"
+    );
+    assert_eq!(
+        conn.call(&["SCAN", "0", "COUNT", "2"])?,
+        "[\"2\", [\"item:00\", \"item:01\"]]"
+    );
+    assert_eq!(
+        conn.call(&["SCAN", "cursor"])?,
+        "(error) ERR invalid cursor"
+    );
+    assert_eq!(
+        conn.call(&["SCAN", "0", "COUNT"])?,
+        "(error) ERR syntax error"
+    );
+    Ok(())
+}
+
+// Inline commands and pipelined commands should be answered in order, and a
+// protocol error should close the connection
+#[test]
+fn inline_and_pipelined_commands() -> Result<()> {
+    let addr = "127.0.0.1:4603";
+    start_server(InMemoryEngine::new(), addr)?;
+    let mut conn = Connection::open(addr)?;
+
+    conn.writer.write_all(b"SET key value\r\nGET  key\n\r\n")?;
+    let mut pipelined = Vec::new();
+    for i in 0..100 {
+        pipelined.extend(command(&["SET", &format!("key{}", i), "value"]));
+    }
+    pipelined.extend(command(&["DBSIZE"]));
+    conn.writer.write_all(&pipelined)?;
+
+    assert_eq!(read_reply(&mut conn.reader)?, "OK");
+    assert_eq!(read_reply(&mut conn.reader)?, "\"value\"");
+    for _ in 0..100 {
+        assert_eq!(read_reply(&mut conn.reader)?, "OK");
+    }
+    assert_eq!(read_reply(&mut conn.reader)?, "(integer) 101");
+
+    conn.writer.write_all(b"*1\r\n+PING\r\n")?;
+    assert_eq!(
+        read_reply(&mut conn.reader)?,
+        "(error) ERR Protocol error: expected '$', got '+'"
+    );
+    assert_eq!(conn.reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
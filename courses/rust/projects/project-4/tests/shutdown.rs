@@ -0,0 +1,139 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsServer, LsmEngine, Result, WatchTarget,
+};
+use tempfile::TempDir;
+
+// Shutting down should close idle connections, stop accepting new ones and
+// leave the engine synced on disk
+#[test]
+fn shutdown_running_server() -> Result<()> {
+    let addr = "127.0.0.1:4801";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!handle.is_shutdown());
+
+    let start = Instant::now();
+    handle.shutdown();
+    running.join().unwrap()?;
+    // the idle connection does not hold the shutdown back
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(handle.is_shutdown());
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(TcpStream::connect(addr).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // shutting down twice is harmless
+    handle.shutdown();
+    Ok(())
+}
+
+// Pipelined requests read before the shutdown should all be answered
+#[test]
+fn in_flight_requests_finish() -> Result<()> {
+    let addr = "127.0.0.1:4802";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        LsmEngine::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), "value".to_owned());
+    }
+    let shutdown = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        handle.shutdown();
+    });
+    // requests cut off by the shutdown fail, but never get a wrong answer
+    let answered = match pipeline.execute() {
+        Ok(results) => results.into_iter().filter(|res| res.is_ok()).count(),
+        Err(_) => 0,
+    };
+    shutdown.join().unwrap();
+    running.join().unwrap()?;
+
+    let store = LsmEngine::open(temp_dir.path())?;
+    let stored = (0..1000)
+        .filter(|i| store.get(format!("key{}", i)).unwrap().is_some())
+        .count();
+    assert!(stored >= answered);
+    Ok(())
+}
+
+// Connections still busy at the deadline should be closed
+#[test]
+fn shutdown_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4803";
+    let engine = InMemoryEngine::new();
+    engine.set("key".to_owned(), "x".repeat(1024 * 1024))?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?)
+        .with_shutdown_timeout(Duration::from_millis(300));
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // the server blocks writing responses the client never reads
+    let mut stream = TcpStream::connect(addr)?;
+    for _ in 0..50 {
+        stream.write_all(br#"{"Get":{"key":"key"}}"#)?;
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let start = Instant::now();
+    handle.shutdown();
+    running.join().unwrap()?;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(5));
+    Ok(())
+}
+
+// A watch should end when the server shuts down rather than hold it back
+#[test]
+fn shutdown_ends_watches() -> Result<()> {
+    let addr = "127.0.0.1:5412";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let client = KvsClient::connect(addr)?;
+    let mut events = client.watch(WatchTarget::Prefix(String::new()))?;
+
+    let start = Instant::now();
+    handle.shutdown();
+    running.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(events.next().is_none_or(|event| event.is_err()));
+    Ok(())
+}
+
+// A server shut down before it runs should return right away
+#[test]
+fn shutdown_before_run() -> Result<()> {
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?);
+    server.shutdown_handle().shutdown();
+    server.run("127.0.0.1:4804")
+}
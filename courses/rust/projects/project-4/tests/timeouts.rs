@@ -0,0 +1,96 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{InMemoryEngine, KvsClient, KvsError, KvsServer, Result};
+
+/// Reads until the server closes the connection, returning how long it took.
+fn wait_closed(mut stream: TcpStream) -> Duration {
+    let start = Instant::now();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // a reset counts as closed too
+    while let Ok(len) = stream.read(&mut [0; 64]) {
+        if len == 0 {
+            break;
+        }
+    }
+    start.elapsed()
+}
+
+// Idle connections should be closed, freeing their thread for other clients
+#[test]
+fn idle_connections_closed() -> Result<()> {
+    let addr = "127.0.0.1:5001";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?)
+        .with_idle_timeout(Some(Duration::from_millis(300)));
+    let registry = server.registry();
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    // a client sending nothing at all holds the only thread
+    assert!(wait_closed(TcpStream::connect(addr)?) < Duration::from_secs(2));
+
+    // a client idle between requests is closed too
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key".to_owned()).is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(registry
+        .render()
+        .contains("kvs_idle_connections_closed_total 2\n"));
+    Ok(())
+}
+
+// A request started but not finished in time should close the connection
+#[test]
+fn read_timeout() -> Result<()> {
+    let addr = "127.0.0.1:5002";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?)
+        .with_read_timeout(Some(Duration::from_millis(300)))
+        .with_idle_timeout(None);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"#)?;
+    assert!(wait_closed(stream) < Duration::from_secs(2));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// The client should time out with a distinct error when the server does not
+// answer
+#[test]
+fn client_timeouts() -> Result<()> {
+    let addr = "127.0.0.1:5003";
+    let listener = TcpListener::bind(addr)?;
+    // accepts connections but never answers them
+    thread::spawn(move || {
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
+    });
+
+    let start = Instant::now();
+    match KvsClient::connect_timeout(addr, Duration::from_millis(300), None) {
+        Err(KvsError::Timeout) => {}
+        res => panic!("Expected a timeout, got {:?}", res.map(|_| ())),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let mut client = KvsClient::connect_json(addr)?;
+    client.set_read_timeout(Some(Duration::from_millis(300)))?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::Timeout) => {}
+        res => panic!("Expected a timeout, got {:?}", res),
+    }
+    Ok(())
+}
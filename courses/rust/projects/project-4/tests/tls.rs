@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    Access, Authenticator, ClientTls, Credentials, InMemoryEngine, KvsClient, KvsError, KvsServer,
+    Result, Scope, ServerTls,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use tempfile::TempDir;
+
+/// A certificate authority writing the certificates it issues to PEM files.
+struct Ca {
+    dir: TempDir,
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new() -> Ca {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "kvs test CA");
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(dir.path().join("ca.pem"), cert.pem()).unwrap();
+        Ca { dir, cert, key }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    /// Issues a certificate to `name`, returning the certificate and key files.
+    fn issue(
+        &self,
+        name: &str,
+        sans: &[&str],
+        usage: ExtendedKeyUsagePurpose,
+    ) -> (PathBuf, PathBuf) {
+        let sans: Vec<String> = sans.iter().map(|&san| san.to_owned()).collect();
+        let mut params = CertificateParams::new(sans).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+        let cert_path = self.dir.path().join(format!("{}.pem", name));
+        let key_path = self.dir.path().join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn server_tls(&self) -> Result<ServerTls> {
+        let (cert, key) = self.issue(
+            "server",
+            &["localhost", "127.0.0.1"],
+            ExtendedKeyUsagePurpose::ServerAuth,
+        );
+        ServerTls::new(cert, key)
+    }
+
+    fn client_tls(&self, name: &str) -> Result<ClientTls> {
+        let (cert, key) = self.issue(name, &[], ExtendedKeyUsagePurpose::ClientAuth);
+        ClientTls::with_client_auth(self.path(), cert, key)
+    }
+}
+
+// Clients trusting the CA of the server should talk to it over TLS, and the
+// others should fail
+#[test]
+fn round_trip() -> Result<()> {
+    let addr = "127.0.0.1:5201";
+    let ca = Ca::new();
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?)
+        .with_tls(ca.server_tls()?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let tls = ClientTls::new(ca.path())?;
+    let mut client = KvsClient::connect_tls(addr, &tls)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let tls = ClientTls::new(ca.path())?.with_server_name("localhost")?;
+    let mut client = KvsClient::connect_tls(addr, &tls)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let tls = ClientTls::new(ca.path())?.with_server_name("example.com")?;
+    assert!(KvsClient::connect_tls(addr, &tls).is_err());
+
+    let other = Ca::new();
+    assert!(KvsClient::connect_tls(addr, &ClientTls::new(other.path())?).is_err());
+
+    // the server does not speak in clear
+    let res = KvsClient::connect(addr).and_then(|mut client| client.get("key".to_owned()));
+    assert!(res.is_err());
+    Ok(())
+}
+
+// With mutual TLS, clients should need a certificate from the client CA, and
+// be granted the scope of its subject
+#[test]
+fn mutual_tls() -> Result<()> {
+    let addr = "127.0.0.1:5202";
+    let ca = Ca::new();
+    let (cert, key) = ca.issue(
+        "server",
+        &["127.0.0.1"],
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let mut auth = Authenticator::new();
+    auth.add_certificate(
+        "backup",
+        Scope::new(Access::ReadOnly).with_prefix("public/"),
+    );
+    auth.add_token("token", Scope::new(Access::ReadWrite));
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?)
+        .with_tls(ServerTls::with_client_auth(cert, key, ca.path())?)
+        .with_auth(auth);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect_tls(addr, &ca.client_tls("backup")?)?;
+    assert_eq!(client.get("public/key".to_owned())?, None);
+    match client.set("public/key".to_owned(), "value".to_owned()) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("Expected a permission denied error, got {:?}", res),
+    }
+
+    // an unknown subject still has to authenticate
+    let mut client = KvsClient::connect_tls(addr, &ca.client_tls("unknown")?)?;
+    match client.get("public/key".to_owned()) {
+        Err(KvsError::Unauthenticated(_)) => {}
+        res => panic!("Expected an unauthenticated error, got {:?}", res),
+    }
+    client.authenticate(Credentials::Token("token".to_owned()))?;
+    client.set("public/key".to_owned(), "value".to_owned())?;
+
+    let res = KvsClient::connect_tls(addr, &ClientTls::new(ca.path())?)
+        .and_then(|mut client| client.get("public/key".to_owned()));
+    assert!(res.is_err());
+
+    let other = Ca::new();
+    let (cert, key) = other.issue("backup", &[], ExtendedKeyUsagePurpose::ClientAuth);
+    let tls = ClientTls::with_client_auth(ca.path(), cert, key)?;
+    let res = KvsClient::connect_tls(addr, &tls)
+        .and_then(|mut client| client.get("public/key".to_owned()));
+    assert!(res.is_err());
+    Ok(())
+}
@@ -0,0 +1,69 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{InMemoryEngine, KvsClient, KvsServer, Result};
+use tempfile::TempDir;
+
+// Clients should reach the server through its socket file, which is created
+// with the given permissions and removed on shutdown
+#[test]
+fn client_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", path.display());
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?)
+        .with_socket_mode(0o600);
+    let handle = server.shutdown_handle();
+    let server_addr = addr.clone();
+    let server = thread::spawn(move || server.run(server_addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mode = fs::metadata(&path)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let mut client = KvsClient::connect_json(&addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+
+    handle.shutdown();
+    server.join().unwrap()?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// A socket file left behind should be replaced, but not one still in use nor
+// a file that is not a socket
+#[test]
+fn stale_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", path.display());
+    drop(UnixListener::bind(&path)?);
+    assert!(path.exists());
+
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?);
+    let server_addr = addr.clone();
+    thread::spawn(move || server.run(server_addr));
+    thread::sleep(Duration::from_millis(500));
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let other = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?);
+    assert!(other.run(&addr).is_err());
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "data")?;
+    let other = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?);
+    assert!(other.run(format!("unix:{}", file.display())).is_err());
+    assert_eq!(fs::read_to_string(&file)?, "data");
+    Ok(())
+}
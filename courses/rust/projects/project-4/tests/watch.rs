@@ -0,0 +1,226 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    BTreeEngine, Event, InMemoryEngine, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, LsmEngine,
+    Result, SledKvsEngine, WatchTarget,
+};
+use tempfile::TempDir;
+
+// A prefix watcher should see the sets and removes under the prefix, in order
+fn watch_prefix<E: KvsEngine>(store: &E) -> Result<()> {
+    let mut watcher = store.watch(WatchTarget::Prefix("user:".to_owned()))?;
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.remove("user:1".to_owned())?;
+
+    assert_eq!(
+        watcher.next().unwrap()?,
+        Event::Set {
+            key: "user:1".to_owned(),
+            value: "alice".to_owned()
+        }
+    );
+    assert_eq!(
+        watcher.next().unwrap()?,
+        Event::Removed {
+            key: "user:1".to_owned()
+        }
+    );
+    Ok(())
+}
+
+fn watch_key<E: KvsEngine>(store: &E) -> Result<()> {
+    store.create_namespace("users")?;
+    let mut watcher = store.watch_in("users", WatchTarget::Key("key".to_owned()))?;
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    store.set_in("users", "key1".to_owned(), "other".to_owned())?;
+    store.set_in("users", "key".to_owned(), "value".to_owned())?;
+
+    assert_eq!(
+        watcher.next().unwrap()?,
+        Event::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned()
+        }
+    );
+    assert!(matches!(
+        store.watch_in("missing", WatchTarget::Key("key".to_owned())),
+        Err(KvsError::NamespaceNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn kvs_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    watch_prefix(&store)?;
+    watch_key(&store)
+}
+
+#[test]
+fn sled_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    watch_prefix(&store)?;
+    watch_key(&store)
+}
+
+#[test]
+fn lsm_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::open(temp_dir.path())?;
+    watch_prefix(&store)?;
+    watch_key(&store)
+}
+
+#[test]
+fn btree_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeEngine::open(temp_dir.path())?;
+    watch_prefix(&store)?;
+    watch_key(&store)
+}
+
+#[test]
+fn memory_watch() -> Result<()> {
+    let store = InMemoryEngine::new();
+    watch_prefix(&store)?;
+    watch_key(&store)
+}
+
+// Dropping a sled watcher should end the thread forwarding its events
+#[cfg(target_os = "linux")]
+#[test]
+fn sled_watch_thread_ends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    let watcher = store.watch(WatchTarget::Key("key".to_owned()))?;
+    assert!(wait_for_sled_watch_threads(|count| count > 0)?);
+
+    drop(watcher);
+    assert!(wait_for_sled_watch_threads(|count| count == 0)?);
+    Ok(())
+}
+
+// The threads take their name once they start, so poll for a few seconds
+#[cfg(target_os = "linux")]
+fn wait_for_sled_watch_threads(f: impl Fn(usize) -> bool) -> Result<bool> {
+    for _ in 0..50 {
+        if f(sled_watch_threads()?) {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(false)
+}
+
+#[cfg(target_os = "linux")]
+fn sled_watch_threads() -> Result<usize> {
+    let mut count = 0;
+    for task in fs::read_dir("/proc/self/task")? {
+        // the thread may have exited since it was listed
+        if let Ok(name) = fs::read_to_string(task?.path().join("comm")) {
+            if name.trim_end() == "kvs-sled-watch" {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+// Writers should not block on a watcher that never reads
+#[test]
+fn slow_watcher_is_disconnected() -> Result<()> {
+    let store = InMemoryEngine::new();
+    let watcher = store.watch(WatchTarget::Prefix(String::new()))?;
+
+    for i in 0..5000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let events: Vec<_> = watcher.collect();
+    assert!(events.len() < 5000);
+    assert!(matches!(events.last(), Some(Err(KvsError::WatcherLagged))));
+    Ok(())
+}
+
+#[test]
+fn watch_over_network() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(4)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let watcher = KvsClient::connect(addr)?;
+    let mut events = watcher.watch(WatchTarget::Prefix("key".to_owned()))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    assert_eq!(
+        events.next().unwrap()?,
+        Event::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        }
+    );
+    assert_eq!(
+        events.next().unwrap()?,
+        Event::Removed {
+            key: "key1".to_owned()
+        }
+    );
+    Ok(())
+}
+
+// Watch streams should not hold the threads of the pool
+#[test]
+fn watch_leaves_pool_free() -> Result<()> {
+    let addr = "127.0.0.1:5410";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(1)?);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let watchers = (0..3)
+        .map(|_| KvsClient::connect(addr)?.watch(WatchTarget::Key("key".to_owned())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    for mut events in watchers {
+        assert_eq!(
+            events.next().unwrap()?,
+            Event::Set {
+                key: "key".to_owned(),
+                value: "value".to_owned()
+            }
+        );
+    }
+    Ok(())
+}
+
+// A client going away should end its watch and free its connection
+#[test]
+fn dropped_watch_frees_connection() -> Result<()> {
+    let addr = "127.0.0.1:5413";
+    let server = KvsServer::new(InMemoryEngine::new(), SharedQueueThreadPool::new(2)?)
+        .with_max_connections(1);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let events = KvsClient::connect(addr)?.watch(WatchTarget::Key("key".to_owned()))?;
+    drop(events);
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    Ok(())
+}